@@ -1,11 +1,71 @@
 use anyhow::{anyhow, Result};
 use chacha20poly1305::{
-    aead::{generic_array::GenericArray, Aead, KeyInit, OsRng},
+    aead::{generic_array::GenericArray, rand_core::RngCore, Aead, KeyInit, OsRng},
     XChaCha20Poly1305, XNonce,
 };
 use std::fs::File;
 use std::io::{BufReader, Cursor, Read, Write};
 
+/// Size in bytes of each plaintext chunk encrypted with XChaCha20-Poly1305.
+pub const ENCRYPTION_CHUNK_SIZE: usize = 1 << 18;
+
+/// Size in bytes of the Poly1305 authentication tag appended to each encrypted chunk.
+pub const ENCRYPTION_TAG_SIZE: usize = 16;
+
+/// Size in bytes of each chunk of an encrypted file, i.e. a plaintext chunk plus its tag.
+pub const ENCRYPTED_CHUNK_SIZE: usize = ENCRYPTION_CHUNK_SIZE + ENCRYPTION_TAG_SIZE;
+
+/// `ENCRYPTION_CHUNK_SIZE` as a power of 2, as recorded in encrypted CIDs.
+pub const ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2: u8 = ENCRYPTION_CHUNK_SIZE.trailing_zeros() as u8;
+
+/// Chunk size, as a power of 2, of files encrypted by vup: 256 KiB plaintext chunks, each
+/// followed by its 16 byte tag.
+pub const VUP_CHUNK_SIZE_AS_POWER_OF_2: u8 = 18;
+
+// Files encrypted under a new random key must stay decryptable by vup's `decrypt_file_xchacha20`
+const _: () = assert!(
+    ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2 == VUP_CHUNK_SIZE_AS_POWER_OF_2,
+    "ENCRYPTION_CHUNK_SIZE must match vup's chunk size"
+);
+
+/// Size in bytes of the per-file salt included in the chunk nonces of a file encrypted with a
+/// caller-supplied key.
+pub const NONCE_SALT_SIZE: usize = 20;
+
+/// Derives the XChaCha20 nonce of a chunk: the chunk index as 4 little-endian bytes, followed
+/// by the file's nonce salt.
+///
+/// A (key, nonce) pair must never encrypt two different chunks, or XChaCha20-Poly1305 leaks
+/// the plaintexts and lets tags be forged. The chunk index makes nonces unique within a file,
+/// which is enough when every file has a fresh random key, so those files use an all-zero salt
+/// and their nonces are the chunk index alone, as S5 and vup expect. A key supplied by the
+/// caller may be reused across files, so those files get a random salt, making their nonces
+/// differ from every other file's.
+///
+/// With an all-zero salt this is vup's nonce: a 24 byte XChaCha20 nonce holding the chunk index
+/// as little-endian bytes, zero-padded, e.g. `01 00 00 00 00 ... 00` for the second chunk.
+///
+/// # Arguments
+/// * `nonce_salt` - The salt of the file, all zeros for a file with its own random key.
+/// * `chunk_index` - The index of the chunk in the file.
+///
+fn chunk_nonce(nonce_salt: &[u8; NONCE_SALT_SIZE], chunk_index: u32) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..4].copy_from_slice(&chunk_index.to_le_bytes());
+    nonce[4..].copy_from_slice(nonce_salt);
+    nonce
+}
+
+/// Encrypts a file with XChaCha20-Poly1305 under a new random key.
+///
+/// # Arguments
+/// * `input_file_path` - The path of the file to encrypt.
+/// * `output_file_path` - The path to write the encrypted file to.
+/// * `padding` - The number of bytes of padding added to the last chunk.
+///
+/// # Returns
+/// The key the file was encrypted with.
+///
 pub fn encrypt_file_xchacha20(
     input_file_path: String,
     output_file_path: String,
@@ -16,25 +76,75 @@ pub fn encrypt_file_xchacha20(
 
     let output = File::create(output_file_path)?;
 
-    let res = encrypt_file_xchacha20_internal(reader, output, padding);
+    // The key is never reused, so the chunk index alone keeps the nonces unique
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    let res =
+        encrypt_file_xchacha20_internal(reader, output, &key, &[0u8; NONCE_SALT_SIZE], padding);
+    res.unwrap();
 
-    Ok(res.unwrap())
+    Ok(key.to_vec())
+}
+
+/// Encrypts a file with XChaCha20-Poly1305 under a key supplied by the caller, which may be
+/// reused across files. A random nonce salt is generated for the file so that no nonce is
+/// ever used twice with the key; it must be recorded alongside the key, e.g. with
+/// `create_salted_encrypted_cid`, and passed to `decrypt_file_xchacha20_with_salt`.
+///
+/// # Arguments
+/// * `input_file_path` - The path of the file to encrypt.
+/// * `output_file_path` - The path to write the encrypted file to.
+/// * `key` - The 32 byte key to encrypt the file with.
+/// * `padding` - The number of bytes of padding added to the last chunk.
+///
+/// # Returns
+/// The nonce salt the file was encrypted with.
+///
+pub fn encrypt_file_xchacha20_with_key(
+    input_file_path: String,
+    output_file_path: String,
+    key: &[u8],
+    padding: usize,
+) -> anyhow::Result<[u8; NONCE_SALT_SIZE]> {
+    if key.len() != 32 {
+        return Err(anyhow!(
+            "Encryption key must be 32 bytes, not {}",
+            key.len()
+        ));
+    }
+
+    let input = File::open(input_file_path)?;
+    let reader = BufReader::new(input);
+
+    let output = File::create(output_file_path)?;
+
+    let mut nonce_salt = [0u8; NONCE_SALT_SIZE];
+    OsRng.fill_bytes(&mut nonce_salt);
+
+    encrypt_file_xchacha20_internal(
+        reader,
+        output,
+        GenericArray::from_slice(key),
+        &nonce_salt,
+        padding,
+    )?;
+
+    Ok(nonce_salt)
 }
 
 fn encrypt_file_xchacha20_internal<R: Read>(
     mut reader: R,
     mut output_file: File,
+    key: &chacha20poly1305::Key,
+    nonce_salt: &[u8; NONCE_SALT_SIZE],
     padding: usize,
-) -> anyhow::Result<Vec<u8>> {
-    //let key = GenericArray::from_slice(&[0u8; 32]);
-    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
-    let cipher = XChaCha20Poly1305::new(&key);
+) -> anyhow::Result<()> {
+    let cipher = XChaCha20Poly1305::new(key);
 
     let mut chunk_index: u32 = 0;
 
-    let chunk_size = 262144;
+    let chunk_size = ENCRYPTION_CHUNK_SIZE;
 
-    let mut buffer = [0u8; 262144];
+    let mut buffer = [0u8; ENCRYPTION_CHUNK_SIZE];
 
     loop {
         let count = reader.read(&mut buffer)?;
@@ -48,14 +158,7 @@ fn encrypt_file_xchacha20_internal<R: Read>(
             count
         };
 
-        let mut nonce = XNonce::default();
-
-        let mut foo = [0u8; 24];
-        for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter()) {
-            *place = *data
-        }
-
-        nonce.copy_from_slice(&foo);
+        let nonce = chunk_nonce(nonce_salt, chunk_index);
 
         let ciphertext = cipher.encrypt(&nonce, &buffer[..length]);
 
@@ -65,7 +168,7 @@ fn encrypt_file_xchacha20_internal<R: Read>(
 
     output_file.flush().unwrap();
 
-    Ok(key.to_vec())
+    Ok(())
 }
 
 pub fn decrypt_file_xchacha20(
@@ -81,15 +184,92 @@ pub fn decrypt_file_xchacha20(
     let output = File::create(output_file_path)?;
 
     println!("let res = decrypt_file_xchacha20_internal(reader, output, key, padding, last_chunk_index);");
-    let res = decrypt_file_xchacha20_internal(reader, output, key, padding, last_chunk_index);
+    let res = decrypt_file_xchacha20_internal(
+        reader,
+        output,
+        key,
+        &[0u8; NONCE_SALT_SIZE],
+        ENCRYPTION_CHUNK_SIZE,
+        padding,
+        last_chunk_index,
+    );
 
     Ok(res.unwrap())
 }
 
+/// Decrypts a file encrypted by `encrypt_file_xchacha20_with_key`, using the nonce salt it
+/// returned.
+///
+/// # Arguments
+/// * `input_file_path` - The path of the encrypted file.
+/// * `output_file_path` - The path to write the decrypted file to.
+/// * `key` - The key the file was encrypted with.
+/// * `nonce_salt` - The nonce salt the file was encrypted with.
+/// * `padding` - The number of bytes of padding added to the last chunk.
+/// * `last_chunk_index` - The index of the last chunk of the file.
+///
+pub fn decrypt_file_xchacha20_with_salt(
+    input_file_path: String,
+    output_file_path: String,
+    key: Vec<u8>,
+    nonce_salt: &[u8; NONCE_SALT_SIZE],
+    padding: usize,
+    last_chunk_index: u32,
+) -> anyhow::Result<u8> {
+    decrypt_file_xchacha20_with_chunk_size(
+        input_file_path,
+        output_file_path,
+        key,
+        nonce_salt,
+        ENCRYPTION_CHUNK_SIZE,
+        padding,
+        last_chunk_index,
+    )
+}
+
+/// Decrypts a file encrypted in chunks of any size, such as a source whose encrypted CID
+/// records a chunk size other than `ENCRYPTION_CHUNK_SIZE`.
+///
+/// # Arguments
+/// * `input_file_path` - The path of the encrypted file.
+/// * `output_file_path` - The path to write the decrypted file to.
+/// * `key` - The key the file was encrypted with.
+/// * `nonce_salt` - The nonce salt the file was encrypted with, all zeros if it has none.
+/// * `chunk_size` - The size in bytes of the plaintext chunks the file was encrypted in.
+/// * `padding` - The number of bytes of padding added to the last chunk.
+/// * `last_chunk_index` - The index of the last chunk of the file.
+///
+pub fn decrypt_file_xchacha20_with_chunk_size(
+    input_file_path: String,
+    output_file_path: String,
+    key: Vec<u8>,
+    nonce_salt: &[u8; NONCE_SALT_SIZE],
+    chunk_size: usize,
+    padding: usize,
+    last_chunk_index: u32,
+) -> anyhow::Result<u8> {
+    let input = File::open(input_file_path)?;
+    let reader = BufReader::new(input);
+
+    let output = File::create(output_file_path)?;
+
+    decrypt_file_xchacha20_internal(
+        reader,
+        output,
+        key,
+        nonce_salt,
+        chunk_size,
+        padding,
+        last_chunk_index,
+    )
+}
+
 fn decrypt_file_xchacha20_internal<R: Read>(
     mut reader: R,
     mut output_file: File,
     key: Vec<u8>,
+    nonce_salt: &[u8; NONCE_SALT_SIZE],
+    chunk_size: usize,
     padding: usize,
     last_chunk_index: u32,
 ) -> anyhow::Result<u8> {
@@ -97,31 +277,28 @@ fn decrypt_file_xchacha20_internal<R: Read>(
 
     let mut chunk_index: u32 = 0;
 
-    let mut buffer = [0u8; 262160];
+    let mut buffer = vec![0u8; chunk_size + ENCRYPTION_TAG_SIZE];
 
     loop {
-        let count = reader.read(&mut buffer)?;
+        let count = read_chunk(&mut reader, &mut buffer)?;
         if count == 0 {
             break;
         }
 
-        let mut nonce = XNonce::default();
-
-        let mut foo = [0u8; 24];
-        for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter()) {
-            *place = *data
-        }
-
-        nonce.copy_from_slice(&foo);
+        let nonce = chunk_nonce(nonce_salt, chunk_index);
 
-        let ciphertext = cipher.decrypt(&nonce, &buffer[..count]);
+        let ciphertext = cipher
+            .decrypt(&nonce, &buffer[..count])
+            .map_err(|_| anyhow!("Failed to decrypt chunk {}", chunk_index))?;
 
         if chunk_index == last_chunk_index {
-            output_file
-                .write(&ciphertext.unwrap()[..(count - 16 - padding)])
-                .unwrap();
+            let end = ciphertext
+                .len()
+                .checked_sub(padding)
+                .ok_or_else(|| anyhow!("Padding {} is larger than the last chunk", padding))?;
+            output_file.write_all(&ciphertext[..end])?;
         } else {
-            output_file.write(&ciphertext.unwrap()).unwrap();
+            output_file.write_all(&ciphertext)?;
         }
 
         chunk_index = chunk_index + 1;
@@ -132,96 +309,195 @@ fn decrypt_file_xchacha20_internal<R: Read>(
     Ok(1)
 }
 
-fn decrypt_file_xchacha20_internal2<R: Read>(
-    mut reader: R,
-    mut output_file: File,
-    key: Vec<u8>,
-    padding: usize,
-    last_chunk_index: u32,
-) -> anyhow::Result<u8> {
-    println!("decrypt_file_xchacha20_internal key: {:?}", key);
-    println!("let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));");
-    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+/// Reads a whole chunk into `buffer`, retrying short reads, so that a chunk is never split
+/// across reads. Returns fewer bytes than the buffer holds only at the end of the input.
+fn read_chunk<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut count = 0;
+    while count < buffer.len() {
+        match reader.read(&mut buffer[count..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => count += bytes_read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(count)
+}
 
-    println!("let mut chunk_index: u32 = 0;");
-    let mut chunk_index: u32 = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Returns a path in the system temp directory named after the test, so that tests running
+    /// in parallel don't share files.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "encrypt_file_tests_{}_{}",
+                std::process::id(),
+                name
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
 
-    println!("let mut buffer = [0u8; 262160];");
-    let mut buffer = [0u8; 262160];
+    /// Plaintext spanning two chunks, the second one partial.
+    fn content() -> Vec<u8> {
+        (0..ENCRYPTION_CHUNK_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
 
-    println!("loop");
-    loop {
-        let count = reader.read(&mut buffer)?;
-        if count == 0 {
-            break;
+    #[test]
+    fn files_encrypted_with_the_same_key_get_distinct_nonces() {
+        let (input, first, second) = (
+            temp_path("same_key_input"),
+            temp_path("same_key_first"),
+            temp_path("same_key_second"),
+        );
+        fs::write(&input, content()).unwrap();
+        let key = [7u8; 32];
+
+        let first_salt =
+            encrypt_file_xchacha20_with_key(input.clone(), first.clone(), &key, 0).unwrap();
+        let second_salt =
+            encrypt_file_xchacha20_with_key(input.clone(), second.clone(), &key, 0).unwrap();
+        let (first_ciphertext, second_ciphertext) =
+            (fs::read(&first).unwrap(), fs::read(&second).unwrap());
+        for path in [&input, &first, &second] {
+            fs::remove_file(path).unwrap();
         }
 
-        println!("count: {}", &count);
+        assert_ne!(first_salt, second_salt);
+        assert_ne!(first_salt, [0u8; NONCE_SALT_SIZE]);
+        for chunk_index in 0..2 {
+            assert_ne!(
+                chunk_nonce(&first_salt, chunk_index),
+                chunk_nonce(&second_salt, chunk_index)
+            );
+        }
+        // The same plaintext under the same key encrypts differently in each file
+        assert_eq!(first_ciphertext.len(), second_ciphertext.len());
+        assert_ne!(
+            first_ciphertext[..ENCRYPTED_CHUNK_SIZE],
+            second_ciphertext[..ENCRYPTED_CHUNK_SIZE]
+        );
+        assert_ne!(
+            first_ciphertext[ENCRYPTED_CHUNK_SIZE..],
+            second_ciphertext[ENCRYPTED_CHUNK_SIZE..]
+        );
+    }
 
-        println!("First 32 bytes of buffer: {}", hex::encode(&buffer[..32]));
+    #[test]
+    fn file_encrypted_with_a_key_decrypts_with_its_salt_only() {
+        let (input, encrypted, decrypted) = (
+            temp_path("salted_input"),
+            temp_path("salted_encrypted"),
+            temp_path("salted_decrypted"),
+        );
+        fs::write(&input, content()).unwrap();
+        let key = vec![9u8; 32];
+
+        let nonce_salt =
+            encrypt_file_xchacha20_with_key(input.clone(), encrypted.clone(), &key, 0).unwrap();
+        let with_salt = decrypt_file_xchacha20_with_salt(
+            encrypted.clone(),
+            decrypted.clone(),
+            key.clone(),
+            &nonce_salt,
+            0,
+            1,
+        );
+        let plaintext = fs::read(&decrypted).unwrap();
+        let without_salt = decrypt_file_xchacha20_with_chunk_size(
+            encrypted.clone(),
+            decrypted.clone(),
+            key,
+            &[0u8; NONCE_SALT_SIZE],
+            ENCRYPTION_CHUNK_SIZE,
+            0,
+            1,
+        );
+        for path in [&input, &encrypted, &decrypted] {
+            fs::remove_file(path).unwrap();
+        }
 
-        println!("let mut nonce = XNonce::default();");
-        let mut nonce = XNonce::default();
+        assert!(with_salt.is_ok());
+        assert_eq!(plaintext, content());
+        assert!(without_salt.is_err());
+    }
 
-        let mut foo = [0u8; 24];
-        println!("for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter())");
-        for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter()) {
-            *place = *data
+    /// Decrypts a file the way vup does, independently of `decrypt_file_xchacha20`: 256 KiB
+    /// chunks, each followed by its tag, with the chunk index as the little-endian bytes of an
+    /// otherwise all-zero 24 byte nonce, and `padding` bytes dropped from the last chunk.
+    fn vup_decrypt(ciphertext: &[u8], key: &[u8], padding: usize) -> Vec<u8> {
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(key));
+        let mut plaintext = Vec::new();
+        for (index, chunk) in ciphertext
+            .chunks((1 << VUP_CHUNK_SIZE_AS_POWER_OF_2) + 16)
+            .enumerate()
+        {
+            let mut nonce = [0u8; 24];
+            nonce[..8].copy_from_slice(&(index as u64).to_le_bytes());
+            plaintext.extend(
+                cipher
+                    .decrypt(XNonce::from_slice(&nonce), chunk)
+                    .unwrap_or_else(|_| panic!("Failed to decrypt chunk {}", index)),
+            );
         }
+        plaintext.truncate(plaintext.len() - padding);
+        plaintext
+    }
+
+    #[test]
+    fn encrypted_file_decrypts_with_the_vup_parameters() {
+        let (input, encrypted) = (temp_path("vup_input"), temp_path("vup_encrypted"));
+        fs::write(&input, content()).unwrap();
 
-        println!("nonce.copy_from_slice(&foo);");
-        nonce.copy_from_slice(&foo);
-
-        //        let ciphertext = cipher.decrypt(&nonce, &buffer[..count]);
-        let ciphertext = cipher.decrypt(&nonce, &buffer[..count]);
-        match ciphertext {
-            Ok(ciphertext) => {
-                if chunk_index == last_chunk_index {
-                    output_file
-                        .write(&ciphertext[..(count - 16 - padding)])
-                        .unwrap();
-
-                //                    output_file.write_all(&ciphertext[..(count - 16 - padding)])?;
-                } else {
-                    println!("output_file.write(&ciphertext.unwrap()).unwrap();");
-                    output_file.write(&ciphertext).unwrap();
-                    //        output_file.write_all(&ciphertext)?;
-                    println!("after output_file.write(&ciphertext.unwrap()).unwrap();");
-                }
-            }
-            Err(e) => return Err(anyhow!("encryption error: {}", e)),
+        let key = encrypt_file_xchacha20(input.clone(), encrypted.clone(), 24).unwrap();
+        let ciphertext = fs::read(&encrypted).unwrap();
+        for path in [&input, &encrypted] {
+            fs::remove_file(path).unwrap();
         }
 
-        println!("if chunk_index == last_chunk_index");
-        // if chunk_index == last_chunk_index {
-        //     output_file
-        //         .write(&ciphertext.unwrap()[..(count - 16 - padding)])
-        //         .unwrap();
-        // } else {
-        //     println!("output_file.write(&ciphertext.unwrap()).unwrap();");
-        //                output_file.write(&ciphertext.unwrap()).unwrap();
-        //     println!("after output_file.write(&ciphertext.unwrap()).unwrap();");
-        // }
-
-        // if chunk_index == last_chunk_index {
-        //     if let Err(e) =
-        //         output_file.write_all(&ciphertext.as_ref().unwrap()[..(count - 16 - padding)])
-        //     {
-        //         return Err(anyhow!("write error: {}", e));
-        //     }
-        // } else {
-        //     println!("output_file.write(&ciphertext.unwrap()).unwrap();");
-        //     if let Err(e) = output_file.write_all(ciphertext.as_ref().unwrap()) {
-        //         return Err(anyhow!("write error: {}", e));
-        //     }
-        //     println!("after output_file.write(&ciphertext.unwrap()).unwrap();");
-        // }
-
-        println!("chunk_index = chunk_index + 1;");
-        chunk_index = chunk_index + 1;
+        assert_eq!(
+            ciphertext.len(),
+            content().len() + 24 + 2 * ENCRYPTION_TAG_SIZE
+        );
+        assert_eq!(vup_decrypt(&ciphertext, &key, 24), content());
     }
 
-    output_file.flush().unwrap();
+    #[test]
+    fn decrypts_a_vup_encrypted_fixture() {
+        // Generated by tests/fixtures/vup_fixture.py: key 0x00..0x1f, 16 bytes of padding
+        let fixture = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/vup_xchacha20.bin"
+        );
+        let decrypted = temp_path("vup_fixture_decrypted");
+        let key: Vec<u8> = (0..32).collect();
+        let plaintext: Vec<u8> = (0..ENCRYPTION_CHUNK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let result = decrypt_file_xchacha20(fixture.to_string(), decrypted.clone(), key, 16, 1);
+        let decrypted_content = fs::read(&decrypted).unwrap();
+        fs::remove_file(&decrypted).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(decrypted_content, plaintext);
+    }
 
-    Ok(1)
+    #[test]
+    fn encrypt_file_xchacha20_with_key_rejects_a_short_key() {
+        let result = encrypt_file_xchacha20_with_key(
+            temp_path("short_key_input"),
+            temp_path("short_key_output"),
+            &[0u8; 16],
+            0,
+        );
+
+        assert!(result.is_err());
+    }
 }
@@ -1,3 +1,19 @@
+use crate::encrypt_file::{NONCE_SALT_SIZE, VUP_CHUNK_SIZE_AS_POWER_OF_2};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+
+/// CID type byte of encrypted CIDs.
+pub const CID_TYPE_ENCRYPTED: u8 = 0xae;
+
+/// Encryption algorithm byte of encrypted CIDs whose chunk nonces are the chunk index alone,
+/// as created by S5 and vup.
+pub const ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305: u8 = 0xa6;
+
+/// Encryption algorithm byte of encrypted CIDs whose chunk nonces also include a per-file
+/// nonce salt, for files encrypted with a caller-supplied key. These CIDs are specific to this
+/// transcoder.
+pub const ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED: u8 = 0xa7;
+
 pub fn create_encrypted_cid(
     cid_type_encrypted: u8,
     encryption_algorithm: u8,
@@ -18,3 +34,262 @@ pub fn create_encrypted_cid(
 
     result
 }
+
+/// Creates the encrypted CID of a file encrypted by `encrypt_file_xchacha20_with_key`. The
+/// layout is that of `create_encrypted_cid` with the `ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED`
+/// algorithm, and the nonce salt following the encryption key, so that the key and salt needed
+/// to decrypt the file are both recorded in the CID.
+pub fn create_salted_encrypted_cid(
+    cid_type_encrypted: u8,
+    chunk_size_as_power_of_2: u8,
+    encrypted_blob_hash: Vec<u8>,
+    encryption_key: Vec<u8>,
+    nonce_salt: [u8; NONCE_SALT_SIZE],
+    padding: u32,
+    original_cid: Vec<u8>,
+) -> Vec<u8> {
+    let mut encryption_key = encryption_key;
+    encryption_key.extend(nonce_salt);
+
+    create_encrypted_cid(
+        cid_type_encrypted,
+        ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED,
+        chunk_size_as_power_of_2,
+        encrypted_blob_hash,
+        encryption_key,
+        padding,
+        original_cid,
+    )
+}
+
+// Sizes in bytes of the fields of an encrypted CID
+const ENCRYPTED_BLOB_HASH_SIZE: usize = 33;
+const KEY_SIZE: usize = 32;
+const PADDING_SIZE: usize = 4;
+
+// The range of chunk sizes, as powers of 2, that encrypted sources are decrypted with. Each chunk
+// is decrypted in memory, so larger chunks are rejected rather than allocated
+const MIN_CHUNK_SIZE_AS_POWER_OF_2: u8 = 10;
+const MAX_CHUNK_SIZE_AS_POWER_OF_2: u8 = 24;
+
+/// The fields of an encrypted CID.
+#[derive(Debug, Clone)]
+pub struct EncryptedCid {
+    /// The encryption algorithm byte, which also identifies the layout of the CID.
+    pub encryption_algorithm: u8,
+    pub chunk_size_as_power_of_2: u8,
+    /// The multihash of the encrypted blob.
+    pub encrypted_blob_hash: Vec<u8>,
+    pub encryption_key: Vec<u8>,
+    /// The nonce salt of `ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED` CIDs, `None` for
+    /// others.
+    pub nonce_salt: Option<[u8; NONCE_SALT_SIZE]>,
+    pub padding: u32,
+    /// The CID of the unencrypted content.
+    pub original_cid: Vec<u8>,
+}
+
+impl EncryptedCid {
+    /// Returns the size in bytes of the plaintext chunks the blob was encrypted in.
+    ///
+    /// # Returns
+    /// The chunk size, or an error if it is outside the supported range of 1 KiB to 16 MiB.
+    ///
+    pub fn chunk_size(&self) -> Result<usize> {
+        if !(MIN_CHUNK_SIZE_AS_POWER_OF_2..=MAX_CHUNK_SIZE_AS_POWER_OF_2)
+            .contains(&self.chunk_size_as_power_of_2)
+        {
+            return Err(anyhow!(
+                "Unsupported encrypted CID chunk size 2^{}, expected 2^{} to 2^{}",
+                self.chunk_size_as_power_of_2,
+                MIN_CHUNK_SIZE_AS_POWER_OF_2,
+                MAX_CHUNK_SIZE_AS_POWER_OF_2
+            ));
+        }
+
+        Ok(1 << self.chunk_size_as_power_of_2)
+    }
+
+    /// Checks that the blob can be decrypted by vup: encrypted with the
+    /// `ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305` algorithm, whose nonces are the chunk index
+    /// alone, in chunks of `VUP_CHUNK_SIZE_AS_POWER_OF_2` with padding smaller than a chunk.
+    ///
+    /// # Returns
+    /// `Ok(())` if the CID matches vup's encryption scheme, otherwise an error naming the
+    /// mismatch.
+    ///
+    pub fn check_vup_compatible(&self) -> Result<()> {
+        if self.encryption_algorithm != ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305 {
+            return Err(anyhow!(
+                "Encryption algorithm 0x{:02x} is not vup compatible, expected 0x{:02x}",
+                self.encryption_algorithm,
+                ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305
+            ));
+        }
+        if self.chunk_size_as_power_of_2 != VUP_CHUNK_SIZE_AS_POWER_OF_2 {
+            return Err(anyhow!(
+                "Chunk size 2^{} is not vup compatible, expected 2^{}",
+                self.chunk_size_as_power_of_2,
+                VUP_CHUNK_SIZE_AS_POWER_OF_2
+            ));
+        }
+        if self.padding as usize >= 1 << VUP_CHUNK_SIZE_AS_POWER_OF_2 {
+            return Err(anyhow!(
+                "Padding {} is not smaller than the chunk size",
+                self.padding
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses an encrypted CID created by `create_encrypted_cid` or `create_salted_encrypted_cid`.
+/// The encryption algorithm byte is the layout version of the CID: the fields following it are
+/// read according to the algorithm, so a CID with an algorithm this transcoder doesn't know is
+/// rejected rather than misread.
+///
+/// # Arguments
+/// * `cid_bytes` - The bytes of the encrypted CID.
+///
+/// # Returns
+/// The fields of the CID, or an error if it isn't an encrypted CID, its algorithm is unknown or
+/// it is too short for its layout.
+///
+pub fn parse_encrypted_cid(cid_bytes: &[u8]) -> Result<EncryptedCid> {
+    let (&cid_type, rest) = cid_bytes
+        .split_first()
+        .ok_or_else(|| anyhow!("Encrypted CID is empty"))?;
+    if cid_type != CID_TYPE_ENCRYPTED {
+        return Err(anyhow!(
+            "Not an encrypted CID: CID type 0x{:02x}, expected 0x{:02x}",
+            cid_type,
+            CID_TYPE_ENCRYPTED
+        ));
+    }
+
+    let (&encryption_algorithm, rest) = rest
+        .split_first()
+        .ok_or_else(|| anyhow!("Encrypted CID is missing its encryption algorithm"))?;
+    let salt_size = match encryption_algorithm {
+        ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305 => 0,
+        ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED => NONCE_SALT_SIZE,
+        _ => {
+            return Err(anyhow!(
+                "Unsupported encrypted CID version: encryption algorithm 0x{:02x}",
+                encryption_algorithm
+            ))
+        }
+    };
+
+    let fixed_size = 1 + ENCRYPTED_BLOB_HASH_SIZE + KEY_SIZE + salt_size + PADDING_SIZE;
+    if rest.len() < fixed_size {
+        return Err(anyhow!(
+            "Encrypted CID is too short: {} bytes after its encryption algorithm, expected at least {}",
+            rest.len(),
+            fixed_size
+        ));
+    }
+
+    let (&chunk_size_as_power_of_2, rest) = rest.split_first().unwrap();
+    let (encrypted_blob_hash, rest) = rest.split_at(ENCRYPTED_BLOB_HASH_SIZE);
+    let (encryption_key, rest) = rest.split_at(KEY_SIZE);
+    let (nonce_salt, rest) = rest.split_at(salt_size);
+    let (padding, original_cid) = rest.split_at(PADDING_SIZE);
+
+    Ok(EncryptedCid {
+        encryption_algorithm,
+        chunk_size_as_power_of_2,
+        encrypted_blob_hash: encrypted_blob_hash.to_vec(),
+        encryption_key: encryption_key.to_vec(),
+        nonce_salt: nonce_salt.try_into().ok(),
+        padding: u32::from_be_bytes(padding.try_into().unwrap()),
+        original_cid: original_cid.to_vec(),
+    })
+}
+
+/// Parses an encrypted CID in its base64url `u...` string form, with or without a file
+/// extension, as given as the source of a transcoding request.
+///
+/// # Arguments
+/// * `encrypted_cid` - The encrypted CID string.
+///
+pub fn parse_encrypted_cid_str(encrypted_cid: &str) -> Result<EncryptedCid> {
+    let cid_without_extension = match encrypted_cid.rfind('.') {
+        Some(index) => &encrypted_cid[..index],
+        None => encrypted_cid,
+    };
+    let encoded = cid_without_extension
+        .strip_prefix('u')
+        .ok_or_else(|| anyhow!("Encrypted CID {} is not base64url encoded", encrypted_cid))?;
+
+    let cid_bytes = general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .map_err(|e| anyhow!("Invalid encrypted CID {}: {}", encrypted_cid, e))?;
+
+    parse_encrypted_cid(&cid_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file::ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2;
+
+    #[test]
+    fn salted_encrypted_cid_round_trips() {
+        let encrypted_blob_hash: Vec<u8> = (0..ENCRYPTED_BLOB_HASH_SIZE as u8).collect();
+        let encryption_key = vec![0x42; KEY_SIZE];
+        let nonce_salt = [0x5a; NONCE_SALT_SIZE];
+        let original_cid = vec![0x26, 0x1f, 1, 2, 3];
+
+        let cid_bytes = create_salted_encrypted_cid(
+            CID_TYPE_ENCRYPTED,
+            ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2,
+            encrypted_blob_hash.clone(),
+            encryption_key.clone(),
+            nonce_salt,
+            300,
+            original_cid.clone(),
+        );
+        let encrypted_cid = format!(
+            "u{}.mp4",
+            general_purpose::URL_SAFE_NO_PAD.encode(&cid_bytes)
+        );
+
+        assert_eq!(cid_bytes[1], ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED);
+        for parsed in [
+            parse_encrypted_cid(&cid_bytes).unwrap(),
+            parse_encrypted_cid_str(&encrypted_cid).unwrap(),
+        ] {
+            assert_eq!(
+                parsed.encryption_algorithm,
+                ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305_SALTED
+            );
+            assert_eq!(
+                parsed.chunk_size_as_power_of_2,
+                ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2
+            );
+            assert_eq!(parsed.encrypted_blob_hash, encrypted_blob_hash);
+            assert_eq!(parsed.encryption_key, encryption_key);
+            assert_eq!(parsed.nonce_salt, Some(nonce_salt));
+            assert_eq!(parsed.padding, 300);
+            assert_eq!(parsed.original_cid, original_cid);
+            assert!(parsed.check_vup_compatible().is_err());
+        }
+    }
+
+    #[test]
+    fn salted_encrypted_cid_without_its_salt_is_too_short() {
+        let cid_bytes = create_salted_encrypted_cid(
+            CID_TYPE_ENCRYPTED,
+            ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2,
+            vec![0; ENCRYPTED_BLOB_HASH_SIZE],
+            vec![0; KEY_SIZE],
+            [0; NONCE_SALT_SIZE],
+            0,
+            Vec::new(),
+        );
+
+        assert!(parse_encrypted_cid(&cid_bytes[..cid_bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use dotenv::var;
+use once_cell::sync::Lazy;
+use std::process::Command;
+
+// The IPFS gateway `ipfs://` sources are downloaded from
+static IPFS_GATEWAY_URL: Lazy<String> = Lazy::new(|| {
+    var("IPFS_GATEWAY_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| String::from("https://ipfs.io"))
+});
+
+/// Parses an IPFS source, either `ipfs://<cid>` or a gateway URL such as
+/// `https://ipfs.io/ipfs/<cid>`.
+///
+/// # Arguments
+/// * `source` - The source CID of a transcoding request.
+///
+/// # Returns
+/// The IPFS CID and the URL to download it from, or `None` if `source` isn't an IPFS source.
+/// `ipfs://` sources are downloaded from `IPFS_GATEWAY_URL`, gateway URLs as given.
+///
+pub fn ipfs_source(source: &str) -> Option<(String, String)> {
+    if let Some(cid) = source.strip_prefix("ipfs://") {
+        let cid = cid.trim_end_matches('/');
+        if cid.is_empty() || cid.contains('/') {
+            return None;
+        }
+
+        let url = format!("{}/ipfs/{}", IPFS_GATEWAY_URL.trim_end_matches('/'), cid);
+        return Some((cid.to_string(), url));
+    }
+
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return None;
+    }
+
+    // Only a whole CID can be verified, not a path within it
+    let url = source.split(['?', '#']).next().unwrap_or_default();
+    let (_, cid) = url.split_once("/ipfs/")?;
+    let cid = cid.trim_end_matches('/');
+    if cid.is_empty() || cid.contains('/') {
+        return None;
+    }
+
+    Some((cid.to_string(), url.to_string()))
+}
+
+/// Checks that a downloaded file is the content of an IPFS CID, by hashing it with
+/// `ipfs add --only-hash` the same way the CID's version is added by default.
+///
+/// # Arguments
+/// * `file_path` - The path of the downloaded file.
+/// * `cid` - The requested IPFS CID, either CIDv0 (`Qm...`) or base32 CIDv1 (`bafy...`).
+///
+/// # Returns
+/// `Ok(())` if the file's CID matches, otherwise an error message.
+///
+pub fn verify_ipfs_cid(file_path: &str, cid: &str) -> Result<(), String> {
+    let cid_version = if cid.starts_with("Qm") { "0" } else { "1" };
+
+    let output = Command::new("ipfs")
+        .args([
+            "add",
+            "--only-hash",
+            "-Q",
+            "--cid-version",
+            cid_version,
+            file_path,
+        ])
+        .output()
+        .map_err(|e| format!("Failed to execute ipfs to verify the CID: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to compute the CID of {}: {}",
+            file_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let computed_cid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if computed_cid != cid {
+        return Err(format!(
+            "CID mismatch: requested {}, but the downloaded content is {}",
+            cid, computed_cid
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,26 @@
+/*
+ * lib.rs
+ *
+ * The transcoding pipeline as a library, used by the gRPC/REST server in `server.rs`.
+ * `pipeline::transcode_asset` downloads a source, transcodes it to each media format and
+ * uploads the results, and `pipeline::transcode_asset_blocking` does the same for callers
+ * that aren't async.
+ */
+
+pub mod encrypt_file;
+pub mod encrypted_cid;
+pub mod ipfs;
+pub mod live;
+pub mod manifest;
+pub mod media_info;
+pub mod pipeline;
+pub mod result_store;
+pub mod s5;
+pub mod selftest;
+pub mod shared;
+pub mod sprites;
+pub mod transcode_video;
+pub mod utils;
+
+pub use pipeline::{transcode_asset, transcode_asset_blocking, JobResult, TranscodeOptions};
+pub use s5::compute_s5_cid;
@@ -0,0 +1,418 @@
+use crate::media_info::probe_media_info;
+use crate::s5::upload_video;
+use crate::shared::{self, Phase};
+use crate::transcode_video::{add_encode_args, add_gpu_device_args, format_encoder, VideoFormat};
+use dotenv::var;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tonic::{Code, Status};
+
+/// Prefix of a source CID that names a growing file in `LIVE_SOURCE_DIR` rather than a CID.
+pub const LIVE_SCHEME: &str = "live://";
+
+// Directory live sources are read from. Live transcoding is disabled unless it is set.
+static LIVE_SOURCE_DIR: Lazy<Option<String>> =
+    Lazy::new(|| var("LIVE_SOURCE_DIR").ok().filter(|dir| !dir.is_empty()));
+
+// Duration in seconds of each HLS segment
+static LIVE_SEGMENT_SECS: Lazy<f64> = Lazy::new(|| {
+    var("LIVE_SEGMENT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|&secs| secs > 0.0)
+        .unwrap_or(6.0)
+});
+
+/// Seconds to wait between checks of a live source for new data.
+pub static LIVE_POLL_SECS: Lazy<u64> = Lazy::new(|| {
+    var("LIVE_POLL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(5)
+});
+
+// Seconds a live source must stop growing for before it is considered finished
+static LIVE_IDLE_TIMEOUT_SECS: Lazy<u64> = Lazy::new(|| {
+    var("LIVE_IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(60)
+});
+
+static PATH_TO_TRANSCODED_FILE: Lazy<String> = Lazy::new(|| {
+    var("PATH_TO_TRANSCODED_FILE")
+        .unwrap_or_else(|_| panic!("PATH_TO_TRANSCODED_FILE not set in .env"))
+});
+
+/// Resolves a live source, e.g. `live://camera1.ts`, to its path in `LIVE_SOURCE_DIR`.
+///
+/// # Arguments
+/// * `source` - The source CID of a transcoding request.
+///
+/// # Returns
+/// `None` if `source` is not a live source, otherwise the path of the source file, or an
+/// error message if live transcoding is disabled or the name is not a plain file name.
+///
+pub fn live_source_path(source: &str) -> Option<Result<String, String>> {
+    let name = source.strip_prefix(LIVE_SCHEME)?;
+
+    let dir = match LIVE_SOURCE_DIR.as_deref() {
+        Some(dir) => dir,
+        None => {
+            return Some(Err(String::from(
+                "Live transcoding is disabled, set LIVE_SOURCE_DIR to enable it",
+            )))
+        }
+    };
+
+    // Only files directly inside LIVE_SOURCE_DIR may be read
+    if name.is_empty() || Path::new(name).file_name().and_then(|n| n.to_str()) != Some(name) {
+        return Some(Err(format!("Invalid live source name: {}", name)));
+    }
+
+    Some(Ok(Path::new(dir).join(name).to_string_lossy().to_string()))
+}
+
+/// The state of one HLS rendition of a live source.
+struct LiveRendition {
+    format_index: usize,
+    format_json: Value,
+    format: VideoFormat,
+    /// Path prefix of the playlist, init segment and media segments written by ffmpeg.
+    output_prefix: String,
+    /// Number of the next media segment ffmpeg will write.
+    next_segment: usize,
+    /// URLs of the uploaded init and media segments, by file name.
+    uploaded: HashMap<String, String>,
+    playlist_cid: Option<String>,
+    portal_url: Option<String>,
+}
+
+impl LiveRendition {
+    fn playlist_path(&self) -> String {
+        format!("{}.m3u8", self.output_prefix)
+    }
+
+    fn init_file_name(&self) -> String {
+        format!("{}_init.mp4", file_name(&self.output_prefix))
+    }
+
+    fn segment_path(&self, number: usize) -> String {
+        format!("{}_{:05}.m4s", self.output_prefix, number)
+    }
+
+    fn scheme(&self) -> &str {
+        match self.format.dest.as_deref() {
+            Some("ipfs") => "ipfs",
+            _ => "s5",
+        }
+    }
+
+    async fn upload(&mut self, path: &str) -> Result<String, Status> {
+        let uploaded = upload_video(path, self.format.dest.clone())
+            .await
+            .map_err(|e| {
+                Status::new(Code::Internal, format!("Failed to upload {}: {}", path, e))
+            })?;
+        self.portal_url = uploaded.portal_url;
+
+        Ok(format!("{}://{}", self.scheme(), uploaded.cid))
+    }
+
+    /// Encodes `start..end` seconds of the source, appending the new segments to the playlist.
+    ///
+    /// # Arguments
+    /// * `source_path` - The path of the live source.
+    /// * `start` - Where to start encoding, in seconds. Always on a segment boundary.
+    /// * `end` - Where to stop encoding, in seconds.
+    /// * `is_last` - Whether this is the last range, so the playlist should be ended.
+    /// * `is_gpu` - Whether to use GPU acceleration for transcoding.
+    ///
+    fn encode_range(
+        &self,
+        source_path: &str,
+        start: f64,
+        end: f64,
+        is_last: bool,
+        is_gpu: bool,
+    ) -> Result<(), Status> {
+        let segment_secs = *LIVE_SEGMENT_SECS;
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-v", "error"]);
+        add_gpu_device_args(&mut cmd, is_gpu, &self.format)?;
+        cmd.arg("-ss").arg(start.to_string());
+        cmd.arg("-t").arg((end - start).to_string());
+        cmd.arg("-i").arg(source_path);
+        add_encode_args(&mut cmd, is_gpu, &self.format)?;
+
+        // Keyframes on every segment boundary, and timestamps continuing from the previous
+        // range, so the segments of consecutive ranges join up
+        cmd.arg("-force_key_frames")
+            .arg(format!("expr:gte(t,n_forced*{})", segment_secs));
+        cmd.arg("-output_ts_offset").arg(start.to_string());
+
+        cmd.args([
+            "-f",
+            "hls",
+            "-hls_playlist_type",
+            "event",
+            "-hls_list_size",
+            "0",
+        ]);
+        cmd.arg("-hls_time").arg(segment_secs.to_string());
+        cmd.args(["-hls_segment_type", "fmp4"]);
+        cmd.arg("-hls_fmp4_init_filename")
+            .arg(self.init_file_name());
+        cmd.arg("-hls_segment_filename")
+            .arg(format!("{}_%05d.m4s", self.output_prefix));
+        cmd.arg("-start_number").arg(self.next_segment.to_string());
+        cmd.arg("-hls_flags").arg(if is_last {
+            "append_list"
+        } else {
+            "append_list+omit_endlist"
+        });
+        cmd.args(["-y", self.playlist_path().as_str()]);
+
+        let output = cmd
+            .output()
+            .map_err(|e| Status::new(Code::Internal, format!("Failed to start ffmpeg: {}", e)))?;
+
+        if !output.status.success() {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Failed to transcode live source: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Uploads the segments written since the last call, then a copy of the playlist that
+    /// refers to the uploaded segments.
+    async fn publish(&mut self) -> Result<(), Status> {
+        let init_file_name = self.init_file_name();
+        if !self.uploaded.contains_key(&init_file_name) {
+            let init_path = format!("{}{}", *PATH_TO_TRANSCODED_FILE, init_file_name);
+            let url = self.upload(&init_path).await?;
+            self.uploaded.insert(init_file_name, url);
+        }
+
+        while Path::new(&self.segment_path(self.next_segment)).exists() {
+            let segment_path = self.segment_path(self.next_segment);
+            let url = self.upload(&segment_path).await?;
+            self.uploaded.insert(file_name(&segment_path), url);
+            self.next_segment += 1;
+        }
+
+        let playlist = fs::read_to_string(self.playlist_path()).map_err(|e| {
+            Status::new(
+                Code::Internal,
+                format!("Failed to read live playlist: {}", e),
+            )
+        })?;
+
+        let published_playlist: Vec<String> = playlist
+            .lines()
+            .map(|line| {
+                if line.starts_with("#EXT-X-MAP:") {
+                    let mut line = line.to_string();
+                    for (file_name, url) in &self.uploaded {
+                        line = line.replace(&format!("\"{}\"", file_name), &format!("\"{}\"", url));
+                    }
+                    line
+                } else if line.starts_with('#') {
+                    line.to_string()
+                } else {
+                    self.uploaded
+                        .get(line.trim())
+                        .cloned()
+                        .unwrap_or_else(|| line.to_string())
+                }
+            })
+            .collect();
+
+        let published_path = format!("{}_published.m3u8", self.output_prefix);
+        fs::write(&published_path, published_playlist.join("\n") + "\n").map_err(|e| {
+            Status::new(
+                Code::Internal,
+                format!("Failed to write live playlist: {}", e),
+            )
+        })?;
+
+        self.playlist_cid = Some(self.upload(&published_path).await?);
+
+        Ok(())
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Incrementally transcodes a source that is still being written, e.g. a live capture, to an
+/// HLS rendition per media format.
+///
+/// Each call to `step` encodes the whole segments that have arrived since the previous call,
+/// appends them to each rendition's playlist and uploads the new segments and the playlist.
+/// Once the source has not grown for `LIVE_IDLE_TIMEOUT_SECS`, the remainder is encoded and
+/// the playlists are ended.
+pub struct LiveTranscode {
+    task_id: String,
+    source_path: String,
+    is_gpu: bool,
+    renditions: Vec<LiveRendition>,
+    /// Seconds of the source encoded so far.
+    processed_secs: f64,
+    source_len: u64,
+    last_growth: Instant,
+}
+
+impl LiveTranscode {
+    /// Prepares the live transcoding of a source to each of the media formats.
+    ///
+    /// # Arguments
+    /// * `task_id` - A unique identifier for the transcoding task.
+    /// * `source_path` - The path of the live source.
+    /// * `media_formats` - The media formats, all of which must have the `m3u8` extension.
+    /// * `is_gpu` - Whether to use GPU acceleration for transcoding.
+    ///
+    /// # Returns
+    /// The `LiveTranscode`, or an `InvalidArgument` status if a media format is not valid.
+    ///
+    pub fn new(
+        task_id: &str,
+        source_path: &str,
+        media_formats: &[Value],
+        is_gpu: bool,
+    ) -> Result<LiveTranscode, Status> {
+        let mut renditions = Vec::new();
+        for (format_index, format_json) in media_formats.iter().enumerate() {
+            let format: VideoFormat = serde_json::from_value(format_json.clone()).map_err(|e| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("Invalid video format: {}", e),
+                )
+            })?;
+
+            if format.ext != "m3u8" {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Live sources can only be transcoded to HLS, with ext m3u8",
+                ));
+            }
+
+            shared::set_encoder(task_id, format_index, format_encoder(is_gpu, &format));
+
+            renditions.push(LiveRendition {
+                format_index,
+                format_json: format_json.clone(),
+                format,
+                output_prefix: format!("{}{}_{}", *PATH_TO_TRANSCODED_FILE, task_id, format_index),
+                next_segment: 0,
+                uploaded: HashMap::new(),
+                playlist_cid: None,
+                portal_url: None,
+            });
+        }
+
+        Ok(LiveTranscode {
+            task_id: task_id.to_string(),
+            source_path: source_path.to_string(),
+            is_gpu,
+            renditions,
+            processed_secs: 0.0,
+            source_len: 0,
+            last_growth: Instant::now(),
+        })
+    }
+
+    /// Encodes and publishes any whole segments that have arrived since the last step, or the
+    /// remainder of the source once it has stopped growing.
+    ///
+    /// # Returns
+    /// Whether the source has finished and every playlist has been ended, or a `Status` error.
+    ///
+    pub async fn step(&mut self) -> Result<bool, Status> {
+        let source_len = fs::metadata(&self.source_path)
+            .map_err(|e| Status::new(Code::NotFound, format!("Failed to read live source: {}", e)))?
+            .len();
+
+        if source_len != self.source_len {
+            self.source_len = source_len;
+            self.last_growth = Instant::now();
+        }
+        let is_last = self.last_growth.elapsed() >= Duration::from_secs(*LIVE_IDLE_TIMEOUT_SECS);
+
+        let duration = probe_media_info(&self.source_path)
+            .map(|media_info| media_info.duration)
+            .unwrap_or_default();
+
+        // While the source grows, its last segment may still be incomplete, so only the whole
+        // segments before it are encoded
+        let segment_secs = *LIVE_SEGMENT_SECS;
+        let end = if is_last {
+            duration
+        } else {
+            ((duration / segment_secs).floor() - 1.0).max(0.0) * segment_secs
+        };
+
+        if end > self.processed_secs {
+            println!(
+                "Live task {}: transcoding {}s to {}s of {}",
+                self.task_id, self.processed_secs, end, self.source_path
+            );
+
+            for rendition in &mut self.renditions {
+                shared::update_progress(&self.task_id, rendition.format_index, Phase::Transcode, 0);
+                rendition.encode_range(
+                    &self.source_path,
+                    self.processed_secs,
+                    end,
+                    is_last,
+                    self.is_gpu,
+                )?;
+                rendition.publish().await?;
+            }
+            self.processed_secs = end;
+        }
+
+        if is_last {
+            for rendition in &self.renditions {
+                shared::update_progress(&self.task_id, rendition.format_index, Phase::Done, 100);
+            }
+        }
+
+        Ok(is_last)
+    }
+
+    /// Returns the media format objects of the renditions published so far, each with the
+    /// `cid` of its latest playlist.
+    pub fn transcoded_formats(&self) -> Vec<Value> {
+        self.renditions
+            .iter()
+            .filter_map(|rendition| {
+                let playlist_cid = rendition.playlist_cid.as_ref()?;
+                let mut format = rendition.format_json.clone();
+                format["cid"] = json!(playlist_cid);
+                format["live"] = json!(true);
+                format["encoder"] = json!(format_encoder(self.is_gpu, &rendition.format));
+                if let Some(portal_url) = &rendition.portal_url {
+                    format["portal"] = json!(portal_url);
+                }
+                Some(format)
+            })
+            .collect()
+    }
+}
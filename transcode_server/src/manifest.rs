@@ -0,0 +1,179 @@
+use crate::s5::upload_video;
+use crate::transcode_video::parse_bitrate;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs;
+
+/// The version of the manifest schema. Increment this whenever the layout of `Manifest`
+/// changes so that clients can tell which fields to expect.
+pub const MANIFEST_VERSION: u32 = 2;
+
+/// Describes every output of a transcoding job, giving clients a single CID that references
+/// all of the renditions of the transcoded asset.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub source: ManifestSource,
+    pub renditions: Vec<ManifestRendition>,
+    /// The HLS master playlist referencing every HLS rendition, if the job had any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hls_master_cid: Option<String>,
+}
+
+/// The source media that was transcoded.
+#[derive(Debug, Serialize)]
+pub struct ManifestSource {
+    pub cid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+}
+
+/// A single transcoded output of the job.
+#[derive(Debug, Serialize)]
+pub struct ManifestRendition {
+    pub id: u64,
+    pub cid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_cid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hls_playlist_cid: Option<String>,
+}
+
+/// Extracts the output resolution from a `scale=WIDTHxHEIGHT` (or `scale=WIDTH:HEIGHT`) video
+/// filter.
+fn resolution_from_vf(vf: &str) -> Option<String> {
+    vf.split(',')
+        .find_map(|filter| filter.trim().strip_prefix("scale="))
+        .map(|scale| scale.replace(':', "x"))
+}
+
+fn string_field(format: &Value, key: &str) -> Option<String> {
+    format[key].as_str().map(String::from)
+}
+
+/// Returns whether a media format object is an HLS rendition.
+pub fn is_hls(format: &Value) -> bool {
+    format["ext"]
+        .as_str()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Builds an HLS master playlist referencing the HLS renditions (`ext` `m3u8`) of a job by
+/// their CIDs, each with its peak bandwidth, from its `maxrate` or else `b_v`, plus `b_a`, and
+/// its resolution from a `scale` filter.
+///
+/// # Arguments
+/// * `transcoded_formats` - The transcoded media format objects.
+///
+/// # Returns
+/// The master playlist, or `None` if the job has no HLS renditions.
+///
+pub fn hls_master_playlist(transcoded_formats: &[Value]) -> Option<String> {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    let mut variants = 0;
+    for format in transcoded_formats {
+        let Some(cid) = string_field(format, "cid") else {
+            continue;
+        };
+        if !is_hls(format) {
+            continue;
+        }
+
+        let bitrate = |key: &str| format[key].as_str().and_then(parse_bitrate);
+        let bandwidth = bitrate("maxrate")
+            .or_else(|| bitrate("b_v"))
+            .unwrap_or_default()
+            + bitrate("b_a").unwrap_or_default();
+        let mut attributes = format!("BANDWIDTH={}", bandwidth.max(1));
+        // Only a fixed size, not e.g. `1280:-2`, which keeps the source's aspect ratio
+        let resolution = format["vf"]
+            .as_str()
+            .and_then(resolution_from_vf)
+            .filter(|resolution| {
+                resolution.split_once('x').is_some_and(|(width, height)| {
+                    width.parse::<u32>().is_ok() && height.parse::<u32>().is_ok()
+                })
+            });
+        if let Some(resolution) = resolution {
+            attributes.push_str(&format!(",RESOLUTION={}", resolution));
+        }
+
+        playlist.push_str(&format!("#EXT-X-STREAM-INF:{}\n{}\n", attributes, cid));
+        variants += 1;
+    }
+
+    (variants > 0).then_some(playlist)
+}
+
+impl Manifest {
+    /// Builds the manifest for a job from the media format objects returned to the client,
+    /// each of which has had its `cid` added once transcoded and uploaded.
+    ///
+    /// # Arguments
+    /// * `source_cid` - The CID of the source media.
+    /// * `duration` - The duration of the source media in seconds, if known.
+    /// * `transcoded_formats` - The transcoded media format objects.
+    ///
+    pub fn from_formats(
+        source_cid: &str,
+        duration: Option<f64>,
+        transcoded_formats: &[Value],
+    ) -> Manifest {
+        let renditions = transcoded_formats
+            .iter()
+            .filter_map(|format| {
+                let cid = string_field(format, "cid")?;
+
+                Some(ManifestRendition {
+                    id: format["id"].as_u64().unwrap_or_default(),
+                    cid,
+                    codec: string_field(format, "vcodec")
+                        .or_else(|| string_field(format, "acodec")),
+                    resolution: format["vf"].as_str().and_then(resolution_from_vf),
+                    bitrate: string_field(format, "b_v").or_else(|| string_field(format, "b_a")),
+                    thumbnail_cid: string_field(format, "thumbnail_cid"),
+                    hls_playlist_cid: string_field(format, "hls_playlist_cid"),
+                })
+            })
+            .collect();
+
+        Manifest {
+            version: MANIFEST_VERSION,
+            source: ManifestSource {
+                cid: source_cid.to_string(),
+                duration,
+            },
+            renditions,
+            hls_master_cid: transcoded_formats
+                .iter()
+                .find_map(|format| string_field(format, "hls_master_cid")),
+        }
+    }
+
+    /// Writes the manifest to `file_path` as JSON and uploads it to the given storage network.
+    ///
+    /// # Arguments
+    /// * `file_path` - Where to write the manifest before uploading it.
+    /// * `dest` - The storage network to upload to, `s5` or `ipfs`. Defaults to `s5`.
+    ///
+    /// # Returns
+    /// The CID of the uploaded manifest, prefixed with `s5://` or `ipfs://`.
+    ///
+    pub async fn upload(&self, file_path: &str, dest: Option<String>) -> anyhow::Result<String> {
+        fs::write(file_path, serde_json::to_string(self)?)?;
+
+        let scheme = match dest.as_deref() {
+            Some("ipfs") => "ipfs",
+            _ => "s5",
+        };
+        let uploaded = upload_video(file_path, dest).await?;
+
+        Ok(format!("{}://{}", scheme, uploaded.cid))
+    }
+}
@@ -0,0 +1,238 @@
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::fmt;
+use std::process::Command;
+
+// Whether the ffprobe binary can be run, checked once
+static FFPROBE_AVAILABLE: Lazy<bool> = Lazy::new(|| {
+    Command::new("ffprobe")
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+});
+
+/// Returns whether ffprobe is installed. Without it sources can still be transcoded, but their
+/// properties, including their duration, are unknown.
+pub fn ffprobe_available() -> bool {
+    *FFPROBE_AVAILABLE
+}
+
+/// A stream of a source media file.
+#[derive(Debug, Clone)]
+pub struct MediaStream {
+    /// The index of the stream in the file, as used by `-map 0:<index>`.
+    pub index: u32,
+    /// The type of the stream, e.g. `video`, `audio` or `subtitle`.
+    pub codec_type: String,
+}
+
+/// Properties of a source media file, read with a single `ffprobe` call. Properties of a
+/// stream the file doesn't have, e.g. the video properties of an audio file, are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct MediaInfo {
+    /// Duration in seconds, 0 if unknown.
+    pub duration: f64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Frames per second of the video stream.
+    pub fps: Option<f64>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    /// Number of channels of the audio stream.
+    pub channels: Option<u32>,
+    /// Sample rate of the audio stream in Hz.
+    pub sample_rate: Option<u32>,
+    /// Overall bitrate in bits per second.
+    pub bitrate: Option<u64>,
+    /// Every stream of the file, in order.
+    pub streams: Vec<MediaStream>,
+}
+
+/// Parses an ffprobe frame rate such as `30000/1001` or `25/1`.
+fn parse_frame_rate(frame_rate: &str) -> Option<f64> {
+    let (numerator, denominator) = frame_rate.split_once('/')?;
+    let numerator = numerator.parse::<f64>().ok()?;
+    let denominator = denominator.parse::<f64>().ok()?;
+
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return None;
+    }
+    Some(numerator / denominator)
+}
+
+/// Parses a number that ffprobe reports as a JSON string, e.g. `"duration": "12.345"`.
+fn parse_field<T: std::str::FromStr>(value: &Value, key: &str) -> Option<T> {
+    match &value[key] {
+        Value::String(s) => s.parse().ok(),
+        Value::Number(n) => n.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+impl MediaInfo {
+    /// Builds a `MediaInfo` from the output of `ffprobe -print_format json -show_format
+    /// -show_streams`, taking the video and audio properties from the first stream of each.
+    fn from_ffprobe_json(probe: &Value) -> MediaInfo {
+        let streams = probe["streams"].as_array().cloned().unwrap_or_default();
+        let first_stream = |codec_type: &str| {
+            streams
+                .iter()
+                .find(|stream| stream["codec_type"] == codec_type)
+                .cloned()
+        };
+        let video = first_stream("video");
+        let audio = first_stream("audio");
+
+        let format = &probe["format"];
+        let duration = parse_field::<f64>(format, "duration")
+            .or_else(|| video.as_ref().and_then(|v| parse_field(v, "duration")))
+            .or_else(|| audio.as_ref().and_then(|a| parse_field(a, "duration")))
+            .unwrap_or_default();
+
+        MediaInfo {
+            duration,
+            width: video.as_ref().and_then(|v| parse_field(v, "width")),
+            height: video.as_ref().and_then(|v| parse_field(v, "height")),
+            fps: video.as_ref().and_then(|v| {
+                v["avg_frame_rate"]
+                    .as_str()
+                    .and_then(parse_frame_rate)
+                    .or_else(|| v["r_frame_rate"].as_str().and_then(parse_frame_rate))
+            }),
+            video_codec: video
+                .as_ref()
+                .and_then(|v| v["codec_name"].as_str().map(String::from)),
+            audio_codec: audio
+                .as_ref()
+                .and_then(|a| a["codec_name"].as_str().map(String::from)),
+            channels: audio.as_ref().and_then(|a| parse_field(a, "channels")),
+            sample_rate: audio.as_ref().and_then(|a| parse_field(a, "sample_rate")),
+            bitrate: parse_field(format, "bit_rate"),
+            streams: streams
+                .iter()
+                .filter_map(|stream| {
+                    Some(MediaStream {
+                        index: stream["index"].as_u64()? as u32,
+                        codec_type: stream["codec_type"].as_str()?.to_string(),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "duration: {}s", self.duration)?;
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            write!(f, ", resolution: {}x{}", width, height)?;
+        }
+        if let Some(fps) = self.fps {
+            write!(f, ", fps: {:.3}", fps)?;
+        }
+        if let Some(video_codec) = &self.video_codec {
+            write!(f, ", video: {}", video_codec)?;
+        }
+        if let Some(audio_codec) = &self.audio_codec {
+            write!(f, ", audio: {}", audio_codec)?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, ", channels: {}", channels)?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            write!(f, ", sample rate: {}", sample_rate)?;
+        }
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ", bitrate: {}", bitrate)?;
+        }
+        Ok(())
+    }
+}
+
+/// Probes a media file with `ffprobe`.
+///
+/// # Arguments
+/// * `file_path`: Path to the media file.
+///
+/// # Returns:
+/// `Result<MediaInfo, String>` - The properties of the media file or error message, also if
+/// ffprobe isn't installed.
+///
+pub fn probe_media_info(file_path: &str) -> Result<MediaInfo, String> {
+    if !ffprobe_available() {
+        return Err(String::from("ffprobe is not installed"));
+    }
+
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            file_path,
+        ])
+        .output()
+        .map_err(|e| format!("Failed to execute ffprobe: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from("Failed to probe media file"));
+    }
+
+    let probe: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse ffprobe output: {}", e))?;
+
+    Ok(MediaInfo::from_ffprobe_json(&probe))
+}
+
+/// Returns whether a source must be read from a seekable file rather than streamed, judging by
+/// its first bytes. An MP4-family file (starting with an `ftyp` box) can only be streamed if
+/// its index, the `moov` box, comes before the media data in the `mdat` box; otherwise ffmpeg
+/// has to seek to the end of the file before it can decode anything. Other containers can be
+/// streamed.
+///
+/// # Arguments
+/// * `prefix` - The first bytes of the source.
+///
+/// # Returns
+/// `true` if the source needs seeking, or if `prefix` ends before the `moov` box is found.
+///
+pub fn requires_seekable_input(prefix: &[u8]) -> bool {
+    if prefix.get(4..8) != Some(b"ftyp".as_slice()) {
+        return false;
+    }
+
+    let mut offset: usize = 0;
+    while let Some(header) = prefix.get(offset..offset + 8) {
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        match &header[4..8] {
+            b"moov" => return false,
+            b"mdat" => return true,
+            _ => {}
+        }
+
+        let size = match size {
+            // A 64-bit size follows the box type
+            1 => match prefix.get(offset + 8..offset + 16) {
+                Some(large_size) => u64::from_be_bytes(large_size.try_into().unwrap()),
+                None => return true,
+            },
+            // The box extends to the end of the file, so nothing follows it
+            0 => return true,
+            size => size,
+        };
+        if size < 8 {
+            return true;
+        }
+        offset = match usize::try_from(size)
+            .ok()
+            .and_then(|size| offset.checked_add(size))
+        {
+            Some(offset) => offset,
+            None => return true,
+        };
+    }
+
+    true
+}
@@ -0,0 +1,1089 @@
+use crate::encrypt_file::{decrypt_file_xchacha20_with_chunk_size, ENCRYPTION_TAG_SIZE};
+use crate::encrypted_cid::parse_encrypted_cid_str;
+use crate::ipfs::{ipfs_source, verify_ipfs_cid};
+use crate::manifest::{hls_master_playlist, is_hls, Manifest};
+use crate::media_info::{probe_media_info, requires_seekable_input, MediaInfo};
+use crate::result_store::{job_key, load_job, store_job, StoredJob};
+use crate::s5::{
+    configured_storage_backends, download_prefix, hash_blake3_file_async, primary_portal_url,
+    upload_video,
+};
+use crate::shared::{self, Phase};
+use crate::transcode_video::{
+    check_format_extensions, check_format_prefixes, check_gpu_encoders, check_hls_ladder,
+    check_unique_format_ids, expand_audio_bitrates, expand_audio_layouts,
+    get_video_format_from_str, transcode_video, transcode_videos, TranscodeVideoResponse,
+};
+use crate::utils::{
+    bytes_to_base64url, check_downloaded_media, download_and_concat_files, download_video,
+    find_downloaded_source, remove_temp_file, rename_with_container_extension, TEMP_DIR,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use dotenv::var;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::read_to_string;
+use std::path::Path;
+use tonic::{Code, Response, Status};
+use uuid::Uuid;
+
+static PATH_TO_FILE: Lazy<String> =
+    Lazy::new(|| var("PATH_TO_FILE").unwrap_or_else(|_| panic!("PATH_TO_FILE not set in .env")));
+static PATH_TO_TRANSCODED_FILE: Lazy<String> = Lazy::new(|| {
+    var("PATH_TO_TRANSCODED_FILE")
+        .unwrap_or_else(|_| panic!("PATH_TO_TRANSCODED_FILE not set in .env"))
+});
+
+// Whether to transcode the formats of a job with a single ffmpeg command where possible
+static MULTI_OUTPUT_TRANSCODE: Lazy<bool> = Lazy::new(|| {
+    var("MULTI_OUTPUT_TRANSCODE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(false)
+});
+
+// Whether encrypted sources must use vup's encryption scheme, rejecting salted CIDs and other
+// chunk sizes
+static VUP_COMPATIBLE_SOURCES: Lazy<bool> = Lazy::new(|| {
+    var("VUP_COMPATIBLE_SOURCES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(false)
+});
+
+// Whether to stream unencrypted sources into ffmpeg while they download, rather than
+// transcoding them once downloaded
+static STREAM_SOURCES: Lazy<bool> = Lazy::new(|| {
+    var("STREAM_SOURCES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(false)
+});
+
+// Bytes read from the start of a source to check whether it can be streamed
+const STREAM_PREFIX_SIZE: u64 = 1 << 20;
+
+/// Options of a transcoding job.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TranscodeOptions {
+    /// Whether the source is encrypted and the outputs should be encrypted.
+    pub is_encrypted: bool,
+    /// Whether to use GPU acceleration for transcoding.
+    pub is_gpu: bool,
+    /// Whether to download and transcode again even if the source or a format is cached.
+    pub force: bool,
+    /// The blake3 hash an unencrypted source must have to be transcoded.
+    pub expected_hash: Option<blake3::Hash>,
+}
+
+/// The outcome of a transcoding job.
+#[derive(Debug, Clone, Default)]
+pub struct JobResult {
+    /// The media format objects that were transcoded, each with the CID of its upload.
+    pub transcoded_formats: Vec<Value>,
+    /// The id and error message of each format that failed to transcode.
+    pub failed_formats: Vec<(u32, String)>,
+    /// CID of the manifest describing all outputs of the job, if one was uploaded.
+    pub manifest_cid: Option<String>,
+    /// Whether the job was cancelled before all formats were transcoded.
+    pub cancelled: bool,
+    /// The key of the job in the result store, if its result was stored or reused from it.
+    pub job_key: Option<String>,
+}
+
+fn get_file_size(file_path: String) -> std::io::Result<u64> {
+    let metadata = fs::metadata(file_path)?;
+    Ok(metadata.len())
+}
+
+/**
+ * Extracts the encryption key from an encrypted CID.
+ * @param encrypted_cid - The encrypted CID to get the key from.
+ * @returns The encryption key from the CID, or an error if the CID can't be parsed.
+ */
+pub fn get_key_from_encrypted_cid(encrypted_cid: &str) -> Result<String> {
+    println!(
+        "get_key_from_encrypted_cid: encrypted_cid = {}",
+        encrypted_cid
+    );
+
+    let key = bytes_to_base64url(&parse_encrypted_cid_str(encrypted_cid)?.encryption_key);
+    println!("get_key_from_encrypted_cid: key = {}", key);
+
+    Ok(key)
+}
+
+fn number_of_bytes(value: u32) -> usize {
+    let mut value = value;
+    let mut bytes = 1;
+
+    while value >= 256 {
+        value >>= 8;
+        bytes += 1;
+    }
+
+    bytes
+}
+
+/// Calculates the SHA-256 hash of the given `encrypted_cid`, encrypts it using AES-256-CBC with
+/// the specified `key`, and then encodes the result as a URL-safe base64 string. This function is
+/// designed for securing sensitive identifiers before storage or transmission.
+///
+/// # Arguments
+/// * `encrypted_cid` - The content identifier to be hashed, encrypted, and encoded.
+///
+pub fn get_base64_url_encrypted_blob_hash(encrypted_cid: &str) -> Result<String> {
+    let encrypted_blob_hash = parse_encrypted_cid_str(encrypted_cid)?.encrypted_blob_hash;
+
+    Ok(bytes_to_base64url(&encrypted_blob_hash))
+}
+
+/// Generates a random filename with the given `prefix` and `extension`.
+/// The filename is guaranteed to be unique and not already exist in the
+/// current directory. Returns the resulting filename as a `String`.
+///
+/// # Arguments
+///
+/// * `prefix` - The prefix to use for the filename.
+/// * `extension` - The extension to use for the filename.
+///
+fn generate_random_filename() -> String {
+    let uuid = Uuid::new_v4();
+    let timestamp = Utc::now().timestamp_nanos();
+    format!("{}_{}", uuid, timestamp)
+}
+
+/// Downloads a source, probes it, transcodes it to each of the media formats and uploads the
+/// results. A format that fails to transcode is recorded in `JobResult::failed_formats` and
+/// doesn't end the job. If the task is cancelled, it stops before transcoding the next format.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task, used to report progress and check for cancellation.
+/// * `source` - The CID of the source video, optionally with an extension, or an IPFS source.
+/// * `media_formats` - JSON array of the media formats to transcode to, or empty to use
+///   `MEDIA_FORMATS_FILE`.
+/// * `options` - The options of the job.
+///
+/// # Returns
+/// The result of the job, or an error if the source couldn't be downloaded or the media formats
+/// are invalid.
+///
+pub async fn transcode_asset(
+    task_id: &str,
+    source: &str,
+    media_formats: &str,
+    options: &TranscodeOptions,
+) -> Result<JobResult> {
+    let TranscodeOptions {
+        is_encrypted,
+        is_gpu,
+        force,
+        expected_hash,
+    } = *options;
+
+    let ipfs_source = ipfs_source(source);
+    if ipfs_source.is_some() && is_encrypted {
+        return Err(anyhow!("IPFS sources cannot be encrypted: {}", source));
+    }
+    if expected_hash.is_some() && is_encrypted {
+        return Err(anyhow!(
+            "The expected hash of an encrypted source can't be checked: {}",
+            source
+        ));
+    }
+
+    let source_cid = Path::new(source)
+        .with_extension("")
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .map(|s| s.to_string());
+
+    let source_cid = source_cid.ok_or_else(|| anyhow!("Invalid source CID: {}", source))?;
+
+    // Another task transcoding the same source would share its download and output files
+    let _source_guard = shared::lock_source(&source_cid).await;
+
+    let portal_url_result = if is_encrypted {
+        var("PORTAL_ENCRYPT_URL")
+    } else {
+        var("PORTAL_URL")
+    };
+
+    let portal_url = match portal_url_result {
+        Ok(urls) => primary_portal_url(&urls),
+        Err(_) => {
+            return Err(anyhow!(
+                "Required environment variable for PORTAL_URL not found"
+            ))
+        }
+    };
+
+    println!("source_cid: {}", source_cid);
+    println!("portal_url: {}", portal_url);
+
+    // Load the formats before downloading, so their progress covers the download too
+    let media_formats_vec = load_media_formats(task_id, media_formats)
+        .map_err(|e| anyhow!("Invalid media formats: {}", e))?;
+    check_gpu_encoders(is_gpu, &media_formats_vec)
+        .map_err(|e| anyhow!("Invalid media formats: {}", e.message()))?;
+    check_hls_ladder(is_encrypted, &media_formats_vec)
+        .map_err(|e| anyhow!("Invalid media formats: {}", e.message()))?;
+
+    // An identical job that already finished is answered from the result store. Encrypted jobs
+    // are never stored, as their CIDs embed the keys of that run. Without a stored result, the
+    // per-format cache of transcoded files below still applies.
+    let job_key = (!is_encrypted).then(|| job_key(source, &media_formats_vec, is_encrypted));
+    let stored_job = job_key.as_deref().and_then(load_job);
+    let stored_at = |stored: &StoredJob| {
+        Utc.timestamp_opt(stored.created_at, 0)
+            .single()
+            .map(|created_at| created_at.to_rfc3339())
+            .unwrap_or_default()
+    };
+    if let Some(stored) = stored_job.as_ref().filter(|_| force) {
+        println!(
+            "Task {}: force re-transcode, overriding the stored result of job {} from {}",
+            task_id,
+            stored.key,
+            stored_at(stored)
+        );
+    }
+    if let Some(stored) = stored_job.filter(|_| !force) {
+        println!(
+            "Task {}: reusing the stored result of job {} from {}",
+            task_id,
+            stored.key,
+            stored_at(&stored)
+        );
+        for (index, rendition) in stored.transcoded_formats.iter().enumerate() {
+            shared::update_progress(task_id, index, Phase::Done, 100);
+            shared::record_rendition(task_id, rendition.clone());
+        }
+        return Ok(JobResult {
+            transcoded_formats: stored.transcoded_formats,
+            manifest_cid: stored.manifest_cid,
+            job_key: Some(stored.key),
+            ..JobResult::default()
+        });
+    }
+
+    shared::update_task_progress(task_id, Phase::Download, 0);
+
+    let mut file_path = format!("{}{}", *PATH_TO_FILE, source_cid);
+
+    let cached_file_path = match find_downloaded_source(&file_path) {
+        Some(cached_file_path) if force => {
+            println!(
+                "Task {}: force re-transcode, downloading again over cached source {}",
+                task_id, cached_file_path
+            );
+            None
+        }
+        cached_file_path => cached_file_path,
+    };
+
+    // An unencrypted source that can be read without seeking is transcoded as it downloads,
+    // unless its hash has to be checked before it is transcoded
+    let streamed = if *STREAM_SOURCES
+        && cached_file_path.is_none()
+        && ipfs_source.is_none()
+        && !is_encrypted
+        && expected_hash.is_none()
+    {
+        let url = format!("{}{}{}", portal_url, "/s5/blob/", source_cid);
+        stream_source(task_id, &url, &file_path, &media_formats_vec, options).await
+    } else {
+        None
+    };
+
+    if let Some(cached_file_path) = cached_file_path {
+        println!("File already exists: {}", &cached_file_path);
+        file_path = cached_file_path;
+    } else if streamed.is_some() {
+        println!("Source {} streamed, skipping the download", source_cid);
+    } else {
+        if let Some((ipfs_cid, url)) = ipfs_source {
+            match download_video(&url, file_path.as_str(), task_id).await {
+                Ok(_) => println!("Video downloaded successfully"),
+                Err(e) if e.code() == Code::Cancelled => return Ok(cancelled_job(task_id)),
+                Err(e) => return Err(anyhow!("Failed to download video from URL {}: {}", &url, e)),
+            };
+
+            // Don't transcode, or cache, content the gateway returned for a different CID
+            if let Err(e) = verify_ipfs_cid(&file_path, &ipfs_cid) {
+                if let Err(e) = fs::remove_file(&file_path) {
+                    eprintln!("Failed to remove {}: {}", file_path, e);
+                }
+                return Err(anyhow!("Failed to verify IPFS source {}: {}", ipfs_cid, e));
+            }
+            println!("Verified IPFS source: {}", ipfs_cid);
+        } else if is_encrypted {
+            println!("source_cid: {}", source_cid);
+            //            println!("Encrypted CID: {}", source_cid);
+            // The blob hash, key and decryption parameters of the source, which must all be
+            // supported before anything is downloaded
+            let encrypted_cid = parse_encrypted_cid_str(&source_cid)
+                .map_err(|e| anyhow!("Invalid encrypted source CID {}: {}", source_cid, e))?;
+            let chunk_size = encrypted_cid
+                .chunk_size()
+                .map_err(|e| anyhow!("Invalid encrypted source CID {}: {}", source_cid, e))?;
+            if *VUP_COMPATIBLE_SOURCES {
+                encrypted_cid
+                    .check_vup_compatible()
+                    .map_err(|e| anyhow!("Invalid encrypted source CID {}: {}", source_cid, e))?;
+            }
+            let padding = encrypted_cid.padding as usize;
+            if padding >= chunk_size {
+                return Err(anyhow!(
+                    "Invalid encrypted source CID {}: padding {} is not smaller than the chunk size {}",
+                    source_cid,
+                    padding,
+                    chunk_size
+                ));
+            }
+
+            // // Extract the BASE64_URL_ENCRYPTED_BLOB_HASH from encrypted CID
+            let base64_url_encrypted_blob_hash =
+                bytes_to_base64url(&encrypted_cid.encrypted_blob_hash);
+
+            // // GET https://s5.cx/api/locations/BASE64_URL_ENCRYPTED_BLOB_HASH?types=5,3 to get download urls for your encrypted file
+            let url = format!(
+                "{}{}{}?types=5,3",
+                portal_url, "/api/locations/", base64_url_encrypted_blob_hash
+            );
+            println!("Downloading and then transcoding video from URL: {}", &url);
+
+            let encrypted_file_path = format!("{}{}_", *TEMP_DIR, source_cid);
+
+            match download_video(&url, encrypted_file_path.as_str(), task_id).await {
+                Ok(_) => println!("Video downloaded successfully"),
+                Err(e) if e.code() == Code::Cancelled => return Ok(cancelled_job(task_id)),
+                Err(e) => {
+                    return Err(anyhow!(
+                        "Failed to download encrypted video from URL {}: {}",
+                        &url,
+                        e
+                    ))
+                }
+            };
+
+            let encrypted_metadata = match std::fs::read_to_string(&encrypted_file_path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    return Err(anyhow!(
+                        "Failed to read encrypted metadata from file {}: {}",
+                        &encrypted_file_path,
+                        e
+                    ))
+                }
+            };
+            remove_temp_file(&encrypted_file_path);
+
+            let file_path_encrypted = format!("{}{}", *TEMP_DIR, generate_random_filename());
+
+            println!("file_encrypted_metadata: {:?}", file_path_encrypted);
+            println!("encrypted_metadata: {:?}", encrypted_metadata);
+
+            // get download urls for your encrypted file
+            // and then just download the encrypted file using any http download library
+            match download_and_concat_files(
+                encrypted_metadata,
+                file_path_encrypted.clone(),
+                task_id,
+            )
+            .await
+            {
+                Ok(()) => println!("Download and concatenation succeeded"),
+                Err(e) => eprintln!("Download and concatenation failed: {}", e),
+            }
+            if shared::is_cancelled(task_id) {
+                remove_temp_file(&file_path_encrypted);
+                return Ok(cancelled_job(task_id));
+            }
+
+            let file_encrypted_size = get_file_size(file_path_encrypted.clone()).unwrap();
+            println!("file_path_encrypted: {}", file_path_encrypted);
+            println!("file_encrypted_size: {}", file_encrypted_size);
+
+            // The last chunk is the one holding the final byte, with the chunk size and padding
+            // the source was encrypted with
+            let encrypted_chunk_size = (chunk_size + ENCRYPTION_TAG_SIZE) as u64;
+            let last_index_size =
+                (file_encrypted_size.saturating_sub(1) / encrypted_chunk_size) as u32;
+
+            let key_bytes = encrypted_cid.encryption_key.clone();
+
+            println!("file_path: {}", file_path);
+            println!("chunk_size: {}", chunk_size);
+            println!("padding: {}", padding);
+            println!("last_index_size: {}", last_index_size);
+
+            // decrypt_file_xchacha20 from vup, with the source's nonce salt if it has one
+            let decrypted = decrypt_file_xchacha20_with_chunk_size(
+                file_path_encrypted.clone(),
+                file_path.clone(),
+                key_bytes,
+                &encrypted_cid.nonce_salt.unwrap_or_default(),
+                chunk_size,
+                padding,
+                last_index_size,
+            );
+            // Only the decrypted source is kept
+            remove_temp_file(&file_path_encrypted);
+            match decrypted {
+                Ok(_) => println!("Decryption succeeded"),
+                Err(error) => return Err(anyhow!("Decryption error: {:?}", error)),
+            }
+        } else {
+            let url = format!("{}{}{}", portal_url, "/s5/blob/", source_cid);
+
+            // First, we download the video and save it locally
+            match download_video(&url, file_path.as_str(), task_id).await {
+                Ok(_) => println!("Video downloaded successfully"),
+                Err(e) if e.code() == Code::Cancelled => return Ok(cancelled_job(task_id)),
+                Err(e) => return Err(anyhow!("Failed to download video from URL {}: {}", &url, e)),
+            };
+        }
+
+        check_downloaded_media(&file_path)
+            .map_err(|e| anyhow!("Failed to download source {}: {}", source_cid, e.message()))?;
+        file_path = rename_with_container_extension(&file_path);
+    }
+
+    // Checked for a cached source too, so that content cached from a bad download isn't reused
+    if let Some(expected_hash) = expected_hash {
+        if let Err(e) = verify_source_hash(&file_path, expected_hash).await {
+            if let Err(e) = fs::remove_file(&file_path) {
+                eprintln!("Failed to remove {}: {}", file_path, e);
+            }
+            return Err(anyhow!(
+                "Failed to verify source {}: {}",
+                source_cid,
+                e.message()
+            ));
+        }
+        println!("Verified the hash of source {}", source_cid);
+    }
+    shared::update_task_progress(task_id, Phase::Download, 100);
+
+    // Formats transcoded together by a single ffmpeg command, by format index
+    let mut multi_output_results = HashMap::new();
+    let media_info = if let Some((media_info, results)) = streamed {
+        multi_output_results.extend(results);
+        media_info
+    } else {
+        // Probe the source once, rather than once per format
+        // Without its duration, the transcoding progress of the source is unknown
+        let media_info = probe_media_info(&file_path).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to probe source media, its progress will be unknown: {}",
+                e
+            );
+            MediaInfo::default()
+        });
+        println!("Source media: {}", media_info);
+
+        if *MULTI_OUTPUT_TRANSCODE {
+            let formats = formats_to_transcode(&media_formats_vec, &file_path, force).await;
+            if let Some(results) = transcode_videos(
+                task_id.to_string(),
+                &formats,
+                &file_path,
+                is_encrypted,
+                is_gpu,
+                &media_info,
+                None,
+            )
+            .await
+            {
+                multi_output_results.extend(results);
+            }
+        }
+        media_info
+    };
+
+    // Then, we transcode the downloaded video with each video format
+    let mut transcoded_formats = Vec::new();
+    let mut failed_formats = Vec::new();
+    for (index, video_format) in media_formats_vec.iter().enumerate() {
+        if shared::is_cancelled(task_id) {
+            println!("Task {} cancelled, skipping remaining formats", task_id);
+            return Ok(JobResult {
+                transcoded_formats,
+                cancelled: true,
+                ..JobResult::default()
+            });
+        }
+
+        let video_format_str = match serde_json::to_string(&video_format) {
+            Ok(str) => str,
+            Err(e) => {
+                eprintln!("Error serializing video format: {:?}", e);
+                continue;
+            }
+        };
+
+        let format_result = get_video_format_from_str(&video_format_str);
+        let format = match format_result {
+            Ok(format) => format,
+            Err(e) => {
+                eprintln!("Failed to get video format from string: {}", e);
+                continue; // Skip the rest of this loop iteration
+            }
+        };
+
+        let is_cached = check_transcoded_file_exists(
+            file_path.as_str(),
+            &format.id.to_string(),
+            format.ext.as_str(),
+        )
+        .await;
+        if is_cached && force {
+            println!(
+                "Task {}: force re-transcode, overwriting cached format {}",
+                task_id, format.id
+            );
+        }
+
+        if force || !is_cached {
+            let transcode_result: std::prelude::v1::Result<
+                Response<TranscodeVideoResponse>,
+                Status,
+            > = match multi_output_results.remove(&index) {
+                Some(result) => result,
+                None => {
+                    transcode_video(
+                        task_id.to_string(),
+                        index,
+                        &file_path,
+                        &video_format_str,
+                        is_encrypted,
+                        is_gpu,
+                        &media_info,
+                    )
+                    .await
+                }
+            };
+
+            let current_progress = shared::calculate_overall_progress(task_id);
+            println!(
+                "Current Overall Progress for task {}: {}%",
+                task_id, current_progress
+            );
+
+            match transcode_result {
+                Ok(transcode_video_response) => {
+                    // Handle the successful response
+                    let response = transcode_video_response.into_inner();
+                    println!(
+                        "Response: status_code: {}, message: {}, cid: {}",
+                        response.status_code, response.message, response.cid
+                    );
+
+                    // Create a mutable clone of video_format
+                    let mut video_format_modified = video_format.clone();
+
+                    match &format.dest {
+                        Some(dest) if dest == "ipfs" => {
+                            video_format_modified["cid"] =
+                                json!(format!("ipfs://{}", response.cid));
+                        }
+                        _ => {
+                            video_format_modified["cid"] = json!(format!("s5://{}", response.cid));
+                        }
+                    }
+                    if let Some(unencrypted_cid) = &response.unencrypted_cid {
+                        let scheme = match format.dest.as_deref() {
+                            Some("ipfs") => "ipfs",
+                            _ => "s5",
+                        };
+                        video_format_modified["unencrypted_cid"] =
+                            json!(format!("{}://{}", scheme, unencrypted_cid));
+                    }
+                    if !response.sprite_cids.is_empty() {
+                        video_format_modified["sprite_cids"] = json!(response.sprite_cids);
+                    }
+                    if let Some(encoder) = response.encoder {
+                        video_format_modified["encoder"] = json!(encoder);
+                    }
+                    if let Some(name) = &response.name {
+                        video_format_modified["name"] = json!(name);
+                    }
+                    if let Some(key) = &response.key {
+                        video_format_modified["key"] = json!(key);
+                    }
+                    if let Some(portal_url) = &response.portal_url {
+                        video_format_modified["portal"] = json!(portal_url);
+                    }
+                    shared::record_rendition(task_id, video_format_modified.clone());
+                    transcoded_formats.push(video_format_modified);
+                }
+                Err(e) if e.code() == Code::Cancelled => {
+                    println!(
+                        "Task {} cancelled while uploading format {}",
+                        task_id, format.id
+                    );
+                    return Ok(JobResult {
+                        transcoded_formats,
+                        cancelled: true,
+                        ..JobResult::default()
+                    });
+                }
+                Err(e) => {
+                    // Record the failure separately and continue with the next format, so a
+                    // failed rendition is never reported with an empty CID
+                    eprintln!("Error transcoding video: {:?}", e);
+                    shared::set_format_error(task_id, index, e.message().to_string());
+                    failed_formats.push((format.id, e.message().to_string()));
+                }
+            }
+        }
+    }
+
+    for (id, message) in &failed_formats {
+        eprintln!("Task {}: format {} failed: {}", task_id, id, message);
+    }
+
+    // A master playlist of the HLS renditions lets players switch between them
+    if let Some(master_playlist) = hls_master_playlist(&transcoded_formats) {
+        let master_path = format!("{}{}_master.m3u8", *PATH_TO_TRANSCODED_FILE, task_id);
+        let dest = transcoded_formats
+            .iter()
+            .find(|format| is_hls(format))
+            .and_then(|format| format["dest"].as_str().map(String::from));
+        let scheme = match dest.as_deref() {
+            Some("ipfs") => "ipfs",
+            _ => "s5",
+        };
+
+        let uploaded = match fs::write(&master_path, master_playlist) {
+            Ok(()) => upload_video(&master_path, dest).await,
+            Err(e) => Err(anyhow!("Failed to write {}: {}", master_path, e)),
+        };
+        match uploaded {
+            Ok(uploaded) => {
+                let master_cid = format!("{}://{}", scheme, uploaded.cid);
+                println!("HLS master playlist uploaded: {}", master_cid);
+                for format in transcoded_formats.iter_mut() {
+                    if is_hls(format) {
+                        format["hls_master_cid"] = json!(master_cid);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to upload HLS master playlist: {}", e),
+        }
+    }
+
+    let manifest_cid = if is_encrypted {
+        // The manifest would list the encrypted CIDs, which embed their keys, so it is not
+        // uploaded in the clear for encrypted jobs
+        None
+    } else if transcoded_formats.is_empty() {
+        None
+    } else {
+        let duration = Some(media_info.duration).filter(|&duration| duration > 0.0);
+        let manifest = Manifest::from_formats(&source_cid, duration, &transcoded_formats);
+        let manifest_path = format!("{}{}_manifest.json", *PATH_TO_TRANSCODED_FILE, task_id);
+        let dest = transcoded_formats[0]["dest"].as_str().map(String::from);
+
+        match manifest.upload(&manifest_path, dest).await {
+            Ok(cid) => {
+                println!("Manifest uploaded: {}", cid);
+                Some(cid)
+            }
+            Err(e) => {
+                eprintln!("Failed to upload manifest: {}", e);
+                None
+            }
+        }
+    };
+
+    // Only a job that produced every format is stored, so a partial result is never reused
+    let job_key = job_key.filter(|_| {
+        failed_formats.is_empty() && transcoded_formats.len() == media_formats_vec.len()
+    });
+    if let Some(key) = &job_key {
+        store_job(&StoredJob {
+            key: key.clone(),
+            source: source.to_string(),
+            transcoded_formats: transcoded_formats.clone(),
+            manifest_cid: manifest_cid.clone(),
+            created_at: Utc::now().timestamp(),
+        });
+    }
+
+    Ok(JobResult {
+        transcoded_formats,
+        failed_formats,
+        manifest_cid,
+        cancelled: false,
+        job_key,
+    })
+}
+
+/// Removes the files a job left in the cache: its downloaded source in `PATH_TO_FILE`, and its
+/// transcoded outputs and manifest in `PATH_TO_TRANSCODED_FILE`, which are named after the
+/// source or the task. Used to clean up after jobs whose results aren't worth caching.
+///
+/// # Arguments
+/// * `source_cid` - The source CID of the job, without its scheme or extension.
+/// * `task_id` - The identifier of the task that ran the job.
+///
+pub fn remove_job_files(source_cid: &str, task_id: &str) {
+    for dir in [PATH_TO_FILE.as_str(), PATH_TO_TRANSCODED_FILE.as_str()] {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(source_cid) || name.starts_with(task_id) {
+                remove_temp_file(&entry.path().to_string_lossy());
+            }
+        }
+    }
+}
+
+/// A rendition copied to another storage network by `mirror_rendition`.
+#[derive(Debug, Clone)]
+pub struct MirroredRendition {
+    /// The CID on the target network, with its `s5://` or `ipfs://` scheme.
+    pub cid: String,
+    /// The S5 portal that stored the rendition, when it was uploaded to S5.
+    pub portal_url: Option<String>,
+    /// Whether the rendition was already on the target network, so nothing was uploaded.
+    pub existing: bool,
+}
+
+/// Copies an uploaded rendition to another storage network without transcoding it again: the
+/// content is downloaded by its CID, from the first portal in `PORTAL_URL` for S5 or from the
+/// IPFS gateway, and uploaded to `dest` like a transcoded rendition.
+///
+/// # Arguments
+/// * `cid` - The rendition's CID, `s5://<cid>` (or a bare S5 CID) or an IPFS source.
+/// * `dest` - The storage network to copy it to, `s5` or `ipfs`.
+///
+/// # Returns
+/// The rendition on `dest`, which is the given CID if it is already on `dest`. An
+/// `InvalidArgument` status for an unknown `dest` or invalid or encrypted CID, a
+/// `FailedPrecondition` status if `dest` isn't configured, or the status of a failed download
+/// or upload.
+///
+pub async fn mirror_rendition(cid: &str, dest: &str) -> Result<MirroredRendition, Status> {
+    if dest != "s5" && dest != "ipfs" {
+        return Err(Status::invalid_argument(format!(
+            "Unknown storage network {}, expected s5 or ipfs",
+            dest
+        )));
+    }
+    if !configured_storage_backends().contains(&dest) {
+        return Err(Status::failed_precondition(format!(
+            "Uploading to {} isn't configured",
+            dest
+        )));
+    }
+
+    let (scheme, cid, url) = match ipfs_source(cid) {
+        Some((ipfs_cid, url)) => ("ipfs", ipfs_cid, url),
+        None => {
+            let s5_cid = cid
+                .strip_prefix("s5://")
+                .unwrap_or(cid)
+                .trim_end_matches('/');
+            if s5_cid.is_empty() || s5_cid.contains(['/', ':', '?', '#']) {
+                return Err(Status::invalid_argument(format!("Invalid CID: {}", cid)));
+            }
+            // The content of an encrypted CID can only be read with its key, so it isn't copied
+            if parse_encrypted_cid_str(s5_cid).is_ok() {
+                return Err(Status::invalid_argument(
+                    "Encrypted renditions can't be mirrored",
+                ));
+            }
+            let portal_url = var("PORTAL_URL")
+                .map(|urls| primary_portal_url(&urls))
+                .map_err(|_| Status::failed_precondition("PORTAL_URL not set in .env"))?;
+            let url = format!("{}{}{}", portal_url, "/s5/blob/", s5_cid);
+            ("s5", s5_cid.to_string(), url)
+        }
+    };
+
+    if scheme == dest {
+        return Ok(MirroredRendition {
+            cid: format!("{}://{}", scheme, cid),
+            portal_url: None,
+            existing: true,
+        });
+    }
+
+    // Downloads are tracked by task, so the mirror gets an id of its own
+    let mirror_id = format!("mirror-{}", Uuid::new_v4());
+    let file_path = format!("{}{}", *TEMP_DIR, mirror_id);
+    println!("Mirroring {}://{} to {}", scheme, cid, dest);
+
+    let uploaded = async {
+        download_video(&url, &file_path, &mirror_id).await?;
+        if scheme == "ipfs" {
+            verify_ipfs_cid(&file_path, &cid).map_err(Status::data_loss)?;
+        }
+
+        upload_video(&file_path, Some(dest.to_string()))
+            .await
+            .map_err(|e| Status::internal(format!("Failed to upload to {}: {}", dest, e)))
+    }
+    .await;
+    remove_temp_file(&file_path);
+    let uploaded = uploaded?;
+
+    println!(
+        "Mirrored {}://{} to {}://{}",
+        scheme, cid, dest, uploaded.cid
+    );
+    Ok(MirroredRendition {
+        cid: format!("{}://{}", dest, uploaded.cid),
+        portal_url: uploaded.portal_url,
+        existing: false,
+    })
+}
+
+/// Blocking version of `transcode_asset`, for callers that aren't running in an async runtime.
+/// It must not be called from within one.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task, used to report progress and check for cancellation.
+/// * `source` - The CID of the source video, optionally with an extension, or an IPFS source.
+/// * `media_formats` - JSON array of the media formats to transcode to, or empty to use
+///   `MEDIA_FORMATS_FILE`.
+/// * `options` - The options of the job.
+///
+pub fn transcode_asset_blocking(
+    task_id: &str,
+    source: &str,
+    media_formats: &str,
+    options: &TranscodeOptions,
+) -> Result<JobResult> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(transcode_asset(task_id, source, media_formats, options))
+}
+
+/// Reads the media formats of a task, expands any audio bitrate ladders and registers the
+/// formats so that progress can be reported by format id.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+/// * `media_formats` - JSON array of the media formats to transcode to, or empty to use
+///   `MEDIA_FORMATS_FILE`.
+///
+/// # Returns
+/// The media formats, or an error message if they can't be read or are invalid, e.g. two of
+/// them share an id.
+///
+pub fn load_media_formats(task_id: &str, media_formats: &str) -> Result<Vec<Value>, String> {
+    let media_formats_json = if !media_formats.is_empty() {
+        media_formats.to_string()
+    } else {
+        let media_formats_file =
+            var("MEDIA_FORMATS_FILE").map_err(|_| "MEDIA_FORMATS_FILE not set in .env")?;
+        read_to_string(media_formats_file.as_str())
+            .map_err(|e| format!("Failed to read video format file: {}", e))?
+    };
+
+    print!("media_formats_json: {}", media_formats_json);
+    let media_formats_vec: Vec<Value> = serde_json::from_str(&media_formats_json)
+        .map_err(|e| format!("Failed to parse video formats: {}", e))?;
+
+    check_unique_format_ids(&media_formats_vec).map_err(|e| e.message().to_string())?;
+    check_format_extensions(&media_formats_vec).map_err(|e| e.message().to_string())?;
+    check_format_prefixes(&media_formats_vec).map_err(|e| e.message().to_string())?;
+    let media_formats_vec =
+        expand_audio_bitrates(media_formats_vec).map_err(|e| e.message().to_string())?;
+    let media_formats_vec =
+        expand_audio_layouts(media_formats_vec).map_err(|e| e.message().to_string())?;
+
+    // Record which format each index is, so progress can be reported by format id
+    shared::register_formats(
+        task_id,
+        media_formats_vec
+            .iter()
+            .map(|format| {
+                (
+                    format["id"].as_u64().map(|id| id as u32),
+                    format["ext"].as_str().map(String::from),
+                )
+            })
+            .collect(),
+    );
+
+    Ok(media_formats_vec)
+}
+
+/// Checks that a downloaded source has the blake3 hash the request expected, so that a portal
+/// serving the wrong or corrupted content for a CID isn't transcoded.
+///
+/// # Arguments
+/// * `file_path` - The path of the downloaded source.
+/// * `expected_hash` - The hash the request expected.
+///
+/// # Returns
+/// `Ok(())` if the hashes match, otherwise a `DataLoss` status, or an `Internal` status if the
+/// file couldn't be hashed.
+///
+async fn verify_source_hash(file_path: &str, expected_hash: blake3::Hash) -> Result<(), Status> {
+    let hash = hash_blake3_file_async(file_path.to_string())
+        .await
+        .map_err(|e| Status::internal(format!("Failed to hash {}: {}", file_path, e)))?;
+
+    if hash != expected_hash {
+        return Err(Status::data_loss(format!(
+            "DATA_LOSS: blake3 hash mismatch, expected {}, but the downloaded content is {}",
+            expected_hash.to_hex(),
+            hash.to_hex()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the formats of a job that aren't cached, or every format if `force` is set, each
+/// with its index and JSON string.
+///
+/// # Arguments
+/// * `media_formats` - The media formats of the job.
+/// * `file_path` - The path of the source, which the transcoded files are named after.
+/// * `force` - Whether to transcode cached formats again.
+///
+async fn formats_to_transcode(
+    media_formats: &[Value],
+    file_path: &str,
+    force: bool,
+) -> Vec<(usize, String)> {
+    let mut formats = Vec::new();
+    for (index, video_format) in media_formats.iter().enumerate() {
+        let Ok(video_format_str) = serde_json::to_string(&video_format) else {
+            continue;
+        };
+        let Ok(format) = get_video_format_from_str(&video_format_str) else {
+            continue;
+        };
+        let is_cached =
+            check_transcoded_file_exists(file_path, &format.id.to_string(), format.ext.as_str())
+                .await;
+        if force || !is_cached {
+            formats.push((index, video_format_str));
+        }
+    }
+    formats
+}
+
+/// Transcodes an unencrypted source with a single ffmpeg command reading it from the portal as
+/// it downloads, so transcoding doesn't wait for the whole download.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+/// * `url` - The URL of the source.
+/// * `file_path` - The path the source would be downloaded to, which the transcoded files are
+///   named after.
+/// * `media_formats` - The media formats of the job.
+/// * `options` - The options of the job.
+///
+/// # Returns
+/// The probed source and the result of each format transcoded, or `None` if the source has to
+/// be downloaded first: a container that needs seeking, such as an MP4 with its index at the
+/// end, a source that can't be probed over HTTP, formats that can't share a command, or a
+/// failed stream.
+///
+async fn stream_source(
+    task_id: &str,
+    url: &str,
+    file_path: &str,
+    media_formats: &[Value],
+    options: &TranscodeOptions,
+) -> Option<(
+    MediaInfo,
+    Vec<(usize, Result<Response<TranscodeVideoResponse>, Status>)>,
+)> {
+    let prefix_url = url.to_string();
+    let prefix = tokio::task::spawn_blocking(move || {
+        download_prefix(&prefix_url, STREAM_PREFIX_SIZE).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|prefix| prefix);
+    match prefix {
+        Ok(prefix) if requires_seekable_input(&prefix) => {
+            println!(
+                "Task {}: source needs seeking, downloading it before transcoding",
+                task_id
+            );
+            return None;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!(
+                "Task {}: failed to read the start of the source: {}",
+                task_id, e
+            );
+            return None;
+        }
+    }
+
+    let probe_url = url.to_string();
+    let media_info = tokio::task::spawn_blocking(move || probe_media_info(&probe_url))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|media_info| media_info);
+    let media_info = match media_info {
+        Ok(media_info) => media_info,
+        Err(e) => {
+            eprintln!(
+                "Task {}: failed to probe the streamed source: {}",
+                task_id, e
+            );
+            return None;
+        }
+    };
+    println!("Source media: {}", media_info);
+
+    let formats = formats_to_transcode(media_formats, file_path, options.force).await;
+    shared::update_task_progress(task_id, Phase::Download, 100);
+    let results = transcode_videos(
+        task_id.to_string(),
+        &formats,
+        file_path,
+        options.is_encrypted,
+        options.is_gpu,
+        &media_info,
+        Some(url),
+    )
+    .await;
+    if results.is_none() {
+        println!(
+            "Task {}: failed to stream the source, downloading it instead",
+            task_id
+        );
+    }
+
+    results.map(|results| (media_info, results))
+}
+
+/// Returns the result of a job cancelled while its source was being downloaded, before any
+/// format was transcoded.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+fn cancelled_job(task_id: &str) -> JobResult {
+    println!("Task {} cancelled while downloading its source", task_id);
+    JobResult {
+        cancelled: true,
+        ..JobResult::default()
+    }
+}
+
+async fn check_transcoded_file_exists(cid: &str, label: &str, ext: &str) -> bool {
+    let filename = format!("{}{}_{}.{}", *PATH_TO_TRANSCODED_FILE, cid, label, ext); // Adjust the path and format as needed.
+    Path::new(&filename).exists()
+}
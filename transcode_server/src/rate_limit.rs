@@ -0,0 +1,147 @@
+use dotenv::var;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::{metadata::MetadataValue, Request, Status};
+
+const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 60;
+
+// Requests per minute allowed for each client on the `transcode` endpoint, 0 disables the limit
+static RATE_LIMIT_PER_MINUTE: Lazy<u32> = Lazy::new(|| {
+    var("RATE_LIMIT_PER_MINUTE")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_RATE_LIMIT_PER_MINUTE)
+});
+
+pub static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(|| RateLimiter::new(*RATE_LIMIT_PER_MINUTE));
+
+/// Identifies the client a request came from, inserted into the request extensions by
+/// `rate_limit_interceptor` so that the gRPC handlers can apply the rate limit.
+#[derive(Debug, Clone)]
+pub struct ClientKey(pub String);
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A per-client token-bucket rate limiter. Each client may burst up to `requests_per_minute`
+/// requests, after which tokens are refilled at a steady `requests_per_minute / 60` per second.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> Self {
+        RateLimiter {
+            capacity: requests_per_minute as f64,
+            refill_per_sec: requests_per_minute as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the client's bucket.
+    ///
+    /// # Arguments
+    /// * `client_key` - Identifies the client, see `client_key`.
+    ///
+    /// # Returns
+    /// `Ok(())` if the request is allowed, otherwise `Err` with how long the client should wait
+    /// before retrying.
+    ///
+    pub fn check(&self, client_key: &str) -> Result<(), Duration> {
+        if self.capacity <= 0.0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // A bucket left untouched for a minute has refilled completely, so it is equivalent to
+        // having no bucket at all and can be dropped to keep the map from growing unbounded.
+        buckets
+            .retain(|_, bucket| now.duration_since(bucket.last_refill) < Duration::from_secs(60));
+
+        let bucket = buckets
+            .entry(client_key.to_string())
+            .or_insert_with(|| TokenBucket {
+                tokens: self.capacity,
+                last_refill: now,
+            });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait_secs = (1.0 - bucket.tokens) / self.refill_per_sec;
+            Err(Duration::from_secs_f64(wait_secs.ceil()))
+        }
+    }
+}
+
+/// Builds the key a client is rate limited by: its bearer token when authenticated, otherwise
+/// its IP address.
+///
+/// # Arguments
+/// * `authorization` - The value of the `Authorization` header, if present.
+/// * `remote_addr` - The address of the connected client, if known.
+///
+pub fn client_key(authorization: Option<&str>, remote_addr: Option<SocketAddr>) -> String {
+    if let Some(token) = authorization
+        .map(|value| value.trim_start_matches("Bearer ").trim())
+        .filter(|token| !token.is_empty())
+    {
+        return format!("token:{}", token);
+    }
+
+    match remote_addr {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => String::from("unknown"),
+    }
+}
+
+/// gRPC interceptor that identifies the calling client and records it in the request
+/// extensions. The limit itself is applied by the `transcode` handler so that polling
+/// `get_transcoded` is never throttled.
+pub fn rate_limit_interceptor(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let authorization = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let key = client_key(authorization.as_deref(), request.remote_addr());
+    request.extensions_mut().insert(ClientKey(key));
+
+    Ok(request)
+}
+
+/// Applies the rate limit to a gRPC request tagged by `rate_limit_interceptor`, returning
+/// `RESOURCE_EXHAUSTED` with a `retry-after` entry in the metadata when the client is over
+/// its limit.
+pub fn check_grpc_rate_limit<T>(request: &Request<T>) -> Result<(), Status> {
+    let key = match request.extensions().get::<ClientKey>() {
+        Some(ClientKey(key)) => key.clone(),
+        None => client_key(None, request.remote_addr()),
+    };
+
+    RATE_LIMITER.check(&key).map_err(|retry_after| {
+        let mut status = Status::resource_exhausted(format!(
+            "Rate limit exceeded, retry after {} seconds",
+            retry_after.as_secs()
+        ));
+        if let Ok(value) = MetadataValue::try_from(retry_after.as_secs().to_string()) {
+            status.metadata_mut().insert("retry-after", value);
+        }
+        status
+    })
+}
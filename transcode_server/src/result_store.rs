@@ -0,0 +1,212 @@
+use dotenv::var;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Directory the results of finished jobs are persisted to, so that an identical job submitted
+// again, even after a restart, reuses them. Results are only persisted when it is set.
+static RESULT_STORE_DIR: Lazy<Option<String>> =
+    Lazy::new(|| var("RESULT_STORE_DIR").ok().filter(|dir| !dir.is_empty()));
+
+/// The persisted result of a job, keyed by `job_key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredJob {
+    /// The key of the job definition the result is for.
+    pub key: String,
+    /// The source of the job, for inspecting the store.
+    pub source: String,
+    /// The media format objects that were transcoded, each with the CID of its upload.
+    pub transcoded_formats: Vec<Value>,
+    /// The CID of the uploaded manifest.
+    pub manifest_cid: Option<String>,
+    /// When the job finished, in seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// Returns the key of a job definition: a blake3 hash of its source, its media formats and
+/// whether it is encrypted. Formats are normalized first, with their properties sorted and a
+/// missing `dest` made the default `s5`, so that equivalent definitions share a key while two
+/// different format sets for the same source don't.
+///
+/// # Arguments
+/// * `source` - The source of the job, as requested.
+/// * `media_formats` - The media formats of the job, as loaded by `load_media_formats`.
+/// * `is_encrypted` - Whether the job is encrypted.
+///
+pub fn job_key(source: &str, media_formats: &[Value], is_encrypted: bool) -> String {
+    let media_formats: Vec<Value> = media_formats
+        .iter()
+        .map(|format| {
+            let mut format = format.clone();
+            if format["dest"].is_null() {
+                format["dest"] = json!("s5");
+            }
+            format
+        })
+        .collect();
+
+    // serde_json's maps are ordered by key, so the serialization is canonical
+    let definition = json!({
+        "source": source.strip_prefix("s5://").unwrap_or(source),
+        "media_formats": media_formats,
+        "is_encrypted": is_encrypted,
+    });
+
+    blake3::hash(definition.to_string().as_bytes())
+        .to_hex()
+        .to_string()
+}
+
+fn job_path(dir: &str, key: &str) -> PathBuf {
+    Path::new(dir).join(format!("{}.json", key))
+}
+
+/// Reads the persisted result of a job.
+///
+/// # Arguments
+/// * `key` - The key of the job, see `job_key`.
+///
+/// # Returns
+/// The result, or `None` if the store is disabled, has no result for the job or the result
+/// can't be read.
+///
+pub fn load_job(key: &str) -> Option<StoredJob> {
+    let dir = RESULT_STORE_DIR.as_deref()?;
+    let contents = fs::read_to_string(job_path(dir, key)).ok()?;
+
+    match serde_json::from_str::<StoredJob>(&contents) {
+        Ok(job) if job.key == key => Some(job),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("Failed to parse the stored result of job {}: {}", key, e);
+            None
+        }
+    }
+}
+
+/// Persists the result of a job, replacing any earlier result for the same key. A result that
+/// can't be written is only logged, as the job itself succeeded.
+///
+/// # Arguments
+/// * `job` - The result to persist.
+///
+pub fn store_job(job: &StoredJob) {
+    let Some(dir) = RESULT_STORE_DIR.as_deref() else {
+        return;
+    };
+
+    let path = job_path(dir, &job.key);
+    // Written to a temporary file and renamed, so a crash never leaves a truncated result
+    let temp_path = path.with_extension("json.tmp");
+    let written = fs::create_dir_all(dir)
+        .and_then(|()| fs::write(&temp_path, serde_json::to_string(job).unwrap_or_default()))
+        .and_then(|()| fs::rename(&temp_path, &path));
+
+    match written {
+        Ok(()) => println!("Stored the result of job {}", job.key),
+        Err(e) => eprintln!("Failed to store the result of job {}: {}", job.key, e),
+    }
+}
+
+/// Removes the persisted result of a job, e.g. after its uploads have been deleted, so that
+/// an identical job is transcoded again rather than answered with deleted CIDs.
+///
+/// # Arguments
+/// * `key` - The key of the job, see `job_key`.
+///
+pub fn remove_job(key: &str) {
+    let Some(dir) = RESULT_STORE_DIR.as_deref() else {
+        return;
+    };
+
+    let path = job_path(dir, key);
+    match fs::remove_file(&path) {
+        Ok(()) => println!("Removed the stored result of job {}", key),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Failed to remove {}: {}", path.display(), e),
+    }
+}
+
+/// Returns every file uploaded for a job: the renditions, including the unencrypted copies of
+/// encrypted ones, their sprite sheets and the manifest.
+///
+/// # Arguments
+/// * `transcoded_formats` - The media format objects of the job, failed formats included.
+/// * `manifest_cid` - The CID of the job's manifest.
+///
+/// # Returns
+/// The URL of each upload, with the S5 portal that stored it if known.
+///
+pub fn uploaded_files(
+    transcoded_formats: &[Value],
+    manifest_cid: Option<&str>,
+) -> Vec<(String, Option<String>)> {
+    let mut uploads = Vec::new();
+    for format in transcoded_formats {
+        let portal = format["portal"].as_str().map(String::from);
+        for key in ["cid", "unencrypted_cid"] {
+            if let Some(url) = format[key].as_str() {
+                uploads.push((url.to_string(), portal.clone()));
+            }
+        }
+        for url in format["sprite_cids"].as_array().into_iter().flatten() {
+            if let Some(url) = url.as_str() {
+                uploads.push((url.to_string(), portal.clone()));
+            }
+        }
+    }
+    if let Some(manifest_cid) = manifest_cid {
+        uploads.push((manifest_cid.to_string(), None));
+    }
+    uploads
+}
+
+/// Returns the URLs of every file uploaded for a persisted job, which must not be deleted
+/// while the job can still be reused.
+pub fn stored_uploads() -> HashSet<String> {
+    let Some(dir) = RESULT_STORE_DIR.as_deref() else {
+        return HashSet::new();
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return HashSet::new();
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .filter_map(|contents| serde_json::from_str::<StoredJob>(&contents).ok())
+        .flat_map(|job| uploaded_files(&job.transcoded_formats, job.manifest_cid.as_deref()))
+        .map(|(url, _)| url)
+        .collect()
+}
+
+/// Removes every persisted result of jobs for a source, e.g. after its renditions have been
+/// deleted.
+///
+/// # Arguments
+/// * `source` - The source of the jobs, as requested.
+///
+pub fn remove_jobs_for_source(source: &str) {
+    let Some(dir) = RESULT_STORE_DIR.as_deref() else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let is_source = fs::read_to_string(entry.path())
+            .ok()
+            .and_then(|contents| serde_json::from_str::<StoredJob>(&contents).ok())
+            .is_some_and(|job| job.source == source);
+        if is_source {
+            if let Err(e) = fs::remove_file(entry.path()) {
+                eprintln!("Failed to remove {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
@@ -1,105 +1,813 @@
+use crate::shared;
 use crate::utils;
 
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose, Engine as _};
 use dotenv::var;
+use once_cell::sync::Lazy;
 use reqwest::multipart;
-use serde_json::Value;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs::File;
-use std::io::copy;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Write};
 use std::process::Command;
 use std::result::Result::{Err, Ok};
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, fs, path::Path};
 use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
-use tus_client::Client;
+use tus_client::{ChecksumAlgorithm, Client, Error as TusError, TusExtension};
 
-use utils::bytes_to_base64url;
+use utils::{bytes_to_base64url, CidHashAlgorithm, CID_TYPE_RAW};
 
-pub fn download_file(url: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a new client with default configuration
-    let client = reqwest::Client::new();
+// Seconds a download may take in total before it is aborted, 0 for no limit
+static DOWNLOAD_TIMEOUT_SECS: Lazy<u64> = Lazy::new(|| {
+    var("DOWNLOAD_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(3600)
+});
+
+/// Largest download in bytes, 0 for no limit.
+pub static DOWNLOAD_MAX_BYTES: Lazy<u64> = Lazy::new(|| {
+    var("DOWNLOAD_MAX_BYTES")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(10 * 1024 * 1024 * 1024)
+});
+
+// Times a chunk of an S5 upload is retried after a transient error, such as a 5xx response or a
+// dropped connection, before the upload fails over to the next portal, 0 to never retry
+static UPLOAD_RETRIES: Lazy<u32> = Lazy::new(|| {
+    var("UPLOAD_RETRIES")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(3)
+});
+
+// Milliseconds before the first retry of a chunk, doubled before each following retry
+static UPLOAD_RETRY_DELAY_MS: Lazy<u64> = Lazy::new(|| {
+    var("UPLOAD_RETRY_DELAY_MS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(1000)
+});
+
+// The tus upload created for each (tus endpoint, content hash), so that uploading the same
+// content again resumes or reuses it rather than creating a new upload
+static UPLOAD_URLS: Lazy<Mutex<HashMap<(String, String), String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Size of the pieces a download is streamed to disk in
+const DOWNLOAD_BUFFER_BYTES: usize = 64 * 1024;
+
+// A partial upload the portal expires sooner than this isn't resumed, since the portal could
+// purge it before the rest of the file is sent
+const RESUME_EXPIRY_MARGIN_SECS: i64 = 300;
+
+/// A download aborted for exceeding `DOWNLOAD_TIMEOUT_SECS` or `DOWNLOAD_MAX_BYTES`, or
+/// because its task was cancelled.
+#[derive(Debug)]
+pub enum DownloadLimitError {
+    /// The download took longer than this many seconds.
+    TimedOut(u64),
+    /// The download is larger than this many bytes.
+    TooLarge(u64),
+    /// The task the download is for was cancelled.
+    Cancelled,
+}
+
+impl std::fmt::Display for DownloadLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DownloadLimitError::TimedOut(secs) => {
+                write!(f, "Download timed out after {} seconds", secs)
+            }
+            DownloadLimitError::TooLarge(max_bytes) => {
+                write!(f, "Download is larger than {} bytes", max_bytes)
+            }
+            DownloadLimitError::Cancelled => write!(f, "Download cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadLimitError {}
+
+/// Downloads `url` to `path`, streaming the response body to disk. The download is aborted
+/// with a `DownloadLimitError` once it takes longer than `DOWNLOAD_TIMEOUT_SECS` or grows
+/// beyond `DOWNLOAD_MAX_BYTES`, or with `DownloadLimitError::Cancelled` as soon as its task is
+/// cancelled. It also fails if the server responds with an error status, or if the body is
+/// shorter or longer than the `Content-Length` it announced. If the download fails part way,
+/// the partial file is deleted so it is never mistaken for a downloaded source.
+///
+/// # Arguments
+/// * `url` - The URL to download.
+/// * `path` - The path to save the download to.
+/// * `task_id` - The task the download is for.
+///
+pub fn download_file(
+    url: &str,
+    path: &str,
+    task_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::create(path)?;
+    let result = download_to(url, &mut file, task_id).map(|_| ());
+    if result.is_err() {
+        drop(file);
+        if let Err(e) = fs::remove_file(path) {
+            eprintln!("Failed to remove partial download {}: {}", path, e);
+        }
+    }
+
+    result
+}
+
+/// Downloads `url` to `writer`, e.g. the stdin of the ffmpeg process transcoding it, with the
+/// limits and checks of `download_file`.
+///
+/// # Arguments
+/// * `url` - The URL to download.
+/// * `writer` - Where to write the response body.
+/// * `task_id` - The task the download is for.
+///
+/// # Returns
+/// The number of bytes downloaded.
+///
+pub fn download_to(
+    url: &str,
+    writer: &mut impl Write,
+    task_id: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let timeout_secs = *DOWNLOAD_TIMEOUT_SECS;
+    let max_bytes = *DOWNLOAD_MAX_BYTES;
+
+    // The client's timeout bounds each read, so a stalled source can't outlast the deadline
+    let mut client = reqwest::Client::builder();
+    if timeout_secs > 0 {
+        client = client.timeout(Duration::from_secs(timeout_secs));
+    }
+    let client = client.build()?;
+
+    if shared::is_cancelled(task_id) {
+        return Err(Box::new(DownloadLimitError::Cancelled));
+    }
 
     // Send a GET request to the download URL
     let mut response = client.get(url).send()?;
 
-    // Save the response body to the specified file
-    let mut file = File::create(path)?;
-    copy(&mut response, &mut file)?;
+    // An error page is not the requested file
+    if !response.status().is_success() {
+        return Err(format!("Download failed with HTTP status {}", response.status()).into());
+    }
 
-    Ok(())
+    if max_bytes > 0 && response.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(Box::new(DownloadLimitError::TooLarge(max_bytes)));
+    }
+
+    // Stream the response body to the writer
+    let content_length = response.content_length();
+    copy_with_limits(&mut response, writer, timeout_secs, max_bytes, task_id).and_then(
+        |total_bytes| match content_length {
+            Some(content_length) if content_length != total_bytes => Err(format!(
+                "Download truncated: received {} of {} bytes",
+                total_bytes, content_length
+            )
+            .into()),
+            _ => Ok(total_bytes),
+        },
+    )
 }
 
-pub async fn upload_video_s5(path: &str) -> Result<String, anyhow::Error> {
-    println!("upload_video_s5: path: {:?}", path);
+/// Downloads the first bytes of `url` with a range request, e.g. to inspect a source's
+/// container before deciding how to download it. A server that ignores the range has its
+/// response cut off after `length` bytes.
+///
+/// # Arguments
+/// * `url` - The URL to download.
+/// * `length` - The most bytes to download.
+///
+pub fn download_prefix(url: &str, length: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut client = reqwest::Client::builder();
+    if *DOWNLOAD_TIMEOUT_SECS > 0 {
+        client = client.timeout(Duration::from_secs(*DOWNLOAD_TIMEOUT_SECS));
+    }
+    let response = client
+        .build()?
+        .get(url)
+        .header(
+            reqwest::header::RANGE,
+            format!("bytes=0-{}", length.saturating_sub(1)),
+        )
+        .send()?;
 
-    let portal_url = var("PORTAL_URL").unwrap();
-    let token = var("TOKEN").unwrap();
+    if !response.status().is_success() {
+        return Err(format!("Download failed with HTTP status {}", response.status()).into());
+    }
 
-    let client = Client::new(reqwest::Client::new()).with_auth_token(token);
+    let mut prefix = Vec::new();
+    response.take(length).read_to_end(&mut prefix)?;
+    Ok(prefix)
+}
 
-    let path = Path::new(path);
-    let metadata = fs::metadata(path).expect("Failed to read metadata");
-    let file_size = metadata.len();
+/// Copies `reader` to `writer` until the end, failing with a `DownloadLimitError` once the
+/// copy has taken longer than `timeout_secs` or copied more than `max_bytes` (0 for no limit),
+/// or once the task `task_id` is cancelled. Dropping the reader then aborts the HTTP stream.
+/// Returns the number of bytes copied.
+fn copy_with_limits(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    timeout_secs: u64,
+    max_bytes: u64,
+    task_id: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut buffer = vec![0; DOWNLOAD_BUFFER_BYTES];
+    let mut total_bytes: u64 = 0;
+
+    loop {
+        if timeout_secs > 0 && started.elapsed() > Duration::from_secs(timeout_secs) {
+            return Err(Box::new(DownloadLimitError::TimedOut(timeout_secs)));
+        }
+        if shared::is_cancelled(task_id) {
+            return Err(Box::new(DownloadLimitError::Cancelled));
+        }
+
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(total_bytes),
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                return Err(Box::new(DownloadLimitError::TimedOut(timeout_secs)))
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        total_bytes += bytes_read as u64;
+        if max_bytes > 0 && total_bytes > max_bytes {
+            return Err(Box::new(DownloadLimitError::TooLarge(max_bytes)));
+        }
+
+        writer.write_all(&buffer[..bytes_read])?;
+    }
+}
+
+// The portal auth token(s), read from the file named by TOKEN_FILE when it is set (e.g. a
+// mounted secret), otherwise from TOKEN
+static TOKEN: Lazy<Result<String, String>> = Lazy::new(|| match token_file() {
+    Some(token_file) => fs::read_to_string(&token_file)
+        .map(|token| token.trim_end_matches(['\r', '\n']).to_string())
+        .map_err(|e| format!("Failed to read TOKEN_FILE '{}': {}", token_file, e)),
+    None => var("TOKEN").map_err(|_| String::from("TOKEN not set in .env")),
+});
+
+/// Returns the path in `TOKEN_FILE`, if it is set and not empty.
+pub fn token_file() -> Option<String> {
+    var("TOKEN_FILE")
+        .ok()
+        .filter(|token_file| !token_file.is_empty())
+}
+
+/// Returns the portal auth token(s), from the file named by `TOKEN_FILE` if set, otherwise
+/// from `TOKEN`. The value is read once, on first use.
+pub fn portal_token() -> Result<String, anyhow::Error> {
+    TOKEN.clone().map_err(|e| anyhow!(e))
+}
+
+// Size of the chunks files are uploaded to S5 portals in
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024 * 5;
+
+/// Whether the size of a file is declared when its tus upload is created, or deferred until its
+/// final chunk is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadLength {
+    /// The file's size on disk is declared up front. The default.
+    Known,
+    /// The file is streamed until its end and its size is sent with the final chunk, for
+    /// outputs whose size isn't settled until the process writing them finishes. Portals that
+    /// don't support the tus `creation-defer-length` extension get a `Known` upload instead.
+    Deferred,
+}
+
+/// The result of uploading a transcoded file to storage.
+#[derive(Debug, Clone)]
+pub struct UploadedVideo {
+    /// The content identifier of the uploaded file.
+    pub cid: String,
+    /// The S5 portal that stored the file, used as a retrieval hint. `None` for IPFS uploads.
+    pub portal_url: Option<String>,
+}
+
+/// Computes the S5 CID a file is uploaded as, without uploading it: the blake3 hash of the
+/// file, prefixed with its multihash byte and turned into a raw CID with `hash_to_cid`, then
+/// base64url encoded with the `u` multibase prefix. The same content always has the same CID,
+/// so it can be compared with the CID of an upload to check its integrity.
+///
+/// # Arguments
+/// * `path` - The path of the file.
+///
+/// # Returns
+/// A `Result` with the `u...` CID, or an error if the file can't be read.
+///
+pub fn compute_s5_cid(path: &str) -> Result<String, anyhow::Error> {
+    let hash = hash_blake3_file(String::from(path))?;
+    let (_, cid) = s5_hash_and_cid(path, &hash)?;
+    Ok(cid)
+}
+
+/// Returns the base64url multihash of a file, as sent in the `hash` metadata of its upload,
+/// and its S5 CID.
+///
+/// # Arguments
+/// * `path` - The path of the file.
+/// * `hash` - The blake3 hash of the file.
+///
+fn s5_hash_and_cid(path: &str, hash: &blake3::Hash) -> Result<(String, String), anyhow::Error> {
+    let file_size = fs::metadata(path)
+        .map_err(|e| anyhow!("Failed to read metadata of {}: {}", path, e))?
+        .len();
     println!("file_size = {}", &file_size);
 
-    let hash = hash_blake3_file(String::from(path.to_str().unwrap())).unwrap();
+    let hash = general_purpose::URL_SAFE_NO_PAD
+        .encode([CidHashAlgorithm::Blake3.multihash_prefix(), hash.as_bytes()].concat());
+    println!("{}", hash);
 
-    let mut metadata = HashMap::new();
+    let cid_bytes = hash_to_cid(&hash, file_size);
+    println!("cid = {:?}", cid_bytes);
+
+    Ok((hash, format!("u{}", bytes_to_base64url(&cid_bytes))))
+}
+
+/// Reads the S5 portals to upload to from `PORTAL_URL`, a comma-separated list of portal URLs
+/// tried in order, paired with the auth tokens from `portal_token`. The token is either a
+/// comma-separated list with one token per portal, or a single token shared by all portals.
+///
+/// # Returns
+/// A `Result` with the `(portal_url, token)` pairs, or an error if the variables are missing
+/// or the number of tokens does not match the number of portals.
+///
+fn s5_portals() -> Result<Vec<(String, String)>, anyhow::Error> {
+    let portal_urls = var("PORTAL_URL").map_err(|_| anyhow!("PORTAL_URL not set in .env"))?;
+    let tokens = portal_token()?;
+
+    let portal_urls: Vec<String> = portal_urls
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+    let tokens: Vec<String> = tokens
+        .split(',')
+        .map(|token| token.trim().to_string())
+        .collect();
+
+    if portal_urls.is_empty() {
+        return Err(anyhow!("PORTAL_URL does not contain any portal"));
+    }
+
+    if tokens.len() != 1 && tokens.len() != portal_urls.len() {
+        return Err(anyhow!(
+            "TOKEN must contain a single token or one token per portal in PORTAL_URL ({} portals, {} tokens)",
+            portal_urls.len(),
+            tokens.len()
+        ));
+    }
+
+    Ok(portal_urls
+        .into_iter()
+        .enumerate()
+        .map(|(index, url)| {
+            let token = tokens.get(index).unwrap_or(&tokens[0]).clone();
+            (url, token)
+        })
+        .collect())
+}
+
+/// Returns the first portal listed in a comma-separated `PORTAL_URL` value, which is the
+/// portal used for downloads.
+///
+/// # Arguments
+/// * `portal_urls` - The value of `PORTAL_URL` or `PORTAL_ENCRYPT_URL`.
+///
+pub fn primary_portal_url(portal_urls: &str) -> String {
+    portal_urls
+        .split(',')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+/// Uploads a file to a single S5 portal. If the same content, by its hash, was uploaded to the
+/// portal before, its existing tus upload is checked with a HEAD request: a complete upload is
+/// reused without sending the file again, and a partial one is resumed from its offset.
+/// Otherwise a new tus upload is created, with its length deferred if `length` is `Deferred`
+/// and the portal supports it.
+///
+/// # Arguments
+/// * `portal_url` - The URL of the S5 portal.
+/// * `token` - The auth token for the portal.
+/// * `path` - The path of the file to upload.
+/// * `metadata` - The tus metadata for the upload, including the file hash.
+/// * `length` - Whether to declare the file's size when the upload is created.
+/// * `on_progress` - Called after each chunk the portal accepts, see `UploadProgress`.
+/// * `cancel` - A token that stops the upload before its next chunk once it is set.
+/// * `chunk_retries` - Counts the chunks retried after a transient error.
+///
+#[allow(clippy::too_many_arguments)]
+fn upload_to_portal(
+    portal_url: &str,
+    token: &str,
+    path: &Path,
+    metadata: HashMap<String, String>,
+    length: UploadLength,
+    on_progress: Option<UploadProgress>,
+    cancel: Option<&Arc<AtomicBool>>,
+    chunk_retries: &AtomicU32,
+) -> Result<(), anyhow::Error> {
+    // Chunks are checksummed so that a portal supporting it rejects, and the client resends, a
+    // chunk corrupted on the way rather than storing it. A chunk failing with a transient error
+    // is retried on the same portal, resuming from the offset it reports
+    let mut client = Client::new(reqwest::Client::new())
+        .with_auth_token(token)
+        .with_checksum(ChecksumAlgorithm::Sha256)
+        .with_retries(
+            *UPLOAD_RETRIES + 1,
+            Duration::from_millis(*UPLOAD_RETRY_DELAY_MS),
+        )
+        .with_retry_callback(|_| {
+            chunk_retries.fetch_add(1, Ordering::SeqCst);
+        });
+    if let Some(on_progress) = on_progress {
+        client = client.with_progress_callback(move |bytes_uploaded, total_size| {
+            on_progress(bytes_uploaded, total_size)
+        });
+    }
+    if let Some(cancel) = cancel {
+        client = client.with_cancellation(cancel.clone());
+    }
+    let tus_url = format!("{}{}", portal_url, "/s5/upload/tus");
+    let file_size = fs::metadata(path)?.len() as usize;
 
-    metadata.insert(
-        String::from("hash"),
-        general_purpose::URL_SAFE_NO_PAD.encode([&[31u8] as &[_], hash.as_bytes()].concat()),
+    let key = (
+        tus_url.clone(),
+        metadata.get("hash").cloned().unwrap_or_default(),
     );
+    let existing_upload_url = UPLOAD_URLS.lock().unwrap().get(&key).cloned();
+    if let Some(upload_url) = existing_upload_url {
+        match client.get_info(&upload_url) {
+            // An upload without a length is a deferred upload that never got its final chunk,
+            // which a resumed upload couldn't complete
+            Ok(info) if info.total_size == Some(file_size) => {
+                if info.bytes_uploaded >= file_size {
+                    println!("Content already uploaded, reusing: {}", &upload_url);
+                    return Ok(());
+                }
 
-    println!("{}", metadata.get("hash").unwrap());
+                let expires_soon = info.expires.is_some_and(|expires| {
+                    expires
+                        < chrono::Utc::now() + chrono::Duration::seconds(RESUME_EXPIRY_MARGIN_SECS)
+                });
+                if expires_soon {
+                    println!(
+                        "Previous upload expires too soon to resume: {}",
+                        &upload_url
+                    );
+                    UPLOAD_URLS.lock().unwrap().remove(&key);
+                } else {
+                    println!(
+                        "Resuming upload {} from offset {}",
+                        &upload_url, info.bytes_uploaded
+                    );
+                    return upload_chunks(&client, &tus_url, &upload_url, path, &key);
+                }
+            }
+            // The portal has purged the expired upload, so it is created again from scratch
+            Err(TusError::Gone) => {
+                println!("Previous upload expired: {}", &upload_url);
+                UPLOAD_URLS.lock().unwrap().remove(&key);
+            }
+            _ => {
+                println!("Previous upload no longer usable: {}", &upload_url);
+                UPLOAD_URLS.lock().unwrap().remove(&key);
+            }
+        }
+    }
 
-    let cid_bytes = hash_to_cid(metadata.get("hash").unwrap(), file_size);
-    println!("cid = {:?}", cid_bytes);
-    println!("path = {}", &path.display());
-    println!("portal_url = {}", &portal_url);
-    println!("metadata = {:?}", metadata);
+    if length == UploadLength::Deferred && supports_deferred_length(&client, &tus_url) {
+        let upload_url = client
+            .create_with_deferred_length(&tus_url, metadata)
+            .map_err(|e| anyhow!("Failed to create file on server: {}", e))?;
+        UPLOAD_URLS
+            .lock()
+            .unwrap()
+            .insert(key.clone(), upload_url.clone());
 
-    let upload_url = match client.create_with_metadata(
-        &format!("{}{}", portal_url, "/s5/upload/tus"),
-        path,
-        metadata,
-    ) {
-        Ok(url) => url,
+        println!("upload_url2 (deferred length) = {}", &upload_url);
+        return upload_streamed(&client, &tus_url, &upload_url, path, &key);
+    }
+
+    let upload_url = client
+        .create_with_metadata(&tus_url, path, metadata)
+        .map_err(|e| anyhow!("Failed to create file on server: {}", e))?;
+    UPLOAD_URLS
+        .lock()
+        .unwrap()
+        .insert(key.clone(), upload_url.clone());
+
+    println!("upload_url2 = {}", &upload_url);
+    upload_chunks(&client, &tus_url, &upload_url, path, &key)
+}
+
+/// Returns whether a portal supports the tus `creation-defer-length` extension, logging why a
+/// deferred upload falls back to a known length otherwise.
+fn supports_deferred_length(client: &Client, tus_url: &str) -> bool {
+    match client.get_server_info(tus_url) {
+        Ok(server_info)
+            if server_info
+                .extensions
+                .contains(&TusExtension::CreationDeferLength) =>
+        {
+            true
+        }
+        Ok(_) => {
+            println!("Portal does not support tus creation-defer-length, declaring the length");
+            false
+        }
         Err(e) => {
-            eprintln!("Failed to create file on server: {}", e);
-            String::new()
+            eprintln!(
+                "Failed to get tus server info, declaring the length: {}: {}",
+                tus_url, e
+            );
+            false
         }
-    };
+    }
+}
 
-    println!("upload_url2 = {}", &upload_url);
-    let chunk_size: usize = 1024 * 1024 * 5;
-    match client.upload_with_chunk_size(&upload_url, path, chunk_size) {
-        Ok(_) => (),
-        Err(e) => eprintln!("Failed to upload file to server: {}", e),
+/// Streams a file to a tus upload created with a deferred length, reading it until its end and
+/// sending its size with the final chunk. If the upload fails, it is deleted where the portal
+/// supports it and then forgotten, as `upload_chunks` does.
+///
+/// # Arguments
+/// * `client` - The tus client for the portal.
+/// * `tus_url` - The portal's tus endpoint.
+/// * `upload_url` - The URL of the upload.
+/// * `path` - The path of the file to upload.
+/// * `key` - The upload's key in `UPLOAD_URLS`.
+///
+fn upload_streamed(
+    client: &Client,
+    tus_url: &str,
+    upload_url: &str,
+    path: &Path,
+    key: &(String, String),
+) -> Result<(), anyhow::Error> {
+    let result = File::open(path)
+        .map_err(tus_client::Error::from)
+        .and_then(|mut file| client.upload_deferred(upload_url, &mut file, UPLOAD_CHUNK_SIZE));
+
+    match result {
+        Ok(size) => {
+            println!("Uploaded {} bytes with a deferred length", size);
+            Ok(())
+        }
+        Err(e) => {
+            if terminate_upload(client, tus_url, upload_url) {
+                UPLOAD_URLS.lock().unwrap().remove(key);
+            }
+            Err(anyhow!("Failed to upload file to server: {}", e))
+        }
     }
+}
 
-    println!("upload_video_s5: cid: {:?}", cid_bytes);
+/// Uploads the rest of a file to a tus upload, from the offset the portal reports. If the upload
+/// fails, the partial upload is deleted where the portal supports it and then forgotten, while a
+/// partial upload that remains on the portal is resumed by the next upload of the same content.
+///
+/// # Arguments
+/// * `client` - The tus client for the portal.
+/// * `tus_url` - The portal's tus endpoint.
+/// * `upload_url` - The URL of the upload.
+/// * `path` - The path of the file to upload.
+/// * `key` - The upload's key in `UPLOAD_URLS`.
+///
+fn upload_chunks(
+    client: &Client,
+    tus_url: &str,
+    upload_url: &str,
+    path: &Path,
+    key: &(String, String),
+) -> Result<(), anyhow::Error> {
+    if let Err(e) = client.upload_with_chunk_size(upload_url, path, UPLOAD_CHUNK_SIZE) {
+        // A portal that is down or not responding couldn't delete the upload either, so it is
+        // kept to be resumed once the portal is back, as is a cancelled upload
+        let unreachable = matches!(e, TusError::ConnectionError(_) | TusError::TimeoutError);
+        if unreachable {
+            eprintln!("Portal unreachable, keeping partial upload: {}", upload_url);
+        }
+        let keep = unreachable || matches!(e, TusError::Cancelled);
+        // An expired upload is already gone, and the next attempt creates it again
+        if matches!(e, TusError::Gone) || (!keep && terminate_upload(client, tus_url, upload_url)) {
+            UPLOAD_URLS.lock().unwrap().remove(key);
+        }
+        return Err(anyhow!("Failed to upload file to server: {}", e));
+    }
 
-    let cid = format!("u{}", bytes_to_base64url(&cid_bytes));
-    Ok(cid)
+    Ok(())
+}
+
+/// Deletes a partial tus upload after its upload has failed, so that failed uploads don't
+/// leave orphaned partial files on the portal. The upload is only deleted if the portal
+/// supports the tus termination extension. Errors are logged rather than returned so that they
+/// never mask the upload error.
+///
+/// # Arguments
+/// * `client` - The tus client the upload was made with.
+/// * `tus_url` - The portal's tus endpoint.
+/// * `upload_url` - The URL of the partial upload.
+///
+/// # Returns
+/// Whether the partial upload was deleted.
+///
+fn terminate_upload(client: &Client, tus_url: &str, upload_url: &str) -> bool {
+    match client.get_server_info(tus_url) {
+        Ok(server_info) if server_info.extensions.contains(&TusExtension::Termination) => {
+            match client.delete(upload_url) {
+                Ok(()) => {
+                    println!("Deleted partial upload: {}", upload_url);
+                    return true;
+                }
+                Err(e) => eprintln!("Failed to delete partial upload {}: {}", upload_url, e),
+            }
+        }
+        Ok(_) => println!(
+            "Portal does not support tus termination, partial upload not deleted: {}",
+            upload_url
+        ),
+        Err(e) => eprintln!(
+            "Failed to get tus server info, partial upload not deleted: {}: {}",
+            upload_url, e
+        ),
+    }
+
+    false
+}
+
+/// A callback reporting the progress of an upload, with the bytes uploaded so far and the size
+/// of the entire upload if known. Only uploads to S5 report progress, from the blocking thread
+/// the upload runs on.
+pub type UploadProgress = Arc<dyn Fn(usize, Option<usize>) + Send + Sync>;
+
+/// Uploads a file to S5, trying each portal configured in `PORTAL_URL` in turn until one
+/// succeeds. As the CID is content-addressed it is the same whichever portal stores the file.
+///
+/// # Arguments
+/// * `path` - The path of the file to upload.
+///
+/// # Returns
+/// A `Result` with the CID and the portal that stored the file, or an error if every portal
+/// failed.
+///
+pub async fn upload_video_s5(path: &str) -> Result<UploadedVideo, anyhow::Error> {
+    upload_video_s5_with_length(path, UploadLength::Known, None, None).await
+}
+
+/// Uploads a file to S5 as `upload_video_s5` does, choosing whether its size is declared when
+/// its tus upload is created. S5 portals need the file's hash up front, so the file is hashed
+/// first either way; with `UploadLength::Deferred` its size is instead taken from the bytes
+/// streamed to the portal.
+///
+/// # Arguments
+/// * `path` - The path of the file to upload.
+/// * `length` - Whether to declare the file's size when its upload is created.
+/// * `on_progress` - Called after each chunk a portal accepts.
+/// * `cancel` - A token that stops the upload before its next chunk once it is set, without
+///   trying the remaining portals. The partial upload is kept, to be resumed by the next upload
+///   of the same file.
+///
+/// # Returns
+/// A `Result` with the CID and the portal that stored the file, or an error if every portal
+/// failed or the upload was cancelled.
+///
+pub async fn upload_video_s5_with_length(
+    path: &str,
+    length: UploadLength,
+    on_progress: Option<UploadProgress>,
+    cancel: Option<&Arc<AtomicBool>>,
+) -> Result<UploadedVideo, anyhow::Error> {
+    println!("upload_video_s5: path: {:?}, length: {:?}", path, length);
+
+    let portals = s5_portals()?;
+
+    let hash = hash_blake3_file_async(String::from(path)).await?;
+    let (hash, cid) = s5_hash_and_cid(path, &hash)?;
+
+    let path = Path::new(path);
+    let mut metadata = HashMap::new();
+    metadata.insert(String::from("hash"), hash);
+
+    println!("path = {}", &path.display());
+    println!("metadata = {:?}", metadata);
+
+    let chunk_retries = Arc::new(AtomicU32::new(0));
+    let mut errors = Vec::new();
+    for (portal_url, token) in portals {
+        println!("portal_url = {}", &portal_url);
+
+        // The tus client blocks on each request, so the upload runs on a blocking thread rather
+        // than holding up an async worker
+        let upload = {
+            let (portal_url, path) = (portal_url.clone(), path.to_path_buf());
+            let (metadata, on_progress, cancel) =
+                (metadata.clone(), on_progress.clone(), cancel.cloned());
+            let chunk_retries = chunk_retries.clone();
+            tokio::task::spawn_blocking(move || {
+                upload_to_portal(
+                    &portal_url,
+                    &token,
+                    &path,
+                    metadata,
+                    length,
+                    on_progress,
+                    cancel.as_ref(),
+                    &chunk_retries,
+                )
+            })
+        };
+        let uploaded = upload
+            .await
+            .unwrap_or_else(|e| Err(anyhow!("Upload task failed: {}", e)));
+
+        match uploaded {
+            Ok(()) => {
+                // Chunks retried on any portal, and the portals that failed before this one,
+                // show a flaky portal or connection before uploads start failing outright
+                println!(
+                    "upload_video_s5: cid: {:?}, portal: {}, chunk_retries: {}, failed_portals: {}",
+                    cid,
+                    portal_url,
+                    chunk_retries.load(Ordering::SeqCst),
+                    errors.len()
+                );
+                return Ok(UploadedVideo {
+                    cid,
+                    portal_url: Some(portal_url),
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to upload to portal {}: {}", portal_url, e);
+                if cancel.is_some_and(|cancel| cancel.load(Ordering::SeqCst)) {
+                    return Err(anyhow!("Upload to portal {} cancelled", portal_url));
+                }
+                errors.push(format!("{}: {}", portal_url, e));
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "Failed to upload file to all S5 portals: {}",
+        errors.join("; ")
+    ))
 }
 
 pub async fn upload_video_ipfs(path: &str) -> Result<String, anyhow::Error> {
+    upload_video_ipfs_with_key(path, None).await
+}
+
+/// Uploads a file to IPFS through Pinata as `upload_video_ipfs` does, pinning it under the
+/// name `key` if given.
+///
+/// # Arguments
+/// * `path` - The path of the file to upload.
+/// * `key` - The name to pin the file under, e.g. `assets/abc/720p/abc_1.mp4`.
+///
+/// # Returns
+/// A `Result` with the CID of the file.
+///
+pub async fn upload_video_ipfs_with_key(
+    path: &str,
+    key: Option<&str>,
+) -> Result<String, anyhow::Error> {
     let pinata_jwt = std::env::var("PINATA_JWT")
         .map_err(|_| anyhow!("PINATA_JWT environment variable not set"))?;
 
     // Using `curl` to upload the file
-    let output = Command::new("curl")
+    let mut command = Command::new("curl");
+    command
         .arg("-X")
         .arg("POST")
         .arg("--header")
         .arg(format!("Authorization: Bearer {}", pinata_jwt))
         .arg("--form")
-        .arg(format!("file=@{}", path))
+        .arg(format!("file=@{}", path));
+    if let Some(key) = key {
+        command
+            .arg("--form-string")
+            .arg(format!("pinataMetadata={}", json!({ "name": key })));
+    }
+    let output = command
         .arg("https://api.pinata.cloud/pinning/pinFileToIPFS")
         .output()
         .map_err(|e| anyhow!("Failed to execute curl command: {}", e))?;
@@ -136,10 +844,146 @@ pub async fn upload_video_ipfs(path: &str) -> Result<String, anyhow::Error> {
 pub async fn upload_video(
     path: &str,
     storage_network: Option<String>,
-) -> Result<String, anyhow::Error> {
+) -> Result<UploadedVideo, anyhow::Error> {
+    upload_video_with_key(path, storage_network, None, None, None).await
+}
+
+/// Uploads a file to a storage network as `upload_video` does, at the path `key`. S5 and IPFS
+/// address files by their content, so there `key` is only informational: IPFS pins the file
+/// under it as its name, and S5 only logs it.
+///
+/// # Arguments
+/// * `path` - The path of the file to upload.
+/// * `storage_network` - `ipfs`, or `s5` when `None`.
+/// * `key` - The path to upload the file to, a folder prefix followed by its file name.
+/// * `on_progress` - Called as the file is uploaded, for uploads to S5.
+/// * `cancel` - A token that stops an upload to S5 before its next chunk once it is set.
+///
+/// # Returns
+/// A `Result` with the CID and the portal that stored the file.
+///
+pub async fn upload_video_with_key(
+    path: &str,
+    storage_network: Option<String>,
+    key: Option<&str>,
+    on_progress: Option<UploadProgress>,
+    cancel: Option<&Arc<AtomicBool>>,
+) -> Result<UploadedVideo, anyhow::Error> {
     match storage_network.as_deref() {
-        Some("ipfs") => upload_video_ipfs(path).await,
-        _ => upload_video_s5(path).await,
+        Some("ipfs") => Ok(UploadedVideo {
+            cid: upload_video_ipfs_with_key(path, key).await?,
+            portal_url: None,
+        }),
+        _ => {
+            if let Some(key) = key {
+                println!(
+                    "upload_video: S5 is content-addressed, key {} is informational",
+                    key
+                );
+            }
+            upload_video_s5_with_length(path, UploadLength::Known, on_progress, cancel).await
+        }
+    }
+}
+
+/// Returns the storage networks transcoded files can be uploaded to with the current
+/// configuration: `s5` when `PORTAL_URL` is set, and `ipfs` when `PINATA_JWT` is set.
+///
+pub fn configured_storage_backends() -> Vec<&'static str> {
+    let is_set = |name: &str| var(name).is_ok_and(|value| !value.trim().is_empty());
+
+    let mut backends = Vec::new();
+    if is_set("PORTAL_URL") {
+        backends.push("s5");
+    }
+    if is_set("PINATA_JWT") {
+        backends.push("ipfs");
+    }
+    backends
+}
+
+/// Unpins a file from IPFS through Pinata.
+///
+/// # Arguments
+/// * `cid` - The IPFS CID of the file.
+///
+fn unpin_ipfs(cid: &str) -> Result<(), anyhow::Error> {
+    let pinata_jwt = std::env::var("PINATA_JWT")
+        .map_err(|_| anyhow!("PINATA_JWT environment variable not set"))?;
+
+    let output = Command::new("curl")
+        .arg("--fail")
+        .arg("-X")
+        .arg("DELETE")
+        .arg("--header")
+        .arg(format!("Authorization: Bearer {}", pinata_jwt))
+        .arg(format!("https://api.pinata.cloud/pinning/unpin/{}", cid))
+        .output()
+        .map_err(|e| anyhow!("Failed to execute curl command: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Failed to read stderr");
+        return Err(anyhow!("curl command failed: {}", stderr));
+    }
+
+    Ok(())
+}
+
+/// Deletes a file from the S5 portal that stored it, or from every portal in `PORTAL_URL`
+/// if it isn't known.
+///
+/// # Arguments
+/// * `cid` - The S5 CID of the file.
+/// * `portal_url` - The portal that stored the file, if known.
+///
+fn delete_s5(cid: &str, portal_url: Option<&str>) -> Result<(), anyhow::Error> {
+    let portals: Vec<(String, String)> = s5_portals()?
+        .into_iter()
+        .filter(|(url, _)| portal_url.is_none_or(|portal_url| portal_url == url))
+        .collect();
+
+    if portals.is_empty() {
+        return Err(anyhow!("No token for portal {}", portal_url.unwrap_or("")));
+    }
+
+    let client = reqwest::Client::new();
+    let mut errors = Vec::new();
+    for (portal_url, token) in portals {
+        let result = client
+            .delete(&format!("{}/s5/delete/{}", portal_url, cid))
+            .header("Authorization", format!("Bearer {}", token))
+            .send();
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                println!("Deleted {} from portal {}", cid, portal_url)
+            }
+            Ok(response) => errors.push(format!("{}: {}", portal_url, response.status())),
+            Err(e) => errors.push(format!("{}: {}", portal_url, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(anyhow!("Failed to delete {}: {}", cid, errors.join("; ")));
+    }
+
+    Ok(())
+}
+
+/// Deletes an uploaded file from the storage network its URL refers to: unpins `ipfs://`
+/// files from Pinata and deletes `s5://` files from the S5 portal.
+///
+/// # Arguments
+/// * `url` - The `s5://` or `ipfs://` URL of the file.
+/// * `portal_url` - The S5 portal that stored the file, if known.
+///
+pub fn delete_uploaded(url: &str, portal_url: Option<&str>) -> Result<(), anyhow::Error> {
+    if let Some(cid) = url.strip_prefix("ipfs://") {
+        unpin_ipfs(cid)
+    } else if let Some(cid) = url.strip_prefix("s5://") {
+        delete_s5(cid, portal_url)
+    } else {
+        Err(anyhow!("Unknown storage network for {}", url))
     }
 }
 
@@ -151,6 +995,45 @@ pub fn hash_blake3_file(path: String) -> Result<blake3::Hash, anyhow::Error> {
     Ok(digest)
 }
 
+/// Hashes a file with blake3 on tokio's blocking thread pool, as hashing is CPU-bound and would
+/// otherwise stall an async worker thread for as long as a large file takes to hash. Use
+/// `hash_blake3_file` outside of an async runtime.
+///
+/// # Arguments
+/// * `path` - The path of the file.
+///
+pub async fn hash_blake3_file_async(path: String) -> Result<blake3::Hash, anyhow::Error> {
+    tokio::task::spawn_blocking(move || hash_blake3_file(path))
+        .await
+        .map_err(|e| anyhow!("Failed to hash file: {}", e))?
+}
+
+/// Hashes a file with any supported CID hash algorithm, on tokio's blocking thread pool.
+///
+/// # Arguments
+/// * `path` - The path of the file.
+/// * `algorithm` - The hash algorithm.
+///
+/// # Returns
+/// The digest of the file, without a multihash prefix.
+///
+pub async fn hash_file_async(
+    path: String,
+    algorithm: CidHashAlgorithm,
+) -> Result<Vec<u8>, anyhow::Error> {
+    match algorithm {
+        CidHashAlgorithm::Blake3 => Ok(hash_blake3_file_async(path).await?.as_bytes().to_vec()),
+        CidHashAlgorithm::Sha256 => tokio::task::spawn_blocking(move || {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut reader, &mut hasher)?;
+            Ok(hasher.finalize().to_vec())
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to hash file: {}", e))?,
+    }
+}
+
 fn blake3_digest<R: Read>(mut reader: R) -> Result<blake3::Hash, anyhow::Error> {
     let mut hasher = blake3::Hasher::new();
 
@@ -174,8 +1057,8 @@ pub fn hash_to_cid(hash: &str, file_size: u64) -> Vec<u8> {
     // Clone the CID to a mutable vector of bytes
     let mut bytes = cid.to_vec();
 
-    // Prepend the byte 0x26 before the full hash
-    bytes.insert(0, 0x26);
+    // Prepend the raw CID type before the multihash
+    bytes.insert(0, CID_TYPE_RAW);
 
     // Append the size of the file, little-endian encoded
     let le_file_size = &file_size.to_le_bytes();
@@ -190,3 +1073,52 @@ pub fn hash_to_cid(hash: &str, file_size: u64) -> Vec<u8> {
 
     bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `content` to a file in the system temp directory named after the test, so that
+    /// tests running in parallel don't share a file.
+    fn file_with(name: &str, content: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("s5_tests_{}_{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn compute_s5_cid_of_fixed_content_is_stable() {
+        let path = file_with("fixed", b"hello world");
+
+        let cid = compute_s5_cid(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // `0x26`, `0x1f`, the blake3 digest of "hello world", then its size, 11, as one byte
+        assert_eq!(cid, "uJh_XSYHvpwoMiAuNjBmF0HXby_Z5uZpfmRTlqvlrgxqeJAs");
+    }
+
+    #[test]
+    fn compute_s5_cid_depends_only_on_the_content() {
+        let first = file_with("first", b"same content");
+        let second = file_with("second", b"same content");
+        let other = file_with("other", b"other content");
+
+        let cids: Vec<String> = [&first, &second, &other]
+            .iter()
+            .map(|path| compute_s5_cid(path).unwrap())
+            .collect();
+        for path in [&first, &second, &other] {
+            fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(cids[0], cids[1]);
+        assert_ne!(cids[0], cids[2]);
+    }
+
+    #[test]
+    fn compute_s5_cid_fails_for_a_missing_file() {
+        let path = env::temp_dir().join(format!("s5_tests_{}_missing", std::process::id()));
+
+        assert!(compute_s5_cid(&path.to_string_lossy()).is_err());
+    }
+}
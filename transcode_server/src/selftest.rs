@@ -0,0 +1,348 @@
+use crate::encrypt_file::{decrypt_file_xchacha20, encrypt_file_xchacha20, ENCRYPTED_CHUNK_SIZE};
+use crate::ipfs::{ipfs_source, verify_ipfs_cid};
+use crate::pipeline::{remove_job_files, transcode_asset, TranscodeOptions};
+use crate::result_store::{remove_jobs_for_source, uploaded_files};
+use crate::s5::{delete_uploaded, hash_blake3_file_async, primary_portal_url, upload_video};
+use crate::shared;
+use crate::utils::{check_downloaded_media, download_video, remove_temp_file, TEMP_DIR};
+
+use dotenv::var;
+use serde::Serialize;
+use serde_json::json;
+use std::future::Future;
+use std::process::Command;
+use std::time::Instant;
+use uuid::Uuid;
+
+// Duration in seconds of the generated clip, long enough for a few key frames
+const CLIP_SECS: u32 = 2;
+
+/// A step of a self-test and how it went.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestStep {
+    /// The name of the step, e.g. `generate` or `transcode`.
+    pub name: &'static str,
+    pub success: bool,
+    /// How long the step took, in milliseconds.
+    pub millis: u128,
+    /// What the step produced, or why it failed.
+    pub detail: String,
+}
+
+/// The outcome of `run_selftest`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    /// Whether every step succeeded.
+    pub success: bool,
+    /// The steps that ran, in order. The steps after a failed one are skipped.
+    pub steps: Vec<SelfTestStep>,
+    /// How long the whole self-test took, in milliseconds.
+    pub total_millis: u128,
+}
+
+impl SelfTestReport {
+    /// Runs a step, recording how long it took and its outcome.
+    async fn step<T, F>(&mut self, name: &'static str, step: F) -> Option<T>
+    where
+        T: ToString,
+        F: Future<Output = Result<T, String>>,
+    {
+        let start = Instant::now();
+        let result = step.await;
+        let millis = start.elapsed().as_millis();
+
+        match &result {
+            Ok(_) => println!("Self-test: {} succeeded in {} ms", name, millis),
+            Err(e) => eprintln!("Self-test: {} failed in {} ms: {}", name, millis, e),
+        }
+        self.steps.push(SelfTestStep {
+            name,
+            success: result.is_ok(),
+            millis,
+            detail: match &result {
+                Ok(detail) => detail.to_string(),
+                Err(e) => e.clone(),
+            },
+        });
+        result.ok()
+    }
+}
+
+/// Generates a short synthetic clip, a test pattern with a tone, with ffmpeg's `lavfi` input.
+/// Each clip gets a random comment, so that it has a CID of its own and nothing cached for an
+/// earlier self-test is reused.
+fn generate_clip(clip_path: &str) -> Result<String, String> {
+    let output = Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-f", "lavfi", "-i"])
+        .arg(format!(
+            "testsrc=duration={}:size=320x240:rate=25",
+            CLIP_SECS
+        ))
+        .args(["-f", "lavfi", "-i"])
+        .arg(format!("sine=frequency=440:duration={}", CLIP_SECS))
+        .args([
+            "-c:v",
+            "libx264",
+            "-preset",
+            "ultrafast",
+            "-c:a",
+            "aac",
+            "-shortest",
+        ])
+        .arg("-metadata")
+        .arg(format!("comment=selftest-{}", Uuid::new_v4()))
+        .arg(clip_path)
+        .output()
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "ffmpeg failed to generate the clip: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(clip_path.to_string())
+}
+
+/// Returns the URL an uploaded `s5://` or `ipfs://` file is downloaded from.
+fn download_url(url: &str, portal_url: Option<&str>) -> Result<String, String> {
+    if let Some((_, url)) = ipfs_source(url) {
+        return Ok(url);
+    }
+
+    let cid = url.strip_prefix("s5://").unwrap_or(url);
+    let portal_url = match portal_url {
+        Some(portal_url) => portal_url.to_string(),
+        None => var("PORTAL_URL")
+            .map(|urls| primary_portal_url(&urls))
+            .map_err(|_| String::from("PORTAL_URL not set in .env"))?,
+    };
+    Ok(format!("{}/s5/blob/{}", portal_url, cid))
+}
+
+/// Downloads an uploaded file to `file_path`, verifying the content of IPFS files.
+async fn download_uploaded(
+    url: &str,
+    portal_url: Option<&str>,
+    file_path: &str,
+    task_id: &str,
+) -> Result<(), String> {
+    download_video(&download_url(url, portal_url)?, file_path, task_id)
+        .await
+        .map_err(|e| format!("Failed to download {}: {}", url, e.message()))?;
+    if let Some((cid, _)) = ipfs_source(url) {
+        verify_ipfs_cid(file_path, &cid)?;
+    }
+    Ok(())
+}
+
+/// Checks that the configured ffmpeg and storage network work, without needing a real source:
+/// a short clip is generated with ffmpeg and uploaded, transcoded from its CID by the full
+/// pipeline, and the rendition is downloaded again and checked to be valid media. With
+/// `encrypt`, the clip is also encrypted, uploaded, downloaded and decrypted, and checked to
+/// match. Every file and upload the self-test made is removed afterwards, whether or not it
+/// succeeded.
+///
+/// # Arguments
+/// * `dest` - The storage network to upload to, `s5` or `ipfs`, or `s5` when `None`.
+/// * `encrypt` - Whether to also check the encryption round trip.
+///
+/// # Returns
+/// A report of each step, with how long it took.
+///
+pub async fn run_selftest(dest: Option<String>, encrypt: bool) -> SelfTestReport {
+    let start = Instant::now();
+    let mut report = SelfTestReport {
+        success: false,
+        steps: Vec::new(),
+        total_millis: 0,
+    };
+
+    let task_id = format!("selftest-{}", Uuid::new_v4());
+    let clip_path = format!("{}{}.mp4", *TEMP_DIR, task_id);
+    let rendition_path = format!("{}{}_rendition", *TEMP_DIR, task_id);
+    let encrypted_path = format!("{}{}_encrypted", *TEMP_DIR, task_id);
+    let decrypted_path = format!("{}{}_decrypted", *TEMP_DIR, task_id);
+    println!("Self-test {} started", task_id);
+
+    // Each upload, with the S5 portal that stored it, to delete when the self-test ends
+    let mut uploads: Vec<(String, Option<String>)> = Vec::new();
+    let mut source = None;
+
+    let passed = 'run: {
+        if report
+            .step("generate", async { generate_clip(&clip_path) })
+            .await
+            .is_none()
+        {
+            break 'run false;
+        }
+
+        let Some(uploaded) = report
+            .step("upload", async {
+                let uploaded = upload_video(&clip_path, dest.clone())
+                    .await
+                    .map_err(|e| format!("Failed to upload the clip: {}", e))?;
+                let url = match dest.as_deref() {
+                    Some("ipfs") => format!("ipfs://{}", uploaded.cid),
+                    _ => format!("s5://{}", uploaded.cid),
+                };
+                uploads.push((url.clone(), uploaded.portal_url));
+                Ok(url)
+            })
+            .await
+        else {
+            break 'run false;
+        };
+        // The pipeline takes S5 sources as bare CIDs
+        let source_url = uploaded
+            .strip_prefix("s5://")
+            .unwrap_or(&uploaded)
+            .to_string();
+        source = Some(source_url.clone());
+
+        let media_formats = json!([{
+            "id": 1,
+            "ext": "mp4",
+            "vcodec": "libx264",
+            "preset": "ultrafast",
+            "vf": "scale=160:120",
+            "dest": dest.as_deref().unwrap_or("s5"),
+        }])
+        .to_string();
+        let options = TranscodeOptions {
+            force: true,
+            ..TranscodeOptions::default()
+        };
+        let Some(rendition) = report
+            .step("transcode", async {
+                let result = transcode_asset(&task_id, &source_url, &media_formats, &options)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                uploads.extend(uploaded_files(
+                    &result.transcoded_formats,
+                    result.manifest_cid.as_deref(),
+                ));
+                if let Some((id, message)) = result.failed_formats.first() {
+                    return Err(format!("Format {} failed: {}", id, message));
+                }
+                result
+                    .transcoded_formats
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| String::from("No rendition was transcoded"))
+            })
+            .await
+        else {
+            break 'run false;
+        };
+
+        if report
+            .step("verify", async {
+                let url = rendition["cid"]
+                    .as_str()
+                    .ok_or_else(|| String::from("The rendition has no CID"))?;
+                download_uploaded(url, rendition["portal"].as_str(), &rendition_path, &task_id)
+                    .await?;
+                check_downloaded_media(&rendition_path).map_err(|e| e.message().to_string())?;
+                Ok(url.to_string())
+            })
+            .await
+            .is_none()
+        {
+            break 'run false;
+        }
+
+        if encrypt
+            && report
+                .step("encrypt", async {
+                    let key = encrypt_file_xchacha20(clip_path.clone(), encrypted_path.clone(), 0)
+                        .map_err(|e| format!("Failed to encrypt the clip: {}", e))?;
+                    let uploaded = upload_video(&encrypted_path, dest.clone())
+                        .await
+                        .map_err(|e| format!("Failed to upload the encrypted clip: {}", e))?;
+                    let url = match dest.as_deref() {
+                        Some("ipfs") => format!("ipfs://{}", uploaded.cid),
+                        _ => format!("s5://{}", uploaded.cid),
+                    };
+                    uploads.push((url.clone(), uploaded.portal_url.clone()));
+                    remove_temp_file(&encrypted_path);
+
+                    download_uploaded(
+                        &url,
+                        uploaded.portal_url.as_deref(),
+                        &encrypted_path,
+                        &task_id,
+                    )
+                    .await?;
+                    let encrypted_size = std::fs::metadata(&encrypted_path)
+                        .map_err(|e| format!("Failed to read the encrypted clip: {}", e))?
+                        .len();
+                    let last_chunk_index =
+                        (encrypted_size.saturating_sub(1) / ENCRYPTED_CHUNK_SIZE as u64) as u32;
+                    decrypt_file_xchacha20(
+                        encrypted_path.clone(),
+                        decrypted_path.clone(),
+                        key,
+                        0,
+                        last_chunk_index,
+                    )
+                    .map_err(|e| format!("Failed to decrypt the clip: {}", e))?;
+
+                    let hash = |path: &str| hash_blake3_file_async(path.to_string());
+                    let (clip_hash, decrypted_hash) =
+                        (hash(&clip_path).await, hash(&decrypted_path).await);
+                    match (clip_hash, decrypted_hash) {
+                        (Ok(clip_hash), Ok(decrypted_hash)) if clip_hash == decrypted_hash => {
+                            Ok(url)
+                        }
+                        (Ok(_), Ok(_)) => {
+                            Err(String::from("The decrypted clip doesn't match the clip"))
+                        }
+                        (Err(e), _) | (_, Err(e)) => Err(format!("Failed to hash the clip: {}", e)),
+                    }
+                })
+                .await
+                .is_none()
+        {
+            break 'run false;
+        }
+
+        true
+    };
+
+    // Clean up after the self-test, whether or not it passed
+    for path in [
+        &clip_path,
+        &rendition_path,
+        &encrypted_path,
+        &decrypted_path,
+    ] {
+        remove_temp_file(path);
+    }
+    if let Some(source) = &source {
+        let source_cid = source.strip_prefix("ipfs://").unwrap_or(source);
+        remove_job_files(source_cid, &task_id);
+        remove_jobs_for_source(source);
+    }
+    for (url, portal_url) in &uploads {
+        if let Err(e) = delete_uploaded(url, portal_url.as_deref()) {
+            eprintln!(
+                "Self-test {}: failed to delete upload {}: {}",
+                task_id, url, e
+            );
+        }
+    }
+    shared::finish_task(&task_id);
+    shared::remove_task(&task_id);
+
+    report.success = passed;
+    report.total_millis = start.elapsed().as_millis();
+    println!(
+        "Self-test {} {} in {} ms",
+        task_id,
+        if passed { "passed" } else { "failed" },
+        report.total_millis
+    );
+    report
+}
@@ -11,45 +11,64 @@
  * Date: 28 May 2023
  */
 
-mod s5;
-
-mod encrypt_file;
-
-mod utils;
-use utils::{base64url_to_bytes, bytes_to_base64url, download_and_concat_files, download_video};
+use transcode_log::live::{live_source_path, LiveTranscode, LIVE_POLL_SECS};
+use transcode_log::pipeline::{load_media_formats, mirror_rendition};
+use transcode_log::result_store::{remove_job, stored_uploads, uploaded_files};
+use transcode_log::s5::{
+    configured_storage_backends, delete_uploaded, portal_token, token_file, DOWNLOAD_MAX_BYTES,
+};
+use transcode_log::selftest::run_selftest;
+use transcode_log::shared::{self, TaskStatus};
+use transcode_log::transcode_video::{
+    available_encoders, check_format_extensions, check_format_prefixes, check_gpu_encoders,
+    check_hls_ladder, check_unique_format_ids, expand_audio_bitrates, expand_audio_layouts,
+    gpu_present, is_hardware_encoder,
+};
+use transcode_log::utils::{ensure_writable_dir, tool_version, CID_HASH_ALGORITHM, TEMP_DIR};
+use transcode_log::{transcode_asset, JobResult, TranscodeOptions};
 
-mod transcode_video;
-use transcode_video::{get_video_format_from_str, transcode_video, TranscodeVideoResponse};
+mod rate_limit;
+use rate_limit::{
+    check_grpc_rate_limit, client_key, rate_limit_interceptor, RateLimiter, RATE_LIMITER,
+};
 
-mod shared;
+mod task_queue;
+use task_queue::{Priority, TaskQueue};
 
-use tonic::{transport::Server, Request, Response, Status};
-use warp::Filter;
+use tonic::{transport::Server, Code, Request, Response, Status};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
 
 use async_trait::async_trait;
 
+use futures::{Stream, StreamExt};
+use hyper::server::conn::AddrStream;
+use hyper::service::Service as _;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::Mutex;
 use transcode::{
     transcode_service_server::{TranscodeService, TranscodeServiceServer},
-    GetTranscodedRequest, GetTranscodedResponse, TranscodeRequest, TranscodeResponse,
+    CancelTranscodeRequest, CancelTranscodeResponse, DeleteTranscodedRequest,
+    DeleteTranscodedResponse, GetCapabilitiesRequest, GetCapabilitiesResponse,
+    GetTranscodedRequest, GetTranscodedResponse, ProgressUpdate, StreamProgressRequest,
+    TranscodeRequest, TranscodeResponse,
 };
 
-mod encrypted_cid;
-use crate::encrypt_file::decrypt_file_xchacha20;
-
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, json, Value};
-use std::fs::read_to_string;
 
 use anyhow::{anyhow, Result};
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use chrono::Utc;
 use uuid::{Uuid, Version};
 
 use base64;
@@ -57,7 +76,44 @@ use std::convert::TryInto;
 
 use dotenv::{dotenv, var};
 
-static TRANSCODED: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// The outcome of a completed transcoding job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TranscodedResult {
+    /// JSON array of the transcoded media format objects.
+    metadata: String,
+    /// CID of the manifest describing all outputs of the job, if one was uploaded.
+    manifest_cid: Option<String>,
+    /// Whether the task was cancelled before all formats were transcoded.
+    cancelled: bool,
+    /// Why the task failed, if it failed as a whole rather than format by format.
+    error: Option<String>,
+    /// The key of the job in the result store, to remove it when the uploads are deleted.
+    #[serde(default)]
+    job_key: Option<String>,
+}
+
+// Results are shared behind an `Arc` so that reading one only holds the lock long enough to
+// clone the pointer, however large its metadata is.
+static TRANSCODED: Lazy<Mutex<HashMap<String, Arc<TranscodedResult>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Directory the results in `TRANSCODED` are persisted to, so that they can still be queried
+// after a restart. Results are only persisted when it is set.
+static STATE_DIR: Lazy<Option<String>> =
+    Lazy::new(|| var("STATE_DIR").ok().filter(|dir| !dir.is_empty()));
+
+// Held while a self-test runs, so that only one runs at a time.
+static SELFTEST_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// Size of the pieces the metadata of a result is streamed to clients in.
+const METADATA_CHUNK_BYTES: usize = 64 * 1024;
+
+// How often `StreamProgress` sends an update.
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+static GRPC_ADDR: Lazy<String> =
+    Lazy::new(|| var("GRPC_ADDR").unwrap_or_else(|_| String::from("0.0.0.0:50051")));
+static REST_ADDR: Lazy<String> =
+    Lazy::new(|| var("REST_ADDR").unwrap_or_else(|_| String::from("0.0.0.0:8000")));
 static PATH_TO_FILE: Lazy<String> =
     Lazy::new(|| var("PATH_TO_FILE").unwrap_or_else(|_| panic!("PATH_TO_FILE not set in .env")));
 static PATH_TO_TRANSCODED_FILE: Lazy<String> = Lazy::new(|| {
@@ -71,369 +127,859 @@ static TRANSCODED_FILE_SIZE_THRESHOLD: Lazy<String> = Lazy::new(|| {
     var("TRANSCODED_FILE_SIZE_THRESHOLD")
         .unwrap_or_else(|_| panic!("TRANSCODED_FILE_SIZE_THRESHOLD not set in .env"))
 });
+// Most renditions a single request may ask for, counting each bitrate of an audio bitrate
+// ladder, 0 for no limit
+static MAX_FORMATS_PER_REQUEST: Lazy<usize> = Lazy::new(|| {
+    var("MAX_FORMATS_PER_REQUEST")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(20)
+});
+// The versions of the ffmpeg and ffprobe binaries on the PATH, probed once at startup
+static FFMPEG_VERSION: Lazy<Option<String>> = Lazy::new(|| tool_version("ffmpeg"));
+static FFPROBE_VERSION: Lazy<Option<String>> = Lazy::new(|| tool_version("ffprobe"));
+
+// Version of the schema of the capabilities reply, increased when a field is removed or changes
+// meaning
+const CAPABILITIES_SCHEMA_VERSION: u32 = 1;
+
+/// What the transcoder supports, detected once at startup, so that clients can build requests
+/// it will accept.
+#[derive(Debug, Clone, Serialize)]
+struct Capabilities {
+    schema_version: u32,
+    version: &'static str,
+    /// Every encoder of the ffmpeg build.
+    encoders: Vec<String>,
+    /// The encoders that require `is_gpu`.
+    hardware_encoders: Vec<String>,
+    /// Whether a GPU was found for `is_gpu` requests.
+    gpu: bool,
+    storage_backends: Vec<&'static str>,
+    /// Largest source in bytes, 0 for no limit.
+    max_input_bytes: u64,
+    /// Most renditions per request, 0 for no limit.
+    max_formats_per_request: usize,
+}
+
+static CAPABILITIES: Lazy<Capabilities> = Lazy::new(|| {
+    let encoders = available_encoders();
+    Capabilities {
+        schema_version: CAPABILITIES_SCHEMA_VERSION,
+        version: env!("CARGO_PKG_VERSION"),
+        hardware_encoders: encoders
+            .iter()
+            .filter(|encoder| is_hardware_encoder(encoder))
+            .cloned()
+            .collect(),
+        encoders,
+        gpu: gpu_present(),
+        storage_backends: configured_storage_backends(),
+        max_input_bytes: *DOWNLOAD_MAX_BYTES,
+        max_formats_per_request: *MAX_FORMATS_PER_REQUEST,
+    }
+});
+
+// Number of tasks processed at the same time, each by its own task receiver
+static TRANSCODE_WORKERS: Lazy<usize> = Lazy::new(|| {
+    var("TRANSCODE_WORKERS")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|&workers| workers > 0)
+        .unwrap_or(1)
+});
+
+// Times a failed task is queued again before it is recorded as failed, 0 to never retry
+static TASK_RETRIES: Lazy<u32> = Lazy::new(|| {
+    var("TASK_RETRIES")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(0)
+});
+// Seconds before the first retry of a failed task, doubling with each further retry
+static TASK_RETRY_BACKOFF_SECS: Lazy<u64> = Lazy::new(|| {
+    var("TASK_RETRY_BACKOFF_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(30)
+});
+
+// Seconds between TCP keepalive probes on idle connections of both servers, 0 to disable them
+static TCP_KEEPALIVE_SECS: Lazy<u64> = Lazy::new(|| {
+    var("TCP_KEEPALIVE_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(60)
+});
+// Seconds between HTTP/2 pings the gRPC server sends on idle connections, 0 to disable them
+static GRPC_KEEPALIVE_INTERVAL_SECS: Lazy<u64> = Lazy::new(|| {
+    var("GRPC_KEEPALIVE_INTERVAL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(30)
+});
+// Seconds the gRPC server waits for a ping to be acknowledged before closing the connection
+static GRPC_KEEPALIVE_TIMEOUT_SECS: Lazy<u64> = Lazy::new(|| {
+    var("GRPC_KEEPALIVE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(20)
+});
+// Most seconds a gRPC call may take, including streaming its response, 0 for no limit
+static GRPC_REQUEST_TIMEOUT_SECS: Lazy<u64> = Lazy::new(|| {
+    var("GRPC_REQUEST_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0)
+});
+// Seconds between keepalive comments on an idle server-sent events stream
+static SSE_KEEPALIVE_SECS: Lazy<u64> = Lazy::new(|| {
+    var("SSE_KEEPALIVE_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(15)
+});
+
 static GARBAGE_COLLECTOR_INTERVAL: Lazy<String> = Lazy::new(|| {
     var("GARBAGE_COLLECTOR_INTERVAL")
         .unwrap_or_else(|_| panic!("GARBAGE_COLLECTOR_INTERVAL not set in .env"))
 });
 
-fn get_file_size(file_path: String) -> std::io::Result<u64> {
-    let metadata = fs::metadata(file_path)?;
-    Ok(metadata.len())
+/// Asynchronously receives transcoding tasks from the task queue, highest priority first, and processes them using
+/// the specified transcoder. Each task involves reading an input file, transcoding it according to the provided
+/// settings, and writing the output to a specified location. Errors encountered during processing are logged.
+/// `TRANSCODE_WORKERS` receivers share the queue, each processing one task at a time. A task that fails is queued again
+/// after a backoff, up to `TASK_RETRIES` times, before it is recorded as failed.
+///
+/// # Arguments
+/// * `worker` - The number of the receiver, for the log.
+/// * `task_queue` - An `Arc<TaskQueue>` representing the shared queue of transcoding tasks. Each task includes the
+///   task id, source CID, desired formats, encryption flag, GPU usage flag, force flag and expected hash.
+///
+async fn transcode_task_receiver(worker: usize, task_queue: Arc<TaskQueue>) {
+    loop {
+        let (task, priority) = task_queue.pop().await;
+        let (task_id, orig_source_cid, media_formats, is_encrypted, is_gpu, force, expected_hash) =
+            task.clone();
+
+        // A task cancelled while it was still queued is skipped without being processed
+        if shared::is_cancelled(&task_id) {
+            println!("Skipping cancelled task: {}", task_id);
+            record_cancelled(&task_id, &[]).await;
+        } else {
+            let attempt = shared::start_attempt(&task_id);
+            shared::set_task_status(&task_id, TaskStatus::Running);
+            let can_retry = attempt <= *TASK_RETRIES;
+            println!(
+                "Worker {} processing task: {} (attempt {})",
+                worker, task_id, attempt
+            );
+
+            // Process the task on its own thread, so that a panic while processing it fails
+            // only that task rather than stopping the receiver, and so that its blocking ffmpeg
+            // and file work doesn't hold up the servers or the other workers
+            let runtime = tokio::runtime::Handle::current();
+            let processing_task_id = task_id.clone();
+            let processing = tokio::task::spawn_blocking(move || {
+                runtime.block_on(process_transcode_task(
+                    processing_task_id,
+                    orig_source_cid,
+                    media_formats,
+                    is_encrypted,
+                    is_gpu,
+                    force,
+                    expected_hash,
+                    can_retry,
+                ))
+            });
+            let failure = match processing.await {
+                Ok(result) => result.err(),
+                Err(e) => {
+                    let reason = match e.try_into_panic() {
+                        Ok(panic) => panic
+                            .downcast_ref::<&str>()
+                            .map(|message| message.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| String::from("unknown panic")),
+                        Err(e) => e.to_string(),
+                    };
+                    eprintln!("Task {} panicked: {}", task_id, reason);
+                    Some(format!("Task panicked: {}", reason))
+                }
+            };
+
+            if let Some(error) = failure {
+                if can_retry && !shared::is_cancelled(&task_id) {
+                    // The task stays pending, so it can still be cancelled while it waits
+                    let backoff = *TASK_RETRY_BACKOFF_SECS * 2u64.saturating_pow(attempt - 1);
+                    eprintln!(
+                        "Task {} failed on attempt {}, retrying in {} seconds: {}",
+                        task_id, attempt, backoff, error
+                    );
+                    shared::set_task_status(&task_id, TaskStatus::Queued);
+                    let task_queue = Arc::clone(&task_queue);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(backoff)).await;
+                        task_queue.push(task, priority);
+                    });
+                    continue;
+                }
+
+                record_failed(&task_id, error).await;
+            }
+        }
+
+        shared::finish_task(&task_id);
+    }
 }
 
-const CID_TYPE_ENCRYPTED_SIZE: usize = 1;
-const ENCRYPTION_ALGORITHM_SIZE: usize = 1;
-const CHUNK_SIZE_AS_POWEROF2_SIZE: usize = 1;
+/// Parses the `expected_hash` of a transcoding request, the hex blake3 hash its downloaded
+/// source must have. Only unencrypted sources can be checked.
+///
+/// # Arguments
+/// * `expected_hash` - The requested hash, empty to not check the source.
+/// * `is_encrypted` - Whether the source is encrypted.
+///
+/// # Returns
+/// The hash, `None` if none was given, or an `InvalidArgument` status.
+///
+fn parse_expected_hash(
+    expected_hash: &str,
+    is_encrypted: bool,
+) -> Result<Option<blake3::Hash>, Status> {
+    let expected_hash = expected_hash.trim();
+    if expected_hash.is_empty() {
+        return Ok(None);
+    }
+    if is_encrypted {
+        return Err(Status::invalid_argument(
+            "expected_hash is only supported for unencrypted sources",
+        ));
+    }
 
-const ENCRYPTED_BLOB_HASH_SIZE: usize = 33;
-const KEY_SIZE: usize = 32;
+    blake3::Hash::from_hex(expected_hash)
+        .map(Some)
+        .map_err(|e| Status::invalid_argument(format!("Invalid expected_hash: {}", e)))
+}
 
-/**
- * Extracts the encryption key from an encrypted CID.
- * @param encrypted_cid - The encrypted CID to get the key from.
- * @returns The encryption key from the CID.
- */
-pub fn get_key_from_encrypted_cid(encrypted_cid: &str) -> String {
-    let extension_index = encrypted_cid.rfind(".");
+/// Checks the media formats of a transcoding request before it is queued, so that a request
+/// with duplicate format ids or unsupported extensions, with more renditions than
+/// `MAX_FORMATS_PER_REQUEST` once any audio bitrate ladders and layouts are expanded, with
+/// encoders that don't match `is_gpu`, or with HLS formats that can't form a ladder, is rejected
+/// rather than failing or monopolizing the worker once it is processed. Media formats that are
+/// empty (`MEDIA_FORMATS_FILE` is used) or can't be parsed are left to be reported when the task
+/// runs.
+///
+/// # Arguments
+/// * `media_formats` - JSON array of the requested media formats.
+/// * `is_encrypted` - Whether the outputs are encrypted.
+/// * `is_gpu` - Whether GPU transcoding is requested.
+///
+/// # Returns
+/// `Ok(())` if the media formats can be queued, otherwise an `InvalidArgument` status.
+///
+fn validate_media_formats(
+    media_formats: &str,
+    is_encrypted: bool,
+    is_gpu: bool,
+) -> Result<(), Status> {
+    if media_formats.is_empty() {
+        return Ok(());
+    }
 
-    let mut cid_without_extension = match extension_index {
-        Some(index) => &encrypted_cid[..index],
-        None => encrypted_cid,
+    let media_formats_vec = match from_str::<Vec<Value>>(media_formats) {
+        Ok(media_formats_vec) => media_formats_vec,
+        Err(_) => return Ok(()),
     };
 
-    println!(
-        "get_key_from_encrypted_cid: encrypted_cid = {}",
-        encrypted_cid
-    );
-    println!(
-        "get_key_from_encrypted_cid: cid_without_extension = {}",
-        cid_without_extension
-    );
+    check_unique_format_ids(&media_formats_vec)?;
+    check_format_extensions(&media_formats_vec)?;
+    check_format_prefixes(&media_formats_vec)?;
+
+    let max_formats = *MAX_FORMATS_PER_REQUEST;
+    let media_formats_vec = expand_audio_layouts(expand_audio_bitrates(media_formats_vec)?)?;
+    let rendition_count = media_formats_vec.len();
+    if max_formats > 0 && rendition_count > max_formats {
+        return Err(Status::invalid_argument(format!(
+            "Too many media formats: {} renditions requested, the maximum is {}",
+            rendition_count, max_formats
+        )));
+    }
 
-    cid_without_extension = &cid_without_extension[1..];
-    let cid_bytes = base64url_to_bytes(cid_without_extension);
+    check_gpu_encoders(is_gpu, &media_formats_vec)?;
+    check_hls_ladder(is_encrypted, &media_formats_vec)?;
 
-    let start_index = CID_TYPE_ENCRYPTED_SIZE
-        + ENCRYPTION_ALGORITHM_SIZE
-        + CHUNK_SIZE_AS_POWEROF2_SIZE
-        + ENCRYPTED_BLOB_HASH_SIZE;
+    Ok(())
+}
 
-    let end_index = start_index + KEY_SIZE;
+/// Records a cancelled task in `TRANSCODED`, along with any formats that had already been
+/// transcoded before it was cancelled.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the cancelled task.
+/// * `transcoded_formats` - The media format objects transcoded before cancellation.
+///
+async fn record_cancelled(task_id: &str, transcoded_formats: &[Value]) {
+    shared::set_task_status(task_id, TaskStatus::Cancelled);
+    record_transcoded(
+        task_id.to_string(),
+        TranscodedResult {
+            metadata: serde_json::to_string(transcoded_formats).unwrap_or_default(),
+            manifest_cid: None,
+            cancelled: true,
+            error: None,
+            job_key: None,
+        },
+    )
+    .await;
+}
 
-    let selected_bytes = &cid_bytes[start_index..end_index];
+/// Records a task that failed as a whole in `TRANSCODED`, so `get_transcoded` reports its
+/// error rather than the task disappearing.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the failed task.
+/// * `error` - Why the task failed.
+///
+async fn record_failed(task_id: &str, error: String) {
+    shared::set_task_status(task_id, TaskStatus::Failed);
+    record_transcoded(
+        task_id.to_string(),
+        TranscodedResult {
+            metadata: String::from("[]"),
+            manifest_cid: None,
+            cancelled: false,
+            error: Some(error),
+            job_key: None,
+        },
+    )
+    .await;
+}
 
-    let key = bytes_to_base64url(selected_bytes);
-    println!("get_key_from_encrypted_cid: key = {}", key);
+/// Processes a transcoding task with `transcode_asset`, or as a live task for a live source, and
+/// records its result in `TRANSCODED`. A job in which every format failed is recorded too, unless
+/// the task can be retried.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+/// * `orig_source_cid` - The CID of the source video, optionally with an extension.
+/// * `media_formats` - JSON array of the media formats to transcode to, or empty to use
+///   `MEDIA_FORMATS_FILE`.
+/// * `is_encrypted` - Whether the source is encrypted and the outputs should be encrypted.
+/// * `is_gpu` - Whether to use GPU acceleration for transcoding.
+/// * `force` - Whether to download and transcode again even if the source or a format is cached.
+/// * `expected_hash` - The blake3 hash the downloaded source must have.
+/// * `can_retry` - Whether the task is retried if it fails.
+///
+/// # Returns
+/// `Ok(())` once the result is recorded, or why the task failed, for the caller to retry it or
+/// record the failure.
+///
+#[allow(clippy::too_many_arguments)]
+async fn process_transcode_task(
+    task_id: String,
+    orig_source_cid: String,
+    media_formats: String,
+    is_encrypted: bool,
+    is_gpu: bool,
+    force: bool,
+    expected_hash: Option<blake3::Hash>,
+    can_retry: bool,
+) -> Result<(), String> {
+    if let Some(live_source) = live_source_path(&orig_source_cid) {
+        let source_path = live_source.map_err(|e| {
+            let message = format!("Invalid live source {}: {}", orig_source_cid, e);
+            eprintln!("{}", message);
+            message
+        })?;
+        return process_live_task(task_id, source_path, media_formats, is_encrypted, is_gpu).await;
+    }
 
-    return key;
+    let options = TranscodeOptions {
+        is_encrypted,
+        is_gpu,
+        force,
+        expected_hash,
+    };
+    match transcode_asset(&task_id, &orig_source_cid, &media_formats, &options).await {
+        Ok(job)
+            if can_retry
+                && !job.cancelled
+                && job.transcoded_formats.is_empty()
+                && !job.failed_formats.is_empty() =>
+        {
+            Err(format!(
+                "Every media format failed: {}",
+                job.failed_formats
+                    .iter()
+                    .map(|(id, message)| format!("{}: {}", id, message))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ))
+        }
+        Ok(job) => {
+            record_job_result(task_id, job).await;
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Task {} failed: {}", task_id, e);
+            Err(e.to_string())
+        }
+    }
 }
 
-fn number_of_bytes(value: u32) -> usize {
-    let mut value = value;
-    let mut bytes = 1;
+/// Records the result of a transcoding job in `TRANSCODED`. Each format that failed is recorded
+/// in the metadata as `{ "id": .., "error": .. }` after the transcoded formats, and a job in which
+/// every format failed is recorded as failed.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+/// * `job` - The result of the job.
+///
+async fn record_job_result(task_id: String, job: JobResult) {
+    let all_failed = job.transcoded_formats.is_empty() && !job.failed_formats.is_empty();
+    let mut formats = job.transcoded_formats;
+    formats.extend(
+        job.failed_formats
+            .iter()
+            .map(|(id, message)| json!({ "id": id, "error": message })),
+    );
 
-    while value >= 256 {
-        value >>= 8;
-        bytes += 1;
+    if job.cancelled {
+        record_cancelled(&task_id, &formats).await;
+        return;
     }
 
-    bytes
+    let transcoded_json = serde_json::to_string(&formats).unwrap_or_else(|e| {
+        eprintln!("Error serializing transcoded formats: {:?}", e);
+        "".to_string()
+    });
+
+    shared::set_task_status(
+        &task_id,
+        if all_failed {
+            TaskStatus::Failed
+        } else {
+            TaskStatus::Done
+        },
+    );
+    record_transcoded(
+        task_id,
+        TranscodedResult {
+            metadata: transcoded_json,
+            manifest_cid: job.manifest_cid,
+            cancelled: false,
+            error: all_failed.then(|| String::from("Every media format failed")),
+            job_key: job.job_key,
+        },
+    )
+    .await;
 }
 
-/// Calculates the SHA-256 hash of the given `encrypted_cid`, encrypts it using AES-256-CBC with
-/// the specified `key`, and then encodes the result as a URL-safe base64 string. This function is
-/// designed for securing sensitive identifiers before storage or transmission.
+/// Records the final result of a task in `TRANSCODED` and persists the results to `STATE_DIR`.
 ///
 /// # Arguments
-/// * `encrypted_cid` - The content identifier to be hashed, encrypted, and encoded.
+/// * `task_id` - The identifier of the task.
+/// * `result` - The result of the task.
 ///
-pub fn get_base64_url_encrypted_blob_hash(encrypted_cid: &str) -> Option<String> {
-    let encrypted_cid = &encrypted_cid[1..];
-    let cid_bytes = base64url_to_bytes(encrypted_cid);
+async fn record_transcoded(task_id: String, result: TranscodedResult) {
+    let snapshot = {
+        let mut transcoded = TRANSCODED.lock().await;
+        transcoded.insert(task_id, Arc::new(result));
+        TranscodedSnapshot::take(&transcoded)
+    };
+    if let Some(snapshot) = snapshot {
+        snapshot.persist().await;
+    }
+}
 
-    let start_index =
-        CID_TYPE_ENCRYPTED_SIZE + ENCRYPTION_ALGORITHM_SIZE + CHUNK_SIZE_AS_POWEROF2_SIZE;
+fn transcoded_state_path(dir: &str) -> PathBuf {
+    Path::new(dir).join("transcoded.json")
+}
 
-    let end_index = start_index + ENCRYPTED_BLOB_HASH_SIZE;
+// Number of snapshots of `TRANSCODED` taken so far, to order them.
+static TRANSCODED_SNAPSHOTS: AtomicU64 = AtomicU64::new(0);
 
-    let encrypted_blob_hash = &cid_bytes[start_index..end_index];
+// Number of the last snapshot written to `STATE_DIR`, held while one is written, so that an
+// older snapshot written late never replaces a newer one.
+static WRITTEN_SNAPSHOT: Lazy<std::sync::Mutex<u64>> = Lazy::new(|| std::sync::Mutex::new(0));
 
-    let base64_url = bytes_to_base64url(encrypted_blob_hash);
+/// A copy of the results in `TRANSCODED`, taken under its lock and persisted after the lock is
+/// released, so that polling clients never wait for the state file to be written.
+struct TranscodedSnapshot {
+    number: u64,
+    results: HashMap<String, Arc<TranscodedResult>>,
+}
+
+impl TranscodedSnapshot {
+    /// Copies the results, which only clones their `Arc`s. Must be called with the `TRANSCODED`
+    /// lock held, so that snapshots are numbered in the order the results changed.
+    ///
+    /// # Returns
+    /// `None` if `STATE_DIR` isn't set, so nothing is persisted.
+    ///
+    fn take(transcoded: &HashMap<String, Arc<TranscodedResult>>) -> Option<TranscodedSnapshot> {
+        STATE_DIR.as_ref()?;
+        Some(TranscodedSnapshot {
+            number: TRANSCODED_SNAPSHOTS.fetch_add(1, Ordering::SeqCst) + 1,
+            results: transcoded.clone(),
+        })
+    }
+
+    /// Writes the results to `STATE_DIR` on a blocking thread, unless a newer snapshot has
+    /// already been written. A state file that can't be written is only logged, as the results
+    /// are still served from memory.
+    async fn persist(self) {
+        let persisted = tokio::task::spawn_blocking(move || self.write()).await;
+        if let Err(e) = persisted {
+            eprintln!("Failed to persist the transcoded results: {}", e);
+        }
+    }
 
-    Some(base64_url)
+    fn write(self) {
+        let Some(dir) = STATE_DIR.as_deref() else {
+            return;
+        };
+        let mut written_snapshot = WRITTEN_SNAPSHOT.lock().unwrap();
+        if *written_snapshot >= self.number {
+            return;
+        }
+
+        let path = transcoded_state_path(dir);
+        let results: HashMap<&String, &TranscodedResult> = self
+            .results
+            .iter()
+            .map(|(task_id, result)| (task_id, result.as_ref()))
+            .collect();
+        // Written to a temporary file and renamed, so a crash never leaves a truncated state file
+        let temp_path = path.with_extension("json.tmp");
+        let written = fs::create_dir_all(dir)
+            .and_then(|()| {
+                fs::write(
+                    &temp_path,
+                    serde_json::to_string(&results).unwrap_or_default(),
+                )
+            })
+            .and_then(|()| fs::rename(&temp_path, &path));
+
+        match written {
+            Ok(()) => *written_snapshot = self.number,
+            Err(e) => eprintln!(
+                "Failed to persist the transcoded results to {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
 }
 
-/// Generates a random filename with the given `prefix` and `extension`.
-/// The filename is guaranteed to be unique and not already exist in the
-/// current directory. Returns the resulting filename as a `String`.
+/// Reads the results persisted to `STATE_DIR` by an earlier run.
 ///
-/// # Arguments
-///
-/// * `prefix` - The prefix to use for the filename.
-/// * `extension` - The extension to use for the filename.
+/// # Returns
+/// The results keyed by task id, empty if `STATE_DIR` isn't set or its state file is missing
+/// or can't be parsed.
 ///
-fn generate_random_filename() -> String {
-    let uuid = Uuid::new_v4();
-    let timestamp = Utc::now().timestamp_nanos();
-    format!("{}_{}", uuid, timestamp)
+fn load_transcoded_state() -> HashMap<String, Arc<TranscodedResult>> {
+    let Some(dir) = STATE_DIR.as_deref() else {
+        return HashMap::new();
+    };
+
+    let path = transcoded_state_path(dir);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Failed to read {}: {}", path.display(), e);
+            }
+            return HashMap::new();
+        }
+    };
+
+    match serde_json::from_str::<HashMap<String, TranscodedResult>>(&contents) {
+        Ok(results) => results
+            .into_iter()
+            .map(|(task_id, result)| (task_id, Arc::new(result)))
+            .collect(),
+        Err(e) => {
+            eprintln!(
+                "Failed to parse {}, starting without persisted results: {}",
+                path.display(),
+                e
+            );
+            HashMap::new()
+        }
+    }
 }
 
-/// Asynchronously receives transcoding tasks from a channel and processes them using the specified transcoder. Each
-/// task involves reading an input file, transcoding it according to the provided settings, and writing the output to
-/// a specified location. Errors encountered during processing are logged, and upon completion of all tasks, a signal
-/// is sent through another channel to indicate completion.
+/// Removes the result of a task from `TRANSCODED`, optionally deleting everything it uploaded
+/// (renditions, sprite sheets and the manifest) from storage.
 ///
 /// # Arguments
-/// * `receiver` - An `Arc<Mutex<mpsc::Receiver<(String, String, String, bool, bool)>>>` representing a shared receiver
-///   channel for transcoding tasks. Each task includes the input file path, output file path, desired format,
-///   encryption flag, and GPU usage flag.
-///
-async fn transcode_task_receiver(
-    receiver: Arc<Mutex<mpsc::Receiver<(String, String, String, bool, bool)>>>,
-) {
-    while let Some((task_id, orig_source_cid, media_formats, is_encrypted, is_gpu)) =
-        receiver.lock().await.recv().await
-    {
-        let source_cid = Path::new(&orig_source_cid)
-            .with_extension("")
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_string());
-
-        if source_cid.is_none() {
-            eprintln!("Invalid source CID: {}", orig_source_cid);
-            continue;
-        }
-
-        let source_cid = source_cid.unwrap();
-
-        let portal_url_result = if is_encrypted {
-            var("PORTAL_ENCRYPT_URL")
+/// * `task_id` - The identifier of the task.
+/// * `delete_uploads` - Whether to also delete the uploaded files from storage.
+///
+/// # Returns
+/// `None` if there is no result for the task, otherwise a message describing the outcome.
+///
+async fn delete_transcoded_result(task_id: &str, delete_uploads: bool) -> Option<String> {
+    let (result, snapshot, other_results) = {
+        let mut transcoded = TRANSCODED.lock().await;
+        let result = transcoded.remove(task_id)?;
+        let other_results: Vec<Arc<TranscodedResult>> = if delete_uploads {
+            transcoded.values().cloned().collect()
         } else {
-            var("PORTAL_URL")
-        };
-
-        let portal_url = match portal_url_result {
-            Ok(url) => url,
-            Err(_) => {
-                eprintln!("Required environment variable for PORTAL_URL not found");
-                continue; // Skip the rest of this loop iteration
-            }
+            Vec::new()
         };
+        (result, TranscodedSnapshot::take(&transcoded), other_results)
+    };
+    if let Some(snapshot) = snapshot {
+        snapshot.persist().await;
+    }
+    shared::remove_task(task_id);
+    println!("Deleted transcoded result of task {}", task_id);
 
-        println!("source_cid: {}", source_cid);
-        println!("portal_url: {}", portal_url);
+    if !delete_uploads {
+        return Some(String::from("Transcoded result deleted"));
+    }
 
-        let file_path = format!("{}{}", *PATH_TO_FILE, source_cid);
+    // The stored job would otherwise answer an identical job with the deleted CIDs
+    if let Some(job_key) = &result.job_key {
+        remove_job(job_key);
+    }
 
-        if !Path::new(&file_path).exists() {
-            if is_encrypted {
-                println!("source_cid: {}", source_cid);
-                //            println!("Encrypted CID: {}", source_cid);
-                // // Extract the BASE64_URL_ENCRYPTED_BLOB_HASH from encrypted CID
-                let base64_url_encrypted_blob_hash =
-                    get_base64_url_encrypted_blob_hash(&source_cid)
-                        .expect("Failed to get base64 URL encrypted blob hash");
+    // Tasks answered from the result store, and formats answered from the cache of transcoded
+    // files, share their uploads, so an upload another result or stored job still references
+    // is kept
+    let formats: Vec<Value> = serde_json::from_str(&result.metadata).unwrap_or_default();
+    let mut uploads = uploaded_files(&formats, result.manifest_cid.as_deref());
+    let kept = tokio::task::spawn_blocking(move || {
+        let mut in_use = stored_uploads();
+        for other in &other_results {
+            let formats: Vec<Value> = serde_json::from_str(&other.metadata).unwrap_or_default();
+            in_use.extend(
+                uploaded_files(&formats, other.manifest_cid.as_deref())
+                    .into_iter()
+                    .map(|(url, _)| url),
+            );
+        }
+        in_use
+    })
+    .await
+    .unwrap_or_default();
+    let upload_count = uploads.len();
+    uploads.retain(|(url, _)| !kept.contains(url));
+    let kept_count = upload_count - uploads.len();
+    if kept_count > 0 {
+        println!(
+            "Task {}: keeping {} uploads still used by other results",
+            task_id, kept_count
+        );
+    }
 
-                // // GET https://s5.cx/api/locations/BASE64_URL_ENCRYPTED_BLOB_HASH?types=5,3 to get download urls for your encrypted file
-                let url = format!(
-                    "{}{}{}?types=5,3",
-                    portal_url, "/api/locations/", base64_url_encrypted_blob_hash
+    // Deleting makes blocking HTTP requests and runs curl, so it is kept off the async workers
+    let deleting_count = uploads.len();
+    let deleting_task_id = task_id.to_string();
+    let failed = tokio::task::spawn_blocking(move || {
+        let mut failed = 0;
+        for (url, portal) in &uploads {
+            if let Err(e) = delete_uploaded(url, portal.as_deref()) {
+                eprintln!(
+                    "Task {}: failed to delete upload {}: {}",
+                    deleting_task_id, url, e
                 );
-                println!("Downloading and then transcoding video from URL: {}", &url);
-
-                let encrypted_file_path = format!("{}{}_", *PATH_TO_FILE, source_cid);
-
-                match download_video(&url, encrypted_file_path.as_str()).await {
-                    Ok(_) => println!("Video downloaded successfully"),
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to download encrypted video from URL {}: {}",
-                            &url, e
-                        );
-                        continue;
-                    }
-                };
+                failed += 1;
+            }
+        }
+        failed
+    })
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Task {}: failed to delete its uploads: {}", task_id, e);
+        deleting_count
+    });
 
-                let encrypted_metadata = match std::fs::read_to_string(&encrypted_file_path) {
-                    Ok(contents) => contents,
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to read encrypted metadata from file {}: {}",
-                            &encrypted_file_path, e
-                        );
-                        continue;
-                    }
-                };
+    let mut message = if failed == 0 {
+        format!(
+            "Transcoded result and its {} uploads deleted",
+            deleting_count
+        )
+    } else {
+        format!(
+            "Transcoded result deleted, but {} of its {} uploads could not be deleted",
+            failed, deleting_count
+        )
+    };
+    if kept_count > 0 {
+        message.push_str(&format!(
+            ", {} uploads still used by other results kept",
+            kept_count
+        ));
+    }
+    Some(message)
+}
 
-                let file_path_encrypted =
-                    format!("{}{}", *PATH_TO_FILE, generate_random_filename());
+/// Transcodes a live source to HLS as it grows. After every step, the playlists published so
+/// far are recorded in `TRANSCODED`, so they can be played before the source has finished.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+/// * `source_path` - The path of the live source.
+/// * `media_formats` - JSON array of the media formats to transcode to, or empty to use
+///   `MEDIA_FORMATS_FILE`.
+/// * `is_encrypted` - Whether the outputs should be encrypted, which live sources don't support.
+/// * `is_gpu` - Whether to use GPU acceleration for transcoding.
+///
+/// # Returns
+/// `Ok(())` once the result is recorded, or why the task couldn't be started, for the caller to
+/// retry it or record the failure.
+///
+async fn process_live_task(
+    task_id: String,
+    source_path: String,
+    media_formats: String,
+    is_encrypted: bool,
+    is_gpu: bool,
+) -> Result<(), String> {
+    let fail = |message: String| {
+        eprintln!("Live task {}: {}", task_id, message);
+        message
+    };
 
-                println!("file_encrypted_metadata: {:?}", file_path_encrypted);
-                println!("encrypted_metadata: {:?}", encrypted_metadata);
+    if is_encrypted {
+        return Err(fail(String::from("live sources cannot be encrypted")));
+    }
 
-                // get download urls for your encrypted file
-                // and then just download the encrypted file using any http download library
-                match download_and_concat_files(encrypted_metadata, file_path_encrypted.clone())
-                    .await
-                {
-                    Ok(()) => println!("Download and concatenation succeeded"),
-                    Err(e) => eprintln!("Download and concatenation failed: {}", e),
-                }
+    let media_formats_vec = load_media_formats(&task_id, &media_formats)
+        .map_err(|e| fail(format!("Invalid media formats: {}", e)))?;
 
-                let file_encrypted_size = get_file_size(file_path_encrypted.clone()).unwrap();
-                println!("file_path_encrypted: {}", file_path_encrypted);
-                println!("file_encrypted_size: {}", file_encrypted_size);
-
-                // last chunk index is floor(encrypted file size / (262144 + 16)) for the default chunk size
-                // iirc padding is 0 in your case
-                let last_index_size =
-                    (file_encrypted_size as f64 / (262144 + 16) as f64).floor() as u32;
-
-                let key = get_key_from_encrypted_cid(&source_cid);
-                let key_bytes = base64url_to_bytes(&key);
-                //let key_bytes = vec![0; 32];
-
-                println!("file_path: {}", file_path);
-                println!("key: {}", key);
-                println!("key_bytes: {:?}", key_bytes);
-                println!("last_index_size: {}", last_index_size);
-
-                // decrypt_file_xchacha20 from vup
-                match decrypt_file_xchacha20(
-                    file_path_encrypted,
-                    file_path.clone(),
-                    key_bytes,
-                    0,
-                    last_index_size,
-                ) {
-                    Ok(_) => println!("Decryption succeeded"),
-                    Err(error) => {
-                        eprintln!("Decryption error: {:?}", error);
-                        continue;
-                    }
-                }
-            } else {
-                let url = format!("{}{}{}", portal_url, "/s5/blob/", source_cid);
-
-                // First, we download the video and save it locally
-                match download_video(&url, file_path.as_str()).await {
-                    Ok(_) => println!("Video downloaded successfully"),
-                    Err(e) => {
-                        eprintln!("Failed to download video from URL {}: {}", &url, e);
-                        continue;
-                    }
-                };
-            }
-        } else {
-            println!("File already exists: {}", &file_path);
+    let mut live = LiveTranscode::new(&task_id, &source_path, &media_formats_vec, is_gpu)
+        .map_err(|e| fail(e.message().to_string()))?;
+
+    loop {
+        if shared::is_cancelled(&task_id) {
+            println!("Live task {} cancelled", task_id);
+            record_cancelled(&task_id, &live.transcoded_formats()).await;
+            return Ok(());
         }
 
-        let media_formats_file = var("MEDIA_FORMATS_FILE").unwrap();
+        // A failed step ends the task, keeping the playlists published so far along with the
+        // error. It isn't retried, as that would transcode the whole source again.
+        let (finished, error) = match live.step().await {
+            Ok(finished) => (finished, None),
+            Err(e) => {
+                eprintln!("Live task {} failed: {}", task_id, e.message());
+                (true, Some(e.message().to_string()))
+            }
+        };
 
-        let media_formats_json = if !media_formats.is_empty() {
-            media_formats.clone()
+        let transcoded_formats = live.transcoded_formats();
+        let status = if error.is_some() {
+            TaskStatus::Failed
         } else {
-            read_to_string(media_formats_file.as_str()).expect("Failed to read video format file")
+            TaskStatus::Done
         };
+        let result = TranscodedResult {
+            metadata: serde_json::to_string(&transcoded_formats).unwrap_or_default(),
+            manifest_cid: None,
+            cancelled: false,
+            error,
+            job_key: None,
+        };
+        if finished {
+            shared::set_task_status(&task_id, status);
+            record_transcoded(task_id, result).await;
+            return Ok(());
+        }
+        // Partial results are only kept in memory, as the task doesn't survive a restart
+        if !transcoded_formats.is_empty() {
+            TRANSCODED
+                .lock()
+                .await
+                .insert(task_id.clone(), Arc::new(result));
+        }
 
-        print!("media_formats_json: {}", media_formats_json);
-        let media_formats_vec: Vec<Value> =
-            serde_json::from_str(&media_formats_json).expect("Failed to parse video formats");
-
-        // Then, we transcode the downloaded video with each video format
-        let mut transcoded_formats = Vec::new();
-        for (index, video_format) in media_formats_vec.iter().enumerate() {
-            let video_format_str = match serde_json::to_string(&video_format) {
-                Ok(str) => str,
-                Err(e) => {
-                    eprintln!("Error serializing video format: {:?}", e);
-                    continue;
-                }
-            };
+        tokio::time::sleep(Duration::from_secs(*LIVE_POLL_SECS)).await;
+    }
+}
 
-            let format_result = get_video_format_from_str(&video_format_str);
-            let format = match format_result {
-                Ok(format) => format,
-                Err(e) => {
-                    eprintln!("Failed to get video format from string: {}", e);
-                    continue; // Skip the rest of this loop iteration
-                }
-            };
+/// Looks up the result of a task, holding the `TRANSCODED` lock only to clone its `Arc`.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+///
+async fn get_transcoded_result(task_id: &str) -> Option<Arc<TranscodedResult>> {
+    TRANSCODED.lock().await.get(task_id).cloned()
+}
 
-            if !check_transcoded_file_exists(
-                file_path.as_str(),
-                &format.id.to_string(),
-                format.ext.as_str(),
-            )
-            .await
-            {
-                let transcode_result: std::prelude::v1::Result<
-                    Response<TranscodeVideoResponse>,
-                    Status,
-                > = transcode_video(
-                    task_id.clone(),
-                    index,
-                    &file_path,
-                    &video_format_str,
-                    is_encrypted,
-                    is_gpu,
-                )
-                .await;
+/// Returns whether a task has finished: its result has been recorded in `TRANSCODED` and it is
+/// no longer pending, which a live task publishing partial results still is. A task whose
+/// result has been deleted is finished too.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+///
+async fn is_task_finished(task_id: &str) -> bool {
+    shared::get_task_status(task_id).is_none()
+        || (get_transcoded_result(task_id).await.is_some() && !shared::is_pending(task_id))
+}
 
-                let current_progress = shared::calculate_overall_progress(&task_id);
-                println!(
-                    "Current Overall Progress for task {}: {}%",
-                    task_id, current_progress
-                );
+/// Splits `text` into byte ranges of at most `METADATA_CHUNK_BYTES`, each ending on a UTF-8
+/// character boundary so that every range can be sliced as a `str`.
+fn metadata_chunk_ranges(text: &str) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
 
-                match transcode_result {
-                    Ok(transcode_video_response) => {
-                        // Handle the successful response
-                        let response = transcode_video_response.into_inner();
-                        println!(
-                            "Response: status_code: {}, message: {}, cid: {}",
-                            response.status_code, response.message, response.cid
-                        );
-
-                        // Create a mutable clone of video_format
-                        let mut video_format_modified = video_format.clone();
-
-                        match &format.dest {
-                            Some(dest) if dest == "ipfs" => {
-                                video_format_modified["cid"] =
-                                    json!(format!("ipfs://{}", response.cid));
-                            }
-                            _ => {
-                                video_format_modified["cid"] =
-                                    json!(format!("s5://{}", response.cid));
-                            }
-                        }
-                        transcoded_formats.push(video_format_modified);
-                    }
-                    Err(e) => {
-                        // Log the error and continue with the next format
-                        eprintln!("Error transcoding video: {:?}", e);
-                        continue;
-                    }
-                }
-            }
+    while start < text.len() {
+        let mut end = (start + METADATA_CHUNK_BYTES).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
         }
+        ranges.push(start..end);
+        start = end;
+    }
 
-        let transcoded_json = serde_json::to_string(&transcoded_formats).unwrap_or_else(|e| {
-            eprintln!("Error serializing transcoded formats: {:?}", e);
-            "".to_string()
-        });
+    ranges
+}
 
-        let mut transcoded = TRANSCODED.lock().await;
-        transcoded.insert(task_id, transcoded_json);
-    }
+/// Escapes text for inclusion in a JSON string literal, without the surrounding quotes. As JSON
+/// escapes each character independently, consecutive pieces of a string can be escaped
+/// separately and concatenated.
+fn escape_json_fragment(text: &str) -> String {
+    let quoted = serde_json::to_string(text).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Returns the progress of each format of a task as gRPC messages.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+///
+fn format_progress_messages(task_id: &str) -> Vec<transcode::FormatProgress> {
+    shared::get_format_progress(task_id)
+        .into_iter()
+        .map(|progress| transcode::FormatProgress {
+            format_id: progress.format_id.unwrap_or_default(),
+            ext: progress.ext.unwrap_or_default(),
+            percent: progress.percent.unwrap_or_default(),
+            phase: progress
+                .phase
+                .map(|phase| phase.as_str().to_string())
+                .unwrap_or_default(),
+            encoder: progress
+                .encoder
+                .map(|encoder| encoder.as_str().to_string())
+                .unwrap_or_default(),
+            error: progress.error.unwrap_or_default(),
+        })
+        .collect()
 }
 
 // The gRPC service implementation
 #[derive(Debug, Clone)]
 struct TranscodeServiceHandler {
-    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<(String, String, String, bool, bool)>>>>,
+    task_queue: Option<Arc<TaskQueue>>,
 }
 
 #[async_trait]
@@ -442,6 +988,8 @@ impl TranscodeService for TranscodeServiceHandler {
         &self,
         request: Request<TranscodeRequest>,
     ) -> Result<Response<TranscodeResponse>, Status> {
+        check_grpc_rate_limit(&request)?;
+
         let mut source_cid = request.get_ref().source_cid.clone();
         if source_cid.starts_with("s5://") {
             source_cid = source_cid.strip_prefix("s5://").unwrap().to_string();
@@ -457,32 +1005,36 @@ impl TranscodeService for TranscodeServiceHandler {
 
         let is_gpu = request.get_ref().is_gpu;
         println!("Received is_gpu: {}", is_gpu);
+        validate_media_formats(&media_formats, is_encrypted, is_gpu)?;
 
-        println!(
-            "transcode_task_sender is None: {}",
-            self.transcode_task_sender.is_none()
-        );
+        let force = request.get_ref().force;
+        println!("Received force: {}", force);
 
-        // Send the transcoding task to the transcoding task receiver
-        let task_id = Uuid::new_v4();
-        if let Some(ref sender) = self.transcode_task_sender {
-            let sender = sender.lock().await.clone();
+        let expected_hash = parse_expected_hash(&request.get_ref().expected_hash, is_encrypted)?;
 
-            if let Err(e) = sender
-                .send((
+        let priority =
+            Priority::parse(&request.get_ref().priority).map_err(Status::invalid_argument)?;
+        println!("Received priority: {:?}", priority);
+
+        println!("task_queue is None: {}", self.task_queue.is_none());
+
+        // Queue the transcoding task for the transcoding task receiver
+        let task_id = Uuid::new_v4();
+        if let Some(ref task_queue) = self.task_queue {
+            shared::add_pending_task(&task_id.to_string());
+            shared::register_task(&task_id.to_string(), &source_cid);
+            task_queue.push(
+                (
                     task_id.to_string(),
                     source_cid.clone(),
                     media_formats.clone(),
                     is_encrypted,
                     is_gpu,
-                ))
-                .await
-            {
-                return Err(Status::internal(format!(
-                    "Failed to send transcoding task: {}",
-                    e
-                )));
-            }
+                    force,
+                    expected_hash,
+                ),
+                priority,
+            );
         }
 
         let response = TranscodeResponse {
@@ -500,144 +1052,681 @@ impl TranscodeService for TranscodeServiceHandler {
     ) -> Result<Response<GetTranscodedResponse>, Status> {
         let task_id = &request.get_ref().task_id;
 
-        let transcoded = TRANSCODED.lock().await;
-        let metadata = transcoded
-            .get(task_id)
-            .cloned()
-            .ok_or_else(|| Status::not_found(format!("CID not found for task_id: {}", task_id)))?;
+        let result = get_transcoded_result(task_id)
+            .await
+            .ok_or_else(|| Status::not_found(format!("CID not found for task_id: {}", task_id)))?;
+
+        let progress = shared::calculate_overall_progress(task_id);
+
+        let response = GetTranscodedResponse {
+            status_code: 200,
+            metadata: result.metadata.clone(),
+            progress,
+            manifest_cid: result.manifest_cid.clone().unwrap_or_default(),
+            cancelled: result.cancelled,
+            format_progress: format_progress_messages(task_id),
+            error: result.error.clone().unwrap_or_default(),
+            attempts: shared::get_attempts(task_id),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    type GetTranscodedStreamStream =
+        Pin<Box<dyn Stream<Item = Result<GetTranscodedResponse, Status>> + Send>>;
+
+    async fn get_transcoded_stream(
+        &self,
+        request: Request<GetTranscodedRequest>,
+    ) -> Result<Response<Self::GetTranscodedStreamStream>, Status> {
+        let task_id = &request.get_ref().task_id;
+
+        let result = get_transcoded_result(task_id)
+            .await
+            .ok_or_else(|| Status::not_found(format!("CID not found for task_id: {}", task_id)))?;
+
+        let progress = shared::calculate_overall_progress(task_id);
+        let format_progress = format_progress_messages(task_id);
+        let attempts = shared::get_attempts(task_id);
+
+        // Each message carries the next piece of the metadata, so that a large result is never
+        // copied whole into a single message
+        let mut ranges = metadata_chunk_ranges(&result.metadata);
+        if ranges.is_empty() {
+            ranges.push(0..0);
+        }
+
+        let stream = tokio_stream::iter(ranges).map(move |range| {
+            Ok(GetTranscodedResponse {
+                status_code: 200,
+                metadata: result.metadata[range].to_string(),
+                progress,
+                manifest_cid: result.manifest_cid.clone().unwrap_or_default(),
+                cancelled: result.cancelled,
+                format_progress: format_progress.clone(),
+                error: result.error.clone().unwrap_or_default(),
+                attempts,
+            })
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type StreamProgressStream = Pin<Box<dyn Stream<Item = Result<ProgressUpdate, Status>> + Send>>;
+
+    async fn stream_progress(
+        &self,
+        request: Request<StreamProgressRequest>,
+    ) -> Result<Response<Self::StreamProgressStream>, Status> {
+        let task_id = request.get_ref().task_id.clone();
+
+        if shared::get_task_status(&task_id).is_none() {
+            return Err(Status::not_found(format!(
+                "No task for task_id: {}",
+                task_id
+            )));
+        }
+
+        // The first update is sent straight away, and the stream ends after the update sent
+        // once the task has finished, so that its final progress is always sent
+        let stream = futures::stream::unfold(Some((task_id, true)), |state| async move {
+            let (task_id, is_first) = state?;
+            if !is_first {
+                tokio::time::sleep(PROGRESS_UPDATE_INTERVAL).await;
+            }
+
+            let finished = is_task_finished(&task_id).await;
+            let update = ProgressUpdate {
+                overall: shared::calculate_overall_progress(&task_id),
+                per_format: format_progress_messages(&task_id),
+            };
+            let next_state = (!finished).then_some((task_id, false));
+            Some((Ok(update), next_state))
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn cancel_transcode(
+        &self,
+        request: Request<CancelTranscodeRequest>,
+    ) -> Result<Response<CancelTranscodeResponse>, Status> {
+        let task_id = &request.get_ref().task_id;
+
+        if !shared::cancel_task(task_id) {
+            return Err(Status::not_found(format!(
+                "No pending task for task_id: {}",
+                task_id
+            )));
+        }
+
+        let response = CancelTranscodeResponse {
+            status_code: 200,
+            message: "Transcoding task cancelled".to_string(),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn delete_transcoded(
+        &self,
+        request: Request<DeleteTranscodedRequest>,
+    ) -> Result<Response<DeleteTranscodedResponse>, Status> {
+        let task_id = &request.get_ref().task_id;
+
+        let message = delete_transcoded_result(task_id, request.get_ref().delete_uploads)
+            .await
+            .ok_or_else(|| {
+                Status::not_found(format!("No transcoded result for task_id: {}", task_id))
+            })?;
+
+        let response = DeleteTranscodedResponse {
+            status_code: 200,
+            message,
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_capabilities(
+        &self,
+        _request: Request<GetCapabilitiesRequest>,
+    ) -> Result<Response<GetCapabilitiesResponse>, Status> {
+        let capabilities = &*CAPABILITIES;
+
+        let response = GetCapabilitiesResponse {
+            schema_version: capabilities.schema_version,
+            version: capabilities.version.to_string(),
+            encoders: capabilities.encoders.clone(),
+            hardware_encoders: capabilities.hardware_encoders.clone(),
+            gpu: capabilities.gpu,
+            storage_backends: capabilities
+                .storage_backends
+                .iter()
+                .map(|backend| backend.to_string())
+                .collect(),
+            max_input_bytes: capabilities.max_input_bytes,
+            max_formats_per_request: capabilities.max_formats_per_request as u32,
+        };
+
+        Ok(Response::new(response))
+    }
+}
+
+impl Drop for TranscodeServiceHandler {
+    fn drop(&mut self) {
+        self.task_queue = None;
+    }
+}
+
+#[derive(Debug)]
+struct RateLimited(Duration);
+
+impl warp::reject::Reject for RateLimited {}
+
+#[derive(Debug)]
+struct InvalidTranscodeBody(String);
+
+impl warp::reject::Reject for InvalidTranscodeBody {}
+
+/// The address of the client a REST request came from, inserted into the request extensions
+/// when the connection is accepted. The REST routes are served by hyper directly rather than
+/// `warp::serve`, so `warp::addr::remote` doesn't know it.
+#[derive(Debug, Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// A warp filter extracting the address of the connected client, from `RemoteAddr` or else from
+/// warp itself.
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .map(
+            |remote_addr: Option<SocketAddr>, inserted: Option<RemoteAddr>| {
+                remote_addr.or(inserted.map(|RemoteAddr(addr)| addr))
+            },
+        )
+}
+
+/// A warp filter that rejects with `RateLimited` when the client, identified by its bearer
+/// token or else its IP address, has exceeded `RATE_LIMIT_PER_MINUTE` requests.
+fn with_rate_limit() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    with_rate_limiter(&RATE_LIMITER)
+}
+
+fn with_rate_limiter(
+    rate_limiter: &'static RateLimiter,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(remote_addr())
+        .and_then(
+            move |authorization: Option<String>, remote_addr: Option<SocketAddr>| async move {
+                rate_limiter
+                    .check(&client_key(authorization.as_deref(), remote_addr))
+                    .map_err(|retry_after| warp::reject::custom(RateLimited(retry_after)))
+            },
+        )
+        .untuple_one()
+}
+
+/// Turns the rejections of the `transcode` and `mirror` routes into JSON responses: a `RateLimited`
+/// rejection into a 429 carrying a `Retry-After` header, and a malformed or invalid JSON body
+/// into a 400. Any other rejection is passed on unchanged.
+async fn handle_transcode_rejection(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(RateLimited(retry_after)) = rejection.find::<RateLimited>() {
+        let reply = warp::reply::json(&json!({
+            "status_code": 429,
+            "message": "Rate limit exceeded",
+        }));
+        let reply =
+            warp::reply::with_header(reply, "Retry-After", retry_after.as_secs().to_string());
+        return Ok(warp::reply::with_status(reply, StatusCode::TOO_MANY_REQUESTS).into_response());
+    }
+
+    let message = if let Some(InvalidTranscodeBody(message)) = rejection.find() {
+        message.clone()
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        format!("Malformed JSON body: {}", e)
+    } else {
+        return Err(rejection);
+    };
+
+    let reply = warp::reply::json(&json!({
+        "status_code": 400,
+        "message": message,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct TranscodeResponseWrapper {
+    status_code: i32,
+    message: String,
+    task_id: String,
+}
+
+impl From<transcode::TranscodeResponse> for TranscodeResponseWrapper {
+    fn from(response: transcode::TranscodeResponse) -> Self {
+        TranscodeResponseWrapper {
+            status_code: response.status_code,
+            message: response.message,
+            task_id: response.task_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RestHandler {
+    task_queue: Option<Arc<TaskQueue>>,
+}
+
+impl RestHandler {
+    #[allow(clippy::too_many_arguments)]
+    async fn transcode(
+        &self,
+        source_cid: String,
+        media_formats: String,
+        is_encrypted: bool,
+        is_gpu: bool,
+        force: bool,
+        expected_hash: String,
+        priority: Priority,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_media_formats(&media_formats, is_encrypted, is_gpu)
+            .map_err(|e| warp::reject::custom(InvalidTranscodeBody(e.message().to_string())))?;
+        let expected_hash = parse_expected_hash(&expected_hash, is_encrypted)
+            .map_err(|e| warp::reject::custom(InvalidTranscodeBody(e.message().to_string())))?;
+
+        let task_id = Uuid::new_v4();
+
+        if let Some(ref task_queue) = self.task_queue {
+            shared::add_pending_task(&task_id.to_string());
+            shared::register_task(&task_id.to_string(), &source_cid);
+            task_queue.push(
+                (
+                    task_id.to_string(),
+                    source_cid.clone(),
+                    media_formats.clone(),
+                    is_encrypted,
+                    is_gpu,
+                    force,
+                    expected_hash,
+                ),
+                priority,
+            );
+        }
+
+        let response = transcode::TranscodeResponse {
+            status_code: 200,
+            message: "Transcoding task queued".to_string(),
+            task_id: task_id.to_string(),
+        };
+
+        Ok(warp::reply::json(&TranscodeResponseWrapper::from(response)))
+    }
+}
+
+impl RestHandler {
+    async fn get_transcoded(&self, task_id: String) -> Result<impl warp::Reply, warp::Rejection> {
+        // Retrieve the metadata and the progress for the given task ID.
+        let result = get_transcoded_result(&task_id)
+            .await
+            .ok_or_else(warp::reject::not_found)?;
+
+        let progress = shared::calculate_overall_progress(&task_id);
+
+        // Stream the response, escaping the metadata a piece at a time into the `metadata`
+        // string rather than serializing the whole response into one buffer
+        let head = String::from("{\"status_code\":200,\"metadata\":\"");
+        let tail = format!(
+            "\",\"progress\":{},\"manifest_cid\":{},\"cancelled\":{},\"format_progress\":{},\"error\":{},\"attempts\":{}}}",
+            progress,
+            json!(result.manifest_cid.clone().unwrap_or_default()),
+            result.cancelled,
+            json!(shared::get_format_progress(&task_id)),
+            json!(result.error),
+            shared::get_attempts(&task_id)
+        );
+        let ranges = metadata_chunk_ranges(&result.metadata);
+
+        let body = futures::stream::once(async move { head })
+            .chain(
+                futures::stream::iter(ranges)
+                    .map(move |range| escape_json_fragment(&result.metadata[range])),
+            )
+            .chain(futures::stream::once(async move { tail }))
+            .map(|chunk| Ok::<_, std::convert::Infallible>(bytes::Bytes::from(chunk)));
+
+        let mut response = warp::reply::Response::new(warp::hyper::Body::wrap_stream(body));
+        response.headers_mut().insert(
+            warp::http::header::CONTENT_TYPE,
+            warp::http::HeaderValue::from_static("application/json"),
+        );
+
+        Ok(response)
+    }
+
+    async fn get_rendition(
+        &self,
+        task_id: String,
+        format_id: u32,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        // A finished task's renditions are in its result, a running task's in `RENDITIONS`
+        let renditions = match get_transcoded_result(&task_id).await {
+            Some(result) => from_str::<Vec<Value>>(&result.metadata).unwrap_or_default(),
+            None => shared::get_renditions(&task_id),
+        };
+
+        // Renditions expanded from an audio bitrate ladder share an id, so all are returned. A
+        // failed format is recorded as an object with its `error` instead.
+        let (failed, metadata): (Vec<Value>, Vec<Value>) = renditions
+            .into_iter()
+            .filter(|rendition| rendition["id"].as_u64() == Some(format_id as u64))
+            .partition(|rendition| rendition.get("error").is_some());
+        if !metadata.is_empty() {
+            return Ok(warp::reply::json(&json!({
+                "status_code": 200,
+                "metadata": metadata,
+            }))
+            .into_response());
+        }
+
+        // Not ready yet if the task is still running and the format is one of its formats, or
+        // its formats haven't been read yet
+        let format_progress = shared::get_format_progress(&task_id);
+        let progress = format_progress
+            .iter()
+            .find(|progress| progress.format_id == Some(format_id));
+        // The progress of a task restored from `STATE_DIR` is unknown, so its result is checked too
+        let error = progress
+            .and_then(|progress| progress.error.clone())
+            .or_else(|| {
+                failed
+                    .first()
+                    .and_then(|failed| failed["error"].as_str().map(String::from))
+            });
+        if let Some(error) = error {
+            let reply = warp::reply::json(&json!({
+                "status_code": 500,
+                "message": "Rendition failed",
+                "error": error,
+            }));
+            return Ok(
+                warp::reply::with_status(reply, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+            );
+        }
+        if !shared::is_pending(&task_id) || (!format_progress.is_empty() && progress.is_none()) {
+            return Err(warp::reject::not_found());
+        }
+
+        let reply = warp::reply::json(&json!({
+            "status_code": 202,
+            "message": "Rendition not ready yet",
+            "percent": progress.and_then(|progress| progress.percent),
+            "phase": progress.and_then(|progress| progress.phase),
+            "attempts": shared::get_attempts(&task_id),
+        }));
+        Ok(warp::reply::with_status(reply, StatusCode::ACCEPTED).into_response())
+    }
+
+    /// Streams the lines of ffmpeg's log for a running task as server-sent events while they are
+    /// produced, ending when the task finishes. A client that falls too far behind gets a
+    /// `lagged` event with the number of lines it missed.
+    ///
+    async fn stream_logs(&self, task_id: String) -> Result<warp::reply::Response, warp::Rejection> {
+        if !shared::is_pending(&task_id) {
+            return Err(warp::reject::not_found());
+        }
+
+        let receiver = shared::subscribe_log(&task_id);
+        let events = futures::stream::unfold(receiver, |mut receiver| async move {
+            let event = match receiver.recv().await {
+                Ok(line) => warp::sse::Event::default().event("log").data(line),
+                Err(RecvError::Lagged(skipped)) => warp::sse::Event::default()
+                    .event("lagged")
+                    .data(skipped.to_string()),
+                Err(RecvError::Closed) => return None,
+            };
+            Some((Ok::<_, Infallible>(event), receiver))
+        });
+
+        let keep_alive = warp::sse::keep_alive().interval(Duration::from_secs(*SSE_KEEPALIVE_SECS));
+        Ok(warp::sse::reply(keep_alive.stream(events)).into_response())
+    }
+
+    /// Reports the overall progress of a task and the progress of each of its formats, `null`
+    /// for a format that hasn't started, without waiting for the task to finish.
+    ///
+    async fn progress(&self, task_id: String) -> Result<impl warp::Reply, warp::Rejection> {
+        if shared::get_task_status(&task_id).is_none() {
+            return Err(warp::reject::not_found());
+        }
+
+        let per_format: Vec<Option<i32>> = shared::get_format_progress(&task_id)
+            .iter()
+            .map(|progress| progress.percent)
+            .collect();
 
-        let progress = shared::calculate_overall_progress(task_id);
+        Ok(warp::reply::json(&json!({
+            "overall": shared::calculate_overall_progress(&task_id),
+            "per_format": per_format,
+        })))
+    }
 
-        let response = GetTranscodedResponse {
-            status_code: 200,
-            metadata,
-            progress,
-        };
+    /// Lists the known tasks, newest first, a page at a time, optionally only those with a
+    /// given status.
+    ///
+    async fn list_tasks(
+        &self,
+        params: ListTasksParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let tasks = shared::list_tasks(params.status);
+        let total = tasks.len();
+        let limit = params.limit.clamp(1, MAX_TASKS_PAGE_SIZE);
+
+        let page: Vec<Value> = tasks
+            .into_iter()
+            .skip(params.offset)
+            .take(limit)
+            .map(|task| {
+                let (formats_done, formats_total) = shared::count_formats_done(&task.task_id);
+                json!({
+                    "task_id": task.task_id,
+                    "source_cid": task.source_cid,
+                    "status": task.status,
+                    "progress": shared::calculate_overall_progress(&task.task_id),
+                    "created_at": task.created_at,
+                    "formats_done": formats_done,
+                    "formats_total": formats_total,
+                })
+            })
+            .collect();
+
+        Ok(warp::reply::json(&json!({
+            "status_code": 200,
+            "total": total,
+            "offset": params.offset,
+            "limit": limit,
+            "tasks": page,
+        })))
+    }
 
-        Ok(Response::new(response))
+    /// Reports the version of the transcoder, the ffmpeg and ffprobe binaries it runs, and
+    /// the storage networks it is configured to upload to.
+    ///
+    async fn version(&self) -> Result<impl warp::Reply, warp::Rejection> {
+        Ok(warp::reply::json(&json!({
+            "status_code": 200,
+            "version": env!("CARGO_PKG_VERSION"),
+            "ffmpeg": *FFMPEG_VERSION,
+            "ffprobe": *FFPROBE_VERSION,
+            "storage_backends": configured_storage_backends(),
+        })))
     }
-}
 
-impl Drop for TranscodeServiceHandler {
-    fn drop(&mut self) {
-        self.transcode_task_sender = None;
+    /// Reports what the transcoder supports, as detected at startup, see `Capabilities`.
+    ///
+    async fn capabilities(&self) -> Result<impl warp::Reply, warp::Rejection> {
+        Ok(warp::reply::json(&*CAPABILITIES))
     }
-}
 
-#[derive(Debug)]
-struct TranscodeError(String);
+    async fn cancel(&self, task_id: String) -> Result<impl warp::Reply, warp::Rejection> {
+        if !shared::cancel_task(&task_id) {
+            return Err(warp::reject::not_found());
+        }
 
-impl warp::reject::Reject for TranscodeError {}
+        Ok(warp::reply::json(&json!({
+            "status_code": 200,
+            "message": "Transcoding task cancelled",
+        })))
+    }
 
-#[derive(Debug, Serialize)]
-struct TranscodeResponseWrapper {
-    status_code: i32,
-    message: String,
-    task_id: String,
-}
+    /// Pauses or resumes a running task, replying with a 409 and the reason if the task isn't
+    /// running (to pause) or paused (to resume).
+    ///
+    async fn pause(
+        &self,
+        task_id: String,
+        pause: bool,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        if shared::get_task_status(&task_id).is_none() {
+            return Err(warp::reject::not_found());
+        }
 
-impl From<transcode::TranscodeResponse> for TranscodeResponseWrapper {
-    fn from(response: transcode::TranscodeResponse) -> Self {
-        TranscodeResponseWrapper {
-            status_code: response.status_code,
-            message: response.message,
-            task_id: response.task_id,
+        let (result, message) = if pause {
+            (shared::pause_task(&task_id), "Transcoding task paused")
+        } else {
+            (shared::resume_task(&task_id), "Transcoding task resumed")
+        };
+
+        match result {
+            Ok(()) => Ok(warp::reply::json(&json!({
+                "status_code": 200,
+                "message": message,
+            }))
+            .into_response()),
+            Err(error) => {
+                let reply = warp::reply::json(&json!({
+                    "status_code": 409,
+                    "message": error,
+                }));
+                Ok(warp::reply::with_status(reply, StatusCode::CONFLICT).into_response())
+            }
         }
     }
-}
 
-impl From<tokio::sync::mpsc::error::SendError<(String, String, String, bool, bool)>>
-    for TranscodeError
-{
-    fn from(e: tokio::sync::mpsc::error::SendError<(String, String, String, bool, bool)>) -> Self {
-        TranscodeError(format!("Failed to send transcoding task: {}", e))
+    /// Copies an uploaded rendition to another storage network, see `mirror_rendition`.
+    ///
+    async fn mirror(&self, body: MirrorBody) -> Result<warp::reply::Response, warp::Rejection> {
+        match mirror_rendition(&body.cid, &body.dest).await {
+            Ok(mirrored) => Ok(warp::reply::json(&json!({
+                "status_code": 200,
+                "cid": mirrored.cid,
+                "portal": mirrored.portal_url,
+                "existing": mirrored.existing,
+            }))
+            .into_response()),
+            Err(e) => {
+                let status = match e.code() {
+                    Code::InvalidArgument | Code::FailedPrecondition => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                let reply = warp::reply::json(&json!({
+                    "status_code": status.as_u16(),
+                    "message": e.message(),
+                }));
+                Ok(warp::reply::with_status(reply, status).into_response())
+            }
+        }
     }
-}
-
-#[derive(Debug, Clone)]
-struct RestHandler {
-    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<(String, String, String, bool, bool)>>>>,
-}
 
-impl RestHandler {
-    async fn transcode(
+    /// Runs a self-test of the configured ffmpeg and storage network, see `run_selftest`. A
+    /// second self-test requested while one is running is rejected with a 409.
+    ///
+    async fn selftest(
         &self,
-        source_cid: String,
-        media_formats: String,
-        is_encrypted: bool,
-        is_gpu: bool,
-    ) -> Result<impl warp::Reply, warp::Rejection> {
-        let task_id = Uuid::new_v4();
-
-        if let Some(ref sender) = self.transcode_task_sender {
-            let sender = sender.lock().await.clone();
-
-            if let Err(e) = sender
-                .send((
-                    task_id.to_string(),
-                    source_cid.clone(),
-                    media_formats.clone(),
-                    is_encrypted,
-                    is_gpu,
-                ))
-                .await
-            {
-                return Err(warp::reject::custom(TranscodeError::from(e)));
+        params: SelfTestParams,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        if let Some(dest) = params.dest.as_deref() {
+            if dest != "s5" && dest != "ipfs" {
+                let reply = warp::reply::json(&json!({
+                    "status_code": 400,
+                    "message": format!("Unknown storage network {}, expected s5 or ipfs", dest),
+                }));
+                return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
             }
         }
 
-        let response = transcode::TranscodeResponse {
-            status_code: 200,
-            message: "Transcoding task queued".to_string(),
-            task_id: task_id.to_string(),
+        let Ok(_guard) = SELFTEST_LOCK.try_lock() else {
+            let reply = warp::reply::json(&json!({
+                "status_code": 409,
+                "message": "A self-test is already running",
+            }));
+            return Ok(warp::reply::with_status(reply, StatusCode::CONFLICT).into_response());
         };
 
-        Ok(warp::reply::json(&TranscodeResponseWrapper::from(response)))
+        let report = run_selftest(params.dest, params.encrypt).await;
+        let status = if report.success {
+            StatusCode::OK
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        let reply = warp::reply::json(&json!({
+            "status_code": status.as_u16(),
+            "success": report.success,
+            "steps": report.steps,
+            "total_millis": report.total_millis,
+        }));
+        Ok(warp::reply::with_status(reply, status).into_response())
     }
-}
 
-#[derive(Debug, Serialize)]
-struct GetTranscodedResponseWrapper {
-    status_code: i32,
-    metadata: String,
-    progress: i32,
-}
+    async fn delete_transcoded(
+        &self,
+        task_id: String,
+        params: DeleteTranscodedParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let message = delete_transcoded_result(&task_id, params.delete_uploads)
+            .await
+            .ok_or_else(warp::reject::not_found)?;
 
-impl From<transcode::GetTranscodedResponse> for GetTranscodedResponseWrapper {
-    fn from(response: transcode::GetTranscodedResponse) -> Self {
-        GetTranscodedResponseWrapper {
-            status_code: response.status_code,
-            metadata: response.metadata,
-            progress: response.progress,
-        }
+        Ok(warp::reply::json(&json!({
+            "status_code": 200,
+            "message": message,
+        })))
     }
 }
 
-impl RestHandler {
-    async fn get_transcoded(&self, task_id: String) -> Result<impl warp::Reply, warp::Rejection> {
-        // Retrieve the metadata and the progress for the given task ID.
-        let transcoded = TRANSCODED.lock().await;
-        let metadata = transcoded
-            .get(&task_id)
-            .cloned()
-            .ok_or_else(|| warp::reject::not_found())?;
+// Most tasks returned in one page of `GET /tasks`.
+const MAX_TASKS_PAGE_SIZE: usize = 1000;
 
-        let progress = shared::calculate_overall_progress(&task_id);
+// Query parameters of `GET /tasks`.
+#[derive(Deserialize)]
+struct ListTasksParams {
+    status: Option<TaskStatus>,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_tasks_page_size")]
+    limit: usize,
+}
 
-        // Construct the response including the progress
-        let response = GetTranscodedResponseWrapper {
-            status_code: 200,
-            metadata,
-            progress,
-        };
+fn default_tasks_page_size() -> usize {
+    100
+}
 
-        Ok(warp::reply::json(&response))
-    }
+// Query parameters of `POST /selftest`.
+#[derive(Deserialize)]
+struct SelfTestParams {
+    dest: Option<String>,
+    #[serde(default)]
+    encrypt: bool,
 }
 
-async fn check_transcoded_file_exists(cid: &str, label: &str, ext: &str) -> bool {
-    let filename = format!("{}{}_{}.{}", *PATH_TO_TRANSCODED_FILE, cid, label, ext); // Adjust the path and format as needed.
-    Path::new(&filename).exists()
+// Query parameters of `DELETE /transcoded/{task_id}`.
+#[derive(Deserialize)]
+struct DeleteTranscodedParams {
+    #[serde(default)]
+    delete_uploads: bool,
 }
 
 fn garbage_collect(directory: &str, size_threshold: u64) {
@@ -675,6 +1764,97 @@ struct QueryParams {
     media_formats: String,
     is_encrypted: bool,
     is_gpu: bool,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    priority: String,
+    #[serde(default)]
+    expected_hash: String,
+}
+
+// Define a struct to receive the JSON body of a `POST /mirror` request.
+#[derive(Deserialize)]
+struct MirrorBody {
+    cid: String,
+    dest: String,
+}
+
+// Maximum size of the JSON body accepted by `POST /mirror`.
+const MAX_MIRROR_BODY_BYTES: u64 = 16 * 1024;
+
+// Maximum size of the JSON body accepted by `POST /transcode`.
+const MAX_TRANSCODE_BODY_BYTES: u64 = 1024 * 1024;
+
+// Define a struct to receive the JSON body of a `POST /transcode` request. Unlike the query
+// parameters, `media_formats` may be given directly as a JSON array rather than as a string.
+#[derive(Deserialize)]
+struct TranscodeBody {
+    source_cid: String,
+    #[serde(default)]
+    media_formats: Value,
+    #[serde(default)]
+    is_encrypted: bool,
+    #[serde(default)]
+    is_gpu: bool,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    priority: String,
+    #[serde(default)]
+    expected_hash: String,
+}
+
+impl TranscodeBody {
+    /// Validates the body and converts it into the same parameters the query string carries.
+    fn into_params(self) -> Result<QueryParams, String> {
+        if self.source_cid.trim().is_empty() {
+            return Err(String::from("source_cid must not be empty"));
+        }
+
+        let media_formats = match self.media_formats {
+            Value::Null => String::new(),
+            Value::String(media_formats) => media_formats,
+            media_formats @ Value::Array(_) => media_formats.to_string(),
+            _ => return Err(String::from("media_formats must be an array")),
+        };
+
+        if !media_formats.is_empty() {
+            serde_json::from_str::<Vec<Value>>(&media_formats)
+                .map_err(|e| format!("media_formats must be an array: {}", e))?;
+        }
+
+        Ok(QueryParams {
+            source_cid: self.source_cid,
+            media_formats,
+            is_encrypted: self.is_encrypted,
+            is_gpu: self.is_gpu,
+            force: self.force,
+            priority: self.priority,
+            expected_hash: self.expected_hash,
+        })
+    }
+}
+
+/// Converts a number of seconds from the `.env` file to a `Duration`, where 0 means none.
+fn optional_secs(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|_| secs > 0)
+}
+
+/// Parses an address for a server to bind to, exiting with a message naming the setting if it
+/// is not a valid `host:port`.
+///
+/// # Arguments
+/// * `name` - The name of the environment variable the address was read from.
+/// * `addr` - The address to parse.
+///
+fn parse_bind_addr(name: &str, addr: &str) -> SocketAddr {
+    addr.parse().unwrap_or_else(|e| {
+        eprintln!(
+            "{} '{}' is not a valid address, expected host:port such as 0.0.0.0:8000: {}",
+            name, addr, e
+        );
+        std::process::exit(1);
+    })
 }
 
 /// The main entry point for the transcode server. Initializes the server
@@ -686,64 +1866,225 @@ struct QueryParams {
 async fn main() {
     dotenv().ok();
 
-    // Create a channel for transcoding tasks
-    let (task_sender, task_receiver) = mpsc::channel::<(String, String, String, bool, bool)>(100);
-    let task_receiver = Arc::new(Mutex::new(task_receiver));
+    // Fail at startup, rather than part way through a job, if a configured directory is unusable
+    for (name, dir) in [
+        ("PATH_TO_FILE", PATH_TO_FILE.as_str()),
+        ("PATH_TO_TRANSCODED_FILE", PATH_TO_TRANSCODED_FILE.as_str()),
+        ("TEMP_DIR", TEMP_DIR.as_str()),
+    ] {
+        if let Err(e) = ensure_writable_dir(dir) {
+            eprintln!(
+                "{} directory '{}' is missing and could not be created, or is not writable: {}",
+                name, dir, e
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // Restore the results of the tasks that finished before the last restart
+    let persisted = load_transcoded_state();
+    if !persisted.is_empty() {
+        println!(
+            "Loaded {} transcoded results from {}",
+            persisted.len(),
+            STATE_DIR.as_deref().unwrap_or_default()
+        );
+    }
+    *TRANSCODED.lock().await = persisted;
+
+    // Read a token file at startup, so that a missing or unreadable secret is reported
+    // immediately rather than on the first upload
+    if token_file().is_some() {
+        if let Err(e) = portal_token() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Parse CID_HASH_ALGORITHM now, so that an invalid value stops the server before it serves
+    // traffic rather than failing every encrypted job part way through
+    println!(
+        "Using {:?} CIDs for encrypted renditions",
+        Lazy::force(&CID_HASH_ALGORITHM)
+    );
 
-    // Start the transcoding task receiver
-    let receiver_clone = Arc::clone(&task_receiver);
-    tokio::spawn(transcode_task_receiver(receiver_clone));
+    // Probe the ffmpeg and ffprobe versions once, rather than on every /version request
+    match FFMPEG_VERSION.as_deref() {
+        Some(version) => println!("Using {}", version),
+        None => eprintln!("Failed to run ffmpeg -version, is ffmpeg installed?"),
+    }
+    match FFPROBE_VERSION.as_deref() {
+        Some(version) => println!("Using {}", version),
+        None => eprintln!(
+            "Failed to run ffprobe -version, is ffprobe installed? Sources will be transcoded without being probed, so their progress will be unknown"
+        ),
+    }
+
+    // Detect the capabilities once, so that serving them is cheap
+    println!(
+        "Found {} ffmpeg encoders, {} of them hardware encoders, GPU: {}",
+        CAPABILITIES.encoders.len(),
+        CAPABILITIES.hardware_encoders.len(),
+        CAPABILITIES.gpu
+    );
 
-    // Wrap task_sender in an Arc<Mutex<>> before passing it to handlers
-    let task_sender = Arc::new(Mutex::new(task_sender));
+    // Validate the bind addresses before starting anything
+    let grpc_addr = parse_bind_addr("GRPC_ADDR", GRPC_ADDR.as_str());
+    let rest_addr = parse_bind_addr("REST_ADDR", REST_ADDR.as_str());
 
-    // Create a gRPC server
-    let grpc_addr = "0.0.0.0:50051"
-        .parse()
-        .expect("Invalid gRPC server address");
+    // Create a priority queue for transcoding tasks
+    let task_queue = Arc::new(TaskQueue::new());
+
+    // Start the transcoding task receivers
+    println!("Starting {} transcoding workers", *TRANSCODE_WORKERS);
+    for worker in 0..*TRANSCODE_WORKERS {
+        tokio::spawn(transcode_task_receiver(worker, Arc::clone(&task_queue)));
+    }
 
+    // Create a gRPC server
     let transcode_service_handler = TranscodeServiceHandler {
-        transcode_task_sender: Some(task_sender.clone()),
+        task_queue: Some(task_queue.clone()),
     };
-    let transcode_service_server = TranscodeServiceServer::new(transcode_service_handler);
-    let grpc_server = Server::builder()
+    let transcode_service_server =
+        TranscodeServiceServer::with_interceptor(transcode_service_handler, rate_limit_interceptor);
+    // Keep idle connections, e.g. of clients waiting on a long job, from being dropped
+    let mut grpc_builder = Server::builder()
+        .tcp_keepalive(optional_secs(*TCP_KEEPALIVE_SECS))
+        .http2_keepalive_interval(optional_secs(*GRPC_KEEPALIVE_INTERVAL_SECS))
+        .http2_keepalive_timeout(optional_secs(*GRPC_KEEPALIVE_TIMEOUT_SECS));
+    if let Some(timeout) = optional_secs(*GRPC_REQUEST_TIMEOUT_SECS) {
+        grpc_builder = grpc_builder.timeout(timeout);
+    }
+    let grpc_server = grpc_builder
         .add_service(transcode_service_server)
         .serve(grpc_addr);
 
     // Create a REST server
     let rest_handler = RestHandler {
-        transcode_task_sender: Some(task_sender.clone()),
+        task_queue: Some(task_queue.clone()),
     };
 
     let rest_handler_transcode = RestHandler {
-        transcode_task_sender: Some(task_sender.clone()),
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_transcode_body = RestHandler {
+        task_queue: Some(task_queue.clone()),
     };
 
     let rest_handler_get_transcoded = RestHandler {
-        transcode_task_sender: Some(task_sender.clone()),
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_get_rendition = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_stream_logs = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_progress = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_list_tasks = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_pause = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_resume = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_cancel = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_version = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_delete_transcoded = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_capabilities = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_mirror = RestHandler {
+        task_queue: Some(task_queue.clone()),
+    };
+
+    let rest_handler_selftest = RestHandler {
+        task_queue: Some(task_queue.clone()),
     };
 
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["POST", "GET"])
+        .allow_methods(vec!["POST", "GET", "DELETE"])
         .allow_headers(vec!["Content-Type"]);
 
     // Modify the transcode endpoint to use warp::query().
-    let transcode = warp::path!("transcode")
+    let transcode_query = warp::path!("transcode")
         .and(warp::query::<QueryParams>())
+        .and(with_rate_limit())
         .and_then(move |params: QueryParams| {
             let rest_handler = rest_handler_transcode.clone();
             async move {
+                let priority = Priority::parse(&params.priority)
+                    .map_err(|e| warp::reject::custom(InvalidTranscodeBody(e)))?;
                 rest_handler
                     .transcode(
                         params.source_cid,
                         params.media_formats,
                         params.is_encrypted,
                         params.is_gpu,
+                        params.force,
+                        params.expected_hash,
+                        priority,
                     )
                     .await
             }
+        });
+
+    // The same endpoint taking its parameters from a JSON body, so that large media_formats
+    // don't have to be URL-encoded into the query string.
+    let transcode_body = warp::post()
+        .and(warp::path!("transcode"))
+        .and(warp::body::content_length_limit(MAX_TRANSCODE_BODY_BYTES))
+        .and(warp::body::json::<TranscodeBody>())
+        .and_then(|body: TranscodeBody| async move {
+            body.into_params()
+                .map_err(|e| warp::reject::custom(InvalidTranscodeBody(e)))
         })
+        .and(with_rate_limit())
+        .and_then(move |params: QueryParams| {
+            let rest_handler = rest_handler_transcode_body.clone();
+            async move {
+                let priority = Priority::parse(&params.priority)
+                    .map_err(|e| warp::reject::custom(InvalidTranscodeBody(e)))?;
+                rest_handler
+                    .transcode(
+                        params.source_cid,
+                        params.media_formats,
+                        params.is_encrypted,
+                        params.is_gpu,
+                        params.force,
+                        params.expected_hash,
+                        priority,
+                    )
+                    .await
+            }
+        });
+
+    let transcode = transcode_body
+        .or(transcode_query)
+        .recover(handle_transcode_rejection)
         .with(cors.clone())
         .boxed();
 
@@ -755,8 +2096,154 @@ async fn main() {
         .with(cors.clone())
         .boxed();
 
-    let routes = transcode.or(get_transcoded);
-    let rest_server = warp::serve(routes).run(([0, 0, 0, 0], 8000));
+    let get_rendition = warp::get()
+        .and(warp::path!("transcoded" / String / u32))
+        .and_then(move |task_id, format_id| {
+            let rest_handler = rest_handler_get_rendition.clone();
+            async move { rest_handler.get_rendition(task_id, format_id).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let stream_logs = warp::get()
+        .and(warp::path!("logs" / String / "stream"))
+        .and_then(move |task_id| {
+            let rest_handler = rest_handler_stream_logs.clone();
+            async move { rest_handler.stream_logs(task_id).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let progress = warp::get()
+        .and(warp::path!("progress" / String))
+        .and_then(move |task_id| {
+            let rest_handler = rest_handler_progress.clone();
+            async move { rest_handler.progress(task_id).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let list_tasks = warp::get()
+        .and(warp::path!("tasks"))
+        .and(warp::query::<ListTasksParams>())
+        .and_then(move |params| {
+            let rest_handler = rest_handler_list_tasks.clone();
+            async move { rest_handler.list_tasks(params).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let version = warp::get()
+        .and(warp::path!("version"))
+        .and_then(move || {
+            let rest_handler = rest_handler_version.clone();
+            async move { rest_handler.version().await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let capabilities = warp::get()
+        .and(warp::path!("capabilities"))
+        .and_then(move || {
+            let rest_handler = rest_handler_capabilities.clone();
+            async move { rest_handler.capabilities().await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let mirror = warp::post()
+        .and(warp::path!("mirror"))
+        .and(warp::body::content_length_limit(MAX_MIRROR_BODY_BYTES))
+        .and(warp::body::json::<MirrorBody>())
+        .and(with_rate_limit())
+        .and_then(move |body| {
+            let rest_handler = rest_handler_mirror.clone();
+            async move { rest_handler.mirror(body).await }
+        })
+        .recover(handle_transcode_rejection)
+        .with(cors.clone())
+        .boxed();
+
+    let selftest = warp::post()
+        .and(warp::path!("selftest"))
+        .and(warp::query::<SelfTestParams>())
+        .and(with_rate_limit())
+        .and_then(move |params| {
+            let rest_handler = rest_handler_selftest.clone();
+            async move { rest_handler.selftest(params).await }
+        })
+        .recover(handle_transcode_rejection)
+        .with(cors.clone())
+        .boxed();
+
+    let cancel = warp::post()
+        .and(warp::path!("cancel" / String))
+        .and_then(move |task_id| {
+            let rest_handler = rest_handler_cancel.clone();
+            async move { rest_handler.cancel(task_id).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let pause = warp::post()
+        .and(warp::path!("pause" / String))
+        .and_then(move |task_id| {
+            let rest_handler = rest_handler_pause.clone();
+            async move { rest_handler.pause(task_id, true).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let resume = warp::post()
+        .and(warp::path!("resume" / String))
+        .and_then(move |task_id| {
+            let rest_handler = rest_handler_resume.clone();
+            async move { rest_handler.pause(task_id, false).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let delete_transcoded = warp::delete()
+        .and(warp::path!("transcoded" / String))
+        .and(warp::query::<DeleteTranscodedParams>())
+        .and_then(move |task_id, params| {
+            let rest_handler = rest_handler_delete_transcoded.clone();
+            async move { rest_handler.delete_transcoded(task_id, params).await }
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let routes = transcode
+        .or(get_transcoded)
+        .or(get_rendition)
+        .or(stream_logs)
+        .or(progress)
+        .or(list_tasks)
+        .or(version)
+        .or(capabilities)
+        .or(mirror)
+        .or(selftest)
+        .or(cancel)
+        .or(pause)
+        .or(resume)
+        .or(delete_transcoded);
+    // Served with hyper directly, as `warp::serve` doesn't expose TCP keepalive. warp then
+    // doesn't know the client's address, so it is passed to the routes as `RemoteAddr`
+    let rest_service = warp::service(routes);
+    let make_rest_service = hyper::service::make_service_fn(move |conn: &AddrStream| {
+        let remote_addr = RemoteAddr(conn.remote_addr());
+        let rest_service = rest_service.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |mut request| {
+                request.extensions_mut().insert(remote_addr);
+                rest_service.clone().call(request)
+            }))
+        }
+    });
+    let rest_server = hyper::Server::bind(&rest_addr)
+        .tcp_keepalive(optional_secs(*TCP_KEEPALIVE_SECS))
+        .http1_keepalive(true)
+        .serve(make_rest_service);
 
     let garbage_collection_secs = match GARBAGE_COLLECTOR_INTERVAL.parse::<u64>() {
         Ok(value) => value,
@@ -782,6 +2269,9 @@ async fn main() {
             };
 
             garbage_collect(PATH_TO_FILE.as_str(), threshold);
+            if TEMP_DIR.as_str() != PATH_TO_FILE.as_str() {
+                garbage_collect(TEMP_DIR.as_str(), threshold);
+            }
 
             let transcoded_threshold = match TRANSCODED_FILE_SIZE_THRESHOLD.parse::<u64>() {
                 Ok(value) => value,
@@ -804,7 +2294,49 @@ async fn main() {
         Err(e) => eprintln!("gRPC server error: {}", e),
     }
     match rest_server.await {
-        Ok(_) => println!("REST server shut down gracefully."),
+        Ok(Ok(())) => println!("REST server shut down gracefully."),
+        Ok(Err(e)) => eprintln!("REST server error: {}", e),
         Err(e) => eprintln!("REST server error: {}", e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_from(addr: &str) -> warp::test::RequestBuilder {
+        warp::test::request().extension(RemoteAddr(addr.parse().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_clients_are_rate_limited_by_ip() {
+        static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(|| RateLimiter::new(1));
+        let route = with_rate_limiter(&RATE_LIMITER).map(warp::reply);
+
+        let first = request_from("192.0.2.1:40000").filter(&route).await;
+        let again = request_from("192.0.2.1:40001").filter(&route).await;
+        let other = request_from("192.0.2.2:40000").filter(&route).await;
+
+        assert!(first.is_ok());
+        assert!(matches!(again, Err(rejection) if rejection.find::<RateLimited>().is_some()));
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn remote_addr_prefers_the_address_warp_knows() {
+        let inserted = request_from("192.0.2.1:40000")
+            .filter(&remote_addr())
+            .await
+            .unwrap();
+        let known = request_from("192.0.2.1:40000")
+            .remote_addr("198.51.100.1:50000".parse().unwrap())
+            .filter(&remote_addr())
+            .await
+            .unwrap();
+        let unknown = warp::test::request().filter(&remote_addr()).await.unwrap();
+
+        assert_eq!(inserted, Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(known, Some("198.51.100.1:50000".parse().unwrap()));
+        assert_eq!(unknown, None);
+    }
+}
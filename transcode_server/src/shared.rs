@@ -1,21 +1,557 @@
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
-use std::sync::Mutex;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, OwnedMutexGuard, RwLock};
+
+/// The phases a format of a task goes through, in order. Formats that aren't encrypted skip
+/// the `Encrypt` phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Download,
+    Transcode,
+    Encrypt,
+    Upload,
+    Done,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [
+        Phase::Download,
+        Phase::Transcode,
+        Phase::Encrypt,
+        Phase::Upload,
+        Phase::Done,
+    ];
+
+    /// The share of a format's progress, out of 100, that the phase accounts for.
+    fn weight(self) -> i32 {
+        match self {
+            Phase::Download => 10,
+            Phase::Transcode => 70,
+            Phase::Encrypt => 5,
+            Phase::Upload => 15,
+            Phase::Done => 0,
+        }
+    }
+
+    /// The progress of a format at the start of the phase, i.e. with every earlier phase done.
+    fn start(self) -> i32 {
+        Phase::ALL
+            .iter()
+            .take_while(|&&phase| phase != self)
+            .map(|phase| phase.weight())
+            .sum()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Download => "download",
+            Phase::Transcode => "transcode",
+            Phase::Encrypt => "encrypt",
+            Phase::Upload => "upload",
+            Phase::Done => "done",
+        }
+    }
+}
+
+/// The status of a task, as listed by `GET /tasks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Waiting in the queue, including while waiting to be retried.
+    Queued,
+    Running,
+    /// Running, with its ffmpeg process suspended until the task is resumed.
+    Paused,
+    Done,
+    /// Failed as a whole, after any retries.
+    Failed,
+    Cancelled,
+}
+
+/// A task known to the transcoder, from when it is queued until its result is deleted.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskRecord {
+    pub task_id: String,
+    pub source_cid: String,
+    pub status: TaskStatus,
+    /// When the task was queued, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+// HashMap<task_id, record of the task>, so that every task can be listed without knowing its id
+static TASKS: Lazy<Mutex<HashMap<String, TaskRecord>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Registers a task as queued when it is created.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `source_cid` - The source of the task.
+///
+pub fn register_task(task_id: &str, source_cid: &str) {
+    let created_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+
+    TASKS.lock().unwrap().insert(
+        task_id.to_string(),
+        TaskRecord {
+            task_id: task_id.to_string(),
+            source_cid: source_cid.to_string(),
+            status: TaskStatus::Queued,
+            created_at,
+        },
+    );
+}
+
+/// Updates the status of a registered task.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `status` - The new status of the task.
+///
+pub fn set_task_status(task_id: &str, status: TaskStatus) {
+    if let Some(task) = TASKS.lock().unwrap().get_mut(task_id) {
+        task.status = status;
+    }
+}
+
+/// Returns the status of a registered task, `None` if the task is unknown.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn get_task_status(task_id: &str) -> Option<TaskStatus> {
+    TASKS.lock().unwrap().get(task_id).map(|task| task.status)
+}
+
+/// Returns every registered task, newest first, optionally only those with a given status.
+///
+/// # Arguments
+/// * `status` - The status to filter by, or `None` for all tasks.
+///
+pub fn list_tasks(status: Option<TaskStatus>) -> Vec<TaskRecord> {
+    let mut tasks: Vec<TaskRecord> = TASKS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|task| status.is_none_or(|status| task.status == status))
+        .cloned()
+        .collect();
+    tasks.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| a.task_id.cmp(&b.task_id))
+    });
+    tasks
+}
+
+/// Removes a task from the registry once its result has been deleted.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn remove_task(task_id: &str) {
+    TASKS.lock().unwrap().remove(task_id);
+}
+
+/// Whether a format was encoded on a GPU or on the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoder {
+    Gpu,
+    Cpu,
+    /// The source already satisfies the format, so its video (or, for audio-only formats, its
+    /// audio) is stream-copied rather than encoded.
+    Copy,
+}
+
+impl Encoder {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoder::Gpu => "gpu",
+            Encoder::Cpu => "cpu",
+            Encoder::Copy => "copy",
+        }
+    }
+}
+
+/// The progress of a single format of a task, identified by the format's `id` and `ext` so
+/// that clients don't have to rely on its position in the requested media formats.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FormatProgress {
+    pub format_id: Option<u32>,
+    pub ext: Option<String>,
+    /// Progress percentage through all phases, `None` until the task starts.
+    pub percent: Option<i32>,
+    /// The phase the format is in, `None` until the task starts.
+    pub phase: Option<Phase>,
+    /// Whether the format is encoded on a GPU or the CPU, `None` until it starts transcoding.
+    pub encoder: Option<Encoder>,
+    /// Why the format failed, e.g. the end of ffmpeg's log, `None` unless it failed.
+    pub error: Option<String>,
+}
 
 // HashMap<task_id, Vec<progress for each format>>
-pub static PROGRESS_MAP: Lazy<Mutex<HashMap<String, Vec<Option<i32>>>>> =
+pub static PROGRESS_MAP: Lazy<Mutex<HashMap<String, Vec<FormatProgress>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// HashMap<task_id, media format objects of the renditions completed so far>, so that a
+// rendition can be retrieved as soon as it is uploaded rather than once the whole task is done
+pub static RENDITIONS: Lazy<Mutex<HashMap<String, Vec<Value>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Ids of tasks that have been queued and have not yet finished processing
+pub static PENDING_TASKS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Ids of pending tasks that have been cancelled. Only pending tasks can be cancelled, and
+// entries are removed once the task finishes, so the set stays bounded by the queue length.
+pub static CANCELLED_TASKS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// HashMap<task_id, token set when the task is cancelled>, so that its uploads stop between chunks
+static UPLOAD_CANCELLATIONS: Lazy<Mutex<HashMap<String, Arc<AtomicBool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// HashMap<task_id, number of times the task has been started>, counting retries of failed tasks
+static TASK_ATTEMPTS: Lazy<Mutex<HashMap<String, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Records that a task is starting another attempt, clearing the format errors of any previous
+/// attempt.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+/// # Returns
+/// The number of the attempt, starting from 1.
+///
+pub fn start_attempt(task_id: &str) -> u32 {
+    if let Some(progress_list) = PROGRESS_MAP.lock().unwrap().get_mut(task_id) {
+        for progress in progress_list.iter_mut() {
+            progress.error = None;
+        }
+    }
+
+    let mut task_attempts = TASK_ATTEMPTS.lock().unwrap();
+    let attempts = task_attempts.entry(task_id.to_string()).or_insert(0);
+    *attempts += 1;
+    *attempts
+}
+
+/// Returns the number of times a task has been started, 0 if it hasn't started yet.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn get_attempts(task_id: &str) -> u32 {
+    TASK_ATTEMPTS
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .copied()
+        .unwrap_or_default()
+}
+
+// Lines of ffmpeg's log kept for a client that falls behind before older lines are dropped
+const LOG_CHANNEL_CAPACITY: usize = 1024;
+
+// HashMap<task_id, channel broadcasting the lines of ffmpeg's log>, for tasks being followed
+static LOG_CHANNELS: Lazy<Mutex<HashMap<String, broadcast::Sender<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Subscribes to the lines of ffmpeg's log for a task as they are produced. The channel is
+/// closed when the task finishes.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn subscribe_log(task_id: &str) -> broadcast::Receiver<String> {
+    LOG_CHANNELS
+        .lock()
+        .unwrap()
+        .entry(task_id.to_string())
+        .or_insert_with(|| broadcast::channel(LOG_CHANNEL_CAPACITY).0)
+        .subscribe()
+}
+
+/// Sends a line of ffmpeg's log to the clients following a task, if any.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `line` - The line of ffmpeg's log.
+///
+pub fn publish_log(task_id: &str, line: &str) {
+    if let Some(sender) = LOG_CHANNELS.lock().unwrap().get(task_id) {
+        // Fails only if every client has disconnected
+        let _ = sender.send(line.to_string());
+    }
+}
+
+// HashMap<task_id, process id of the ffmpeg process the task is running>
+static FFMPEG_PROCESSES: Lazy<Mutex<HashMap<String, u32>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// Updates the transcoding progress for a specific format of a given task in a global progress map.
-/// If the task or format index does not exist, they are created. Progress is stored as a percentage.
+// Ids of running tasks that have been paused. Held while a task's ffmpeg process is signalled,
+// so that a process can't start or be resumed between a task being paused and its process
+// being stopped.
+static PAUSED_TASKS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Sends a signal, e.g. `STOP` or `CONT`, to a process with `kill`.
+fn signal_process(pid: u32, signal: &str) -> Result<(), String> {
+    let status = std::process::Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .status()
+        .map_err(|e| format!("Failed to execute kill: {}", e))?;
+
+    if !status.success() {
+        return Err(format!("Failed to send SIG{} to process {}", signal, pid));
+    }
+    Ok(())
+}
+
+/// Records the ffmpeg process a task is running, so that the task can be paused. The process is
+/// stopped straight away if the task was paused before it started.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `pid` - The process id of the ffmpeg process.
+///
+pub fn register_process(task_id: &str, pid: u32) {
+    let paused_tasks = PAUSED_TASKS.lock().unwrap();
+    FFMPEG_PROCESSES
+        .lock()
+        .unwrap()
+        .insert(task_id.to_string(), pid);
+
+    if paused_tasks.contains(task_id) {
+        if let Err(e) = signal_process(pid, "STOP") {
+            eprintln!("Task {}: {}", task_id, e);
+        }
+    }
+}
+
+/// Forgets the ffmpeg process of a task once it has exited.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn unregister_process(task_id: &str) {
+    FFMPEG_PROCESSES.lock().unwrap().remove(task_id);
+}
+
+/// Pauses a running task by suspending its ffmpeg process with `SIGSTOP`. If the task isn't
+/// running ffmpeg at the moment, e.g. while it uploads a rendition, its next ffmpeg process is
+/// suspended as soon as it starts.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+/// # Returns
+/// `Ok(())` if the task is now paused, or an error if it isn't running or can't be suspended.
+///
+pub fn pause_task(task_id: &str) -> Result<(), String> {
+    let mut paused_tasks = PAUSED_TASKS.lock().unwrap();
+    if get_task_status(task_id) != Some(TaskStatus::Running) {
+        return Err(format!("Task {} is not running", task_id));
+    }
+
+    if let Some(&pid) = FFMPEG_PROCESSES.lock().unwrap().get(task_id) {
+        signal_process(pid, "STOP")?;
+    }
+    paused_tasks.insert(task_id.to_string());
+    set_task_status(task_id, TaskStatus::Paused);
+    Ok(())
+}
+
+/// Resumes a paused task by continuing its ffmpeg process with `SIGCONT`.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+/// # Returns
+/// `Ok(())` if the task is running again, or an error if it isn't paused or can't be continued.
+///
+pub fn resume_task(task_id: &str) -> Result<(), String> {
+    let mut paused_tasks = PAUSED_TASKS.lock().unwrap();
+    if !paused_tasks.contains(task_id) {
+        return Err(format!("Task {} is not paused", task_id));
+    }
+
+    if let Some(&pid) = FFMPEG_PROCESSES.lock().unwrap().get(task_id) {
+        signal_process(pid, "CONT")?;
+    }
+    paused_tasks.remove(task_id);
+    set_task_status(task_id, TaskStatus::Running);
+    Ok(())
+}
+
+// HashMap<source CID, lock held while a task processes that source>. Tasks for the same source
+// download it to, and transcode it to, the same files, so they must not run at the same time.
+static SOURCE_LOCKS: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Waits until no other task is processing a source, then holds it until the returned guard is
+/// dropped. Tasks for different sources run in parallel.
+///
+/// # Arguments
+/// * `source_cid` - The CID of the source.
+///
+pub async fn lock_source(source_cid: &str) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut source_locks = SOURCE_LOCKS.lock().unwrap();
+        // Forget the locks no task holds or waits for
+        source_locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        source_locks
+            .entry(source_cid.to_string())
+            .or_default()
+            .clone()
+    };
+
+    lock.lock_owned().await
+}
+
+/// Registers a task as pending when it is queued, so that it can be cancelled.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn add_pending_task(task_id: &str) {
+    PENDING_TASKS.lock().unwrap().insert(task_id.to_string());
+}
+
+/// Returns whether a task has been queued and has not yet finished processing.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn is_pending(task_id: &str) -> bool {
+    PENDING_TASKS.lock().unwrap().contains(task_id)
+}
+
+/// Marks a pending task as cancelled. A queued task is then skipped when dequeued, and a
+/// running task stops before transcoding its next format or uploading the next chunk of a
+/// rendition to S5.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+/// # Returns
+/// `true` if the task was pending and is now cancelled, `false` if the task is unknown or has
+/// already finished.
+///
+pub fn cancel_task(task_id: &str) -> bool {
+    if !PENDING_TASKS.lock().unwrap().contains(task_id) {
+        return false;
+    }
+
+    CANCELLED_TASKS.lock().unwrap().insert(task_id.to_string());
+    if let Some(token) = UPLOAD_CANCELLATIONS.lock().unwrap().get(task_id) {
+        token.store(true, Ordering::SeqCst);
+    }
+    // A paused task must run again to reach the point where it stops
+    if is_paused(task_id) {
+        if let Err(e) = resume_task(task_id) {
+            eprintln!("Failed to resume cancelled task {}: {}", task_id, e);
+        }
+    }
+    true
+}
+
+/// Returns whether a task has been paused.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn is_paused(task_id: &str) -> bool {
+    PAUSED_TASKS.lock().unwrap().contains(task_id)
+}
+
+/// Returns the token that is set when a task is cancelled, for stopping its uploads between
+/// chunks. The token is already set if the task has been cancelled.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn upload_cancellation(task_id: &str) -> Arc<AtomicBool> {
+    // Locked while the token is created, so that a concurrent cancel_task either sees the token
+    // or has already recorded the cancellation it is created with
+    let mut cancellations = UPLOAD_CANCELLATIONS.lock().unwrap();
+    cancellations
+        .entry(task_id.to_string())
+        .or_insert_with(|| Arc::new(AtomicBool::new(is_cancelled(task_id))))
+        .clone()
+}
+
+/// Returns whether a task has been cancelled.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn is_cancelled(task_id: &str) -> bool {
+    CANCELLED_TASKS.lock().unwrap().contains(task_id)
+}
+
+/// Removes a task from the pending, cancelled and paused sets, its completed renditions and its
+/// log channel, once it has finished processing and its result has been recorded.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn finish_task(task_id: &str) {
+    PENDING_TASKS.lock().unwrap().remove(task_id);
+    CANCELLED_TASKS.lock().unwrap().remove(task_id);
+    UPLOAD_CANCELLATIONS.lock().unwrap().remove(task_id);
+    PAUSED_TASKS.lock().unwrap().remove(task_id);
+    RENDITIONS.lock().unwrap().remove(task_id);
+    LOG_CHANNELS.lock().unwrap().remove(task_id);
+}
+
+/// Records a rendition of a task as soon as it has been transcoded and uploaded.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `rendition` - The media format object of the rendition, with its `cid`.
+///
+pub fn record_rendition(task_id: &str, rendition: Value) {
+    RENDITIONS
+        .lock()
+        .unwrap()
+        .entry(task_id.to_string())
+        .or_default()
+        .push(rendition);
+}
+
+/// Returns the renditions of a task completed so far, in the order they were completed.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn get_renditions(task_id: &str) -> Vec<Value> {
+    RENDITIONS
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Updates the progress for a specific format of a given task in a global progress map.
+/// If the task or format index does not exist, they are created. Progress is stored as a
+/// percentage through all phases, weighting each phase by how long it typically takes, so it
+/// keeps advancing after ffmpeg finishes rather than waiting at 100% for the upload.
 ///
 /// # Arguments
 /// * `task_id` - Identifier for the transcoding task.
 /// * `format_index` - Index of the format being transcoded.
-/// * `progress` - Progress percentage of the transcoding task for the specified format.
+/// * `phase` - The phase the format is in.
+/// * `phase_progress` - Progress percentage through the phase.
 ///
-pub fn update_progress(task_id: &str, format_index: usize, progress: i32) {
+pub fn update_progress(task_id: &str, format_index: usize, phase: Phase, phase_progress: i32) {
     let mut progress_map = PROGRESS_MAP.lock().unwrap();
     let progress_list = progress_map
         .entry(task_id.to_string())
@@ -23,11 +559,132 @@ pub fn update_progress(task_id: &str, format_index: usize, progress: i32) {
 
     // Ensure the vector is large enough to hold progress for all formats
     if progress_list.len() <= format_index {
-        progress_list.resize(format_index + 1, None);
+        progress_list.resize(format_index + 1, FormatProgress::default());
     }
 
     // Update the specific format's progress
-    progress_list[format_index] = Some(progress);
+    progress_list[format_index].percent =
+        Some(phase.start() + phase.weight() * phase_progress.clamp(0, 100) / 100);
+    progress_list[format_index].phase = Some(phase);
+}
+
+/// Records whether a format of a task is encoded on a GPU or the CPU.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `format_index` - Index of the format being transcoded.
+/// * `encoder` - The encoder used for the format.
+///
+pub fn set_encoder(task_id: &str, format_index: usize, encoder: Encoder) {
+    let mut progress_map = PROGRESS_MAP.lock().unwrap();
+    let progress_list = progress_map
+        .entry(task_id.to_string())
+        .or_insert_with(Vec::new);
+
+    if progress_list.len() <= format_index {
+        progress_list.resize(format_index + 1, FormatProgress::default());
+    }
+
+    progress_list[format_index].encoder = Some(encoder);
+}
+
+/// Records why a format of a task failed.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `format_index` - Index of the format that failed.
+/// * `error` - Why the format failed.
+///
+pub fn set_format_error(task_id: &str, format_index: usize, error: String) {
+    let mut progress_map = PROGRESS_MAP.lock().unwrap();
+    let progress_list = progress_map
+        .entry(task_id.to_string())
+        .or_insert_with(Vec::new);
+
+    if progress_list.len() <= format_index {
+        progress_list.resize(format_index + 1, FormatProgress::default());
+    }
+
+    progress_list[format_index].error = Some(error);
+}
+
+/// Updates the progress of every registered format of a task, for phases shared by all of
+/// them such as downloading the source.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `phase` - The phase every format is in.
+/// * `phase_progress` - Progress percentage through the phase.
+///
+pub fn update_task_progress(task_id: &str, phase: Phase, phase_progress: i32) {
+    let format_count = PROGRESS_MAP
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .map_or(0, |progress_list| progress_list.len());
+
+    for format_index in 0..format_count {
+        update_progress(task_id, format_index, phase, phase_progress);
+    }
+}
+
+/// Records the `id` and `ext` of each format of a task, in the order of the requested media
+/// formats, so that the progress of each format can be reported against them.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `formats` - The `(id, ext)` of each format, indexed by format index.
+///
+pub fn register_formats(task_id: &str, formats: Vec<(Option<u32>, Option<String>)>) {
+    let mut progress_map = PROGRESS_MAP.lock().unwrap();
+    let progress_list = progress_map
+        .entry(task_id.to_string())
+        .or_insert_with(Vec::new);
+
+    if progress_list.len() < formats.len() {
+        progress_list.resize(formats.len(), FormatProgress::default());
+    }
+
+    for (progress, (format_id, ext)) in progress_list.iter_mut().zip(formats) {
+        progress.format_id = format_id;
+        progress.ext = ext;
+    }
+}
+
+/// Returns the number of formats of a task that are done, and the number of formats it has, 0
+/// for both until its formats have been read.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn count_formats_done(task_id: &str) -> (usize, usize) {
+    PROGRESS_MAP
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .map(|progress_list| {
+            let done = progress_list
+                .iter()
+                .filter(|progress| progress.phase == Some(Phase::Done))
+                .count();
+            (done, progress_list.len())
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the progress of each format of a task, in the order of the requested media formats,
+/// or an empty list if the task ID is not found.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn get_format_progress(task_id: &str) -> Vec<FormatProgress> {
+    PROGRESS_MAP
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .cloned()
+        .unwrap_or_default()
 }
 
 /// Calculates the overall progress for a given task by averaging the progress values stored in
@@ -39,8 +696,8 @@ pub fn update_progress(task_id: &str, format_index: usize, progress: i32) {
 pub fn calculate_overall_progress(task_id: &str) -> i32 {
     let progress_map = PROGRESS_MAP.lock().unwrap();
     if let Some(progress_list) = progress_map.get(task_id) {
-        let sum: i32 = progress_list.iter().filter_map(|&p| p).sum();
-        let count: i32 = progress_list.iter().filter_map(|&p| p).count() as i32;
+        let sum: i32 = progress_list.iter().filter_map(|p| p.percent).sum();
+        let count: i32 = progress_list.iter().filter_map(|p| p.percent).count() as i32;
         if count > 0 {
             sum / count
         } else {
@@ -0,0 +1,243 @@
+use crate::s5::upload_video;
+use crate::shared::{self, Phase};
+use crate::transcode_video::TranscodeVideoResponse;
+use serde::Deserialize;
+use std::fs;
+use std::process::Command;
+use tonic::{Code, Status};
+
+/// Options for generating seek bar preview thumbnails: frames are extracted every `interval`
+/// seconds, scaled to `width` pixels wide and tiled `columns` x `rows` to a sprite sheet.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpriteOptions {
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_grid")]
+    pub columns: u32,
+    #[serde(default = "default_grid")]
+    pub rows: u32,
+}
+
+fn default_interval() -> f64 {
+    10.0
+}
+
+fn default_width() -> u32 {
+    160
+}
+
+fn default_grid() -> u32 {
+    10
+}
+
+/// Formats a time in seconds as a WebVTT timestamp, `HH:MM:SS.mmm`.
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        millis % 1000
+    )
+}
+
+/// Gets the `(width, height)` of an image or video using `ffprobe`.
+fn get_dimensions(file_path: &str) -> Result<(u32, u32), String> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height",
+            "-of",
+            "csv=s=x:p=0",
+            file_path,
+        ])
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from("Failed to retrieve dimensions"));
+    }
+
+    let dimensions = String::from_utf8_lossy(&output.stdout);
+    let mut parts = dimensions.trim().split('x');
+    match (parts.next(), parts.next()) {
+        (Some(width), Some(height)) => Ok((
+            width.parse().map_err(|_| "Invalid width".to_string())?,
+            height.parse().map_err(|_| "Invalid height".to_string())?,
+        )),
+        _ => Err(String::from("Failed to parse dimensions")),
+    }
+}
+
+/// Builds the WebVTT file mapping each thumbnail's time range to its region of a sprite sheet.
+///
+/// # Arguments
+/// * `sprite_urls` - The URLs of the sprite sheets, in order.
+/// * `options` - The sprite options the sheets were generated with.
+/// * `tile_height` - The height in pixels of a single thumbnail.
+/// * `total_duration` - The duration of the video in seconds.
+///
+fn build_vtt(
+    sprite_urls: &[String],
+    options: &SpriteOptions,
+    tile_height: u32,
+    total_duration: f64,
+) -> String {
+    let tiles_per_sheet = (options.columns * options.rows) as usize;
+    let tile_count = (total_duration / options.interval).ceil() as usize;
+
+    let mut vtt = String::from("WEBVTT\n\n");
+    for index in 0..tile_count.min(sprite_urls.len() * tiles_per_sheet) {
+        let start = index as f64 * options.interval;
+        let end = (start + options.interval).min(total_duration);
+        let position = (index % tiles_per_sheet) as u32;
+        let x = (position % options.columns) * options.width;
+        let y = (position / options.columns) * tile_height;
+
+        vtt.push_str(&format!(
+            "{} --> {}\n{}#xywh={},{},{},{}\n\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end),
+            sprite_urls[index / tiles_per_sheet],
+            x,
+            y,
+            options.width,
+            tile_height
+        ));
+    }
+
+    vtt
+}
+
+/// Generates seek bar preview thumbnails for a video: extracts a frame every
+/// `options.interval` seconds, tiles the frames into sprite sheets (as many as needed for the
+/// length of the video), writes a WebVTT file mapping timestamps to sprite regions, and uploads
+/// the sprite sheets and the WebVTT file.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index of the sprite format in the requested media formats.
+/// * `file_path` - The path to the input video file.
+/// * `output_prefix` - The path prefix for the generated sprite sheets and WebVTT file.
+/// * `options` - How to extract and tile the thumbnails.
+/// * `total_duration` - The duration of the video in seconds.
+/// * `dest` - The storage network to upload to.
+///
+/// # Returns
+/// A `TranscodeVideoResponse` whose `cid` is the WebVTT file's and whose `sprite_cids` are the
+/// sprite sheets', or a `Status` error on failure.
+///
+pub async fn generate_sprites(
+    task_id: &str,
+    format_index: usize,
+    file_path: &str,
+    output_prefix: &str,
+    options: &SpriteOptions,
+    total_duration: f64,
+    dest: Option<String>,
+) -> Result<TranscodeVideoResponse, Status> {
+    if options.interval <= 0.0 || options.width == 0 || options.columns == 0 || options.rows == 0 {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "Sprite interval, width, columns and rows must be positive",
+        ));
+    }
+
+    if total_duration <= 0.0 {
+        return Err(Status::new(
+            Code::Internal,
+            "Cannot generate sprites without the video duration",
+        ));
+    }
+
+    shared::update_progress(task_id, format_index, Phase::Transcode, 0);
+
+    let sprite_pattern = format!("{}_sprite_%03d.jpg", output_prefix);
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", file_path, "-vf"])
+        .arg(format!(
+            "fps=1/{},scale={}:-2,tile={}x{}",
+            options.interval, options.width, options.columns, options.rows
+        ))
+        .args(["-q:v", "5", "-y", sprite_pattern.as_str()])
+        .output()
+        .map_err(|e| Status::new(Code::Internal, format!("Failed to start ffmpeg: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(Status::new(
+            Code::Internal,
+            format!(
+                "Failed to generate sprites: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+
+    let mut sprite_paths = Vec::new();
+    for index in 1.. {
+        let sprite_path = format!("{}_sprite_{:03}.jpg", output_prefix, index);
+        if !std::path::Path::new(&sprite_path).exists() {
+            break;
+        }
+        sprite_paths.push(sprite_path);
+    }
+
+    let first_sprite = sprite_paths
+        .first()
+        .ok_or_else(|| Status::new(Code::Internal, "No sprites were generated"))?;
+    let (_, sheet_height) =
+        get_dimensions(first_sprite).map_err(|e| Status::new(Code::Internal, e))?;
+    let tile_height = sheet_height / options.rows;
+
+    let scheme = match dest.as_deref() {
+        Some("ipfs") => "ipfs",
+        _ => "s5",
+    };
+
+    let mut sprite_cids = Vec::new();
+    for sprite_path in &sprite_paths {
+        let uploaded = upload_video(sprite_path, dest.clone())
+            .await
+            .map_err(|e| Status::new(Code::Internal, format!("Failed to upload sprite: {}", e)))?;
+        sprite_cids.push(uploaded.cid);
+    }
+
+    let sprite_urls: Vec<String> = sprite_cids
+        .iter()
+        .map(|cid| format!("{}://{}", scheme, cid))
+        .collect();
+
+    let vtt_path = format!("{}.vtt", output_prefix);
+    fs::write(
+        &vtt_path,
+        build_vtt(&sprite_urls, options, tile_height, total_duration),
+    )
+    .map_err(|e| Status::new(Code::Internal, format!("Failed to write WebVTT: {}", e)))?;
+
+    shared::update_progress(task_id, format_index, Phase::Upload, 0);
+
+    let uploaded = upload_video(&vtt_path, dest)
+        .await
+        .map_err(|e| Status::new(Code::Internal, format!("Failed to upload WebVTT: {}", e)))?;
+
+    shared::update_progress(task_id, format_index, Phase::Done, 100);
+
+    Ok(TranscodeVideoResponse {
+        status_code: 200,
+        message: String::from("Sprite generation successful"),
+        cid: uploaded.cid,
+        portal_url: uploaded.portal_url,
+        sprite_cids: sprite_urls,
+        unencrypted_cid: None,
+        name: None,
+        key: None,
+        encoder: None,
+    })
+}
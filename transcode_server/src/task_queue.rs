@@ -0,0 +1,161 @@
+use dotenv::var;
+use once_cell::sync::Lazy;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+const DEFAULT_PRIORITY_AGING_SECS: u64 = 60;
+
+// Seconds a queued task must wait to gain one priority level, 0 disables aging
+static PRIORITY_AGING_SECS: Lazy<u64> = Lazy::new(|| {
+    var("PRIORITY_AGING_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_PRIORITY_AGING_SECS)
+});
+
+/// A queued transcoding task: `(task_id, source_cid, media_formats, is_encrypted, is_gpu, force,
+/// expected_hash)`.
+pub type TranscodeTask = (
+    String,
+    String,
+    String,
+    bool,
+    bool,
+    bool,
+    Option<blake3::Hash>,
+);
+
+/// The priority a transcoding task is queued with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+/// The accepted priority levels, lowest first.
+pub const PRIORITY_LEVELS: [&str; 3] = ["low", "normal", "high"];
+
+impl Priority {
+    /// Parses a priority level, case-insensitively. An empty string is `Normal`.
+    ///
+    /// # Arguments
+    /// * `priority` - One of `PRIORITY_LEVELS`, or empty.
+    ///
+    /// # Returns
+    /// The priority, or an error message listing the accepted levels.
+    ///
+    pub fn parse(priority: &str) -> Result<Priority, String> {
+        match priority.trim().to_lowercase().as_str() {
+            "" | "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            "high" => Ok(Priority::High),
+            other => Err(format!(
+                "Invalid priority '{}', expected one of: {}",
+                other,
+                PRIORITY_LEVELS.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QueuedTask {
+    score: f64,
+    sequence: u64,
+    priority: Priority,
+    task: TranscodeTask,
+}
+
+impl PartialEq for QueuedTask {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedTask {}
+
+impl PartialOrd for QueuedTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedTask {
+    // Higher scores are dequeued first, and tasks with equal scores in the order they were queued
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// A queue of transcoding tasks that dequeues higher priority tasks first.
+///
+/// To keep low priority tasks from being starved, a task gains one priority level for every
+/// `PRIORITY_AGING_SECS` it has been waiting. Every queued task ages at the same rate, so this
+/// is equivalent to ranking each task by `priority * PRIORITY_AGING_SECS - time queued`, which
+/// is fixed when the task is queued and lets a binary heap keep the tasks in order.
+#[derive(Debug)]
+pub struct TaskQueue {
+    tasks: Mutex<BinaryHeap<QueuedTask>>,
+    notify: Notify,
+    started: Instant,
+    next_sequence: Mutex<u64>,
+}
+
+impl TaskQueue {
+    pub fn new() -> Self {
+        TaskQueue {
+            tasks: Mutex::new(BinaryHeap::new()),
+            notify: Notify::new(),
+            started: Instant::now(),
+            next_sequence: Mutex::new(0),
+        }
+    }
+
+    /// Queues a transcoding task and wakes the task receiver.
+    ///
+    /// # Arguments
+    /// * `task` - The transcoding task.
+    /// * `priority` - The priority to queue the task with.
+    ///
+    pub fn push(&self, task: TranscodeTask, priority: Priority) {
+        let score = if *PRIORITY_AGING_SECS > 0 {
+            priority as u64 as f64 * *PRIORITY_AGING_SECS as f64
+                - self.started.elapsed().as_secs_f64()
+        } else {
+            priority as u64 as f64
+        };
+
+        let sequence = {
+            let mut next_sequence = self.next_sequence.lock().unwrap();
+            *next_sequence += 1;
+            *next_sequence
+        };
+
+        self.tasks.lock().unwrap().push(QueuedTask {
+            score,
+            sequence,
+            priority,
+            task,
+        });
+        self.notify.notify_one();
+    }
+
+    /// Waits for and removes the next task to process, returning it with the priority it was
+    /// queued with.
+    pub async fn pop(&self) -> (TranscodeTask, Priority) {
+        loop {
+            let queued_task = self.tasks.lock().unwrap().pop();
+            if let Some(queued_task) = queued_task {
+                return (queued_task.task, queued_task.priority);
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
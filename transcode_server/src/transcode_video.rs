@@ -1,25 +1,32 @@
-use crate::shared;
+use crate::shared::{self, Encoder, Phase};
 
-use crate::encrypt_file::encrypt_file_xchacha20;
-use crate::encrypted_cid::create_encrypted_cid;
-use crate::s5::hash_blake3_file;
-use crate::s5::upload_video;
+use crate::encrypt_file::{encrypt_file_xchacha20, ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2};
+use crate::encrypted_cid::{
+    create_encrypted_cid, parse_encrypted_cid, CID_TYPE_ENCRYPTED,
+    ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305,
+};
+use crate::media_info::MediaInfo;
+use crate::s5::{download_to, hash_blake3_file_async, hash_file_async};
+use crate::s5::{upload_video, upload_video_with_key, UploadProgress};
+use crate::sprites::{generate_sprites, SpriteOptions};
 use crate::utils::{
     base64url_to_bytes, bytes_to_base64url, download_and_concat_files, download_video,
-    hash_bytes_to_cid,
+    hash_bytes_to_cid, hash_bytes_to_cid_with_algorithm, CidHashAlgorithm, CID_HASH_ALGORITHM,
 };
 use base64::{engine::general_purpose, DecodeError, Engine as _};
 use dotenv::var;
 use once_cell::sync::Lazy;
-use regex::Regex;
 use sanitize_filename::sanitize;
 use serde::Deserialize;
 use serde_json;
+use std::collections::{BTreeSet, HashSet, VecDeque};
 use std::error::Error;
 use std::fs::metadata;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 use tonic::{transport::Server, Code, Request, Response, Status};
 
@@ -30,6 +37,33 @@ static PATH_TO_TRANSCODED_FILE: Lazy<String> = Lazy::new(|| {
         .unwrap_or_else(|_| panic!("PATH_TO_TRANSCODED_FILE not set in .env"))
 });
 
+// The encoders the ffmpeg binary was built with, or `None` if they couldn't be listed
+static FFMPEG_ENCODERS: Lazy<Option<HashSet<String>>> = Lazy::new(list_ffmpeg_encoders);
+
+// Whether a format the source already satisfies is stream-copied rather than re-encoded
+static COPY_COMPATIBLE: Lazy<bool> = Lazy::new(|| {
+    var("COPY_COMPATIBLE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(false)
+});
+
+// How far, in percent, the source's width and height may be from a format's to be copied
+static COPY_RESOLUTION_TOLERANCE: Lazy<f64> = Lazy::new(|| {
+    var("COPY_RESOLUTION_TOLERANCE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(5.0)
+});
+
+// How far, in percent, the source's bitrate may exceed a format's to be copied
+static COPY_BITRATE_TOLERANCE: Lazy<f64> = Lazy::new(|| {
+    var("COPY_BITRATE_TOLERANCE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(10.0)
+});
+
 pub mod transcode {
     tonic::include_proto!("transcode");
 }
@@ -39,6 +73,15 @@ pub struct TranscodeVideoResponse {
     pub status_code: i32,
     pub message: String,
     pub cid: String,
+    pub portal_url: Option<String>,
+    pub sprite_cids: Vec<String>,
+    pub unencrypted_cid: Option<String>,
+    /// The file name the rendition was uploaded as, if the format has a `name_template`.
+    pub name: Option<String>,
+    /// The path the rendition was uploaded to, if the format has a `prefix`.
+    pub key: Option<String>,
+    /// Whether the rendition was encoded on a GPU or the CPU, `None` for sprite sheets.
+    pub encoder: Option<Encoder>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,506 +89,2321 @@ pub struct VideoFormat {
     pub id: u32,
     pub ext: String,
     vcodec: Option<String>,
+    tag_v: Option<String>,
     acodec: Option<String>,
     preset: Option<String>,
     profile: Option<String>,
     ch: Option<u8>,
     vf: Option<String>,
     b_v: Option<String>,
+    crf: Option<u8>,
+    b_a: Option<String>,
     ar: Option<String>,
     minrate: Option<String>,
     maxrate: Option<String>,
     bufsize: Option<String>,
     gpu: Option<bool>,
+    gpu_index: Option<u32>,
     compression_level: Option<u8>,
     pub dest: Option<String>,
+    sprite: Option<SpriteOptions>,
+    keep_unencrypted: Option<bool>,
+    name_template: Option<String>,
+    video_stream: Option<u32>,
+    audio_stream: Option<u32>,
+    layout: Option<String>,
+    /// Whether to move an MP4 output's index to the front, overriding `MP4_FASTSTART`.
+    faststart: Option<bool>,
+    /// Duration in seconds of the segments of an HLS output, overriding `HLS_SEGMENT_SECS`.
+    hls_time: Option<f64>,
+    /// Folder the output is placed in by path-based storage, e.g. `assets/{source}/720p/`.
+    prefix: Option<String>,
+    /// Whether the source satisfies the format and is stream-copied, see `source_satisfies`.
+    #[serde(skip)]
+    stream_copy: bool,
 }
 
+// Whether MP4 outputs have their index (the moov atom) moved to the front by default, so that
+// they can start playing before they are fully downloaded
+static MP4_FASTSTART: Lazy<bool> = Lazy::new(|| {
+    var("MP4_FASTSTART")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(true)
+});
+
+// Default duration in seconds of the segments of HLS outputs
+static HLS_SEGMENT_SECS: Lazy<f64> = Lazy::new(|| {
+    var("HLS_SEGMENT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|&secs| secs > 0.0)
+        .unwrap_or(6.0)
+});
+
+// Extensions of the MP4-family containers that support `-movflags +faststart`
+const FASTSTART_CONTAINERS: [&str; 4] = ["mp4", "m4v", "mov", "m4a"];
+
+// Lines of the end of ffmpeg's log, and at most how many characters of them, that a failed
+// rendition's error reports
+const FFMPEG_ERROR_LINES: usize = 20;
+const FFMPEG_ERROR_MAX_CHARS: usize = 2000;
+
 fn add_arg(cmd: &mut Command, arg: &str, value: Option<&str>) {
     if let Some(value) = value {
         cmd.arg(arg).arg(value);
     }
 }
 
+// Extensions a media format may be output as. The extension names the output file and selects
+// ffmpeg's container, so anything else, such as a path, is rejected
+const OUTPUT_EXTENSIONS: [&str; 17] = [
+    "mp4", "m4v", "mov", "mkv", "webm", "ts", "m3u8", "m4a", "mp3", "aac", "flac", "ogg", "oga",
+    "opus", "wav", "avi", "vtt",
+];
+
+// Containers that can hold H.265/HEVC video
+const HEVC_CONTAINERS: [&str; 5] = ["mp4", "m4v", "mov", "mkv", "ts"];
+
+/// Returns whether a video codec encodes H.265/HEVC, e.g. `libx265` or `hevc_nvenc`.
+fn is_hevc(vcodec: &str) -> bool {
+    vcodec == "libx265" || vcodec.starts_with("hevc")
+}
+
+/// Returns the `-tag:v` codec tag to set for a format: `tag_v` if given, otherwise `hvc1` for
+/// HEVC in MP4 and MOV containers. ffmpeg tags HEVC as `hev1` by default, which Safari and iOS
+/// refuse to play.
+///
+/// # Arguments
+/// * `format` - The output media format.
+///
+/// # Returns
+/// The codec tag, if any, or an `InvalidArgument` status if the format encodes HEVC to a
+/// container that cannot hold it.
+///
+fn video_tag(format: &VideoFormat) -> Result<Option<String>, Status> {
+    if !is_hevc(format.vcodec.as_deref().unwrap_or_default()) {
+        return Ok(format.tag_v.clone());
+    }
+
+    let ext = format.ext.to_lowercase();
+    if !HEVC_CONTAINERS.contains(&ext.as_str()) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!("Container '{}' does not support HEVC video", format.ext),
+        ));
+    }
+
+    Ok(format
+        .tag_v
+        .clone()
+        .or_else(|| matches!(ext.as_str(), "mp4" | "m4v" | "mov").then(|| String::from("hvc1"))))
+}
+
+/// Counts the GPUs a hardware encoder can use: the NVIDIA GPUs listed by `nvidia-smi` for
+/// NVENC, otherwise the DRM render nodes, as used by QSV and VAAPI.
+fn detect_gpu_count(vcodec: &str) -> usize {
+    if vcodec.ends_with("_nvenc") {
+        return Command::new("nvidia-smi")
+            .arg("-L")
+            .output()
+            .map(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .filter(|line| line.starts_with("GPU "))
+                    .count()
+            })
+            .unwrap_or(0);
+    }
+
+    std::fs::read_dir("/dev/dri")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("renderD"))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+/// Adds the ffmpeg input options that pin a GPU format with a `gpu_index` to that GPU. They
+/// must come before the input. The NVENC encoder is also given the index, by
+/// `add_encode_args`.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command, before its input is added.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `format` - The desired output video format.
+///
+/// # Returns
+/// A `Result<(), Status>` that is an `InvalidArgument` error if the index is out of range of
+/// the detected GPUs, or the video codec isn't an NVENC, QSV or VAAPI encoder.
+///
+pub fn add_gpu_device_args(
+    cmd: &mut Command,
+    is_gpu: bool,
+    format: &VideoFormat,
+) -> Result<(), Status> {
+    let gpu_index = match (is_gpu, format.gpu_index) {
+        (true, Some(gpu_index)) => gpu_index,
+        _ => return Ok(()),
+    };
+    let vcodec = format.vcodec.as_deref().unwrap_or_default();
+
+    let gpu_count = detect_gpu_count(vcodec);
+    if gpu_index as usize >= gpu_count {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "gpu_index {} is out of range, {} GPUs detected",
+                gpu_index, gpu_count
+            ),
+        ));
+    }
+
+    // DRM render nodes are numbered from 128
+    let render_node = format!("/dev/dri/renderD{}", 128 + gpu_index);
+    if vcodec.ends_with("_nvenc") {
+        cmd.args(["-hwaccel", "cuda", "-hwaccel_device"])
+            .arg(gpu_index.to_string());
+    } else if vcodec.ends_with("_qsv") {
+        cmd.args(["-hwaccel", "qsv", "-qsv_device", render_node.as_str()]);
+    } else if vcodec.ends_with("_vaapi") {
+        cmd.args(["-vaapi_device", render_node.as_str()]);
+    } else {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "gpu_index is only supported for NVENC, QSV and VAAPI encoders",
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn get_video_format_from_str(video_format: &str) -> Result<VideoFormat, Status> {
-    serde_json::from_str::<VideoFormat>(video_format).map_err(|err| {
+    let format = serde_json::from_str::<VideoFormat>(video_format).map_err(|err| {
         Status::new(
             Code::InvalidArgument,
             format!("Invalid video format: {}", err),
         )
-    })
+    })?;
+    check_output_extension(&format.ext)?;
+
+    Ok(format)
 }
 
-/// Gets video duration in seconds using `ffprobe`.
+/// Checks that an output extension is one of `OUTPUT_EXTENSIONS`, ignoring case.
 ///
 /// # Arguments
-/// * `file_path`: Path to the video file.
+/// * `ext` - The `ext` of a media format.
 ///
-/// # Returns:
-/// `Result<f64, String>` - Duration in seconds or error message.
+/// # Returns
+/// `Ok(())` if the extension is allowed, otherwise an `InvalidArgument` status.
 ///
-fn get_video_duration(file_path: &str) -> Result<f64, String> {
-    let output = Command::new("ffprobe")
-        .args([
-            "-v",
-            "error",
-            "-show_entries",
-            "format=duration",
-            "-of",
-            "default=noprint_wrappers=1:nokey=1",
-            file_path,
-        ])
-        .output()
-        .expect("failed to execute ffprobe");
-
-    if output.status.success() {
-        let duration_str = String::from_utf8(output.stdout).unwrap();
-        duration_str
-            .trim()
-            .parse::<f64>()
-            .map_err(|e| e.to_string())
-    } else {
-        Err(String::from("Failed to retrieve video duration"))
+fn check_output_extension(ext: &str) -> Result<(), Status> {
+    if OUTPUT_EXTENSIONS
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(ext))
+    {
+        return Ok(());
     }
+
+    Err(Status::new(
+        Code::InvalidArgument,
+        format!(
+            "Unsupported media format ext '{}', expected one of: {}",
+            ext,
+            OUTPUT_EXTENSIONS.join(", ")
+        ),
+    ))
 }
 
-/// Parses ffmpeg progress output to calculate and return the transcoding progress as a percentage.
-/// This function searches for time stamps in the ffmpeg output and calculates the progress based
-/// on the total duration of the video. If the total duration is not positive, it returns 0 to
-/// prevent division by zero errors.
+/// Checks that the `ext` of each requested media format is an allowed output extension, so a
+/// request with an unknown or path-like extension is rejected as a whole rather than having the
+/// format skipped when it is transcoded.
 ///
 /// # Arguments
-/// * `line` - A string slice containing a line of ffmpeg output.
-/// * `total_duration` - The total duration of the video in seconds.
+/// * `media_formats` - The requested media formats.
 ///
 /// # Returns
-/// An `Option<i32>` representing the transcoding progress percentage, or `None` if the progress
-/// cannot be determined from the given line.
+/// `Ok(())` if every extension is allowed, otherwise an `InvalidArgument` status.
 ///
-fn parse_progress(line: &str, total_duration: f64) -> Option<i32> {
-    if total_duration <= 0.0 {
-        return Some(0); // Prevent division by zero
+pub fn check_format_extensions(media_formats: &[serde_json::Value]) -> Result<(), Status> {
+    for format in media_formats {
+        if let Some(ext) = format["ext"].as_str() {
+            check_output_extension(ext)?;
+        }
     }
 
-    let re = Regex::new(r"time=(\d+):(\d+):(\d+\.\d+)").unwrap();
-    if let Some(caps) = re.captures(line) {
-        let hours = caps.get(1).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
-        let minutes = caps.get(2).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
-        let seconds = caps.get(3).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
-        let current_time_seconds = hours * 3600.0 + minutes * 60.0 + seconds;
-        let progress = ((current_time_seconds / total_duration) * 100.0).round() as i32;
-        return Some(progress);
+    Ok(())
+}
+
+/// Checks that a destination prefix is a relative folder path that stays within the storage
+/// root: made of non-empty segments of ASCII letters, digits, `-`, `_`, `.` and the `{` `}` of
+/// placeholders, separated by `/`, with no `.` or `..` segment. A trailing `/` is optional.
+///
+/// # Arguments
+/// * `prefix` - The prefix, before or after its placeholders are replaced.
+///
+/// # Returns
+/// `Ok(())` if the prefix is valid, otherwise an `InvalidArgument` status.
+///
+fn check_prefix(prefix: &str) -> Result<(), Status> {
+    let invalid = |reason: &str| {
+        Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid prefix '{}': {}", prefix, reason),
+        ))
+    };
+
+    if prefix.starts_with('/') {
+        return invalid("it must be relative");
+    }
+    for segment in prefix.strip_suffix('/').unwrap_or(prefix).split('/') {
+        if segment.is_empty() {
+            return invalid("it has an empty segment");
+        }
+        if segment == "." || segment == ".." {
+            return invalid("it can't contain '.' or '..' segments");
+        }
+        if !segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.{}".contains(c))
+        {
+            return invalid("segments can only contain ASCII letters, digits, '-', '_' and '.'");
+        }
     }
 
-    None
+    Ok(())
 }
 
-/// Executes the ffmpeg command to transcode a video file based on the specified parameters.
-/// This function supports GPU acceleration and handles various video formats.
+/// Checks the `prefix` of each requested media format with `check_prefix`, so that a request
+/// with a prefix that could escape its folder is rejected before anything is transcoded.
 ///
 /// # Arguments
-/// * `task_id` - A unique identifier for the transcoding task.
-/// * `format_index` - The index specifying the target video format from a predefined list.
-/// * `file_path` - The path to the input video file to be transcoded.
-/// * `file_name` - The name of the input video file.
-/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
-/// * `format` - The desired output video format.
-/// * `total_duration` - The total duration of the video file in seconds.
+/// * `media_formats` - The requested media formats.
 ///
 /// # Returns
-/// A `Result<(), Status>` indicating the success or failure of the transcoding operation.
+/// `Ok(())` if every prefix is valid, otherwise an `InvalidArgument` status.
 ///
-fn run_ffmpeg(
-    task_id: String,
-    format_index: usize,
-    file_path: &str,
-    file_name: &str,
-    is_gpu: bool,
-    format: &VideoFormat,
-    total_duration: f64,
-) -> Result<(), Status> {
-    let mut cmd = Command::new("ffmpeg");
-    // Ensure verbose output for detailed progress information
-    cmd.arg("-v").arg("info");
-    cmd.arg("-progress").arg("pipe:2");
-    cmd.arg("-stats_period").arg("1");
+pub fn check_format_prefixes(media_formats: &[serde_json::Value]) -> Result<(), Status> {
+    for format in media_formats {
+        match &format["prefix"] {
+            serde_json::Value::Null => {}
+            serde_json::Value::String(prefix) => check_prefix(prefix)?,
+            _ => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!(
+                        "The prefix of media format {} must be a string",
+                        format["id"]
+                    ),
+                ))
+            }
+        }
+    }
 
-    if is_gpu {
-        println!("GPU transcoding");
+    Ok(())
+}
 
-        add_arg(&mut cmd, "-i", Some(file_path));
-        add_arg(&mut cmd, "-c:v", format.vcodec.as_deref());
-        add_arg(&mut cmd, "-b:v", format.b_v.as_deref());
-        add_arg(&mut cmd, "-c:a", Some("libopus")); // Keep this as-is, if not present in VideoFormat
-        add_arg(&mut cmd, "-b:a", Some("192k")); // Keep this as-is, if not present in VideoFormat
-        if let Some(ch) = format.ch {
-            add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
+/// Checks that the HLS formats (`ext` `m3u8`) of a request form a ladder players can switch
+/// between: they must share the same segment duration, `hls_time` or else `HLS_SEGMENT_SECS`,
+/// so that every rendition's segments start at the same timestamps. HLS outputs are made of
+/// many files, so they can't be encrypted.
+///
+/// # Arguments
+/// * `is_encrypted` - Whether the outputs are encrypted.
+/// * `media_formats` - The requested media formats.
+///
+/// # Returns
+/// `Ok(())` if the HLS formats can be transcoded together, otherwise an `InvalidArgument`
+/// status.
+///
+pub fn check_hls_ladder(
+    is_encrypted: bool,
+    media_formats: &[serde_json::Value],
+) -> Result<(), Status> {
+    let mut ladder_segment_secs: Option<(&serde_json::Value, f64)> = None;
+    for format in media_formats {
+        if !format["ext"]
+            .as_str()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8"))
+        {
+            continue;
         }
-        add_arg(&mut cmd, "-ar", format.ar.as_deref());
-        add_arg(&mut cmd, "-vf", format.vf.as_deref());
-        if let Some(ref minrate) = format.minrate {
-            cmd.args(["-minrate", minrate]);
+
+        let id = &format["id"];
+        if is_encrypted {
+            return Err(Status::invalid_argument(format!(
+                "Media format {} is HLS, which can't be encrypted",
+                id
+            )));
         }
 
-        if let Some(ref maxrate) = format.maxrate {
-            cmd.args(["-maxrate", maxrate]);
+        let segment_secs = match &format["hls_time"] {
+            serde_json::Value::Null => *HLS_SEGMENT_SECS,
+            hls_time => hls_time
+                .as_f64()
+                .filter(|&secs| secs > 0.0)
+                .ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "Media format {} has an invalid hls_time, it must be a positive number of seconds",
+                        id
+                    ))
+                })?,
+        };
+
+        match ladder_segment_secs {
+            Some((first_id, first_secs)) if first_secs != segment_secs => {
+                return Err(Status::invalid_argument(format!(
+                    "HLS media formats must share the same segment duration so that their segments align, but format {} has {}s segments and format {} {}s",
+                    first_id, first_secs, id, segment_secs
+                )));
+            }
+            Some(_) => {}
+            None => ladder_segment_secs = Some((id, segment_secs)),
         }
+    }
 
-        if let Some(ref bufsize) = format.bufsize {
-            cmd.args(["-bufsize", bufsize]);
+    Ok(())
+}
+
+/// Parses a bitrate such as `128k`, `4.5M` or `96000` into bits per second.
+pub fn parse_bitrate(bitrate: &str) -> Option<u64> {
+    let bitrate = bitrate.trim();
+    let (number, multiplier) = match bitrate.chars().last()? {
+        'k' | 'K' => (&bitrate[..bitrate.len() - 1], 1_000.0),
+        'm' | 'M' => (&bitrate[..bitrate.len() - 1], 1_000_000.0),
+        _ => (bitrate, 1.0),
+    };
+
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|value| (value * multiplier).round() as u64)
+}
+
+/// Checks that no two of the requested media formats share an `id`, since the id names their
+/// output files, which would otherwise overwrite each other.
+///
+/// # Arguments
+/// * `media_formats` - The requested media formats, before any audio bitrate ladder is expanded.
+///
+/// # Returns
+/// `Ok(())` if the ids are unique, otherwise an `InvalidArgument` status listing the duplicates.
+///
+pub fn check_unique_format_ids(media_formats: &[serde_json::Value]) -> Result<(), Status> {
+    let mut seen = HashSet::new();
+    let mut duplicates = BTreeSet::new();
+    for format in media_formats {
+        if let Some(id) = format["id"].as_u64() {
+            if !seen.insert(id) {
+                duplicates.insert(id);
+            }
         }
+    }
 
-        cmd.args([
-            "-y",
-            format!(
-                "{}{}_ue.{}",
-                *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-            )
-            .as_str(),
-        ]);
-    } else {
-        println!("CPU transcoding");
+    if duplicates.is_empty() {
+        return Ok(());
+    }
 
-        if let Some(vcodec) = &format.vcodec {
-            if !vcodec.is_empty() {
-                add_arg(&mut cmd, "-i", Some(file_path));
-                add_arg(&mut cmd, "-c:v", format.vcodec.as_deref());
-                add_arg(&mut cmd, "-cpu-used", Some("4")); // set encoding speed to 4 (range 0-8, lower is slower)
-                add_arg(&mut cmd, "-b:v", format.b_v.as_deref());
-                add_arg(&mut cmd, "-crf", Some("30")); // set quality level to 30 (range 0-63, lower is better)
-                add_arg(&mut cmd, "-c:a", Some("libopus")); // use libopus encoder for audio
-                add_arg(&mut cmd, "-b:a", Some("192k")); // Keep this as-is, if not present in VideoFormat
-                if let Some(ch) = format.ch {
-                    add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
-                }
-                add_arg(&mut cmd, "-vf", format.vf.as_deref());
-                add_arg(
-                    &mut cmd,
-                    "-y",
-                    Some(&format!(
-                        "{}{}_ue.{}",
-                        *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-                    )),
-                );
-            } else {
+    let duplicates: Vec<String> = duplicates.iter().map(|id| id.to_string()).collect();
+    Err(Status::new(
+        Code::InvalidArgument,
+        format!("Duplicate media format ids: {}", duplicates.join(", ")),
+    ))
+}
+
+/// Expands each media format that specifies an `audio_bitrates` ladder into one media format
+/// per bitrate, with `b_a` set to that bitrate, so that each bitrate is transcoded, uploaded
+/// and has its progress tracked as a separate format. Other media formats are returned as is.
+///
+/// # Arguments
+/// * `media_formats` - The requested media formats.
+///
+/// # Returns
+/// The expanded media formats, or an `InvalidArgument` status if a ladder is empty, has a
+/// bitrate that isn't positive, or isn't sorted in ascending order.
+///
+pub fn expand_audio_bitrates(
+    media_formats: Vec<serde_json::Value>,
+) -> Result<Vec<serde_json::Value>, Status> {
+    let mut expanded = Vec::new();
+
+    for format in media_formats {
+        let bitrates = match format.get("audio_bitrates") {
+            None | Some(serde_json::Value::Null) => {
+                expanded.push(format);
+                continue;
+            }
+            Some(serde_json::Value::Array(bitrates)) if !bitrates.is_empty() => bitrates.clone(),
+            Some(_) => {
                 return Err(Status::new(
                     Code::InvalidArgument,
-                    "No video codec specified",
+                    "audio_bitrates must be a non-empty array of bitrates",
                 ));
             }
-        } else if let Some(acodec) = &format.acodec {
-            if !acodec.is_empty() {
-                println!("Transcoding audio");
-                add_arg(&mut cmd, "-i", Some(file_path));
-                add_arg(&mut cmd, "-acodec", format.acodec.as_deref());
-                if let Some(ch) = format.ch {
-                    add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
-                }
-                add_arg(&mut cmd, "-ar", format.ar.as_deref());
+        };
 
-                if let Some(compression_level) = format.compression_level {
-                    add_arg(
-                        &mut cmd,
-                        "-compression_level",
-                        Some(&compression_level.to_string()),
-                    );
-                }
-                add_arg(
-                    &mut cmd,
-                    "-y",
-                    Some(&format!(
-                        "{}{}_ue.{}",
-                        *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-                    )),
-                );
-            } else {
+        let mut previous_bitrate = 0;
+        for bitrate in &bitrates {
+            let bits_per_sec = bitrate
+                .as_str()
+                .and_then(parse_bitrate)
+                .filter(|&bits_per_sec| bits_per_sec > 0)
+                .ok_or_else(|| {
+                    Status::new(
+                        Code::InvalidArgument,
+                        format!("Invalid audio bitrate: {}", bitrate),
+                    )
+                })?;
+
+            if bits_per_sec <= previous_bitrate {
                 return Err(Status::new(
                     Code::InvalidArgument,
-                    "No audio codec specified",
+                    "audio_bitrates must be sorted in ascending order",
                 ));
             }
-        } else {
-            return Err(Status::new(Code::InvalidArgument, "No codec specified"));
+            previous_bitrate = bits_per_sec;
+        }
+
+        for bitrate in bitrates {
+            let mut rendition = format.clone();
+            if let Some(rendition) = rendition.as_object_mut() {
+                rendition.remove("audio_bitrates");
+            }
+            rendition["b_a"] = bitrate;
+            expanded.push(rendition);
         }
     }
 
-    // // Ensure stderr is captured
-    // cmd.stderr(Stdio::piped());
+    Ok(expanded)
+}
+
+// Range of audio sample rates accepted in `audio_sample_rates`, in Hz
+const MIN_SAMPLE_RATE: u64 = 8_000;
+const MAX_SAMPLE_RATE: u64 = 192_000;
 
-    // Ensure stderr is captured and stdout is suppressed
-    cmd.stderr(Stdio::piped()).stdout(Stdio::null());
+// Largest number of channels accepted in `audio_channels`
+const MAX_CHANNELS: u64 = 8;
 
-    let mut child = cmd.spawn().expect("failed to start ffmpeg command");
+/// Expands each media format that lists `audio_sample_rates` and/or `audio_channels` into one
+/// media format per combination of sample rate and channel count, with `ar` and `ch` set to
+/// them, so that e.g. a stereo 48 kHz and a mono 16 kHz output can be requested as one format.
+/// Each combination is transcoded, uploaded and has its progress tracked as a separate format,
+/// and is labelled with a `layout`, e.g. `48000_2ch`, that distinguishes its output file. A
+/// format listing only one of them keeps its own `ar` or `ch` for the other. Other media formats
+/// are returned as is.
+///
+/// # Arguments
+/// * `media_formats` - The requested media formats, after any audio bitrate ladder is expanded.
+///
+/// # Returns
+/// The expanded media formats, or an `InvalidArgument` status if a list is empty, or has a
+/// sample rate outside 8000 to 192000 Hz or a channel count outside 1 to 8.
+///
+pub fn expand_audio_layouts(
+    media_formats: Vec<serde_json::Value>,
+) -> Result<Vec<serde_json::Value>, Status> {
+    let mut expanded = Vec::new();
+
+    for format in media_formats {
+        let sample_rates = layout_values(&format, "audio_sample_rates", |value| {
+            value
+                .as_str()
+                .and_then(parse_bitrate)
+                .or_else(|| value.as_u64())
+                .filter(|rate| (MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(rate))
+        })?;
+        let channels = layout_values(&format, "audio_channels", |value| {
+            value
+                .as_u64()
+                .filter(|channels| (1..=MAX_CHANNELS).contains(channels))
+        })?;
+        if sample_rates.is_none() && channels.is_none() {
+            expanded.push(format);
+            continue;
+        }
 
-    // Take the stderr handle if available
-    if let Some(stderr) = child.stderr.take() {
-        let reader = BufReader::new(stderr);
+        let sample_rates: Vec<Option<u64>> = match sample_rates {
+            Some(sample_rates) => sample_rates.into_iter().map(Some).collect(),
+            None => vec![None],
+        };
+        let channels: Vec<Option<u64>> = match channels {
+            Some(channels) => channels.into_iter().map(Some).collect(),
+            None => vec![None],
+        };
 
-        // Assuming `reader` is a `BufReader` wrapped around `ChildStderr` or similar
-        let mut last_progress = 0; // Initialize last known progress
+        for sample_rate in &sample_rates {
+            for channel_count in &channels {
+                let mut rendition = format.clone();
+                if let Some(rendition) = rendition.as_object_mut() {
+                    rendition.remove("audio_sample_rates");
+                    rendition.remove("audio_channels");
+                }
 
-        for line_result in reader.lines() {
-            if let Ok(line) = line_result {
-                if let Some(progress) = parse_progress(&line, total_duration) {
-                    last_progress = progress;
-                    shared::update_progress(&task_id, format_index, last_progress);
-                    // Update the global progress map
+                let mut layout = Vec::new();
+                if let Some(sample_rate) = sample_rate {
+                    rendition["ar"] = serde_json::json!(sample_rate.to_string());
+                    layout.push(sample_rate.to_string());
                 }
-                println!("£££££ {} £££££", line);
-                println!("Progress: {}%", last_progress);
+                if let Some(channel_count) = channel_count {
+                    rendition["ch"] = serde_json::json!(channel_count);
+                    layout.push(format!("{}ch", channel_count));
+                }
+                rendition["layout"] = serde_json::json!(layout.join("_"));
+                expanded.push(rendition);
             }
         }
     }
 
-    // Wait for ffmpeg to finish
-    let output = child.wait().expect("Transcode process wasn't running");
-    println!("Transcode finished with status: {}", output);
-
-    Ok(())
+    Ok(expanded)
 }
 
-/// Asynchronously transcodes a video from a given format to another using ffmpeg,
-/// based on the specified transcoder settings. This function supports optional
-/// encryption and GPU acceleration.
+/// Reads a list of audio layout values from a media format, e.g. its `audio_sample_rates`.
 ///
 /// # Arguments
-/// * `task_id` - A unique identifier for the transcoding task.
-/// * `format_index` - The index specifying the target video format from a predefined list.
-/// * `file_path` - The path to the input video file to be transcoded.
-/// * `video_format` - The desired output video format.
-/// * `is_encrypted` - A boolean flag indicating whether the output video should be encrypted.
-/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `format` - The media format.
+/// * `key` - The key of the list.
+/// * `parse` - Parses and validates a value of the list.
 ///
 /// # Returns
-/// A `Result` wrapping a `Response` with the `TranscodeVideoResponse` on success,
-/// or a `Status` error on failure.
+/// The parsed values, `None` if the format has no such list, or an `InvalidArgument` status if
+/// the list is empty or has an invalid value.
 ///
-pub async fn transcode_video(
-    task_id: String,
-    format_index: usize,
-    file_path: &str,
-    video_format: &str,
-    is_encrypted: bool,
-    is_gpu: bool,
-) -> Result<Response<TranscodeVideoResponse>, Status> {
-    println!("transcode_video: Processing video at: {}", file_path);
-    println!("transcode_video: video_format: {}", video_format);
-    println!("transcode_video: is_encrypted: {}", is_encrypted);
-    println!("transcode_video: is_gpu: {}", is_gpu);
-
-    let file_name = Path::new(file_path)
-        .file_name()
-        .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid file path"))?
-        .to_string_lossy()
-        .to_string();
-
-    let format = get_video_format_from_str(video_format)?;
-
-    let file_name = format!("{}_{}", file_name, format.id.to_string());
+fn layout_values(
+    format: &serde_json::Value,
+    key: &str,
+    parse: impl Fn(&serde_json::Value) -> Option<u64>,
+) -> Result<Option<Vec<u64>>, Status> {
+    let values = match format.get(key) {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(serde_json::Value::Array(values)) if !values.is_empty() => values,
+        Some(_) => {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("{} must be a non-empty array", key),
+            ));
+        }
+    };
+
+    values
+        .iter()
+        .map(|value| {
+            parse(value).ok_or_else(|| {
+                Status::new(Code::InvalidArgument, format!("Invalid {}: {}", key, value))
+            })
+        })
+        .collect::<Result<Vec<u64>, Status>>()
+        .map(Some)
+}
 
-    println!("Transcoding video: {}", &file_path);
-    println!("is_gpu = {}", &is_gpu);
+/// Parses a line of the `key=value` progress stream ffmpeg writes with `-progress` to calculate
+/// and return the transcoding progress as a percentage. Progress is taken from the
+/// `out_time_us` key, the position reached in the output in microseconds, relative to the
+/// total duration of the video. If the total duration is not positive, it returns 0 to
+/// prevent division by zero errors.
+///
+/// # Arguments
+/// * `line` - A string slice containing a line of ffmpeg progress output.
+/// * `total_duration` - The total duration of the video in seconds.
+///
+/// # Returns
+/// An `Option<i32>` representing the transcoding progress percentage, or `None` if the progress
+/// cannot be determined from the given line, e.g. for other keys or `out_time_us=N/A`.
+///
+fn parse_progress(line: &str, total_duration: f64) -> Option<i32> {
+    let (key, value) = line.trim().split_once('=')?;
+    if key != "out_time_us" {
+        return None;
+    }
+    let out_time_us = value.parse::<i64>().ok()?;
 
-    let total_duration = get_video_duration(file_path).unwrap_or_else(|_| 0.0);
-    println!("Total video duration: {} seconds", total_duration);
+    if total_duration <= 0.0 {
+        return Some(0); // Prevent division by zero
+    }
 
-    let mut encryption_key1: Vec<u8> = Vec::new();
+    let current_time_seconds = out_time_us.max(0) as f64 / 1_000_000.0;
+    let progress = ((current_time_seconds / total_duration) * 100.0).round() as i32;
+    Some(progress.min(100))
+}
 
-    let response: TranscodeVideoResponse;
+/// Adds the arguments for the constant quality mode of a GPU encoder, mapping a format's `crf`
+/// to NVENC's `-cq`, QSV's `-global_quality` or VAAPI's `-qp`. Other encoders don't support a
+/// quality mode, so they fall back to encoding by bitrate with a warning.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command.
+/// * `format` - The desired output video format.
+///
+/// # Returns
+/// Whether quality mode was used, in which case the bitrate isn't set.
+///
+fn add_gpu_quality_args(cmd: &mut Command, format: &VideoFormat) -> bool {
+    let crf = match format.crf {
+        Some(crf) => crf.to_string(),
+        None => return false,
+    };
+    let vcodec = format.vcodec.as_deref().unwrap_or_default();
+
+    if vcodec.ends_with("_nvenc") {
+        // -b:v 0 lifts NVENC's default bitrate cap, which would otherwise limit the quality
+        cmd.args(["-rc", "vbr", "-cq", crf.as_str(), "-b:v", "0"]);
+    } else if vcodec.ends_with("_qsv") {
+        cmd.args(["-global_quality", crf.as_str()]);
+    } else if vcodec.ends_with("_vaapi") {
+        cmd.args(["-qp", crf.as_str()]);
+    } else {
+        println!(
+            "Warning: {} has no constant quality mode, encoding by bitrate instead of crf {}",
+            vcodec, crf
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Returns whether a `vcodec` is a hardware encoder, i.e. one ending in `_nvenc`, `_qsv`,
+/// `_vaapi`, `_amf` or `_videotoolbox`.
+///
+/// # Arguments
+/// * `vcodec` - The name of the ffmpeg video encoder.
+///
+pub fn is_hardware_encoder(vcodec: &str) -> bool {
+    ["_nvenc", "_qsv", "_vaapi", "_amf", "_videotoolbox"]
+        .iter()
+        .any(|suffix| vcodec.ends_with(suffix))
+}
+
+/// Lists the encoders of the ffmpeg binary from `ffmpeg -encoders`, whose lines after the
+/// `------` separator start with the encoder's capability flags and then its name.
+///
+/// # Returns
+/// The names of the encoders, or `None` if ffmpeg can't be run.
+///
+fn list_ffmpeg_encoders() -> Option<HashSet<String>> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let encoders = stdout
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("------"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect();
+
+    Some(encoders)
+}
+
+/// Returns the encoders of the ffmpeg binary, sorted by name, empty if ffmpeg can't be run.
+pub fn available_encoders() -> Vec<String> {
+    let mut encoders: Vec<String> = FFMPEG_ENCODERS
+        .as_ref()
+        .map(|encoders| encoders.iter().cloned().collect())
+        .unwrap_or_default();
+    encoders.sort();
+    encoders
+}
+
+/// Returns whether the server has a GPU hardware encoders can use: an NVIDIA GPU listed by
+/// `nvidia-smi`, or a DRM render node.
+pub fn gpu_present() -> bool {
+    detect_gpu_count("h264_nvenc") > 0 || detect_gpu_count("h264_vaapi") > 0
+}
+
+/// Checks that `is_gpu` and the `vcodec` of each media format agree, since the GPU and CPU
+/// encoding options only suit their own kind of encoder: with `is_gpu` every video format must
+/// use a hardware encoder, without it none may, and audio-only formats can't be transcoded with
+/// `is_gpu`. A hardware encoder must also be one the ffmpeg binary was built with. Sprite
+/// formats and `copy` are accepted either way.
+///
+/// # Arguments
+/// * `is_gpu` - A boolean flag indicating whether GPU acceleration was requested.
+/// * `media_formats` - The requested media formats.
+///
+/// # Returns
+/// `Ok(())` if every format can be encoded as requested, otherwise an `InvalidArgument` status
+/// naming the first format that can't.
+///
+pub fn check_gpu_encoders(is_gpu: bool, media_formats: &[serde_json::Value]) -> Result<(), Status> {
+    for format in media_formats {
+        if format.get("sprite").is_some_and(|sprite| !sprite.is_null()) {
+            continue;
+        }
+
+        let id = &format["id"];
+        let vcodec = format["vcodec"].as_str().unwrap_or_default();
+        if vcodec == "copy" {
+            continue;
+        }
+
+        let message = if vcodec.is_empty() {
+            is_gpu.then(|| {
+                format!(
+                    "is_gpu is set, but media format {} has no vcodec; audio-only formats are transcoded on the CPU",
+                    id
+                )
+            })
+        } else if is_gpu && !is_hardware_encoder(vcodec) {
+            Some(format!(
+                "is_gpu is set, but media format {} uses the CPU encoder {}",
+                id, vcodec
+            ))
+        } else if !is_gpu && is_hardware_encoder(vcodec) {
+            Some(format!(
+                "Media format {} uses the hardware encoder {}, which requires is_gpu",
+                id, vcodec
+            ))
+        } else if is_gpu
+            && FFMPEG_ENCODERS
+                .as_ref()
+                .is_some_and(|encoders| !encoders.contains(vcodec))
+        {
+            Some(format!(
+                "Media format {} uses the encoder {}, which this ffmpeg build doesn't support",
+                id, vcodec
+            ))
+        } else {
+            None
+        };
+
+        if let Some(message) = message {
+            return Err(Status::new(Code::InvalidArgument, message));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether a format is actually encoded on a GPU. GPU transcoding passes the format's
+/// `vcodec` to ffmpeg as is, so a GPU transcode of a format with a software encoder such as
+/// `libx264`, or of an audio-only format, still runs on the CPU.
+///
+/// # Arguments
+/// * `is_gpu` - A boolean flag indicating whether GPU acceleration was requested.
+/// * `format` - The output video format.
+///
+pub fn format_encoder(is_gpu: bool, format: &VideoFormat) -> Encoder {
+    let vcodec = format.vcodec.as_deref().unwrap_or_default();
+
+    if is_gpu && is_hardware_encoder(vcodec) {
+        Encoder::Gpu
+    } else {
+        Encoder::Cpu
+    }
+}
+
+/// Returns the codec an ffmpeg encoder encodes to, as ffprobe names it, e.g. `h264` for
+/// `libx264` and `h264_nvenc`. Encoders named after their codec, such as `aac` or `flac`, are
+/// returned as is.
+fn encoder_codec(encoder: &str) -> &str {
+    match encoder {
+        "libx264" => "h264",
+        "libx265" => "hevc",
+        "libvpx" => "vp8",
+        "libvpx-vp9" => "vp9",
+        "libaom-av1" | "libsvtav1" | "librav1e" => "av1",
+        "libopus" => "opus",
+        "libvorbis" => "vorbis",
+        "libmp3lame" => "mp3",
+        "libfdk_aac" => "aac",
+        _ => encoder.split('_').next().unwrap_or(encoder),
+    }
+}
+
+/// Returns the audio encoder of a video format: its `acodec` if it has one, otherwise the
+/// default for its container. MP4, MOV, MPEG-TS and HLS outputs default to AAC, as opus in
+/// them isn't playable on many devices, using `libfdk_aac` if this ffmpeg build has it;
+/// other containers, such as WebM and MKV, default to opus.
+///
+/// # Arguments
+/// * `format` - The output video format.
+///
+fn video_audio_encoder(format: &VideoFormat) -> &str {
+    if let Some(acodec) = format.acodec.as_deref().filter(|acodec| !acodec.is_empty()) {
+        return acodec;
+    }
+
+    match format.ext.to_lowercase().as_str() {
+        "mp4" | "m4v" | "mov" | "ts" | "m3u8" => {
+            if FFMPEG_ENCODERS
+                .as_ref()
+                .is_some_and(|encoders| encoders.contains("libfdk_aac"))
+            {
+                "libfdk_aac"
+            } else {
+                "aac"
+            }
+        }
+        _ => "libopus",
+    }
+}
+
+/// Returns whether `value` is within `tolerance` percent of `target`.
+fn within_tolerance(value: f64, target: f64, tolerance: f64) -> bool {
+    (value - target).abs() <= target * tolerance / 100.0
+}
+
+/// Checks whether the source already satisfies a format closely enough to be stream-copied
+/// rather than re-encoded. For a video format, the source's video codec must be the one the
+/// format's `vcodec` encodes to, its width and height within `COPY_RESOLUTION_TOLERANCE`
+/// percent of the format's, and its bitrate no more than `COPY_BITRATE_TOLERANCE` percent
+/// above the format's `b_v` (or `maxrate`) plus `b_a`; the audio is still encoded. For an
+/// audio-only format, the source must have no video and its audio must match the format's
+/// `acodec`, `ch` and `ar`, with its bitrate within the tolerance of `b_a`.
+///
+/// # Arguments
+/// * `format` - The output video format.
+/// * `media_info` - The probed properties of the source.
+///
+/// # Returns
+/// `Ok(())` if the format can be copied, otherwise the reason it can't.
+///
+fn source_satisfies(format: &VideoFormat, media_info: &MediaInfo) -> Result<(), String> {
+    if format.video_stream.is_some() || format.audio_stream.is_some() {
+        return Err(String::from("it selects its own streams"));
+    }
+
+    let bitrate_within = |target: u64| match media_info.bitrate {
+        Some(bitrate) => bitrate as f64 <= target as f64 * (1.0 + *COPY_BITRATE_TOLERANCE / 100.0),
+        None => false,
+    };
+
+    let vcodec = format.vcodec.as_deref().unwrap_or_default();
+    if !vcodec.is_empty() {
+        if vcodec == "copy" {
+            return Err(String::from("its vcodec is already copy"));
+        }
+        if media_info.video_codec.as_deref() != Some(encoder_codec(vcodec)) {
+            return Err(format!(
+                "the source's video codec {:?} isn't encoded by {}",
+                media_info.video_codec, vcodec
+            ));
+        }
+
+        // Only scaling may be left undone, any other filter changes the video
+        let filters = format.vf.as_deref().unwrap_or_default();
+        if filters
+            .split(',')
+            .any(|filter| !filter.trim().is_empty() && !filter.trim().starts_with("scale="))
+        {
+            return Err(format!("its vf {} filters the video", filters));
+        }
+
+        let (width, height) = output_dimensions(format, media_info);
+        for (dimension, source_dimension) in
+            [(width, media_info.width), (height, media_info.height)]
+        {
+            match (dimension, source_dimension) {
+                (Some(dimension), Some(source_dimension))
+                    if within_tolerance(
+                        source_dimension as f64,
+                        dimension as f64,
+                        *COPY_RESOLUTION_TOLERANCE,
+                    ) => {}
+                _ => return Err(String::from("the source's resolution doesn't match")),
+            }
+        }
+
+        let video_bitrate = format
+            .b_v
+            .as_deref()
+            .or(format.maxrate.as_deref())
+            .and_then(parse_bitrate);
+        if let Some(video_bitrate) = video_bitrate {
+            let audio_bitrate = parse_bitrate(format.b_a.as_deref().unwrap_or("192k"));
+            if !bitrate_within(video_bitrate + audio_bitrate.unwrap_or_default()) {
+                return Err(String::from("the source's bitrate is too high"));
+            }
+        }
+
+        return Ok(());
+    }
+
+    let acodec = format.acodec.as_deref().unwrap_or_default();
+    if acodec.is_empty() {
+        return Err(String::from("it has no codec"));
+    }
+    if media_info.video_codec.is_some() {
+        return Err(String::from("the source has video"));
+    }
+    if media_info.audio_codec.as_deref() != Some(encoder_codec(acodec)) {
+        return Err(format!(
+            "the source's audio codec {:?} isn't encoded by {}",
+            media_info.audio_codec, acodec
+        ));
+    }
+    if format
+        .ch
+        .is_some_and(|ch| media_info.channels != Some(ch as u32))
+    {
+        return Err(String::from("the source's channels don't match"));
+    }
+    if format
+        .ar
+        .as_deref()
+        .and_then(parse_bitrate)
+        .is_some_and(|ar| media_info.sample_rate.map(u64::from) != Some(ar))
+    {
+        return Err(String::from("the source's sample rate doesn't match"));
+    }
+    if let Some(audio_bitrate) = format.b_a.as_deref().and_then(parse_bitrate) {
+        if !bitrate_within(audio_bitrate) {
+            return Err(String::from("the source's bitrate is too high"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the ffmpeg arguments that stream-copy the input to a format the source satisfies. The
+/// video of a video format is copied while its audio is encoded as `add_encode_args` would, and
+/// the audio of an audio-only format is copied.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command, with its input already added.
+/// * `format` - The output video format.
+///
+fn add_copy_args(cmd: &mut Command, format: &VideoFormat) -> Result<(), Status> {
+    if format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty())
+    {
+        println!("Copying video");
+
+        add_arg(cmd, "-c:v", Some("copy"));
+        add_arg(cmd, "-tag:v", video_tag(format)?.as_deref());
+        add_arg(cmd, "-c:a", Some(video_audio_encoder(format)));
+        add_arg(cmd, "-b:a", format.b_a.as_deref().or(Some("192k")));
+        if let Some(ch) = format.ch {
+            add_arg(cmd, "-ac", Some(&ch.to_string()));
+        }
+        add_arg(cmd, "-ar", format.ar.as_deref());
+    } else {
+        println!("Copying audio");
+
+        add_arg(cmd, "-c:a", Some("copy"));
+    }
+
+    Ok(())
+}
+
+/// Checks that the `video_stream` and `audio_stream` a format selects are streams of the source
+/// of that type. Nothing is checked if the source couldn't be probed.
+///
+/// # Arguments
+/// * `format` - The output video format.
+/// * `media_info` - The probed properties of the source.
+///
+/// # Returns
+/// `Ok(())` if the streams exist, otherwise an `InvalidArgument` status listing the source's
+/// streams.
+///
+fn check_stream_indices(format: &VideoFormat, media_info: &MediaInfo) -> Result<(), Status> {
+    if media_info.streams.is_empty() {
+        return Ok(());
+    }
+
+    for (field, index, codec_type) in [
+        ("video_stream", format.video_stream, "video"),
+        ("audio_stream", format.audio_stream, "audio"),
+    ] {
+        let index = match index {
+            Some(index) => index,
+            None => continue,
+        };
+
+        let exists = media_info
+            .streams
+            .iter()
+            .any(|stream| stream.index == index && stream.codec_type == codec_type);
+        if !exists {
+            let streams: Vec<String> = media_info
+                .streams
+                .iter()
+                .map(|stream| format!("{}: {}", stream.index, stream.codec_type))
+                .collect();
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "{} {} is not a {} stream of the source, whose streams are {}",
+                    field,
+                    index,
+                    codec_type,
+                    streams.join(", ")
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the `-map` options selecting the input streams of a format with a `video_stream` or
+/// `audio_stream`. Mapping one stream turns off ffmpeg's automatic stream selection, so the
+/// first stream of the other type is mapped too, if the source has one.
+fn add_stream_map_args(cmd: &mut Command, format: &VideoFormat) {
+    if format.video_stream.is_none() && format.audio_stream.is_none() {
+        return;
+    }
+
+    let video_map = match format.video_stream {
+        Some(index) => format!("0:{}", index),
+        None => String::from("0:v:0?"),
+    };
+    let audio_map = match format.audio_stream {
+        Some(index) => format!("0:{}", index),
+        None => String::from("0:a:0?"),
+    };
+    cmd.args(["-map", video_map.as_str(), "-map", audio_map.as_str()]);
+}
+
+/// Adds `-movflags +faststart` for an MP4-family output, unless disabled by the format's
+/// `faststart` or `MP4_FASTSTART`. ffmpeg then makes a second pass over the finished file to
+/// move its index to the front, so that players can start before it is fully downloaded. Other
+/// containers, including HLS outputs, are left as they are.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command, with its input already added.
+/// * `format` - The output video format.
+///
+fn add_faststart_args(cmd: &mut Command, format: &VideoFormat) {
+    if format.faststart.unwrap_or(*MP4_FASTSTART)
+        && FASTSTART_CONTAINERS.contains(&format.ext.as_str())
+    {
+        cmd.args(["-movflags", "+faststart"]);
+    }
+}
+
+/// Returns the path pattern ffmpeg writes the segments of an HLS output to, e.g.
+/// `video_1_00000.ts` for the first segment of the playlist `video_1.m3u8`.
+fn hls_segment_pattern(output_path: &str) -> String {
+    format!("{}_%05d.ts", output_path.trim_end_matches(".m3u8"))
+}
+
+/// Adds the ffmpeg arguments that write a format as a VOD HLS playlist and its MPEG-TS
+/// segments. Keyframes are forced at every multiple of the segment duration, and nowhere else
+/// on a scene change, so every rendition of a ladder with the same duration is cut at the same
+/// timestamps and players can switch between them at any segment. Copied video can't be given
+/// keyframes, so it is cut at its own.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command, with the format's encoding options already added.
+/// * `format` - The output video format, whose `ext` is `m3u8`.
+/// * `output_path` - The path of the playlist.
+///
+fn add_hls_args(cmd: &mut Command, format: &VideoFormat, output_path: &str) {
+    let segment_secs = format.hls_time.unwrap_or(*HLS_SEGMENT_SECS);
+
+    let vcodec = format.vcodec.as_deref().unwrap_or_default();
+    if !vcodec.is_empty() && !format.stream_copy {
+        cmd.arg("-force_key_frames")
+            .arg(format!("expr:gte(t,n_forced*{})", segment_secs));
+        cmd.args(["-sc_threshold", "0"]);
+        if vcodec.ends_with("_nvenc") {
+            // NVENC otherwise forces non-IDR keyframes, which a segment can't start with
+            cmd.args(["-forced-idr", "1"]);
+        }
+    }
+
+    cmd.args([
+        "-f",
+        "hls",
+        "-hls_playlist_type",
+        "vod",
+        "-hls_list_size",
+        "0",
+    ]);
+    cmd.arg("-hls_time").arg(segment_secs.to_string());
+    cmd.arg("-hls_segment_filename")
+        .arg(hls_segment_pattern(output_path));
+}
+
+/// Uploads the segments of a transcoded HLS playlist, then rewrites the playlist in place to
+/// refer to them by their `s5://` or `ipfs://` CIDs, so that the uploaded playlist is playable
+/// on its own.
+///
+/// # Arguments
+/// * `playlist_path` - The path of the playlist, whose segments are in the same directory.
+/// * `dest` - The storage network to upload the segments to.
+///
+async fn publish_hls_segments(playlist_path: &str, dest: Option<String>) -> Result<(), Status> {
+    let playlist = std::fs::read_to_string(playlist_path).map_err(|e| {
+        Status::internal(format!(
+            "Failed to read HLS playlist {}: {}",
+            playlist_path, e
+        ))
+    })?;
+    let dir = Path::new(playlist_path)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let scheme = match dest.as_deref() {
+        Some("ipfs") => "ipfs",
+        _ => "s5",
+    };
+
+    let mut published = Vec::new();
+    for line in playlist.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            published.push(line.to_string());
+            continue;
+        }
+
+        let segment_path = dir.join(line.trim()).to_string_lossy().to_string();
+        let uploaded = upload_video(&segment_path, dest.clone())
+            .await
+            .map_err(|e| {
+                Status::internal(format!(
+                    "Failed to upload HLS segment {}: {}",
+                    segment_path, e
+                ))
+            })?;
+        published.push(format!("{}://{}", scheme, uploaded.cid));
+    }
+
+    std::fs::write(playlist_path, published.join("\n") + "\n").map_err(|e| {
+        Status::internal(format!(
+            "Failed to write HLS playlist {}: {}",
+            playlist_path, e
+        ))
+    })
+}
+
+/// Adds the ffmpeg arguments that encode the input to a format, i.e. those between the input
+/// and the output file.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command, with its input already added.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `format` - The desired output video format.
+///
+/// # Returns
+/// A `Result<(), Status>` that is an `InvalidArgument` error if the format has no codec.
+///
+pub fn add_encode_args(
+    cmd: &mut Command,
+    is_gpu: bool,
+    format: &VideoFormat,
+) -> Result<(), Status> {
+    add_stream_map_args(cmd, format);
+    add_faststart_args(cmd, format);
+
+    if format.stream_copy {
+        return add_copy_args(cmd, format);
+    }
+
+    if is_gpu {
+        println!("GPU transcoding");
+
+        add_arg(cmd, "-c:v", format.vcodec.as_deref());
+        if let Some(gpu_index) = format.gpu_index {
+            if format
+                .vcodec
+                .as_deref()
+                .unwrap_or_default()
+                .ends_with("_nvenc")
+            {
+                add_arg(cmd, "-gpu", Some(&gpu_index.to_string()));
+            }
+        }
+        add_arg(cmd, "-tag:v", video_tag(format)?.as_deref());
+        if !add_gpu_quality_args(cmd, format) {
+            add_arg(cmd, "-b:v", format.b_v.as_deref());
+        }
+        add_arg(cmd, "-c:a", Some(video_audio_encoder(format)));
+        add_arg(cmd, "-b:a", format.b_a.as_deref().or(Some("192k")));
+        if let Some(ch) = format.ch {
+            add_arg(cmd, "-ac", Some(&ch.to_string()));
+        }
+        add_arg(cmd, "-ar", format.ar.as_deref());
+        add_arg(cmd, "-vf", format.vf.as_deref());
+        if let Some(ref minrate) = format.minrate {
+            cmd.args(["-minrate", minrate]);
+        }
+
+        if let Some(ref maxrate) = format.maxrate {
+            cmd.args(["-maxrate", maxrate]);
+        }
+
+        if let Some(ref bufsize) = format.bufsize {
+            cmd.args(["-bufsize", bufsize]);
+        }
+    } else {
+        println!("CPU transcoding");
+
+        if let Some(vcodec) = &format.vcodec {
+            if !vcodec.is_empty() {
+                add_arg(cmd, "-c:v", format.vcodec.as_deref());
+                add_arg(cmd, "-tag:v", video_tag(format)?.as_deref());
+                if is_hevc(vcodec) {
+                    // x265 has no -cpu-used, its encoding speed is set by the preset
+                    add_arg(cmd, "-preset", format.preset.as_deref().or(Some("medium")));
+                    add_arg(cmd, "-b:v", format.b_v.as_deref());
+                    add_arg(cmd, "-crf", Some(&format.crf.unwrap_or(28).to_string()));
+                // set quality level, 28 by default (range 0-51, lower is better)
+                } else {
+                    add_arg(cmd, "-cpu-used", Some("4")); // set encoding speed to 4 (range 0-8, lower is slower)
+                    add_arg(cmd, "-b:v", format.b_v.as_deref());
+                    add_arg(cmd, "-crf", Some(&format.crf.unwrap_or(30).to_string()));
+                    // set quality level, 30 by default (range 0-63, lower is better)
+                }
+                add_arg(cmd, "-c:a", Some(video_audio_encoder(format)));
+                add_arg(cmd, "-b:a", format.b_a.as_deref().or(Some("192k")));
+                if let Some(ch) = format.ch {
+                    add_arg(cmd, "-ac", Some(&ch.to_string()));
+                }
+                add_arg(cmd, "-vf", format.vf.as_deref());
+            } else {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "No video codec specified",
+                ));
+            }
+        } else if let Some(acodec) = &format.acodec {
+            if !acodec.is_empty() {
+                println!("Transcoding audio");
+                add_arg(cmd, "-acodec", format.acodec.as_deref());
+                if let Some(ch) = format.ch {
+                    add_arg(cmd, "-ac", Some(&ch.to_string()));
+                }
+                add_arg(cmd, "-ar", format.ar.as_deref());
+                add_arg(cmd, "-b:a", format.b_a.as_deref());
+
+                if let Some(compression_level) = format.compression_level {
+                    add_arg(
+                        cmd,
+                        "-compression_level",
+                        Some(&compression_level.to_string()),
+                    );
+                }
+            } else {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "No audio codec specified",
+                ));
+            }
+        } else {
+            return Err(Status::new(Code::InvalidArgument, "No codec specified"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the output width and height of a format from the `scale` filter of its `vf`, e.g.
+/// `scale=1280x720` or `scale=1280:-2`. A dimension the filter leaves to ffmpeg is scaled in
+/// proportion to the source, and without a `scale` filter the source's dimensions are used.
+///
+/// # Arguments
+/// * `format` - The output video format.
+/// * `media_info` - The probed properties of the source.
+///
+fn output_dimensions(format: &VideoFormat, media_info: &MediaInfo) -> (Option<u32>, Option<u32>) {
+    let scale = format.vf.as_deref().and_then(|vf| {
+        vf.split(',')
+            .find_map(|filter| filter.trim().strip_prefix("scale="))
+    });
+    let scale = match scale {
+        Some(scale) => scale,
+        None => return (media_info.width, media_info.height),
+    };
+
+    let mut dimensions = scale.split(['x', ':']).map(|dimension| {
+        dimension
+            .trim_start_matches("w=")
+            .trim_start_matches("h=")
+            .parse::<u32>()
+            .ok()
+    });
+    let width = dimensions.next().flatten();
+    let height = dimensions.next().flatten();
+
+    let proportional = |dimension: u32, from: Option<u32>, to: Option<u32>| match (from, to) {
+        (Some(from), Some(to)) if from > 0 => {
+            Some((dimension as f64 * to as f64 / from as f64).round() as u32)
+        }
+        _ => None,
+    };
+    match (width, height) {
+        (Some(width), None) => (
+            Some(width),
+            proportional(width, media_info.width, media_info.height),
+        ),
+        (None, Some(height)) => (
+            proportional(height, media_info.height, media_info.width),
+            Some(height),
+        ),
+        dimensions => dimensions,
+    }
+}
+
+/// Replaces the placeholders `{source}`, `{id}`, `{width}`, `{height}`, `{b_a}`, `{layout}`
+/// and `{ext}` of a `name_template` or `prefix` with the properties of a format's output.
+fn render_placeholders(
+    template: &str,
+    source: &str,
+    format: &VideoFormat,
+    media_info: &MediaInfo,
+) -> String {
+    let (width, height) = output_dimensions(format, media_info);
+    template
+        .replace("{source}", source)
+        .replace("{id}", &format.id.to_string())
+        .replace("{width}", &width.map(|w| w.to_string()).unwrap_or_default())
+        .replace(
+            "{height}",
+            &height.map(|h| h.to_string()).unwrap_or_default(),
+        )
+        .replace("{b_a}", format.b_a.as_deref().unwrap_or_default())
+        .replace("{layout}", format.layout.as_deref().unwrap_or_default())
+        .replace("{ext}", &format.ext)
+}
+
+/// Renders the `prefix` of a format into the folder its output is placed in, ending with `/`.
+/// The rendered prefix is checked again, as a placeholder may have been replaced with
+/// characters a prefix can't contain.
+///
+/// # Arguments
+/// * `prefix` - The prefix, e.g. `assets/{source}/{height}p/`.
+/// * `source` - The name of the source file, without its extension.
+/// * `format` - The output video format.
+/// * `media_info` - The probed properties of the source.
+///
+/// # Returns
+/// The folder, or an `InvalidArgument` status if it isn't a valid prefix once rendered.
+///
+fn render_prefix(
+    prefix: &str,
+    source: &str,
+    format: &VideoFormat,
+    media_info: &MediaInfo,
+) -> Result<String, Status> {
+    let mut prefix = render_placeholders(prefix, source, format, media_info);
+    check_prefix(&prefix)?;
+    if prefix.contains(['{', '}']) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid prefix '{}': unknown placeholder", prefix),
+        ));
+    }
+
+    if !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    Ok(prefix)
+}
+
+/// Renders the `name_template` of a format into the file name its output is uploaded as,
+/// replacing the placeholders of `render_placeholders`.
+/// The name is sanitized, so that it can't contain a path separator or `..` and always stays
+/// within `PATH_TO_TRANSCODED_FILE`, and `.{ext}` is appended if it doesn't already end with
+/// it, since ffmpeg chooses the container from the extension.
+///
+/// # Arguments
+/// * `template` - The naming template, e.g. `{source}_{height}p.{ext}`.
+/// * `source` - The name of the source file, without its extension.
+/// * `format` - The output video format.
+/// * `media_info` - The probed properties of the source.
+///
+/// # Returns
+/// The file name, or an `InvalidArgument` status if it is empty once sanitized.
+///
+fn render_output_name(
+    template: &str,
+    source: &str,
+    format: &VideoFormat,
+    media_info: &MediaInfo,
+) -> Result<String, Status> {
+    let mut name = sanitize(render_placeholders(template, source, format, media_info));
+    if name.is_empty() {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!("name_template {} renders an empty file name", template),
+        ));
+    }
+
+    let ext = format!(".{}", format.ext);
+    if !name.ends_with(&ext) {
+        name.push_str(&ext);
+    }
+    Ok(name)
+}
+
+/// Executes the ffmpeg command to transcode a video file based on the specified parameters.
+/// This function supports GPU acceleration and handles various video formats. Given several
+/// outputs, a single ffmpeg command decodes the input once and encodes every output from it,
+/// and its progress is reported for each of their formats.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `file_path` - The path to the input video file to be transcoded.
+/// * `outputs` - The format index, desired output video format and path to write the
+///   transcoded video file to, of each output. Their GPU device options must be the same.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `total_duration` - The total duration of the video file in seconds.
+/// * `stream_url` - The URL to stream the input from into ffmpeg's stdin while it transcodes,
+///   instead of reading `file_path`.
+///
+/// # Returns
+/// A `Result<(), Status>` indicating the success or failure of the transcoding operation. If
+/// ffmpeg fails, the `Internal` status includes the end of its log, see `ffmpeg_error_tail`.
+/// A streamed input also fails the operation if its download fails, even if ffmpeg succeeded
+/// with the part of it that arrived.
+///
+fn run_ffmpeg(
+    task_id: String,
+    file_path: &str,
+    outputs: &[(usize, &VideoFormat, &str)],
+    is_gpu: bool,
+    total_duration: f64,
+    stream_url: Option<&str>,
+) -> Result<(), Status> {
+    let mut cmd = Command::new("ffmpeg");
+    // Ensure verbose output for detailed progress information
+    cmd.arg("-v").arg("info");
+    // Structured progress goes to stdout, keeping it apart from the human-readable log on stderr
+    cmd.arg("-progress").arg("pipe:1");
+    cmd.arg("-stats_period").arg("1");
+
+    if let Some((_, format, _)) = outputs.first() {
+        add_gpu_device_args(&mut cmd, is_gpu, format)?;
+    }
+    add_arg(
+        &mut cmd,
+        "-i",
+        Some(if stream_url.is_some() {
+            "pipe:0"
+        } else {
+            file_path
+        }),
+    );
+    for (_, format, output_path) in outputs {
+        add_encode_args(&mut cmd, is_gpu, format)?;
+        if format.ext.eq_ignore_ascii_case("m3u8") {
+            add_hls_args(&mut cmd, format, output_path);
+        }
+        add_arg(&mut cmd, "-y", Some(output_path));
+    }
+
+    // Capture the progress stream on stdout and the log on stderr
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    if stream_url.is_some() {
+        cmd.stdin(Stdio::piped());
+    }
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| Status::new(Code::Internal, format!("Failed to start ffmpeg: {}", e)))?;
+    // Track the process, so that the task can be paused while ffmpeg runs
+    shared::register_process(&task_id, child.id());
+
+    // Download a streamed input into ffmpeg's stdin on its own thread, closing it at the end
+    let stream_thread = stream_url.zip(child.stdin.take()).map(|(url, mut stdin)| {
+        let url = url.to_string();
+        let stream_task_id = task_id.clone();
+        std::thread::spawn(move || {
+            download_to(&url, &mut stdin, &stream_task_id).map_err(|e| e.to_string())
+        })
+    });
+
+    // Drain the log on its own thread, so ffmpeg never blocks on a full stderr pipe, keeping
+    // its last lines to report if it fails and sending each line to clients following the task
+    let log_task_id = task_id.clone();
+    let log_thread = child.stderr.take().map(|stderr| {
+        std::thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(FFMPEG_ERROR_LINES);
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                println!("£££££ {} £££££", line);
+                shared::publish_log(&log_task_id, &line);
+                if is_ffmpeg_error_line(&line) {
+                    if tail.len() == FFMPEG_ERROR_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            }
+            tail
+        })
+    });
+
+    if total_duration <= 0.0 {
+        println!("Unknown duration, progress will only be reported once transcoded");
+    }
+
+    // Take the stdout handle if available
+    if let Some(stdout) = child.stdout.take() {
+        let reader = BufReader::new(stdout);
 
-    run_ffmpeg(
-        task_id,
+        for line_result in reader.lines() {
+            if let Ok(line) = line_result {
+                if let Some(progress) = parse_progress(&line, total_duration) {
+                    // Update the global progress map
+                    for (format_index, _, _) in outputs {
+                        shared::update_progress(
+                            &task_id,
+                            *format_index,
+                            Phase::Transcode,
+                            progress,
+                        );
+                    }
+                    println!("Progress: {}%", progress);
+                }
+            }
+        }
+    }
+
+    let log_tail = log_thread
+        .and_then(|log_thread| log_thread.join().ok())
+        .unwrap_or_default();
+
+    // Wait for ffmpeg to finish
+    let output = child.wait();
+    shared::unregister_process(&task_id);
+    let output = output
+        .map_err(|e| Status::new(Code::Internal, format!("Failed to wait for ffmpeg: {}", e)))?;
+    println!("Transcode finished with status: {}", output);
+
+    // A download cut short can leave ffmpeg with a truncated input that it still transcodes
+    if let Some(stream_thread) = stream_thread {
+        let streamed = stream_thread
+            .join()
+            .unwrap_or_else(|_| Err(String::from("Streaming thread panicked")));
+        if let Err(e) = streamed {
+            return Err(Status::new(
+                Code::Internal,
+                format!("Failed to stream the source: {}", e),
+            ));
+        }
+    }
+
+    if !output.success() {
+        return Err(Status::new(
+            Code::Internal,
+            format!(
+                "ffmpeg failed with {}: {}",
+                output,
+                ffmpeg_error_tail(log_tail)
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns whether a line of ffmpeg's log may explain a failure, rather than being one of its
+/// periodic `frame=...`/`size=...` statistics or a blank line.
+fn is_ffmpeg_error_line(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with("frame=") && !line.starts_with("size=")
+}
+
+/// Joins the last lines of ffmpeg's log into the message of a failed rendition, keeping at most
+/// the last `FFMPEG_ERROR_MAX_CHARS` characters, since the error is at the end.
+fn ffmpeg_error_tail(lines: VecDeque<String>) -> String {
+    let tail = Vec::from(lines).join("\n");
+    let chars = tail.chars().count();
+    if chars <= FFMPEG_ERROR_MAX_CHARS {
+        return tail;
+    }
+
+    let truncated: String = tail.chars().skip(chars - FFMPEG_ERROR_MAX_CHARS).collect();
+    format!("...{}", truncated)
+}
+
+/// Asynchronously transcodes a video from a given format to another using ffmpeg,
+/// based on the specified transcoder settings. This function supports optional
+/// encryption and GPU acceleration.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index specifying the target video format from a predefined list.
+/// * `file_path` - The path to the input video file to be transcoded.
+/// * `video_format` - The desired output video format.
+/// * `is_encrypted` - A boolean flag indicating whether the output video should be encrypted.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `media_info` - The probed properties of the input video file.
+///
+/// # Returns
+/// A `Result` wrapping a `Response` with the `TranscodeVideoResponse` on success,
+/// or a `Status` error if transcoding, encrypting or uploading the rendition fails.
+///
+pub async fn transcode_video(
+    task_id: String,
+    format_index: usize,
+    file_path: &str,
+    video_format: &str,
+    is_encrypted: bool,
+    is_gpu: bool,
+    media_info: &MediaInfo,
+) -> Result<Response<TranscodeVideoResponse>, Status> {
+    println!("transcode_video: Processing video at: {}", file_path);
+    println!("transcode_video: video_format: {}", video_format);
+    println!("transcode_video: is_encrypted: {}", is_encrypted);
+    println!("transcode_video: is_gpu: {}", is_gpu);
+
+    let format = get_video_format_from_str(video_format)?;
+    let (source_name, file_name) = output_file_name(file_path, &format)?;
+
+    println!("Transcoding video: {}", &file_path);
+    println!("is_gpu = {}", &is_gpu);
+
+    let total_duration = media_info.duration;
+    println!("Total video duration: {} seconds", total_duration);
+
+    if let Some(sprite) = &format.sprite {
+        if is_encrypted {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Sprite generation is not supported for encrypted videos",
+            ));
+        }
+
+        let response = generate_sprites(
+            &task_id,
+            format_index,
+            file_path,
+            &format!("{}{}", *PATH_TO_TRANSCODED_FILE, file_name),
+            sprite,
+            total_duration,
+            format.dest.clone(),
+        )
+        .await?;
+
+        return Ok(Response::new(response));
+    }
+
+    let rendition = prepare_rendition(
+        &task_id,
         format_index,
-        file_path,
+        &source_name,
         &file_name,
+        format,
+        is_encrypted,
+        is_gpu,
+        media_info,
+    )?;
+
+    run_ffmpeg(
+        task_id.clone(),
+        file_path,
+        &[(
+            format_index,
+            &rendition.format,
+            rendition.transcoded_path.as_str(),
+        )],
         is_gpu,
-        &format,
         total_duration,
+        None,
     )?;
 
-    if is_encrypted {
-        match encrypt_file_xchacha20(
+    finish_rendition(task_id, format_index, rendition, is_encrypted).await
+}
+
+/// Transcodes several formats of a video file with a single ffmpeg command, so the input is
+/// downloaded, demuxed and decoded once, then encrypts and uploads each rendition as
+/// `transcode_video` does. The progress of the command is reported for every format.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `formats` - The format index and desired output video format of each format to transcode.
+/// * `file_path` - The path to the input video file to be transcoded.
+/// * `is_encrypted` - A boolean flag indicating whether the output video should be encrypted.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `media_info` - The probed properties of the input video file.
+/// * `stream_url` - The URL to stream the input from while it is transcoded, rather than
+///   reading it from `file_path`, which then only names the outputs. A single format is
+///   transcoded too, since the input is only streamed once.
+///
+/// # Returns
+/// The format index and result of each format, or `None` if the formats can't be transcoded
+/// together and should each be transcoded with `transcode_video` instead: fewer than two
+/// formats, sprite sheets, formats needing different GPU device options, or a failure of the
+/// combined ffmpeg command, so one bad format doesn't fail the others.
+///
+pub async fn transcode_videos(
+    task_id: String,
+    formats: &[(usize, String)],
+    file_path: &str,
+    is_encrypted: bool,
+    is_gpu: bool,
+    media_info: &MediaInfo,
+    stream_url: Option<&str>,
+) -> Option<Vec<(usize, Result<Response<TranscodeVideoResponse>, Status>)>> {
+    if formats.is_empty() || (formats.len() < 2 && stream_url.is_none()) {
+        return None;
+    }
+
+    let mut parsed = Vec::new();
+    for (format_index, video_format) in formats {
+        let format = get_video_format_from_str(video_format).ok()?;
+        if format.sprite.is_some() {
+            return None;
+        }
+        parsed.push((*format_index, format));
+    }
+
+    // All outputs share the input, so they must share its hardware decoding options
+    let device_args = |format: &VideoFormat| {
+        let mut cmd = Command::new("ffmpeg");
+        add_gpu_device_args(&mut cmd, is_gpu, format).ok()?;
+        Some(
+            cmd.get_args()
+                .map(|arg| arg.to_os_string())
+                .collect::<Vec<_>>(),
+        )
+    };
+    let first_device_args = device_args(&parsed[0].1)?;
+    for (_, format) in &parsed[1..] {
+        if device_args(format)? != first_device_args {
+            return None;
+        }
+    }
+
+    let mut renditions = Vec::new();
+    for (format_index, format) in parsed {
+        // Check the encode options now, rather than failing the combined command
+        add_encode_args(&mut Command::new("ffmpeg"), is_gpu, &format).ok()?;
+
+        let (source_name, file_name) = output_file_name(file_path, &format).ok()?;
+        let rendition = prepare_rendition(
+            &task_id,
+            format_index,
+            &source_name,
+            &file_name,
+            format,
+            is_encrypted,
+            is_gpu,
+            media_info,
+        )
+        .ok()?;
+        renditions.push((format_index, rendition));
+    }
+
+    println!(
+        "Transcoding video: {} to {} formats with a single ffmpeg command",
+        &file_path,
+        renditions.len()
+    );
+    println!("is_gpu = {}", &is_gpu);
+
+    let total_duration = media_info.duration;
+    println!("Total video duration: {} seconds", total_duration);
+
+    let outputs: Vec<(usize, &VideoFormat, &str)> = renditions
+        .iter()
+        .map(|(format_index, rendition)| {
+            (
+                *format_index,
+                &rendition.format,
+                rendition.transcoded_path.as_str(),
+            )
+        })
+        .collect();
+    if let Err(e) = run_ffmpeg(
+        task_id.clone(),
+        file_path,
+        &outputs,
+        is_gpu,
+        total_duration,
+        stream_url,
+    ) {
+        eprintln!(
+            "Task {}: single ffmpeg command failed, transcoding each format separately: {}",
+            task_id,
+            e.message()
+        );
+        return None;
+    }
+
+    let mut results = Vec::new();
+    for (format_index, rendition) in renditions {
+        let result = finish_rendition(task_id.clone(), format_index, rendition, is_encrypted).await;
+        results.push((format_index, result));
+    }
+
+    Some(results)
+}
+
+/// Returns the name of the source file without its extension, and the name its outputs for a
+/// format are given by default, without an extension.
+///
+/// # Arguments
+/// * `file_path` - The path to the input video file.
+/// * `format` - The output video format.
+///
+fn output_file_name(file_path: &str, format: &VideoFormat) -> Result<(String, String), Status> {
+    let source_name = Path::new(file_path)
+        .file_stem()
+        .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid file path"))?
+        .to_string_lossy()
+        .to_string();
+
+    // Renditions expanded from an audio bitrate ladder share an id, so include the bitrate
+    let mut file_name = match &format.b_a {
+        Some(b_a) => format!("{}_{}_{}", source_name, format.id, sanitize(b_a)),
+        None => format!("{}_{}", source_name, format.id),
+    };
+    // As do renditions expanded from audio sample rates and channels, so include their layout
+    if let Some(layout) = &format.layout {
+        file_name = format!("{}_{}", file_name, sanitize(layout));
+    }
+
+    Ok((source_name, file_name))
+}
+
+/// A format about to be transcoded, with the files it is transcoded and encrypted to.
+struct Rendition {
+    format: VideoFormat,
+    /// The file name the rendition is uploaded as, if the format has a `name_template`.
+    output_name: Option<String>,
+    /// The path the rendition is uploaded to, its rendered `prefix` followed by the name of the
+    /// uploaded file, if the format has a `prefix`.
+    key: Option<String>,
+    /// The file ffmpeg transcodes to.
+    transcoded_path: String,
+    /// The file the transcoded file is encrypted to, for encrypted videos.
+    encrypted_path: String,
+    encoder: Encoder,
+}
+
+/// Works out the files a format is transcoded and encrypted to, checks the source streams it
+/// selects and records whether it is encoded on a GPU or the CPU, or copied if
+/// `COPY_COMPATIBLE` is set and the source already satisfies it.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index specifying the target video format from a predefined list.
+/// * `source_name` - The name of the source file without its extension.
+/// * `file_name` - The default name of the format's outputs, from `output_file_name`.
+/// * `format` - The desired output video format.
+/// * `is_encrypted` - A boolean flag indicating whether the output video should be encrypted.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `media_info` - The probed properties of the input video file.
+///
+#[allow(clippy::too_many_arguments)]
+fn prepare_rendition(
+    task_id: &str,
+    format_index: usize,
+    source_name: &str,
+    file_name: &str,
+    mut format: VideoFormat,
+    is_encrypted: bool,
+    is_gpu: bool,
+    media_info: &MediaInfo,
+) -> Result<Rendition, Status> {
+    let output_name = match &format.name_template {
+        Some(template) => Some(render_output_name(
+            template,
+            source_name,
+            &format,
+            media_info,
+        )?),
+        None => None,
+    };
+
+    // The file ffmpeg transcodes to, and the file it is encrypted to for encrypted videos. With a
+    // `name_template`, whichever of them is uploaded gets the rendered name.
+    let transcoded_path = match &output_name {
+        Some(name) if !is_encrypted => format!("{}{}", *PATH_TO_TRANSCODED_FILE, name),
+        Some(name) => {
+            let stem = Path::new(name).file_stem().unwrap_or_default();
             format!(
                 "{}{}_ue.{}",
-                *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-            ),
-            format!("{}{}.{}", *PATH_TO_TRANSCODED_FILE, file_name, format.ext),
-            0,
-        ) {
-            Ok(bytes) => {
-                // Encryption succeeded, and `bytes` contains the encrypted data
-                // Add your success handling code here
-                encryption_key1 = bytes;
-                println!("Encryption succeeded");
-            }
-            Err(error) => {
-                // Encryption failed
-                // Handle the error here
-                eprintln!("Encryption error: {:?}", error);
-                // Optionally, you can return an error or perform error-specific handling
-            }
+                *PATH_TO_TRANSCODED_FILE,
+                stem.to_string_lossy(),
+                format.ext
+            )
         }
-
-        let file_path = format!(
+        None => format!(
             "{}{}_ue.{}",
             *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-        );
-        let file_path_encrypted =
-            format!("{}{}.{}", *PATH_TO_TRANSCODED_FILE, file_name, format.ext);
-
-        let hash_result = hash_blake3_file(file_path.clone());
-        let hash_result_encrypted = hash_blake3_file(file_path_encrypted.to_owned());
+        ),
+    };
+    let encrypted_path = match &output_name {
+        Some(name) => format!("{}{}", *PATH_TO_TRANSCODED_FILE, name),
+        None => format!("{}{}.{}", *PATH_TO_TRANSCODED_FILE, file_name, format.ext),
+    };
+
+    let key = match &format.prefix {
+        Some(prefix) => {
+            let uploaded_path = if is_encrypted {
+                &encrypted_path
+            } else {
+                &transcoded_path
+            };
+            let uploaded_name = Path::new(uploaded_path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            Some(format!(
+                "{}{}",
+                render_prefix(prefix, source_name, &format, media_info)?,
+                uploaded_name
+            ))
+        }
+        None => None,
+    };
 
-        let cid_type_encrypted: u8 = 0xae; // replace with your actual cid type encrypted
-        let encryption_algorithm: u8 = 0xa6; // replace with your actual encryption algorithm
-        let chunk_size_as_power_of_2: u8 = 18; // replace with your actual chunk size as power of 2
-        let padding: u32 = 0; // replace with your actual padding
+    check_stream_indices(&format, media_info)?;
 
-        // Upload the transcoded videos to storage
-        match upload_video(file_path_encrypted.as_str(), format.dest).await {
-            Ok(cid_encrypted) => {
+    if *COPY_COMPATIBLE {
+        match source_satisfies(&format, media_info) {
+            Ok(()) => {
                 println!(
-                    "****************************************** cid: {:?}",
-                    &cid_encrypted
+                    "Task {}: the source satisfies format {}, copying it",
+                    task_id, format.id
                 );
+                format.stream_copy = true;
+            }
+            Err(reason) => println!(
+                "Task {}: encoding format {}, as {}",
+                task_id, format.id, reason
+            ),
+        }
+    }
 
-                let mut hash = Vec::new();
-                match hash_result {
-                    Ok(hash1) => {
-                        hash = hash1.as_bytes().to_vec();
-                        // Now you can use bytes as needed.
-                    }
-                    Err(err) => {
-                        eprintln!("Error computing blake3 hash: {}", err);
+    let encoder = if format.stream_copy {
+        Encoder::Copy
+    } else {
+        format_encoder(is_gpu, &format)
+    };
+    if is_gpu && encoder == Encoder::Cpu {
+        println!(
+            "GPU transcoding requested, but format {} is encoded on the CPU",
+            format.id
+        );
+    }
+    shared::set_encoder(task_id, format_index, encoder);
+
+    Ok(Rendition {
+        format,
+        output_name,
+        key,
+        transcoded_path,
+        encrypted_path,
+        encoder,
+    })
+}
 
-                        return Err(Status::new(
-                            Code::Internal,
-                            format!("Error computing blake3 hash: {}", err),
-                        ));
-                    }
-                }
+/// Encrypts a transcoded rendition if the video is encrypted, and uploads it.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index specifying the target video format from a predefined list.
+/// * `rendition` - The rendition, already transcoded to its `transcoded_path`.
+/// * `is_encrypted` - A boolean flag indicating whether the output video should be encrypted.
+///
+/// # Returns
+/// A `Result` wrapping a `Response` with the `TranscodeVideoResponse` on success,
+/// or a `Status` error if encrypting or uploading the rendition fails.
+///
+async fn finish_rendition(
+    task_id: String,
+    format_index: usize,
+    rendition: Rendition,
+    is_encrypted: bool,
+) -> Result<Response<TranscodeVideoResponse>, Status> {
+    let Rendition {
+        format,
+        output_name,
+        key,
+        transcoded_path,
+        encrypted_path,
+        encoder,
+    } = rendition;
 
-                let mut hash_encrypted = Vec::new();
-                match hash_result_encrypted {
-                    Ok(hash1) => {
-                        hash_encrypted = hash1.as_bytes().to_vec();
-                        // Now you can use bytes as needed.
-                    }
-                    Err(err) => {
-                        eprintln!("Error computing blake3 hash: {}", err);
+    let response: TranscodeVideoResponse;
 
-                        return Err(Status::new(
-                            Code::Internal,
-                            format!("Error computing blake3 hash: {}", err),
-                        ));
-                    }
-                }
+    // The upload is reported as it goes rather than only once it completes, as a large
+    // rendition can take a long time to upload
+    let on_upload_progress: UploadProgress = {
+        let task_id = task_id.clone();
+        Arc::new(move |bytes_uploaded: usize, total_size: Option<usize>| {
+            if let Some(total_size) = total_size.filter(|&total_size| total_size > 0) {
+                let percent = (bytes_uploaded.min(total_size) * 100 / total_size) as i32;
+                shared::update_progress(&task_id, format_index, Phase::Upload, percent);
+            }
+        })
+    };
+    // Cancelling the task stops the upload between chunks, rather than once it completes
+    let cancel = shared::upload_cancellation(&task_id);
+    let upload_error = |e: anyhow::Error| {
+        let code = if cancel.load(Ordering::SeqCst) {
+            Code::Cancelled
+        } else {
+            Code::Internal
+        };
+        Status::new(code, format!("Failed to upload transcoded file: {}", e))
+    };
 
-                let mut encrypted_blob_hash = vec![0x1f];
-                encrypted_blob_hash.extend(hash_encrypted);
+    if is_encrypted {
+        shared::update_progress(&task_id, format_index, Phase::Encrypt, 0);
+
+        let encryption_key1 =
+            match encrypt_file_xchacha20(transcoded_path.clone(), encrypted_path.clone(), 0) {
+                Ok(bytes) => {
+                    // Encryption succeeded, and `bytes` contains the encryption key
+                    println!("Encryption succeeded");
+                    bytes
+                }
+                Err(error) => {
+                    eprintln!("Encryption error: {:?}", error);
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("Failed to encrypt transcoded file: {:?}", error),
+                    ));
+                }
+            };
 
-                let cloned_hash = encrypted_blob_hash.clone();
+        let file_path = transcoded_path;
+        let file_path_encrypted = encrypted_path;
 
-                let file_path_path = Path::new(&file_path);
-                let metadata = std::fs::metadata(file_path_path).expect("Failed to read metadata");
-                let file_size = metadata.len();
+        let hash_result = hash_blake3_file_async(file_path.clone()).await;
+        let hash_result_encrypted = hash_blake3_file_async(file_path_encrypted.to_owned()).await;
 
-                let cid = hash_bytes_to_cid(hash, file_size);
+        shared::update_progress(&task_id, format_index, Phase::Upload, 0);
 
-                println!("encryption_key1: {:?}", encryption_key1);
-                println!("cid_encrypted: {:?}", cid_encrypted);
-                println!("cid: {:?}", cid);
+        // Optionally upload the plaintext rendition as well, e.g. for a public preview
+        let unencrypted_cid = if format.keep_unencrypted.unwrap_or(false) {
+            match upload_video(file_path.as_str(), format.dest.clone()).await {
+                Ok(uploaded) => Some(uploaded.cid),
+                Err(e) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("Failed to upload unencrypted file: {}", e),
+                    ));
+                }
+            }
+        } else {
+            None
+        };
 
-                println!(
-                    "upload_video Ok: encrypted_blob_hash = {:?}",
-                    hex::encode(&encrypted_blob_hash)
-                );
-                println!(
-                    "upload_video Ok: encryption_key1 = {:?}",
-                    hex::encode(&encryption_key1)
-                );
-                println!("upload_video Ok: cid = {:?}", hex::encode(&cid));
+        let cid_type_encrypted: u8 = CID_TYPE_ENCRYPTED;
+        let encryption_algorithm: u8 = ENCRYPTION_ALGORITHM_XCHACHA20_POLY1305;
+        let chunk_size_as_power_of_2: u8 = ENCRYPTION_CHUNK_SIZE_AS_POWER_OF_2;
+        let padding: u32 = 0; // replace with your actual padding
 
-                let hash = hash_blake3_file(file_path_encrypted).unwrap();
-                println!(
-                    "`upload_video: encryptedBlobMHashBase64url` = {}",
-                    general_purpose::URL_SAFE_NO_PAD
-                        .encode([&[31u8] as &[_], hash.as_bytes()].concat())
-                );
+        // Upload the transcoded videos to storage
+        let uploaded = upload_video_with_key(
+            file_path_encrypted.as_str(),
+            format.dest,
+            key.as_deref(),
+            Some(on_upload_progress.clone()),
+            Some(&cancel),
+        )
+        .await
+        .map_err(upload_error)?;
 
-                let encrypted_cid_bytes = create_encrypted_cid(
-                    cid_type_encrypted,
-                    encryption_algorithm,
-                    chunk_size_as_power_of_2,
-                    encrypted_blob_hash,
-                    encryption_key1,
-                    padding,
-                    cid,
-                );
+        let cid_encrypted = uploaded.cid;
+        println!(
+            "****************************************** cid: {:?}",
+            &cid_encrypted
+        );
 
-                println!(
-                    "upload_video Ok: encrypted_cid_bytes = {:?}",
-                    hex::encode(&encrypted_cid_bytes)
-                );
-                let encrypted_cid = format!("u{}", bytes_to_base64url(&encrypted_cid_bytes));
-                println!("upload_video Ok: encrypted_cid = {}", encrypted_cid);
+        let mut hash = Vec::new();
+        match hash_result {
+            Ok(hash1) => {
+                hash = hash1.as_bytes().to_vec();
+                // Now you can use bytes as needed.
+            }
+            Err(err) => {
+                eprintln!("Error computing blake3 hash: {}", err);
 
-                // Now you have your encrypted_blob_hash and encrypted_cid
-                println!("Encrypted Blob Hash: {:02x?}", cloned_hash);
-                println!("Encrypted CID: {:?}", encrypted_cid);
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Error computing blake3 hash: {}", err),
+                ));
+            }
+        }
 
-                println!("Transcoding task finished");
+        let mut hash_encrypted = Vec::new();
+        match hash_result_encrypted {
+            Ok(hash1) => {
+                hash_encrypted = hash1.as_bytes().to_vec();
+                // Now you can use bytes as needed.
+            }
+            Err(err) => {
+                eprintln!("Error computing blake3 hash: {}", err);
 
-                // Return the TranscodeVideoResponse with the job ID
-                response = TranscodeVideoResponse {
-                    status_code: 200,
-                    message: String::from("Transcoding successful"),
-                    cid: encrypted_cid,
-                };
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Error computing blake3 hash: {}", err),
+                ));
             }
-            Err(e) => {
-                println!("!!!!!!!!!!!!!!!!!!!!!2160p no cid");
-                println!("Error: {}", e); // This line is added to print out the error message
-
-                response = TranscodeVideoResponse {
-                    status_code: 500,
-                    message: format!("Transcoding task failed with error {}", e),
-                    cid: "".to_string(),
-                };
+        }
+
+        // The portal addresses the encrypted blob by its blake3 hash
+        let mut encrypted_blob_hash = CidHashAlgorithm::Blake3.multihash_prefix().to_vec();
+        encrypted_blob_hash.extend(hash_encrypted);
+
+        let cloned_hash = encrypted_blob_hash.clone();
+
+        let file_path_path = Path::new(&file_path);
+        let metadata = std::fs::metadata(file_path_path).expect("Failed to read metadata");
+        let file_size = metadata.len();
+
+        let cid = match *CID_HASH_ALGORITHM {
+            CidHashAlgorithm::Blake3 => hash_bytes_to_cid(hash, file_size),
+            algorithm => {
+                let hash = hash_file_async(file_path.clone(), algorithm)
+                    .await
+                    .map_err(|e| {
+                        Status::new(
+                            Code::Internal,
+                            format!("Error computing {:?} hash: {}", algorithm, e),
+                        )
+                    })?;
+                hash_bytes_to_cid_with_algorithm(algorithm, hash, file_size)
             }
         };
-    } else {
-        let file_path = format!(
-            "{}{}_ue.{}",
-            *PATH_TO_TRANSCODED_FILE, file_name, format.ext
+
+        println!("encryption_key1: {:?}", encryption_key1);
+        println!("cid_encrypted: {:?}", cid_encrypted);
+        println!("cid: {:?}", cid);
+
+        println!(
+            "upload_video Ok: encrypted_blob_hash = {:?}",
+            hex::encode(&encrypted_blob_hash)
+        );
+        println!(
+            "upload_video Ok: encryption_key1 = {:?}",
+            hex::encode(&encryption_key1)
         );
+        println!("upload_video Ok: cid = {:?}", hex::encode(&cid));
+
+        let hash = hash_blake3_file_async(file_path_encrypted).await.unwrap();
+        println!(
+            "`upload_video: encryptedBlobMHashBase64url` = {}",
+            general_purpose::URL_SAFE_NO_PAD.encode([&[31u8] as &[_], hash.as_bytes()].concat())
+        );
+
+        let encrypted_cid_bytes = create_encrypted_cid(
+            cid_type_encrypted,
+            encryption_algorithm,
+            chunk_size_as_power_of_2,
+            encrypted_blob_hash,
+            encryption_key1,
+            padding,
+            cid,
+        );
+
+        println!(
+            "upload_video Ok: encrypted_cid_bytes = {:?}",
+            hex::encode(&encrypted_cid_bytes)
+        );
+
+        // Renditions are shared with vup clients, so a CID they can't decrypt is never returned
+        if let Err(e) =
+            parse_encrypted_cid(&encrypted_cid_bytes).and_then(|cid| cid.check_vup_compatible())
+        {
+            return Err(Status::new(
+                Code::Internal,
+                format!("Encrypted CID is not vup compatible: {}", e),
+            ));
+        }
+        let encrypted_cid = format!("u{}", bytes_to_base64url(&encrypted_cid_bytes));
+        println!("upload_video Ok: encrypted_cid = {}", encrypted_cid);
+
+        // Now you have your encrypted_blob_hash and encrypted_cid
+        println!("Encrypted Blob Hash: {:02x?}", cloned_hash);
+        println!("Encrypted CID: {:?}", encrypted_cid);
+
+        println!("Transcoding task finished");
+
+        // Return the TranscodeVideoResponse with the job ID
+        response = TranscodeVideoResponse {
+            status_code: 200,
+            message: String::from("Transcoding successful"),
+            cid: encrypted_cid,
+            portal_url: uploaded.portal_url,
+            sprite_cids: Vec::new(),
+            unencrypted_cid,
+            name: output_name,
+            key,
+            encoder: Some(encoder),
+        };
+    } else {
+        let file_path = transcoded_path;
+
+        shared::update_progress(&task_id, format_index, Phase::Upload, 0);
+
+        if format.ext.eq_ignore_ascii_case("m3u8") {
+            publish_hls_segments(&file_path, format.dest.clone()).await?;
+        }
 
         // Upload the transcoded videos to storage
-        match upload_video(file_path.as_str(), format.dest.clone()).await {
-            Ok(cid) => {
-                println!("cid: {:?}", cid);
-
-                println!("Transcoding task finished");
-
-                // Return the TranscodeVideoResponse with the job ID
-                response = TranscodeVideoResponse {
-                    status_code: 200,
-                    message: String::from("Transcoding successful"),
-                    cid,
-                };
-            }
-            Err(e) => {
-                println!("!!!!!!!!!!!!!!!!!!!!!2160p no cid");
-                println!("Error: {}", e); // This line is added to print out the error message
-
-                response = TranscodeVideoResponse {
-                    status_code: 500,
-                    message: format!("Transcoding task failed with error {}", e),
-                    cid: "".to_string(),
-                };
-            }
+        let uploaded = upload_video_with_key(
+            file_path.as_str(),
+            format.dest.clone(),
+            key.as_deref(),
+            Some(on_upload_progress.clone()),
+            Some(&cancel),
+        )
+        .await
+        .map_err(upload_error)?;
+
+        println!("cid: {:?}", uploaded.cid);
+
+        println!("Transcoding task finished");
+
+        // Return the TranscodeVideoResponse with the job ID
+        response = TranscodeVideoResponse {
+            status_code: 200,
+            message: String::from("Transcoding successful"),
+            cid: uploaded.cid,
+            portal_url: uploaded.portal_url,
+            sprite_cids: Vec::new(),
+            unencrypted_cid: None,
+            name: output_name,
+            key,
+            encoder: Some(encoder),
         };
     }
 
+    shared::update_progress(&task_id, format_index, Phase::Done, 100);
+
     Ok(Response::new(response))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn check_unique_format_ids_accepts_distinct_ids() {
+        let media_formats = vec![
+            json!({ "id": 1 }),
+            json!({ "id": 2 }),
+            json!({ "ext": "mp4" }),
+        ];
+
+        assert!(check_unique_format_ids(&media_formats).is_ok());
+    }
+
+    #[test]
+    fn check_unique_format_ids_rejects_colliding_ids() {
+        let media_formats = vec![
+            json!({ "id": 3 }),
+            json!({ "id": 1 }),
+            json!({ "id": 3 }),
+            json!({ "id": 1 }),
+            json!({ "id": 1 }),
+            json!({ "id": 2 }),
+        ];
+
+        let status = check_unique_format_ids(&media_formats).unwrap_err();
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Duplicate media format ids: 1, 3");
+    }
+}
@@ -11,13 +11,43 @@ use std::error::Error;
 use std::fs::metadata;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
+use std::path::Path;
+use std::process::Command;
 
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 
-use sanitize_filename::sanitize;
-
-use crate::s5::download_file;
+use crate::media_info::{ffprobe_available, probe_media_info};
+use crate::s5::{download_file, DownloadLimitError};
+use once_cell::sync::Lazy;
+
+/// The directory intermediate files are written to: the parts and metadata of encrypted
+/// sources while they are downloaded, and an encrypted source before it is decrypted.
+/// `TEMP_DIR` if set, otherwise `PATH_TO_FILE`, so that it can be put on fast local storage
+/// apart from the downloaded sources and the transcoded outputs.
+pub static TEMP_DIR: Lazy<String> = Lazy::new(|| {
+    var("TEMP_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| {
+            var("PATH_TO_FILE").unwrap_or_else(|_| panic!("PATH_TO_FILE not set in .env"))
+        })
+});
+
+/// Removes an intermediate file once it is no longer needed, logging rather than returning any
+/// error. A file that was already removed is ignored.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+///
+pub fn remove_temp_file(path: &str) {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Failed to remove temporary file {}: {}", path, e),
+    }
+}
 
 pub fn bytes_to_base64url(bytes: &[u8]) -> String {
     let engine = general_purpose::STANDARD_NO_PAD;
@@ -44,12 +74,81 @@ pub fn base64url_to_bytes(base64url: &str) -> Vec<u8> {
     engine.decode(&base64).unwrap()
 }
 
+/// CID type byte of raw (unencrypted) CIDs.
+pub const CID_TYPE_RAW: u8 = 0x26;
+
+/// A hash algorithm CIDs can be constructed with. A raw CID is laid out as `CID_TYPE_RAW`,
+/// the algorithm's multihash prefix, the digest, then the file size in as few little-endian
+/// bytes as it needs:
+///
+/// * `Blake3` - prefix `0x1f`, as S5 portals address files, followed by the 32 byte digest.
+/// * `Sha256` - prefix `0x12 0x20`, the multihash code and digest length of sha2-256,
+///   followed by the 32 byte digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CidHashAlgorithm {
+    Blake3,
+    Sha256,
+}
+
+impl CidHashAlgorithm {
+    /// The bytes preceding the digest in a multihash of the algorithm.
+    pub fn multihash_prefix(self) -> &'static [u8] {
+        match self {
+            CidHashAlgorithm::Blake3 => &[0x1f],
+            CidHashAlgorithm::Sha256 => &[0x12, 0x20],
+        }
+    }
+
+    /// Parses an algorithm name, `blake3` or `sha256`, in any case.
+    pub fn parse(name: &str) -> Result<CidHashAlgorithm, String> {
+        match name.to_lowercase().as_str() {
+            "blake3" => Ok(CidHashAlgorithm::Blake3),
+            "sha256" => Ok(CidHashAlgorithm::Sha256),
+            _ => Err(format!(
+                "Unknown CID hash algorithm {}, expected blake3 or sha256",
+                name
+            )),
+        }
+    }
+}
+
+/// The hash algorithm of the CIDs the transcoder constructs for the content of encrypted
+/// renditions, `CID_HASH_ALGORITHM` if set, otherwise blake3. Panics on first use if the
+/// setting is invalid, so the server forces it at startup. Blobs are still addressed on S5
+/// portals by their blake3 hash, so uploads and encrypted blob hashes always use blake3.
+pub static CID_HASH_ALGORITHM: Lazy<CidHashAlgorithm> = Lazy::new(|| {
+    var("CID_HASH_ALGORITHM")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .map(|name| CidHashAlgorithm::parse(&name).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or(CidHashAlgorithm::Blake3)
+});
+
+/// Constructs the raw CID of a file from its blake3 hash.
+///
+/// # Arguments
+/// * `hash` - The blake3 digest of the file.
+/// * `file_size` - The size of the file in bytes.
+///
 pub fn hash_bytes_to_cid(hash: Vec<u8>, file_size: u64) -> Vec<u8> {
-    // Decode the base64url hash back to bytes
-    // Prepend the byte 0x26 before the full hash
-    let mut bytes = hash.to_vec();
-    bytes.insert(0, 0x1f);
-    bytes.insert(0, 0x26);
+    hash_bytes_to_cid_with_algorithm(CidHashAlgorithm::Blake3, hash, file_size)
+}
+
+/// Constructs the raw CID of a file from its hash with any supported algorithm.
+///
+/// # Arguments
+/// * `algorithm` - The algorithm `hash` was computed with.
+/// * `hash` - The digest of the file.
+/// * `file_size` - The size of the file in bytes.
+///
+pub fn hash_bytes_to_cid_with_algorithm(
+    algorithm: CidHashAlgorithm,
+    hash: Vec<u8>,
+    file_size: u64,
+) -> Vec<u8> {
+    let mut bytes = vec![CID_TYPE_RAW];
+    bytes.extend_from_slice(algorithm.multihash_prefix());
+    bytes.extend(hash);
 
     // Append the size of the file, little-endian encoded
     let le_file_size = &file_size.to_le_bytes();
@@ -73,27 +172,294 @@ pub fn hash_bytes_to_cid(hash: Vec<u8>, file_size: u64) -> Vec<u8> {
 /// # Arguments
 ///
 /// * `url` - The URL of the video to download.
+/// * `file_path` - The path to save the video to.
+/// * `task_id` - The task the video is downloaded for.
 ///
-pub async fn download_video(url: &str, file_path: &str) -> Result<(), Status> {
+/// # Returns
+/// `Ok(())` on success, a `DeadlineExceeded` or `ResourceExhausted` status if the download
+/// exceeded `DOWNLOAD_TIMEOUT_SECS` or `DOWNLOAD_MAX_BYTES`, a `Cancelled` status if the task
+/// was cancelled, or else an `Internal` status.
+///
+pub async fn download_video(url: &str, file_path: &str, task_id: &str) -> Result<(), Status> {
     println!(" {}", url);
 
-    match download_file(url, file_path) {
+    match download_file(url, file_path, task_id) {
         Ok(()) => println!("File downloaded successfully"),
         Err(e) => {
             eprintln!("Error downloading file: {}", e);
-            return Err(Status::new(
-                Code::Internal,
-                format!("Error downloading file: {}", e),
-            ));
+            let code = match e.downcast_ref::<DownloadLimitError>() {
+                Some(DownloadLimitError::TimedOut(_)) => Code::DeadlineExceeded,
+                Some(DownloadLimitError::TooLarge(_)) => Code::ResourceExhausted,
+                Some(DownloadLimitError::Cancelled) => Code::Cancelled,
+                None => Code::Internal,
+            };
+            return Err(Status::new(code, format!("Error downloading file: {}", e)));
+        }
+    }
+
+    Ok(())
+}
+
+// Length in hex characters of the URL hash used as a download's file name
+const DOWNLOAD_FILE_NAME_HASH_LEN: usize = 32;
+
+/// Returns a short file name to download `url` to: a blake3 hash of the URL, followed by the
+/// extension of the URL's path if it has one. The same URL always maps to the same name, and
+/// the name stays well within file system limits however long the URL is.
+///
+/// # Arguments
+///
+/// * `url` - The URL to download.
+///
+pub fn download_file_name(url: &str) -> String {
+    let hash = blake3::hash(url.as_bytes()).to_hex();
+    let hash = &hash[..DOWNLOAD_FILE_NAME_HASH_LEN];
+
+    let url_path = url.split(['?', '#']).next().unwrap_or_default();
+    let last_segment = url_path.rsplit('/').next().unwrap_or_default();
+    match last_segment.rsplit_once('.') {
+        Some((stem, ext))
+            if !stem.is_empty()
+                && (1..=5).contains(&ext.len())
+                && ext.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            format!("{}.{}", hash, ext.to_ascii_lowercase())
         }
+        _ => hash.to_string(),
     }
+}
+
+/// Creates a directory (and any missing parents) if it does not exist, and checks that files
+/// can be written to it by creating and removing a temporary file.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to check. An empty path refers to the current directory.
+///
+pub fn ensure_writable_dir(dir: &str) -> std::io::Result<()> {
+    let dir = if dir.is_empty() { "." } else { dir };
+    std::fs::create_dir_all(dir)?;
+
+    let probe_path = Path::new(dir).join(format!(".write_test_{}", std::process::id()));
+    File::create(&probe_path)?;
+    std::fs::remove_file(&probe_path)?;
 
     Ok(())
 }
 
+/// Returns the version line a tool such as `ffmpeg` or `ffprobe` prints first for `-version`,
+/// e.g. `ffmpeg version 6.1.1 Copyright (c) 2000-2023 the FFmpeg developers`.
+///
+/// # Arguments
+///
+/// * `binary` - The name or path of the tool.
+///
+/// # Returns
+///
+/// The version line, or `None` if the tool can't be run.
+///
+pub fn tool_version(binary: &str) -> Option<String> {
+    let output = Command::new(binary).arg("-version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+}
+
+/// Maps the `format_name` reported by ffprobe to the file extension ffmpeg
+/// associates with that container. ffprobe reports the demuxer name, which
+/// may be a comma-separated list of aliases (e.g. `mov,mp4,m4a,3gp,3g2,mj2`),
+/// so only the first alias is used.
+///
+/// # Arguments
+///
+/// * `format_name` - The `format_name` value reported by ffprobe.
+///
+fn container_extension(format_name: &str) -> Option<&'static str> {
+    let demuxer = format_name.trim().split(',').next()?;
+
+    match demuxer {
+        "mov" => Some("mp4"),
+        "matroska" => Some("mkv"),
+        "avi" => Some("avi"),
+        "flv" => Some("flv"),
+        "mpegts" => Some("ts"),
+        "mpeg" => Some("mpg"),
+        "asf" => Some("asf"),
+        "ogg" => Some("ogg"),
+        "wav" => Some("wav"),
+        "mp3" => Some("mp3"),
+        "flac" => Some("flac"),
+        "aac" => Some("aac"),
+        _ => None,
+    }
+}
+
+/// Probes a file with ffprobe and returns the file extension matching its
+/// container format. Returns `None` if ffprobe is unavailable, fails to
+/// recognise the file, or reports a container that is not mapped.
+///
+/// # Arguments
+///
+/// * `file_path` - The path to the media file to probe.
+///
+pub fn detect_container_extension(file_path: &str) -> Option<String> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=format_name",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            file_path,
+        ])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let format_name = String::from_utf8(output.stdout).ok()?;
+    container_extension(&format_name).map(String::from)
+}
+
+// Smallest file that can plausibly be a media file, rather than an empty body or an error page
+const MIN_MEDIA_FILE_BYTES: u64 = 256;
+
+/// Checks that a downloaded source is media ffmpeg can transcode: at least
+/// `MIN_MEDIA_FILE_BYTES` long, and recognized by ffprobe as having at least one
+/// stream. A file that isn't is deleted, so it is never cached as the source. Without ffprobe
+/// only the size is checked.
+///
+/// # Arguments
+///
+/// * `file_path` - The path to the downloaded (and, for encrypted sources, decrypted) file.
+///
+/// # Returns
+///
+/// `Ok(())` if the file is valid media, otherwise an `Internal` status.
+///
+pub fn check_downloaded_media(file_path: &str) -> Result<(), Status> {
+    let size = metadata(file_path)
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+
+    let problem = if size < MIN_MEDIA_FILE_BYTES {
+        Some(format!("it is only {} bytes", size))
+    } else if !ffprobe_available() {
+        None
+    } else {
+        match probe_media_info(file_path) {
+            Ok(media_info) if media_info.streams.is_empty() => {
+                Some(String::from("ffprobe found no streams in it"))
+            }
+            Ok(_) => None,
+            Err(e) => Some(e),
+        }
+    };
+
+    match problem {
+        None => Ok(()),
+        Some(problem) => {
+            if let Err(e) = std::fs::remove_file(file_path) {
+                eprintln!("Failed to remove {}: {}", file_path, e);
+            }
+            Err(Status::new(
+                Code::Internal,
+                format!("downloaded file is not valid media: {}", problem),
+            ))
+        }
+    }
+}
+
+/// Renames a downloaded source file so that it carries the extension of its
+/// detected container, as ffmpeg can guess the wrong demuxer for some
+/// containers when the file has no extension. If detection or the rename
+/// fails, the file is left extensionless for ffmpeg to sniff.
+///
+/// # Arguments
+///
+/// * `file_path` - The path to the downloaded, extensionless file.
+///
+/// # Returns
+///
+/// The path of the file after any rename.
+///
+pub fn rename_with_container_extension(file_path: &str) -> String {
+    let ext = match detect_container_extension(file_path) {
+        Some(ext) => ext,
+        None => {
+            println!("Could not detect container format of {}", file_path);
+            return file_path.to_string();
+        }
+    };
+
+    let renamed_path = format!("{}.{}", file_path, ext);
+    match std::fs::rename(file_path, &renamed_path) {
+        Ok(()) => {
+            println!("Detected container format {}: {}", ext, renamed_path);
+            renamed_path
+        }
+        Err(e) => {
+            eprintln!("Failed to rename {} to {}: {}", file_path, renamed_path, e);
+            file_path.to_string()
+        }
+    }
+}
+
+/// Looks for a previously downloaded source file, which may have been renamed
+/// with the extension of its detected container.
+///
+/// # Arguments
+///
+/// * `file_path` - The extensionless path the source was downloaded to.
+///
+/// # Returns
+///
+/// The path of the cached source, or `None` if it has not been downloaded.
+///
+pub fn find_downloaded_source(file_path: &str) -> Option<String> {
+    let path = Path::new(file_path);
+    if path.exists() {
+        return Some(file_path.to_string());
+    }
+
+    let file_name = path.file_name()?.to_str()?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|p| {
+            p.extension().is_some() && p.file_stem().and_then(|s| s.to_str()) == Some(file_name)
+        })
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Downloads the parts of an encrypted file listed in its locations metadata and concatenates
+/// them into a single file. If the task is cancelled, the download in flight is aborted and the
+/// partially concatenated file is deleted.
+///
+/// # Arguments
+///
+/// * `data` - The locations metadata of the encrypted file, as JSON.
+/// * `file_path` - The path to save the concatenated file to.
+/// * `task_id` - The task the file is downloaded for.
+///
 pub async fn download_and_concat_files(
     data: String,
     file_path: String,
+    task_id: &str,
 ) -> Result<(), Box<dyn Error>> {
     // Parse the JSON data
     let json_data: JsonData = serde_json::from_str(&data)?;
@@ -114,10 +480,17 @@ pub async fn download_and_concat_files(
 
             println!("download_and_concat_files part: {}", part);
 
-            let path_to_file = var("PATH_TO_FILE").unwrap();
-            let tmp_file_path = String::from(path_to_file.to_owned() + &sanitize(part.as_str()));
+            let tmp_file_path = format!("{}{}", *TEMP_DIR, download_file_name(part));
 
-            download_video(&part, tmp_file_path.as_str()).await?;
+            if let Err(e) = download_video(&part, tmp_file_path.as_str(), task_id).await {
+                if e.code() == Code::Cancelled {
+                    drop(final_file);
+                    if let Err(e) = std::fs::remove_file(&file_path) {
+                        eprintln!("Failed to remove partial download {}: {}", file_path, e);
+                    }
+                }
+                return Err(Box::new(e));
+            }
 
             let mut downloaded_file = match fs::File::open(&tmp_file_path).await {
                 Ok(file) => file,
@@ -154,3 +527,63 @@ struct Location {
 struct JsonData {
     locations: Vec<Location>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest() -> Vec<u8> {
+        (0..32).collect()
+    }
+
+    #[test]
+    fn blake3_cid_layout() {
+        let cid = hash_bytes_to_cid_with_algorithm(CidHashAlgorithm::Blake3, digest(), 0x01_02_03);
+
+        let mut expected = vec![0x26, 0x1f];
+        expected.extend(digest());
+        expected.extend([0x03, 0x02, 0x01]);
+        assert_eq!(cid, expected);
+        assert_eq!(hash_bytes_to_cid(digest(), 0x01_02_03), expected);
+    }
+
+    #[test]
+    fn sha256_cid_layout() {
+        let cid = hash_bytes_to_cid_with_algorithm(CidHashAlgorithm::Sha256, digest(), 0x01_02_03);
+
+        let mut expected = vec![0x26, 0x12, 0x20];
+        expected.extend(digest());
+        expected.extend([0x03, 0x02, 0x01]);
+        assert_eq!(cid, expected);
+    }
+
+    #[test]
+    fn cid_file_size_keeps_inner_zero_bytes_and_drops_trailing_ones() {
+        for algorithm in [CidHashAlgorithm::Blake3, CidHashAlgorithm::Sha256] {
+            let prefix_len = 1 + algorithm.multihash_prefix().len() + digest().len();
+            let size = |file_size| {
+                hash_bytes_to_cid_with_algorithm(algorithm, digest(), file_size)[prefix_len..]
+                    .to_vec()
+            };
+
+            assert_eq!(size(0), Vec::<u8>::new());
+            assert_eq!(size(0xff), vec![0xff]);
+            assert_eq!(size(0x0100), vec![0x00, 0x01]);
+            assert_eq!(size(0x0001_0000_0000), vec![0x00, 0x00, 0x00, 0x00, 0x01]);
+            assert_eq!(size(u64::MAX), vec![0xff; 8]);
+        }
+    }
+
+    #[test]
+    fn cid_hash_algorithm_parse() {
+        assert_eq!(
+            CidHashAlgorithm::parse("BLAKE3"),
+            Ok(CidHashAlgorithm::Blake3)
+        );
+        assert_eq!(
+            CidHashAlgorithm::parse("sha256"),
+            Ok(CidHashAlgorithm::Sha256)
+        );
+        assert!(CidHashAlgorithm::parse("md5").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+#!/usr/bin/env python3
+"""Generates vup_xchacha20.bin, a file encrypted the way vup encrypts files, for the
+`decrypts_a_vup_encrypted_fixture` test in src/encrypt_file.rs.
+
+vup's scheme: XChaCha20-Poly1305 over 256 KiB plaintext chunks, each followed by its 16 byte
+tag, the 24 byte nonce of a chunk being its index as little-endian bytes followed by zeros, and
+the last chunk padded with zero bytes. XChaCha20 is built here from HChaCha20 and the
+ChaCha20-Poly1305 of the `cryptography` package, independently of the Rust crates under test.
+
+Usage: python3 vup_fixture.py > vup_xchacha20.bin
+"""
+import struct
+import sys
+
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+
+KEY = bytes(range(32))
+CHUNK_SIZE = 1 << 18
+PADDING = 16
+PLAINTEXT = bytes(i % 251 for i in range(CHUNK_SIZE + 100))
+
+
+def rotl(value, shift):
+    return ((value << shift) | (value >> (32 - shift))) & 0xFFFFFFFF
+
+
+def quarter_round(state, a, b, c, d):
+    state[a] = (state[a] + state[b]) & 0xFFFFFFFF
+    state[d] = rotl(state[d] ^ state[a], 16)
+    state[c] = (state[c] + state[d]) & 0xFFFFFFFF
+    state[b] = rotl(state[b] ^ state[c], 12)
+    state[a] = (state[a] + state[b]) & 0xFFFFFFFF
+    state[d] = rotl(state[d] ^ state[a], 8)
+    state[c] = (state[c] + state[d]) & 0xFFFFFFFF
+    state[b] = rotl(state[b] ^ state[c], 7)
+
+
+def hchacha20(key, nonce):
+    state = list(struct.unpack("<4I", b"expand 32-byte k"))
+    state += struct.unpack("<8I", key) + struct.unpack("<4I", nonce)
+    for _ in range(10):
+        quarter_round(state, 0, 4, 8, 12)
+        quarter_round(state, 1, 5, 9, 13)
+        quarter_round(state, 2, 6, 10, 14)
+        quarter_round(state, 3, 7, 11, 15)
+        quarter_round(state, 0, 5, 10, 15)
+        quarter_round(state, 1, 6, 11, 12)
+        quarter_round(state, 2, 7, 8, 13)
+        quarter_round(state, 3, 4, 9, 14)
+    return struct.pack("<8I", *(state[0:4] + state[12:16]))
+
+
+def xchacha20poly1305_encrypt(key, nonce, plaintext):
+    subkey = hchacha20(key, nonce[:16])
+    return ChaCha20Poly1305(subkey).encrypt(b"\0" * 4 + nonce[16:], plaintext, None)
+
+
+def main():
+    chunks = [PLAINTEXT[i : i + CHUNK_SIZE] for i in range(0, len(PLAINTEXT), CHUNK_SIZE)]
+    chunks[-1] += b"\0" * PADDING
+    for index, chunk in enumerate(chunks):
+        nonce = index.to_bytes(24, "little")
+        sys.stdout.buffer.write(xchacha20poly1305_encrypt(KEY, nonce, chunk))
+
+
+if __name__ == "__main__":
+    main()
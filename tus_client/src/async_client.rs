@@ -0,0 +1,211 @@
+use crate::headers;
+use crate::http::{
+    default_headers, normalize_headers, AsyncHttpHandler, Headers, HeadersBuilder, HttpMethod,
+    HttpRequest, HttpResponse,
+};
+use crate::{
+    build_request, create_upload_headers, encode_metadata, location_from_response,
+    upload_info_from_response, upload_offset_from_response, Error, UploadInfo, DEFAULT_CHUNK_SIZE,
+};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+/// Used to interact with a [tus](https://tus.io) endpoint from async code. It behaves like `Client`, but awaits each request and file read rather than blocking the calling thread, so it can be used inside a tokio runtime.
+pub struct AsyncClient<'a> {
+    use_method_override: bool,
+    http_handler: Box<dyn AsyncHttpHandler + Send + Sync + 'a>,
+    auth_token: Option<String>,
+}
+
+impl<'a> AsyncClient<'a> {
+    /// Instantiates a new instance of `AsyncClient`. `http_handler` needs to implement the `AsyncHttpHandler` trait.
+    /// An implementation of this trait for `reqwest_async::Client` is available by enabling the `reqwest` feature.
+    pub fn new(http_handler: impl AsyncHttpHandler + Send + Sync + 'a) -> Self {
+        AsyncClient {
+            use_method_override: false,
+            http_handler: Box::new(http_handler),
+            auth_token: None,
+        }
+    }
+
+    /// Creates an `AsyncClient` which uses the `X-HTTP-METHOD-OVERRIDE` header to specify the `PATCH` and `DELETE` methods, as `Client::with_method_override` does.
+    pub fn with_method_override(http_handler: impl AsyncHttpHandler + Send + Sync + 'a) -> Self {
+        AsyncClient {
+            use_method_override: true,
+            http_handler: Box::new(http_handler),
+            auth_token: None,
+        }
+    }
+
+    pub fn with_auth_token(mut self, auth_token: impl Into<String>) -> Self {
+        self.auth_token = Some(auth_token.into());
+        self
+    }
+
+    /// Retrieves information about an upload from the Tus server, as `Client::get_info` does.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the upload on the Tus server.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the upload information is successfully retrieved, otherwise `Err`: `Error::Gone` if the server has expired the upload, or `Error::NotFoundError` if the server doesn't know it.
+    pub async fn get_info_async(&self, url: &str) -> Result<UploadInfo, Error> {
+        let req = self.create_request(HttpMethod::Head, url, None, Some(default_headers()));
+
+        let response = self.send(req).await?;
+
+        upload_info_from_response(&response)
+    }
+
+    /// Uploads a file to a given URL using the default chunk size.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL to upload the file to.
+    /// * `path` - The path of the file to be uploaded.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the file is successfully uploaded, otherwise `Err`.
+    pub async fn upload_async(&self, url: &str, path: &Path) -> Result<(), Error> {
+        self.upload_with_chunk_size_async(url, path, DEFAULT_CHUNK_SIZE)
+            .await
+    }
+
+    /// Uploads a file to a given URL in chunks of a specified size, resuming from the offset the server reports, as `Client::upload_with_chunk_size` does.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL to upload the file to.
+    /// * `path` - The path of the file to be uploaded.
+    /// * `chunk_size` - The size of each chunk to be uploaded. Must be greater than zero.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the file is successfully uploaded, otherwise `Err`. Returns `Error::InvalidChunkSize` if `chunk_size` is zero.
+    pub async fn upload_with_chunk_size_async(
+        &self,
+        url: &str,
+        path: &Path,
+        chunk_size: usize,
+    ) -> Result<(), Error> {
+        if chunk_size == 0 {
+            return Err(Error::InvalidChunkSize(chunk_size));
+        }
+
+        let info = self.get_info_async(url).await?;
+        let mut file = File::open(path).await?;
+        let file_len = file.metadata().await?.len() as usize;
+
+        if let Some(total_size) = info.total_size {
+            if file_len != total_size {
+                return Err(Error::UnequalSizeError);
+            }
+        }
+
+        let mut buffer = vec![0; chunk_size.min(file_len)];
+        let mut progress = info.bytes_uploaded;
+
+        file.seek(SeekFrom::Start(progress as u64)).await?;
+
+        while progress < file_len {
+            let bytes_read = read_chunk(&mut file, &mut buffer).await?;
+            if bytes_read == 0 {
+                return Err(Error::FileReadError);
+            }
+
+            let req = self.create_request(
+                HttpMethod::Patch,
+                url,
+                Some(&buffer[..bytes_read]),
+                Some(create_upload_headers(progress)),
+            );
+
+            let response = self.send(req).await?;
+
+            progress = upload_offset_from_response(&response, progress + bytes_read)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a file on the server, receiving the upload URL of the file.
+    pub async fn create_async(&self, url: &str, path: &Path) -> Result<String, Error> {
+        self.create_with_metadata_async(url, path, HashMap::new())
+            .await
+    }
+
+    /// Creates a new upload with metadata on the Tus server, as `Client::create_with_metadata` does.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the Tus server.
+    /// * `path` - The path of the file to be uploaded.
+    /// * `metadata` - A map of metadata to be associated with the upload.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` with the upload URL if the upload is successfully created, otherwise `Err`.
+    pub async fn create_with_metadata_async(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let file_len = tokio::fs::metadata(path).await?.len();
+        let mut headers = HeadersBuilder::from_headers(default_headers()).upload_length(file_len);
+        if !metadata.is_empty() {
+            headers = headers.insert(headers::UPLOAD_METADATA, encode_metadata(&metadata)?);
+        }
+
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers.build()));
+
+        let response = self.send(req).await?;
+
+        location_from_response(&response)
+    }
+
+    /// Executes a request with the HTTP handler, normalizing the names of the response headers so that each header has a single entry.
+    async fn send(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+        let mut response = self.http_handler.handle_request(req).await?;
+        response.headers = normalize_headers(response.headers);
+        Ok(response)
+    }
+
+    fn create_request<'b>(
+        &self,
+        method: HttpMethod,
+        url: &str,
+        body: Option<&'b [u8]>,
+        headers: Option<Headers>,
+    ) -> HttpRequest<'b> {
+        build_request(
+            method,
+            url,
+            body,
+            headers,
+            self.auth_token.as_deref(),
+            self.use_method_override,
+        )
+    }
+}
+
+/// Reads from `reader` until `buffer` is full or the end is reached, as the blocking `read_chunk` does.
+async fn read_chunk(
+    reader: &mut (impl AsyncRead + Unpin),
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]).await? {
+            0 => break,
+            bytes_read => len += bytes_read,
+        }
+    }
+    Ok(len)
+}
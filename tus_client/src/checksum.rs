@@ -0,0 +1,35 @@
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+
+/// Enumerates the algorithms the client can checksum chunks with, for servers supporting the checksum extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// The name of the algorithm, as listed in the server's `Tus-Checksum-Algorithm` header and sent in `Upload-Checksum`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Computes the `Upload-Checksum` header of a chunk: the name of the algorithm and the base64 encoded digest of `data`, separated by a space.
+    pub(crate) fn upload_checksum(&self, data: &[u8]) -> String {
+        let digest = match self {
+            ChecksumAlgorithm::Sha1 => Sha1::digest(data).to_vec(),
+            ChecksumAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+        };
+        format!("{} {}", self.name(), base64::encode(&digest))
+    }
+}
+
+impl Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.name())
+    }
+}
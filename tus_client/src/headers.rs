@@ -22,11 +22,23 @@ pub const X_HTTP_METHOD_OVERRIDE: &'static str = "x-http-method-override";
 /// Use this header if its environment does not support the PATCH or DELETE methods.
 pub const CONTENT_TYPE: &'static str = "content-type";
 
-/// Use this header if its environment does not support the PATCH or DELETE methods.
-//pub const UPLOAD_DEFER_LENGTH: &'static str = "upload-defer-length";
+/// Indicates that the size of the upload is not known yet, and will be given by a later `Upload-Length`.
+pub const UPLOAD_DEFER_LENGTH: &'static str = "upload-defer-length";
 
 /// Use this header if its environment does not support the PATCH or DELETE methods.
 pub const UPLOAD_METADATA: &'static str = "upload-metadata";
 
+/// The checksum of the chunk in a `PATCH` request: the name of the algorithm and the base64 encoded digest, separated by a space.
+pub const UPLOAD_CHECKSUM: &'static str = "upload-checksum";
+
+/// A comma-separated list of the checksum algorithms supported by the server.
+pub const TUS_CHECKSUM_ALGORITHM: &'static str = "tus-checksum-algorithm";
+
+/// Marks an upload created as a part of a file, `partial`, or as the concatenation of partial uploads, `final;` followed by their URLs separated by spaces.
+pub const UPLOAD_CONCAT: &'static str = "upload-concat";
+
+/// When the server will expire an unfinished upload, as an RFC 7231 date.
+pub const UPLOAD_EXPIRES: &'static str = "upload-expires";
+
 /// Use this header if its environment does not support the PATCH or DELETE methods.
 pub const LOCATION: &'static str = "location";
@@ -1,12 +1,16 @@
+use crate::headers;
 use crate::Error;
 use std::collections::HashMap;
 use std::fmt;
 
+/// The version of the tus protocol used by the client.
+const TUS_VERSION: &str = "1.0.0";
+
 /// An alias for `HashMap<String, String>`, which represents a set of HTTP headers and their values.
 pub type Headers = HashMap<String, String>;
 
 /// Enumerates the HTTP methods used by `tus_client::Client`.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HttpMethod {
     Head,
     Patch,
@@ -42,12 +46,166 @@ pub trait HttpHandler {
     fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error>;
 }
 
+impl<T: HttpHandler + ?Sized> HttpHandler for &T {
+    fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+        (**self).handle_request(req)
+    }
+}
+
+/// The required trait used by `tus_client::AsyncClient` to represent a handler to execute `HttpRequest`s without blocking the calling thread, e.g. inside a tokio runtime.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncHttpHandler {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error>;
+}
+
+/// Builds a set of `Headers`. Header names are normalized to lowercase, so there is a single entry per header name whatever the casing it was given in.
+#[derive(Debug, Default, Clone)]
+pub struct HeadersBuilder {
+    headers: Headers,
+}
+
+impl HeadersBuilder {
+    /// Instantiates a new, empty `HeadersBuilder`.
+    pub fn new() -> Self {
+        HeadersBuilder {
+            headers: Headers::new(),
+        }
+    }
+
+    /// Instantiates a `HeadersBuilder` which adds to an existing set of headers. Headers whose names differ only in casing are merged with `append`.
+    pub fn from_headers(headers: Headers) -> Self {
+        let mut builder = HeadersBuilder::new();
+        for (key, value) in headers {
+            builder = builder.append(key, value);
+        }
+        builder
+    }
+
+    /// Sets the value of a header, replacing any existing value for the same header name.
+    pub fn insert(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers
+            .insert(key.into().to_ascii_lowercase(), value.into());
+        self
+    }
+
+    /// Adds a value to a header. If the header already has a different value, the values are combined into a comma-separated list, as HTTP does for repeated headers.
+    /// A single-valued header such as `Upload-Offset` then fails to parse rather than one of its values being silently chosen.
+    pub fn append(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let value = value.into();
+        let entry = self
+            .headers
+            .entry(key.into().to_ascii_lowercase())
+            .or_default();
+
+        if entry.is_empty() {
+            *entry = value;
+        } else if *entry != value {
+            entry.push_str(", ");
+            entry.push_str(&value);
+        }
+        self
+    }
+
+    /// Returns the value of a header, matching the header name case-insensitively.
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.headers.get(&key.to_ascii_lowercase())
+    }
+
+    /// Sets the `Tus-Resumable` header to the version of the protocol used.
+    pub fn tus_resumable(self, version: &str) -> Self {
+        self.insert(headers::TUS_RESUMABLE, version)
+    }
+
+    /// Sets the `Content-Type` header.
+    pub fn content_type(self, content_type: &str) -> Self {
+        self.insert(headers::CONTENT_TYPE, content_type)
+    }
+
+    /// Sets the `Upload-Offset` header to the byte offset the request's body starts at.
+    pub fn upload_offset(self, offset: usize) -> Self {
+        self.insert(headers::UPLOAD_OFFSET, offset.to_string())
+    }
+
+    /// Sets the `Upload-Length` header to the size of the entire upload in bytes.
+    pub fn upload_length(self, length: u64) -> Self {
+        self.insert(headers::UPLOAD_LENGTH, length.to_string())
+    }
+
+    /// Returns the built headers.
+    pub fn build(self) -> Headers {
+        self.headers
+    }
+}
+
+/// Returns a copy of `headers` with every header name normalized to lowercase, merging headers whose names differ only in casing.
+pub fn normalize_headers(headers: Headers) -> Headers {
+    HeadersBuilder::from_headers(headers).build()
+}
+
 /// Returns the default headers required to make requests to an tus enabled endpoint.
 pub fn default_headers() -> Headers {
-    let mut map = Headers::new();
-    map.insert(
-        String::from(crate::headers::TUS_RESUMABLE),
-        String::from("1.0.0"),
-    );
-    map
+    HeadersBuilder::new().tus_resumable(TUS_VERSION).build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HeaderMap;
+
+    #[test]
+    fn insert_keeps_a_single_entry_per_header_name() {
+        let headers = HeadersBuilder::new()
+            .insert("Upload-Offset", "10")
+            .insert("upload-offset", "20")
+            .insert("UPLOAD-OFFSET", "30")
+            .build();
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get_by_key("Upload-Offset"), Some(&"30".to_owned()));
+    }
+
+    #[test]
+    fn append_merges_mixed_case_duplicates() {
+        let headers = HeadersBuilder::new()
+            .append("Upload-Offset", "10")
+            .append("upload-offset", "20")
+            .build();
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["upload-offset"], "10, 20");
+        assert!(headers["upload-offset"].parse::<usize>().is_err());
+    }
+
+    #[test]
+    fn append_keeps_a_repeated_identical_value_once() {
+        let headers = HeadersBuilder::new()
+            .append("Tus-Resumable", "1.0.0")
+            .append("tus-resumable", "1.0.0")
+            .build();
+
+        assert_eq!(headers, default_headers());
+    }
+
+    #[test]
+    fn normalize_headers_merges_mixed_case_duplicates() {
+        let mut headers = Headers::new();
+        headers.insert(
+            "Location".to_owned(),
+            "https://tus.example/files/1".to_owned(),
+        );
+        headers.insert("upload-length".to_owned(), "100".to_owned());
+        headers.insert("Upload-Length".to_owned(), "200".to_owned());
+
+        let headers = normalize_headers(headers);
+
+        assert_eq!(headers.len(), 2);
+        assert_eq!(
+            headers.get_by_key("LOCATION"),
+            Some(&"https://tus.example/files/1".to_owned())
+        );
+        // The order of the merged values depends on the iteration order of the input
+        let upload_length = &headers["upload-length"];
+        assert!(upload_length == "100, 200" || upload_length == "200, 100");
+    }
 }
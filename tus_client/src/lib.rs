@@ -1,5 +1,10 @@
 #![doc(html_root_url = "https://docs.rs/tus_client/0.1.1")]
-use crate::http::{default_headers, Headers, HttpHandler, HttpMethod, HttpRequest};
+use crate::http::{
+    default_headers, normalize_headers, Headers, HeadersBuilder, HttpHandler, HttpMethod,
+    HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt::{Display, Formatter};
@@ -10,7 +15,12 @@ use std::num::ParseIntError;
 use std::ops::Deref;
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+mod checksum;
 mod headers;
 /// Contains the `HttpHandler` trait and related structs. This module is only relevant when implement `HttpHandler` manually.
 pub mod http;
@@ -18,13 +28,45 @@ pub mod http;
 #[cfg(feature = "reqwest")]
 mod reqwest;
 
-const DEFAULT_CHUNK_SIZE: usize = 5 * 1024 * 1024;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+
+/// The reqwest version whose `Client` implements `AsyncHttpHandler`, re-exported so that it matches the one `tus_client` was built with.
+#[cfg(feature = "reqwest")]
+pub use reqwest_async;
+
+/// Contains `MockTusServer`, an in-memory `HttpHandler` for testing code which uses `Client`. Enable the `test-util` feature to use it.
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+
+pub use checksum::ChecksumAlgorithm;
+
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 5 * 1024 * 1024;
+
+// How many parts `upload_parallel` uploads at a time
+const MAX_PARALLEL_PARTS: usize = 4;
+
+// How many times a chunk is sent in all when the server keeps answering that its checksum doesn't match
+const MAX_CHECKSUM_ATTEMPTS: u32 = 3;
+
+/// A callback receiving the bytes uploaded so far and the size of the upload, if known.
+pub type ProgressCallback<'a> = Box<dyn Fn(usize, Option<usize>) + 'a>;
+
+/// A callback receiving the number of the attempt about to be made, each time a chunk is retried.
+pub type RetryCallback<'a> = Box<dyn Fn(u32) + 'a>;
 
 /// Used to interact with a [tus](https://tus.io) endpoint.
 pub struct Client<'a> {
     use_method_override: bool,
     http_handler: Box<dyn HttpHandler + 'a>,
     auth_token: Option<String>,
+    progress_callback: Option<ProgressCallback<'a>>,
+    retry_callback: Option<RetryCallback<'a>>,
+    retries: Option<(u32, Duration)>,
+    checksum: Option<ChecksumAlgorithm>,
+    cancellation: Option<Arc<AtomicBool>>,
 }
 
 impl<'a> Client<'a> {
@@ -35,6 +77,11 @@ impl<'a> Client<'a> {
             use_method_override: false,
             http_handler: Box::new(http_handler),
             auth_token: None,
+            progress_callback: None,
+            retry_callback: None,
+            retries: None,
+            checksum: None,
+            cancellation: None,
         }
     }
 
@@ -44,6 +91,11 @@ impl<'a> Client<'a> {
             use_method_override: true,
             http_handler: Box::new(http_handler),
             auth_token: None,
+            progress_callback: None,
+            retry_callback: None,
+            retries: None,
+            checksum: None,
+            cancellation: None,
         }
     }
 
@@ -52,6 +104,131 @@ impl<'a> Client<'a> {
         self
     }
 
+    /// Sets a callback invoked after each chunk the server accepts, with the number of bytes uploaded so far and the size of the entire upload, if known. It is called once per chunk, the last time when the upload is complete.
+    ///
+    /// The size is always known to `upload` and `upload_with_chunk_size`; `upload_deferred` only knows it with the final chunk.
+    pub fn with_progress_callback(
+        mut self,
+        progress_callback: impl Fn(usize, Option<usize>) + 'a,
+    ) -> Self {
+        self.progress_callback = Some(Box::new(progress_callback));
+        self
+    }
+
+    /// Retries chunks that fail with a transient error, i.e. an IO, HTTP handler, connection or timeout error or a 500, 502, 503 or 504 response, up to `max_attempts` times in all, so `1` never retries. Before each retry the client waits, `base_delay` before the first retry and twice as long before each following one, then asks the server for the upload's offset with `get_info` and resumes from it.
+    ///
+    /// Other errors, such as a 409 for a wrong offset or a 413 for a file that is too large, are returned straight away, and once the attempts are exhausted the last error is returned unchanged. Retries apply to `upload` and `upload_with_chunk_size`; `upload_deferred` can't read a chunk again, so it never retries.
+    pub fn with_retries(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.retries = Some((max_attempts.max(1), base_delay));
+        self
+    }
+
+    /// Sets a callback invoked each time a chunk is retried after a transient error, see `with_retries`, with the number of the attempt about to be made, e.g. to count the retries of an upload.
+    pub fn with_retry_callback(mut self, retry_callback: impl Fn(u32) + 'a) -> Self {
+        self.retry_callback = Some(Box::new(retry_callback));
+        self
+    }
+
+    /// Sends an `Upload-Checksum` of each chunk, computed with `algorithm`, if the server supports the checksum extension. A server that finds the chunk corrupted answers with a 460, and the chunk is sent again, up to 3 times in all before `Error::ChecksumMismatch` is returned.
+    ///
+    /// Support is checked with `get_server_info` before each upload, so a server which doesn't list the extension, or lists the algorithms it supports in `Tus-Checksum-Algorithm` without `algorithm`, gets chunks without a checksum.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = Some(algorithm);
+        self
+    }
+
+    /// Sets a token to cancel uploads with: once it is set to `true`, e.g. from another thread, an upload stops before its next chunk and returns `Error::Cancelled`, without sending any further requests. What the server has received stays there, so the upload can be resumed later from the offset `get_info` reports.
+    pub fn with_cancellation(mut self, token: Arc<AtomicBool>) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Returns `Error::Cancelled` if the cancellation token is set.
+    fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancellation {
+            Some(token) if token.load(Ordering::SeqCst) => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the algorithm to checksum the chunks of an upload with: the one set with `with_checksum`, if the server supports it.
+    fn negotiate_checksum(&self, url: &str) -> Option<ChecksumAlgorithm> {
+        let algorithm = self.checksum?;
+        match self.get_server_info(url) {
+            Ok(info)
+                if info.extensions.contains(&TusExtension::Checksum)
+                    && info.checksum_algorithms.as_ref().is_none_or(|algorithms| {
+                        algorithms.iter().any(|a| a == algorithm.name())
+                    }) =>
+            {
+                Some(algorithm)
+            }
+            Ok(_) => {
+                println!(
+                    "upload: the server doesn't support {} checksums, uploading without them",
+                    algorithm
+                );
+                None
+            }
+            Err(e) => {
+                println!(
+                    "upload: failed to check the server's checksum support, uploading without checksums: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Handles an error uploading a chunk on attempt `attempt`: if it is transient and attempts remain, waits and fetches the offset to resume from with `get_info`, retrying that too on a transient error.
+    ///
+    /// # Returns
+    ///
+    /// The offset to resume the upload from, or the last error once it isn't transient or the attempts are exhausted.
+    fn resume_after_error(
+        &self,
+        url: &str,
+        error: Error,
+        attempt: &mut u32,
+    ) -> Result<usize, Error> {
+        let (max_attempts, base_delay) = self.retries.unwrap_or((1, Duration::default()));
+        let mut last_error = error;
+        loop {
+            if *attempt >= max_attempts || !last_error.is_transient() {
+                return Err(last_error);
+            }
+
+            self.check_cancelled()?;
+            let delay = base_delay.saturating_mul(1 << (*attempt - 1).min(16));
+            println!(
+                "upload: attempt {} of {} failed, retrying in {:?}: {}",
+                attempt, max_attempts, delay, last_error
+            );
+            thread::sleep(delay);
+            *attempt += 1;
+            self.report_retry(*attempt);
+
+            match self.get_info(url) {
+                Ok(info) => return Ok(info.bytes_uploaded),
+                Err(e) => last_error = e,
+            }
+        }
+    }
+
+    /// Invokes the retry callback, if one is set.
+    fn report_retry(&self, attempt: u32) {
+        if let Some(retry_callback) = &self.retry_callback {
+            retry_callback(attempt);
+        }
+    }
+
+    /// Invokes the progress callback, if one is set.
+    fn report_progress(&self, bytes_uploaded: usize, total_size: Option<usize>) {
+        if let Some(progress_callback) = &self.progress_callback {
+            progress_callback(bytes_uploaded, total_size);
+        }
+    }
+
     /// Retrieves information about an upload from the Tus server.
     ///
     /// # Arguments
@@ -60,48 +237,13 @@ impl<'a> Client<'a> {
     ///
     /// # Returns
     ///
-    /// A `Result` which is `Ok` if the upload information is successfully retrieved, otherwise `Err`.
+    /// A `Result` which is `Ok` if the upload information is successfully retrieved, otherwise `Err`: `Error::Gone` if the server has expired the upload, so it must be created again, or `Error::NotFoundError` if the server doesn't know it.
     pub fn get_info(&self, url: &str) -> Result<UploadInfo, Error> {
         let req = self.create_request(HttpMethod::Head, url, None, Some(default_headers()));
 
-        let response = self.http_handler.deref().handle_request(req)?;
-
-        let bytes_uploaded = match response.headers.get_by_key(headers::UPLOAD_OFFSET) {
-            Some(val) => val.parse::<usize>()?,
-            None => return Err(Error::NotFoundError),
-        };
-
-        let total_size = response
-            .headers
-            .get_by_key(headers::UPLOAD_LENGTH)
-            .and_then(|l| l.parse::<usize>().ok());
-
-        let metadata = response
-            .headers
-            .get_by_key(headers::UPLOAD_METADATA)
-            .and_then(|data| base64::decode(data).ok())
-            .and_then(|decoded| String::from_utf8(decoded).ok())
-            .map(|decoded_str| {
-                decoded_str
-                    .split(';')
-                    .filter_map(|key_val| {
-                        let mut parts = key_val.splitn(2, ':');
-                        let key = parts.next()?;
-                        let value = parts.next().map_or(String::new(), String::from);
-                        Some((String::from(key), value))
-                    })
-                    .collect::<HashMap<String, String>>()
-            });
+        let response = self.send(req)?;
 
-        if response.status_code.to_string().starts_with('4') {
-            return Err(Error::NotFoundError);
-        }
-
-        Ok(UploadInfo {
-            bytes_uploaded,
-            total_size,
-            metadata,
-        })
+        upload_info_from_response(&response)
     }
 
     /// Uploads a file to a given URL using the default chunk size.
@@ -124,36 +266,100 @@ impl<'a> Client<'a> {
     ///
     /// * `url` - The URL to upload the file to.
     /// * `path` - The path of the file to be uploaded.
-    /// * `chunk_size` - The size of each chunk to be uploaded.
+    /// * `chunk_size` - The size of each chunk to be uploaded. Must be greater than zero. Chunks are never larger than the file, so a `chunk_size` larger than the file only allocates a buffer the size of the file.
     ///
     /// # Returns
     ///
-    /// A `Result` which is `Ok` if the file is successfully uploaded, otherwise `Err`.
+    /// A `Result` which is `Ok` if the file is successfully uploaded, otherwise `Err`. Returns `Error::InvalidChunkSize` if `chunk_size` is zero.
     pub fn upload_with_chunk_size(
         &self,
         url: &str,
         path: &Path,
         chunk_size: usize,
     ) -> Result<(), Error> {
-        let info = self.get_info(url)?;
+        if chunk_size == 0 {
+            return Err(Error::InvalidChunkSize(chunk_size));
+        }
+
         let file = File::open(path)?;
-        let file_len = file.metadata()?.len();
+        let file_len = file.metadata()?.len() as usize;
+
+        self.upload_from_reader(url, &mut BufReader::new(file), file_len, chunk_size)
+    }
 
+    /// Uploads `len` bytes from a seekable reader, e.g. a `Cursor` over content in memory, to a given URL in chunks of a specified size. Like `upload_with_chunk_size`, the upload resumes from the offset the server reports, by seeking `reader` to it, so `reader` must start with the upload's content at offset 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL to upload the content to.
+    /// * `reader` - The content to upload.
+    /// * `len` - The size of the content, which must match the size of the upload the server reports, if any, since a reader can't tell its own size.
+    /// * `chunk_size` - The size of each chunk to be uploaded. Must be greater than zero.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the content is successfully uploaded, otherwise `Err`. Returns `Error::InvalidChunkSize` if `chunk_size` is zero and `Error::UnequalSizeError` if `len` isn't the size of the upload.
+    pub fn upload_from_reader<R: Read + Seek>(
+        &self,
+        url: &str,
+        reader: &mut R,
+        len: usize,
+        chunk_size: usize,
+    ) -> Result<(), Error> {
+        if chunk_size == 0 {
+            return Err(Error::InvalidChunkSize(chunk_size));
+        }
+
+        let checksum = self.negotiate_checksum(url);
+
+        self.upload_range(url, reader, 0, len, chunk_size, checksum, &|progress| {
+            self.report_progress(progress, Some(len))
+        })
+    }
+
+    /// Uploads `len` bytes of `reader`, starting at `start`, to an upload of that size, in chunks of a specified size, resuming from the offset the server reports. The chunking behind `upload_with_chunk_size` and each part of `upload_parallel`.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the upload.
+    /// * `reader` - The content to upload.
+    /// * `start` - The offset in `reader` the upload's content starts at.
+    /// * `len` - The size of the upload.
+    /// * `chunk_size` - The size of each chunk to be uploaded. Must be greater than zero.
+    /// * `checksum` - The algorithm to send the checksum of each chunk with, if any.
+    /// * `on_progress` - Called with the upload's offset after each chunk the server accepts.
+    #[allow(clippy::too_many_arguments)]
+    fn upload_range(
+        &self,
+        url: &str,
+        reader: &mut (impl Read + Seek),
+        start: u64,
+        len: usize,
+        chunk_size: usize,
+        checksum: Option<ChecksumAlgorithm>,
+        on_progress: &dyn Fn(usize),
+    ) -> Result<(), Error> {
+        let info = self.get_info(url)?;
         if let Some(total_size) = info.total_size {
-            if file_len as usize != total_size {
+            if len != total_size {
                 return Err(Error::UnequalSizeError);
             }
         }
 
-        let mut reader = BufReader::new(&file);
-        let mut buffer = vec![0; chunk_size];
+        let mut buffer = vec![0; chunk_size.min(len)];
         let mut progress = info.bytes_uploaded;
 
-        reader.seek(SeekFrom::Start(progress as u64))?;
+        reader.seek(SeekFrom::Start(start + progress as u64))?;
 
         let mut chunk_index = 0;
+        let mut attempt = 1;
+        let mut checksum_attempt = 1;
         loop {
-            let bytes_read = reader.read(&mut buffer)?;
+            self.check_cancelled()?;
+
+            // A chunk never reaches past the end of the range
+            let chunk_len = buffer.len().min(len.saturating_sub(progress));
+            let bytes_read = reader.read(&mut buffer[..chunk_len])?;
             if bytes_read == 0 {
                 return Err(Error::FileReadError);
             }
@@ -164,38 +370,275 @@ impl<'a> Client<'a> {
                 HttpMethod::Patch,
                 url,
                 Some(&buffer[..bytes_read]),
-                Some(create_upload_headers(progress)),
+                Some(create_chunk_headers(
+                    progress,
+                    &buffer[..bytes_read],
+                    checksum,
+                )),
             );
 
-            let response = self.http_handler.deref().handle_request(req)?;
+            let result = self
+                .send(req)
+                .and_then(|response| upload_offset_from_response(&response, progress + bytes_read));
+
+            match result {
+                Ok(new_progress) => {
+                    progress = new_progress;
+                    attempt = 1;
+                    checksum_attempt = 1;
+                    on_progress(progress);
+                }
+                // The server discarded the corrupted chunk, so it is sent again from the same offset
+                Err(Error::ChecksumMismatch) if checksum_attempt < MAX_CHECKSUM_ATTEMPTS => {
+                    println!(
+                        "upload: checksum mismatch on attempt {} of {}, sending the chunk again",
+                        checksum_attempt, MAX_CHECKSUM_ATTEMPTS
+                    );
+                    checksum_attempt += 1;
+                    reader.seek(SeekFrom::Start(start + progress as u64))?;
+                    continue;
+                }
+                Err(e) => {
+                    // The chunk may have been partly received, so it is sent again from the
+                    // offset the server reports
+                    progress = self.resume_after_error(url, e, &mut attempt)?;
+                    reader.seek(SeekFrom::Start(start + progress as u64))?;
+                }
+            }
 
-            if response.status_code == 409 {
-                return Err(Error::WrongUploadOffsetError);
+            if progress >= len {
+                break;
             }
 
-            if response.status_code == 404 {
-                return Err(Error::NotFoundError);
+            chunk_index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Uploads a file as several parts at once, with the concatenation extension: the file is split into `parts` byte ranges of about the same size, each uploaded to a partial upload of its own, and the server is asked to concatenate the partial uploads into a final upload. At most 4 parts are uploaded at a time, each in chunks of the default size like `upload`.
+    ///
+    /// The parts are uploaded from several threads, which send their requests with `http_handler` rather than the client's own handler, so that only the handler of parallel uploads has to be `Sync`. The progress callback is still only called from the calling thread.
+    ///
+    /// If the server doesn't support the concatenation extension, or `parts` is 1 or less, the file is created and uploaded as a single upload instead. A failed part fails the whole upload.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the Tus server.
+    /// * `path` - The path of the file to be uploaded.
+    /// * `parts` - The number of parts to split the file into. Files smaller than `parts` bytes are split into single bytes.
+    /// * `http_handler` - The handler the parts are uploaded with, typically another instance of the client's handler.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` with the URL of the final upload if the file is successfully uploaded, otherwise `Err`.
+    pub fn upload_parallel(
+        &self,
+        url: &str,
+        path: &Path,
+        parts: usize,
+        http_handler: &(dyn HttpHandler + Sync),
+    ) -> Result<String, Error> {
+        let file_len = std::fs::metadata(path)?.len() as usize;
+        let parts = parts.min(file_len);
+        let supports_concatenation = parts > 1
+            && self
+                .get_server_info(url)?
+                .extensions
+                .contains(&TusExtension::Concatenation);
+        if !supports_concatenation {
+            let upload_url = self.create(url, path)?;
+            self.upload(&upload_url, path)?;
+            return Ok(upload_url);
+        }
+
+        // Each part is the same size, except that the first ones get a byte of the remainder each
+        let ranges: Vec<(u64, usize)> = (0..parts)
+            .map(|part| {
+                let start = part * (file_len / parts) + part.min(file_len % parts);
+                let len = file_len / parts + usize::from(part < file_len % parts);
+                (start as u64, len)
+            })
+            .collect();
+
+        let mut part_urls = Vec::with_capacity(parts);
+        for (_, len) in ranges.iter() {
+            let headers = HeadersBuilder::from_headers(default_headers())
+                .upload_length(*len as u64)
+                .insert(headers::UPLOAD_CONCAT, "partial");
+            part_urls.push(self.create_with_headers(url, headers, HashMap::new())?);
+        }
+
+        // Each thread uploads with a client of its own, configured like this one but sending
+        // its requests with `http_handler`
+        let Client {
+            use_method_override,
+            auth_token,
+            retries,
+            checksum,
+            cancellation,
+            ..
+        } = self;
+        let part_client = |update_sender: mpsc::Sender<PartUpdate>| Client {
+            use_method_override: *use_method_override,
+            http_handler: Box::new(http_handler),
+            auth_token: auth_token.clone(),
+            progress_callback: None,
+            retry_callback: Some(Box::new(move |attempt| {
+                let _ = update_sender.send(PartUpdate::Retry(attempt));
+            })),
+            retries: *retries,
+            checksum: *checksum,
+            cancellation: cancellation.clone(),
+        };
+
+        let checksum = self.negotiate_checksum(url);
+        let (next_part, failure, ranges, part_urls) = (
+            &AtomicUsize::new(0),
+            &Mutex::new(None::<Error>),
+            &ranges,
+            &part_urls,
+        );
+        let (update_sender, update_receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..parts.min(MAX_PARALLEL_PARTS) {
+                let update_sender = update_sender.clone();
+                let part_client = &part_client;
+                scope.spawn(move || loop {
+                    let part = next_part.fetch_add(1, Ordering::SeqCst);
+                    if part >= parts || failure.lock().unwrap().is_some() {
+                        break;
+                    }
+
+                    // Each chunk sends how many bytes it added to the progress of the file
+                    let part_progress = Cell::new(0);
+                    let on_progress = |progress: usize| {
+                        let delta = progress.saturating_sub(part_progress.replace(progress));
+                        let _ = update_sender.send(PartUpdate::Progress(delta));
+                    };
+
+                    let (start, len) = ranges[part];
+                    let uploaded_part = File::open(path).map_err(Error::from).and_then(|file| {
+                        part_client(update_sender.clone()).upload_range(
+                            &part_urls[part],
+                            &mut BufReader::new(file),
+                            start,
+                            len,
+                            DEFAULT_CHUNK_SIZE,
+                            checksum,
+                            &on_progress,
+                        )
+                    });
+                    if let Err(e) = uploaded_part {
+                        failure.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+                });
             }
 
-            if response.status_code != 204 {
-                return Err(Error::UnexpectedStatusCode(response.status_code));
+            // The progress of the whole file is the sum of the progress of its parts, reported,
+            // like the retries of the parts, from this thread until every part has finished
+            drop(update_sender);
+            let mut uploaded = 0;
+            for update in update_receiver {
+                match update {
+                    PartUpdate::Progress(delta) => {
+                        uploaded += delta;
+                        self.report_progress(uploaded, Some(file_len));
+                    }
+                    PartUpdate::Retry(attempt) => self.report_retry(attempt),
+                }
             }
+        });
+        if let Some(e) = failure.lock().unwrap().take() {
+            return Err(e);
+        }
+
+        let headers = HeadersBuilder::from_headers(default_headers()).insert(
+            headers::UPLOAD_CONCAT,
+            format!("final;{}", part_urls.join(" ")),
+        );
+        self.create_with_headers(url, headers, HashMap::new())
+    }
 
-            let upload_offset = match response.headers.get_by_key(headers::UPLOAD_OFFSET) {
-                Some(offset) => Ok(offset),
-                None => Err(Error::MissingHeader(headers::UPLOAD_OFFSET.to_owned())),
-            }?;
+    /// Uploads the content of `reader` to an upload created with `create_with_deferred_length`, in chunks of a specified size, reading until the end of `reader`. The size of the upload is only known once the end is reached, so it is sent as `Upload-Length` with the final chunk. An empty `reader` is sent as a single empty chunk.
+    ///
+    /// `reader` must start at the upload's current offset, i.e. at the beginning for a new upload.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the upload.
+    /// * `reader` - The content to upload.
+    /// * `chunk_size` - The size of each chunk to be uploaded. Must be greater than zero.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` with the size of the entire upload if the content is successfully uploaded, otherwise `Err`. Returns `Error::InvalidChunkSize` if `chunk_size` is zero.
+    pub fn upload_deferred(
+        &self,
+        url: &str,
+        reader: &mut impl Read,
+        chunk_size: usize,
+    ) -> Result<usize, Error> {
+        if chunk_size == 0 {
+            return Err(Error::InvalidChunkSize(chunk_size));
+        }
 
-            progress = upload_offset.parse()?;
+        let info = self.get_info(url)?;
+        let mut progress = info.bytes_uploaded;
+        let checksum = self.negotiate_checksum(url);
 
-            if progress >= file_len as usize {
-                break;
+        // Each chunk is read before the previous one is sent, so that the final chunk is known
+        // to be final when it is sent
+        let mut chunk = vec![0; chunk_size];
+        let mut chunk_len = read_chunk(reader, &mut chunk)?;
+        let mut next_chunk = vec![0; chunk_size];
+        loop {
+            self.check_cancelled()?;
+
+            let next_chunk_len = if chunk_len == chunk_size {
+                read_chunk(reader, &mut next_chunk)?
+            } else {
+                0
+            };
+
+            let mut headers = HeadersBuilder::from_headers(create_chunk_headers(
+                progress,
+                &chunk[..chunk_len],
+                checksum,
+            ));
+            if next_chunk_len == 0 {
+                headers = headers.upload_length((progress + chunk_len) as u64);
+            }
+            let headers = headers.build();
+
+            // The chunk is still in memory, so a chunk the server found corrupted can be sent again
+            let mut checksum_attempt = 1;
+            progress = loop {
+                let req = self.create_request(
+                    HttpMethod::Patch,
+                    url,
+                    Some(&chunk[..chunk_len]),
+                    Some(headers.clone()),
+                );
+                let response = self.send(req)?;
+
+                match upload_offset_from_response(&response, progress + chunk_len) {
+                    Err(Error::ChecksumMismatch) if checksum_attempt < MAX_CHECKSUM_ATTEMPTS => {
+                        checksum_attempt += 1
+                    }
+                    result => break result?,
+                }
+            };
+            self.report_progress(progress, Some(progress).filter(|_| next_chunk_len == 0));
+
+            if next_chunk_len == 0 {
+                return Ok(progress);
             }
 
-            chunk_index += 1;
+            std::mem::swap(&mut chunk, &mut next_chunk);
+            chunk_len = next_chunk_len;
         }
-
-        Ok(())
     }
 
     /// Retrieves information about the server's Tus capabilities.
@@ -210,7 +653,7 @@ impl<'a> Client<'a> {
     pub fn get_server_info(&self, url: &str) -> Result<ServerInfo, Error> {
         let req = self.create_request(HttpMethod::Options, url, None, None);
 
-        let response = self.http_handler.deref().handle_request(req)?;
+        let response = self.send(req)?;
 
         if ![200_usize, 204].contains(&response.status_code) {
             return Err(Error::UnexpectedStatusCode(response.status_code));
@@ -228,8 +671,13 @@ impl<'a> Client<'a> {
             .headers
             .get_by_key(headers::TUS_EXTENSION)
             .map_or_else(Vec::new, |ext| {
+                // Extensions the enum doesn't model are kept as `Unknown`, so they can still be detected
                 ext.split(',')
-                    .filter_map(|e| e.parse().ok())
+                    .filter(|e| !e.trim().is_empty())
+                    .map(|e| {
+                        e.parse()
+                            .unwrap_or_else(|UnknownExtension(name)| TusExtension::Unknown(name))
+                    })
                     .collect::<Vec<TusExtension>>()
             });
 
@@ -238,10 +686,21 @@ impl<'a> Client<'a> {
             .get_by_key(headers::TUS_MAX_SIZE)
             .and_then(|h| h.parse::<usize>().ok());
 
+        let checksum_algorithms = response
+            .headers
+            .get_by_key(headers::TUS_CHECKSUM_ALGORITHM)
+            .map(|algorithms| {
+                algorithms
+                    .split(',')
+                    .map(|a| a.trim().to_lowercase())
+                    .collect::<Vec<String>>()
+            });
+
         Ok(ServerInfo {
             supported_versions,
             extensions,
             max_upload_size,
+            checksum_algorithms,
         })
     }
 
@@ -260,52 +719,152 @@ impl<'a> Client<'a> {
     ///
     /// # Returns
     ///
-    /// A `Result` which is `Ok` if the upload is successfully created, otherwise `Err`.
+    /// A `Result` which is `Ok` if the upload is successfully created, otherwise `Err`. Fails with `Error::InvalidMetadataKey` without sending the request if a key is empty, isn't printable ASCII or contains a space or comma.
     pub fn create_with_metadata(
         &self,
         url: &str,
         path: &Path,
         metadata: HashMap<String, String>,
     ) -> Result<String, Error> {
-        let mut headers = default_headers();
-        headers.insert(
-            headers::UPLOAD_LENGTH.to_owned(),
-            path.metadata()?.len().to_string(),
-        );
+        let headers =
+            HeadersBuilder::from_headers(default_headers()).upload_length(path.metadata()?.len());
+
+        self.create_with_headers(url, headers, metadata)
+    }
+
+    /// Creates a new upload with metadata and uploads a file to it, sending its first chunk in the body of the creation request if the server supports the `creation-with-upload` extension, which saves a round trip per upload: a file no larger than a chunk is uploaded with a single request. The rest of a larger file is uploaded in chunks like `upload` does. Without the extension, the upload is created with `create_with_metadata` and the file uploaded with `upload`.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the Tus server.
+    /// * `path` - The path of the file to be uploaded.
+    /// * `metadata` - A map of metadata to be associated with the upload.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` with the upload URL and the upload's offset after the creation request, i.e. the bytes the server accepted with it, 0 without the extension, if the file is successfully uploaded, otherwise `Err`.
+    pub fn create_with_upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: HashMap<String, String>,
+    ) -> Result<(String, usize), Error> {
+        let supports_creation_with_upload = self
+            .get_server_info(url)?
+            .extensions
+            .contains(&TusExtension::CreationWithUpload);
+        if !supports_creation_with_upload {
+            let upload_url = self.create_with_metadata(url, path, metadata)?;
+            self.upload(&upload_url, path)?;
+            return Ok((upload_url, 0));
+        }
+
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let mut chunk = vec![0; DEFAULT_CHUNK_SIZE.min(file_len)];
+        let chunk_len = read_chunk(&mut BufReader::new(file), &mut chunk)?;
+
+        let mut headers = HeadersBuilder::from_headers(default_headers())
+            .upload_length(file_len as u64)
+            .content_type("application/offset+octet-stream");
         if !metadata.is_empty() {
-            let data = metadata
-                .iter()
-                .map(|(key, value)| format!("{} {}", key, base64::encode(value)))
-                .collect::<Vec<_>>()
-                .join(",");
-            headers.insert(headers::UPLOAD_METADATA.to_owned(), data);
+            headers = headers.insert(headers::UPLOAD_METADATA, encode_metadata(&metadata)?);
         }
 
-        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+        let req = self.create_request(
+            HttpMethod::Post,
+            url,
+            Some(&chunk[..chunk_len]),
+            Some(headers.build()),
+        );
+        let response = self.send(req)?;
+        let upload_url = location_from_response(&response)?;
 
-        let response = self.http_handler.deref().handle_request(req)?;
+        // A server may accept less than the whole chunk, or leave out the offset if it accepted none
+        let offset = match response.headers.get_by_key(headers::UPLOAD_OFFSET) {
+            Some(offset) => offset.parse::<usize>()?,
+            None => 0,
+        };
+        self.report_progress(offset, Some(file_len));
 
-        if response.status_code == 413 {
-            return Err(Error::FileTooLarge);
+        if offset < file_len {
+            self.upload(&upload_url, path)?;
         }
 
-        if response.status_code != 201 {
-            return Err(Error::UnexpectedStatusCode(response.status_code));
+        Ok((upload_url, offset))
+    }
+
+    /// Creates a new upload whose size is not known yet, such as the output of a process that is still writing it, using the `creation-defer-length` extension. Upload its content with `upload_deferred`, which sets the size with the final chunk.
+    ///
+    /// Check that the server supports the extension first, with `get_server_info`.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the Tus server.
+    /// * `metadata` - A map of metadata to be associated with the upload.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` with the upload URL if the upload is successfully created, otherwise `Err`.
+    pub fn create_with_deferred_length(
+        &self,
+        url: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let headers = HeadersBuilder::from_headers(default_headers())
+            .insert(headers::UPLOAD_DEFER_LENGTH, "1");
+
+        self.create_with_headers(url, headers, metadata)
+    }
+
+    /// Sends the creation request of a new upload, with `headers` giving its length (or deferring it) and `metadata` encoded into `Upload-Metadata`.
+    fn create_with_headers(
+        &self,
+        url: &str,
+        mut headers: HeadersBuilder,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        if !metadata.is_empty() {
+            headers = headers.insert(headers::UPLOAD_METADATA, encode_metadata(&metadata)?);
         }
 
-        let location = response
-            .headers
-            .get_by_key(headers::LOCATION)
-            .ok_or_else(|| Error::MissingHeader(headers::LOCATION.to_owned()))?;
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers.build()));
 
-        Ok(location.to_owned())
+        let response = self.send(req)?;
+
+        location_from_response(&response)
+    }
+
+    /// Creates a new upload with metadata on the Tus server, declaring the MIME type of the file so that the server can store it and serve the file with it later.
+    ///
+    /// The content type is sent as the `filetype` metadata key, the key tus clients and servers use for it, replacing any `filetype` in `metadata`. It isn't sent as a `Content-Type` header, since the creation request has no body for the header to describe.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the Tus server.
+    /// * `path` - The path of the file to be uploaded.
+    /// * `metadata` - A map of metadata to be associated with the upload.
+    /// * `content_type` - The MIME type of the file, e.g. `video/mp4`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the upload is successfully created, otherwise `Err`.
+    pub fn create_with_content_type(
+        &self,
+        url: &str,
+        path: &Path,
+        mut metadata: HashMap<String, String>,
+        content_type: &str,
+    ) -> Result<String, Error> {
+        metadata.insert(String::from("filetype"), content_type.to_owned());
+        self.create_with_metadata(url, path, metadata)
     }
 
     /// Delete a file on the server.
     pub fn delete(&self, url: &str) -> Result<(), Error> {
         let req = self.create_request(HttpMethod::Delete, url, None, Some(default_headers()));
 
-        let response = self.http_handler.deref().handle_request(req)?;
+        let response = self.send(req)?;
 
         if response.status_code != 204 {
             return Err(Error::UnexpectedStatusCode(response.status_code));
@@ -314,6 +873,13 @@ impl<'a> Client<'a> {
         Ok(())
     }
 
+    /// Executes a request with the HTTP handler, normalizing the names of the response headers so that each header has a single entry.
+    fn send(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+        let mut response = self.http_handler.deref().handle_request(req)?;
+        response.headers = normalize_headers(response.headers);
+        Ok(response)
+    }
+
     /// Creates an HTTP request with the specified method, URL, body, and headers.
     ///
     /// # Arguments
@@ -333,32 +899,154 @@ impl<'a> Client<'a> {
         body: Option<&'b [u8]>,
         headers: Option<Headers>,
     ) -> HttpRequest<'b> {
-        let mut headers = headers.unwrap_or_default();
-
-        if let Some(auth_token) = &self.auth_token {
-            headers.insert("Authorization".to_owned(), format!("Bearer {}", auth_token));
-            //println!("{}", format!("Bearer {}", auth_token));
-        }
-
-        let method = if self.use_method_override {
-            headers.insert(
-                headers::X_HTTP_METHOD_OVERRIDE.to_owned(),
-                method.to_string(),
-            );
-            HttpMethod::Post
-        } else {
-            method
-        };
-
-        HttpRequest {
+        build_request(
             method,
-            url: String::from(url),
+            url,
             body,
             headers,
-        }
+            self.auth_token.as_deref(),
+            self.use_method_override,
+        )
+    }
+}
+
+/// Creates an HTTP request with the specified method, URL, body, and headers, adding the auth token and, for clients using the method override, the `X-HTTP-METHOD-OVERRIDE` header.
+pub(crate) fn build_request<'b>(
+    method: HttpMethod,
+    url: &str,
+    body: Option<&'b [u8]>,
+    headers: Option<Headers>,
+    auth_token: Option<&str>,
+    use_method_override: bool,
+) -> HttpRequest<'b> {
+    let mut headers = HeadersBuilder::from_headers(headers.unwrap_or_default());
+
+    if let Some(auth_token) = auth_token {
+        headers = headers.insert("Authorization", format!("Bearer {}", auth_token));
+        //println!("{}", format!("Bearer {}", auth_token));
+    }
+
+    let method = if use_method_override {
+        headers = headers.insert(headers::X_HTTP_METHOD_OVERRIDE, method.to_string());
+        HttpMethod::Post
+    } else {
+        method
+    };
+
+    HttpRequest {
+        method,
+        url: String::from(url),
+        body,
+        headers: headers.build(),
     }
 }
 
+/// Reads the `UploadInfo` from the response to a `HEAD` request for an upload.
+///
+/// Returns `Error::Gone` if the server has expired the upload, or `Error::NotFoundError` if the server doesn't know it.
+pub(crate) fn upload_info_from_response(response: &HttpResponse) -> Result<UploadInfo, Error> {
+    if response.status_code == 410 {
+        return Err(Error::Gone);
+    }
+
+    if response.status_code.to_string().starts_with('4') {
+        return Err(Error::NotFoundError);
+    }
+
+    // A server error says nothing about whether the upload exists, and may be transient
+    if response.status_code >= 500 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    let bytes_uploaded = match response.headers.get_by_key(headers::UPLOAD_OFFSET) {
+        Some(val) => val.parse::<usize>()?,
+        None => return Err(Error::NotFoundError),
+    };
+
+    let total_size = response
+        .headers
+        .get_by_key(headers::UPLOAD_LENGTH)
+        .and_then(|l| l.parse::<usize>().ok());
+
+    let metadata = response
+        .headers
+        .get_by_key(headers::UPLOAD_METADATA)
+        .map(|data| decode_metadata(data));
+
+    // An RFC 7231 date, e.g. `Wed, 25 Jun 2014 16:00:00 GMT`, is an RFC 2822 date with a `GMT` zone
+    let expires = response
+        .headers
+        .get_by_key(headers::UPLOAD_EXPIRES)
+        .and_then(|expires| DateTime::parse_from_rfc2822(expires.trim()).ok())
+        .map(|expires| expires.with_timezone(&Utc));
+
+    Ok(UploadInfo {
+        bytes_uploaded,
+        total_size,
+        metadata,
+        expires,
+    })
+}
+
+/// Reads the new `Upload-Offset` from the response to a `PATCH` request, which must be `expected_offset`, the offset the chunk ends at.
+///
+/// The offset must advance by exactly the bytes sent, otherwise a stale offset could loop forever and a skipped one would leave a gap in the upload.
+pub(crate) fn upload_offset_from_response(
+    response: &HttpResponse,
+    expected_offset: usize,
+) -> Result<usize, Error> {
+    if response.status_code == 409 {
+        return Err(Error::WrongUploadOffsetError);
+    }
+
+    if response.status_code == 404 {
+        return Err(Error::NotFoundError);
+    }
+
+    if response.status_code == 410 {
+        return Err(Error::Gone);
+    }
+
+    if response.status_code == 460 {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    if response.status_code != 204 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    let upload_offset = response
+        .headers
+        .get_by_key(headers::UPLOAD_OFFSET)
+        .ok_or_else(|| Error::MissingHeader(headers::UPLOAD_OFFSET.to_owned()))?;
+    let new_offset: usize = upload_offset.parse()?;
+    if new_offset != expected_offset {
+        return Err(Error::UnexpectedUploadOffset(expected_offset, new_offset));
+    }
+
+    Ok(new_offset)
+}
+
+/// Reads the upload URL from the response to a creation request.
+///
+/// Returns `Error::FileTooLarge` if the server rejected the size of the upload.
+pub(crate) fn location_from_response(response: &HttpResponse) -> Result<String, Error> {
+    if response.status_code == 413 {
+        return Err(Error::FileTooLarge);
+    }
+
+    if response.status_code != 201 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    let location = response
+        .headers
+        .get_by_key(headers::LOCATION)
+        .ok_or_else(|| Error::MissingHeader(headers::LOCATION.to_owned()))?;
+
+    Ok(location.to_owned())
+}
+
 /// Describes a file on the server.
 #[derive(Debug)]
 pub struct UploadInfo {
@@ -368,6 +1056,8 @@ pub struct UploadInfo {
     pub total_size: Option<usize>,
     /// Metadata supplied when the file was created.
     pub metadata: Option<HashMap<String, String>>,
+    /// When the server will expire the upload if it isn't finished, from the `Upload-Expires` header of servers supporting the expiration extension. `None` if the header is missing or isn't a valid date.
+    pub expires: Option<DateTime<Utc>>,
 }
 
 /// Describes the tus enabled server.
@@ -379,6 +1069,8 @@ pub struct ServerInfo {
     pub extensions: Vec<TusExtension>,
     /// The maximum supported total size of a file.
     pub max_upload_size: Option<usize>,
+    /// The checksum algorithms supported by the server, in lowercase, if it lists them.
+    pub checksum_algorithms: Option<Vec<String>>,
 }
 
 /// Enumerates the extensions to the tus protocol.
@@ -386,6 +1078,10 @@ pub struct ServerInfo {
 pub enum TusExtension {
     /// The server supports creating files.
     Creation,
+    /// The server supports creating files whose size is not known yet.
+    CreationDeferLength,
+    /// The server supports sending the first chunk of a file with the request creating it.
+    CreationWithUpload,
     //// The server supports setting expiration time on files and uploads.
     Expiration,
     /// The server supports verifying checksums of uploaded chunks.
@@ -394,19 +1090,36 @@ pub enum TusExtension {
     Termination,
     /// The server supports parallel uploads of a single file.
     Concatenation,
+    /// An extension this enum doesn't model, e.g. one newer than the client, by the name the server lists it with, in lowercase.
+    Unknown(String),
+}
+
+/// The error parsing a `TusExtension` from the name of an extension this enum doesn't model, which it carries in lowercase.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownExtension(pub String);
+
+impl Display for UnknownExtension {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "Unknown tus extension '{}'", self.0)
+    }
 }
 
+impl StdError for UnknownExtension {}
+
 impl FromStr for TusExtension {
-    type Err = ();
+    type Err = UnknownExtension;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.trim().to_lowercase().as_str() {
+        let name = s.trim().to_lowercase();
+        match name.as_str() {
             "creation" => Ok(TusExtension::Creation),
+            "creation-defer-length" => Ok(TusExtension::CreationDeferLength),
+            "creation-with-upload" => Ok(TusExtension::CreationWithUpload),
             "expiration" => Ok(TusExtension::Expiration),
             "checksum" => Ok(TusExtension::Checksum),
             "termination" => Ok(TusExtension::Termination),
             "concatenation" => Ok(TusExtension::Concatenation),
-            _ => Err(()),
+            _ => Err(UnknownExtension(name)),
         }
     }
 }
@@ -418,6 +1131,8 @@ pub enum Error {
     UnexpectedStatusCode(usize),
     /// The file specified was not found by the server.
     NotFoundError,
+    /// The upload existed, but the server has expired and purged it (a 410 response), so it must be created again from scratch.
+    Gone,
     /// A required header was missing from the server response.
     MissingHeader(String),
     /// An error occurred while doing disk IO. This may be while reading a file, or during a network call.
@@ -434,6 +1149,58 @@ pub enum Error {
     FileTooLarge,
     /// An error occurred in the HTTP handler.
     HttpHandlerError(String),
+    /// The HTTP handler couldn't connect to the server, e.g. because it is down or unreachable.
+    ConnectionError(String),
+    /// The request to the server timed out.
+    TimeoutError,
+    /// The chunk size given to `upload_with_chunk_size` is not valid.
+    InvalidChunkSize(usize),
+    /// The `Upload-Offset` returned after uploading a chunk (the second value) is not the offset the chunk ends at (the first value).
+    UnexpectedUploadOffset(usize, usize),
+    /// A metadata key is empty, isn't printable ASCII or contains a space or comma, which the `Upload-Metadata` header can't carry.
+    InvalidMetadataKey(String),
+    /// The server found that the checksum of a chunk didn't match its content (a 460 response), every time it was sent.
+    ChecksumMismatch,
+    /// The upload was cancelled with the token set by `with_cancellation`.
+    Cancelled,
+}
+
+/// Encodes metadata as an `Upload-Metadata` header: comma-separated `key value` pairs, with each value base64 encoded from its UTF-8 bytes. A key with an empty value is sent on its own, without the space.
+///
+/// Returns `Error::InvalidMetadataKey` for a key the tus spec doesn't allow: an empty key, one containing a space or comma, which couldn't be told apart from the separators, or one with characters other than printable ASCII, which an HTTP header can't carry.
+pub(crate) fn encode_metadata(metadata: &HashMap<String, String>) -> Result<String, Error> {
+    let mut pairs = Vec::with_capacity(metadata.len());
+    for (key, value) in metadata {
+        // Printable ASCII excludes spaces and control characters
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_graphic() && c != ',') {
+            return Err(Error::InvalidMetadataKey(key.to_owned()));
+        }
+
+        if value.is_empty() {
+            pairs.push(key.to_owned());
+        } else {
+            pairs.push(format!("{} {}", key, base64::encode(value)));
+        }
+    }
+
+    Ok(pairs.join(","))
+}
+
+/// Decodes an `Upload-Metadata` header into its keys and values. A key without a value has an empty value, and a pair whose value isn't base64 encoded UTF-8 is skipped.
+fn decode_metadata(data: &str) -> HashMap<String, String> {
+    data.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next().filter(|key| !key.is_empty())?;
+            let value = match parts.next().map(str::trim) {
+                Some(value) if !value.is_empty() => {
+                    String::from_utf8(base64::decode(value).ok()?).ok()?
+                }
+                _ => String::new(),
+            };
+            Some((key.to_owned(), value))
+        })
+        .collect()
 }
 
 /// Implements the `Display` trait for the `Error` enum.
@@ -445,6 +1212,7 @@ impl Display for Error {
         let message = match self {
             Error::UnexpectedStatusCode(status_code) => format!("The status code returned by the server was not one of the expected ones: {}", status_code),
             Error::NotFoundError => "The file specified was not found by the server".to_string(),
+            Error::Gone => "The upload has expired and was removed by the server".to_string(),
             Error::MissingHeader(header_name) => format!("The '{}' header was missing from the server response", header_name),
             Error::IoError(error) => format!("An error occurred while doing disk IO. This may be while reading a file, or during a network call: {}", error),
             Error::ParsingError(error) => format!("Unable to parse a value, which should be an integer: {}", error),
@@ -453,6 +1221,13 @@ impl Display for Error {
             Error::WrongUploadOffsetError => "The client tried to upload the file with an incorrect offset".to_string(),
             Error::FileTooLarge => "The specified file is larger that what is supported by the server".to_string(),
             Error::HttpHandlerError(message) => format!("An error occurred in the HTTP handler: {}", message),
+            Error::ConnectionError(message) => format!("Unable to connect to the server, which may be down or unreachable: {}", message),
+            Error::TimeoutError => "The request to the server timed out".to_string(),
+            Error::Cancelled => "The upload was cancelled".to_string(),
+            Error::InvalidChunkSize(chunk_size) => format!("The chunk size must be greater than zero, but was {}", chunk_size),
+            Error::UnexpectedUploadOffset(expected, received) => format!("The server returned an upload offset of {} after the chunk, but expected {}", received, expected),
+            Error::InvalidMetadataKey(key) => format!("The metadata key '{}' must be printable ASCII, not empty and without spaces or commas", key),
+            Error::ChecksumMismatch => "The server found that the checksum of the uploaded chunk didn't match its content".to_string(),
         };
 
         write!(f, "{}", message)?;
@@ -463,6 +1238,20 @@ impl Display for Error {
 
 impl StdError for Error {}
 
+impl Error {
+    /// Whether the error may be transient, so that the request is worth retrying: an IO, HTTP handler, connection or timeout error, e.g. a dropped connection, or a 500, 502, 503 or 504 response.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::IoError(_)
+            | Error::HttpHandlerError(_)
+            | Error::ConnectionError(_)
+            | Error::TimeoutError => true,
+            Error::UnexpectedStatusCode(status_code) => [500, 502, 503, 504].contains(status_code),
+            _ => false,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::IoError(e)
@@ -475,18 +1264,34 @@ impl From<ParseIntError> for Error {
     }
 }
 
-trait HeaderMap {
+pub(crate) trait HeaderMap {
     fn get_by_key(&self, key: &str) -> Option<&String>;
 }
 
 impl HeaderMap for HashMap<String, String> {
     fn get_by_key(&self, key: &str) -> Option<&String> {
-        self.keys()
-            .find(|k| k.to_lowercase().as_str() == key)
-            .and_then(|k| self.get(k))
+        self.get(&key.to_ascii_lowercase())
     }
 }
 
+/// Reads from `reader` until `buffer` is full or the end is reached, since a single read may return fewer bytes than are available.
+///
+/// # Returns
+///
+/// The number of bytes read, which is less than the size of `buffer` only at the end of `reader`.
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => len += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::IoError(e)),
+        }
+    }
+    Ok(len)
+}
+
 /// Creates HTTP headers for an upload request, including the current progress.
 ///
 /// # Arguments
@@ -496,12 +1301,442 @@ impl HeaderMap for HashMap<String, String> {
 /// # Returns
 ///
 /// A `Headers` object containing the created headers.
-fn create_upload_headers(progress: usize) -> Headers {
-    let mut headers = default_headers();
-    headers.insert(
-        headers::CONTENT_TYPE.to_owned(),
-        "application/offset+octet-stream".to_owned(),
-    );
-    headers.insert(headers::UPLOAD_OFFSET.to_owned(), progress.to_string());
-    headers
+pub(crate) fn create_upload_headers(progress: usize) -> Headers {
+    HeadersBuilder::from_headers(default_headers())
+        .content_type("application/offset+octet-stream")
+        .upload_offset(progress)
+        .build()
+}
+
+/// What a thread uploading a part of `upload_parallel` reports to the calling thread, which invokes the callbacks.
+enum PartUpdate {
+    /// The part's progress grew by this many bytes.
+    Progress(usize),
+    /// A chunk of the part is retried, with this attempt.
+    Retry(u32),
+}
+
+/// Creates HTTP headers for uploading a chunk at `progress`, with the `Upload-Checksum` of `chunk` if an algorithm is given.
+fn create_chunk_headers(
+    progress: usize,
+    chunk: &[u8],
+    checksum: Option<ChecksumAlgorithm>,
+) -> Headers {
+    let headers = HeadersBuilder::from_headers(create_upload_headers(progress));
+    match checksum {
+        Some(algorithm) => headers
+            .insert(headers::UPLOAD_CHECKSUM, algorithm.upload_checksum(chunk))
+            .build(),
+        None => headers.build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTusServer, MockUpload};
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use tempfile::NamedTempFile;
+
+    const UPLOAD_URL: &str = "https://tus.example/files/1";
+
+    /// Wraps a `MockTusServer`, letting a test alter its responses, e.g. to misbehave like a buggy server or proxy.
+    struct TamperingHandler<F> {
+        server: MockTusServer,
+        tamper: F,
+    }
+
+    impl<F: Fn(&HttpMethod, &mut HttpResponse)> HttpHandler for TamperingHandler<F> {
+        fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+            let method = req.method.clone();
+            let mut response = self.server.handle_request(req)?;
+            (self.tamper)(&method, &mut response);
+            Ok(response)
+        }
+    }
+
+    fn content() -> Vec<u8> {
+        (0..100).collect()
+    }
+
+    fn file_with(content: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    fn patch_count(server: &MockTusServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|method| *method == "Patch")
+            .count()
+    }
+
+    #[test]
+    fn upload_resumes_from_the_offset_the_server_reports() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                data: content[..40].to_vec(),
+                length: Some(content.len()),
+                ..MockUpload::default()
+            },
+        );
+
+        Client::new(server.clone())
+            .upload_with_chunk_size(UPLOAD_URL, file.path(), 10)
+            .unwrap();
+
+        assert_eq!(server.upload(UPLOAD_URL).unwrap().data, content);
+        // Only the 60 bytes the server didn't have were sent
+        assert_eq!(patch_count(&server), 6);
+    }
+
+    #[test]
+    fn upload_fails_when_the_server_rejects_its_offset() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                data: content[..40].to_vec(),
+                length: Some(content.len()),
+                ..MockUpload::default()
+            },
+        );
+        // A stale offset makes the client send its first chunk at an offset the server doesn't have
+        let handler = TamperingHandler {
+            server: server.clone(),
+            tamper: |method: &HttpMethod, response: &mut HttpResponse| {
+                if let HttpMethod::Head = method {
+                    response
+                        .headers
+                        .insert(headers::UPLOAD_OFFSET.to_ascii_lowercase(), "0".to_owned());
+                }
+            },
+        };
+
+        let result = Client::new(handler).upload_with_chunk_size(UPLOAD_URL, file.path(), 10);
+
+        assert!(matches!(result, Err(Error::WrongUploadOffsetError)));
+        assert_eq!(server.upload(UPLOAD_URL).unwrap().data, &content[..40]);
+    }
+
+    #[test]
+    fn upload_fails_when_the_file_size_doesnt_match_the_upload() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                length: Some(content.len() / 2),
+                ..MockUpload::default()
+            },
+        );
+
+        let result = Client::new(server.clone()).upload(UPLOAD_URL, file.path());
+
+        assert!(matches!(result, Err(Error::UnequalSizeError)));
+        assert_eq!(patch_count(&server), 0);
+    }
+
+    #[test]
+    fn get_info_tells_an_expired_upload_from_an_unknown_one() {
+        let server = MockTusServer::new();
+        server.insert_upload(UPLOAD_URL, MockUpload::default());
+        server.expire_upload(UPLOAD_URL);
+        let client = Client::new(server);
+
+        assert!(matches!(client.get_info(UPLOAD_URL), Err(Error::Gone)));
+        assert!(matches!(
+            client.get_info("https://tus.example/files/2"),
+            Err(Error::NotFoundError)
+        ));
+    }
+
+    #[test]
+    fn upload_fails_with_gone_when_the_upload_expires() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                length: Some(content.len()),
+                ..MockUpload::default()
+            },
+        );
+        // The server expires the upload once it has accepted the first chunk
+        let expiring = server.clone();
+        let handler = TamperingHandler {
+            server: server.clone(),
+            tamper: move |method: &HttpMethod, _: &mut HttpResponse| {
+                if matches!(method, HttpMethod::Patch) {
+                    expiring.expire_upload(UPLOAD_URL);
+                }
+            },
+        };
+
+        let result = Client::new(handler)
+            .with_retries(3, Duration::from_millis(0))
+            .upload_with_chunk_size(UPLOAD_URL, file.path(), 10);
+
+        assert!(matches!(result, Err(Error::Gone)));
+        // An expired upload isn't retried
+        assert_eq!(patch_count(&server), 2);
+    }
+
+    #[test]
+    fn created_upload_is_uploaded_in_full() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        let client = Client::new(server.clone());
+
+        let url = client
+            .create("https://tus.example/files", file.path())
+            .unwrap();
+        client
+            .upload_with_chunk_size(&url, file.path(), 30)
+            .unwrap();
+
+        let upload = server.upload(&url).unwrap();
+        assert_eq!(upload.length, Some(content.len()));
+        assert_eq!(upload.data, content);
+    }
+
+    #[test]
+    fn upload_fails_when_the_server_returns_a_wrong_offset() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                length: Some(content.len()),
+                ..MockUpload::default()
+            },
+        );
+        // A stale offset, as if the chunk hadn't been received
+        let handler = TamperingHandler {
+            server: server.clone(),
+            tamper: |method: &HttpMethod, response: &mut HttpResponse| {
+                if let HttpMethod::Patch = method {
+                    response
+                        .headers
+                        .insert(headers::UPLOAD_OFFSET.to_ascii_lowercase(), "0".to_owned());
+                }
+            },
+        };
+
+        let result = Client::new(handler).upload_with_chunk_size(UPLOAD_URL, file.path(), 10);
+
+        assert!(matches!(result, Err(Error::UnexpectedUploadOffset(10, 0))));
+        // The client stopped rather than sending the next chunk
+        assert_eq!(patch_count(&server), 1);
+    }
+
+    #[test]
+    fn upload_rejects_a_zero_chunk_size() {
+        let file = file_with(&content());
+        let server = MockTusServer::new();
+
+        let result = Client::new(server.clone()).upload_with_chunk_size(UPLOAD_URL, file.path(), 0);
+
+        assert!(matches!(result, Err(Error::InvalidChunkSize(0))));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn upload_sends_chunks_of_the_given_size() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                length: Some(content.len()),
+                ..MockUpload::default()
+            },
+        );
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&progress);
+
+        Client::new(server.clone())
+            .with_progress_callback(move |bytes_uploaded, total_size| {
+                reported.lock().unwrap().push((bytes_uploaded, total_size))
+            })
+            .upload_with_chunk_size(UPLOAD_URL, file.path(), 30)
+            .unwrap();
+
+        assert_eq!(server.upload(UPLOAD_URL).unwrap().data, content);
+        // The last chunk holds the remaining 10 bytes
+        assert_eq!(
+            *progress.lock().unwrap(),
+            vec![
+                (30, Some(100)),
+                (60, Some(100)),
+                (90, Some(100)),
+                (100, Some(100))
+            ]
+        );
+    }
+
+    #[test]
+    fn upload_retries_a_chunk_after_a_transient_error() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        server.insert_upload(
+            UPLOAD_URL,
+            MockUpload {
+                length: Some(content.len()),
+                ..MockUpload::default()
+            },
+        );
+        // The second chunk is answered with a 503, although the server stored it
+        let patches = Cell::new(0);
+        let handler = TamperingHandler {
+            server: server.clone(),
+            tamper: |method: &HttpMethod, response: &mut HttpResponse| {
+                if matches!(method, HttpMethod::Patch) {
+                    patches.set(patches.get() + 1);
+                    if patches.get() == 2 {
+                        response.status_code = 503;
+                    }
+                }
+            },
+        };
+        let attempts = RefCell::new(Vec::new());
+
+        Client::new(handler)
+            .with_retries(3, Duration::from_millis(0))
+            .with_retry_callback(|attempt| attempts.borrow_mut().push(attempt))
+            .upload_with_chunk_size(UPLOAD_URL, file.path(), 30)
+            .unwrap();
+
+        assert_eq!(server.upload(UPLOAD_URL).unwrap().data, content);
+        assert_eq!(attempts.into_inner(), vec![2]);
+        // The chunk was resumed from the offset the server reported rather than sent again
+        assert_eq!(patches.get(), 4);
+    }
+
+    #[test]
+    fn upload_parallel_uploads_parts_with_its_own_handler() {
+        let content = content();
+        let file = file_with(&content);
+        let server = MockTusServer::new();
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let reported = Rc::clone(&progress);
+        // Neither the client's handler nor its progress callback is `Sync`
+        let calls = Rc::new(Cell::new(0));
+        let client_handler = TamperingHandler {
+            server: server.clone(),
+            tamper: move |_: &HttpMethod, _: &mut HttpResponse| calls.set(calls.get() + 1),
+        };
+
+        let url = Client::new(client_handler)
+            .with_progress_callback(move |bytes_uploaded, total_size| {
+                reported.borrow_mut().push((bytes_uploaded, total_size))
+            })
+            .upload_parallel("https://tus.example/files", file.path(), 3, &server)
+            .unwrap();
+
+        assert_eq!(server.upload(&url).unwrap().data, content);
+        // Three partial uploads and the final one were created
+        let creations = server
+            .requests()
+            .iter()
+            .filter(|method| *method == "Post")
+            .count();
+        assert_eq!(creations, 4);
+        let progress = progress.borrow();
+        assert_eq!(progress.last(), Some(&(100, Some(100))));
+        assert!(progress.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn encode_metadata_sends_an_empty_value_as_a_key_only_entry() {
+        let encoded = encode_metadata(&metadata(&[("is_confidential", "")])).unwrap();
+
+        assert_eq!(encoded, "is_confidential");
+    }
+
+    #[test]
+    fn encode_metadata_base64_encodes_utf8_values() {
+        let encoded = encode_metadata(&metadata(&[("filename", "vidéo.mp4")])).unwrap();
+
+        assert_eq!(encoded, format!("filename {}", base64::encode("vidéo.mp4")));
+    }
+
+    #[test]
+    fn encode_metadata_rejects_invalid_keys() {
+        for key in ["", "file name", "file,name", "filé"] {
+            let result = encode_metadata(&metadata(&[(key, "value")]));
+
+            assert!(
+                matches!(&result, Err(Error::InvalidMetadataKey(invalid)) if invalid == key),
+                "key {:?} was accepted",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn decode_metadata_reads_key_only_and_empty_entries() {
+        let decoded = decode_metadata(&format!(
+            "filename {},is_confidential,empty ,  spaced {} ",
+            base64::encode("video.mp4"),
+            base64::encode("value")
+        ));
+
+        assert_eq!(
+            decoded,
+            metadata(&[
+                ("filename", "video.mp4"),
+                ("is_confidential", ""),
+                ("empty", ""),
+                ("spaced", "value"),
+            ])
+        );
+    }
+
+    #[test]
+    fn decode_metadata_skips_values_that_arent_base64_utf8() {
+        let decoded = decode_metadata(&format!(
+            "filename {},broken !!!,binary {}",
+            base64::encode("video.mp4"),
+            base64::encode(&[0xff, 0xfe])
+        ));
+
+        assert_eq!(decoded, metadata(&[("filename", "video.mp4")]));
+    }
+
+    #[test]
+    fn metadata_round_trips_through_get_info() {
+        let file = file_with(&content());
+        let sent = metadata(&[("filename", "video.mp4"), ("is_confidential", "")]);
+        let client = Client::new(MockTusServer::new());
+
+        let url = client
+            .create_with_metadata("https://tus.example/files", file.path(), sent.clone())
+            .unwrap();
+
+        assert_eq!(client.get_info(&url).unwrap().metadata, Some(sent));
+    }
 }
@@ -0,0 +1,334 @@
+use crate::headers;
+use crate::http::{Headers, HeadersBuilder, HttpHandler, HttpMethod, HttpRequest, HttpResponse};
+use crate::{ChecksumAlgorithm, Error};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// An upload stored by `MockTusServer`.
+#[derive(Debug, Clone, Default)]
+pub struct MockUpload {
+    /// The bytes received so far.
+    pub data: Vec<u8>,
+    /// The size of the entire upload, as given when it was created.
+    pub length: Option<usize>,
+    /// The raw `Upload-Metadata` header given when the upload was created.
+    pub metadata: Option<String>,
+    /// Whether the upload was created as a part of a file, to be concatenated by a final upload.
+    pub partial: bool,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    uploads: HashMap<String, MockUpload>,
+    /// URLs of uploads that have expired, answered with a 410.
+    expired: HashSet<String>,
+    next_id: usize,
+    requests: Vec<HttpMethod>,
+}
+
+/// An in-memory `HttpHandler` implementing the core tus protocol and the creation, creation-defer-length, creation-with-upload, checksum (with sha1 and sha256), concatenation and termination extensions, so that the behavior of `Client` can be tested without a live tus server.
+///
+/// Clones share the same state, so a clone can be kept to inspect or tamper with the uploads after the original has been moved into a `Client`.
+#[derive(Debug, Clone, Default)]
+pub struct MockTusServer {
+    state: Arc<Mutex<MockState>>,
+    max_size: Option<usize>,
+}
+
+impl MockTusServer {
+    /// Instantiates a new `MockTusServer` with no uploads.
+    pub fn new() -> Self {
+        MockTusServer::default()
+    }
+
+    /// Sets the maximum size of an upload the server accepts, reported as `Tus-Max-Size`. Creating a larger upload fails with a 413.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Returns a copy of the upload at `url`, if it exists.
+    pub fn upload(&self, url: &str) -> Option<MockUpload> {
+        self.state.lock().unwrap().uploads.get(url).cloned()
+    }
+
+    /// Adds or replaces the upload at `url`, e.g. to simulate a partially completed upload to be resumed.
+    pub fn insert_upload(&self, url: &str, upload: MockUpload) {
+        self.state
+            .lock()
+            .unwrap()
+            .uploads
+            .insert(url.to_owned(), upload);
+    }
+
+    /// Removes the upload at `url` as if it had expired, so that requests for it are answered with a 410 Gone rather than a 404.
+    pub fn expire_upload(&self, url: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.uploads.remove(url);
+        state.expired.insert(url.to_owned());
+    }
+
+    /// Returns the methods of the requests handled so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .map(HttpMethod::to_string)
+            .collect()
+    }
+
+    fn response(status_code: usize, headers: Headers) -> Result<HttpResponse, Error> {
+        Ok(HttpResponse {
+            headers,
+            status_code,
+        })
+    }
+
+    fn options(&self) -> Result<HttpResponse, Error> {
+        let mut response_headers = HeadersBuilder::new()
+            .insert(headers::TUS_VERSION, "1.0.0")
+            .insert(
+                headers::TUS_EXTENSION,
+                "creation,creation-defer-length,creation-with-upload,checksum,termination,concatenation",
+            )
+            .insert(headers::TUS_CHECKSUM_ALGORITHM, "sha1,sha256");
+        if let Some(max_size) = self.max_size {
+            response_headers = response_headers.insert(headers::TUS_MAX_SIZE, max_size.to_string());
+        }
+
+        Self::response(204, response_headers.build())
+    }
+
+    fn create(
+        &self,
+        state: &mut MockState,
+        req: &HeadersBuilder,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> Result<HttpResponse, Error> {
+        let concat = req.get(headers::UPLOAD_CONCAT).map(String::as_str);
+        if let Some(part_urls) = concat.and_then(|concat| concat.strip_prefix("final;")) {
+            return Self::concatenate(state, part_urls, url);
+        }
+
+        let length = match req.get(headers::UPLOAD_LENGTH).map(|l| l.parse::<usize>()) {
+            Some(Ok(length)) => Some(length),
+            None if req.get(headers::UPLOAD_DEFER_LENGTH).map(String::as_str) == Some("1") => None,
+            _ => return Self::response(400, Headers::new()),
+        };
+
+        if length.is_some_and(|length| self.max_size.is_some_and(|max_size| length > max_size)) {
+            return Self::response(413, Headers::new());
+        }
+
+        // With creation-with-upload, the body is the upload's first chunk
+        let data = body.unwrap_or_default().to_vec();
+        if !data.is_empty()
+            && req.get(headers::CONTENT_TYPE).map(String::as_str)
+                != Some("application/offset+octet-stream")
+        {
+            return Self::response(415, Headers::new());
+        }
+        if length.is_some_and(|length| data.len() > length) {
+            return Self::response(400, Headers::new());
+        }
+
+        state.next_id += 1;
+        let location = format!("{}/{}", url.trim_end_matches('/'), state.next_id);
+        state.uploads.insert(
+            location.clone(),
+            MockUpload {
+                length,
+                metadata: req.get(headers::UPLOAD_METADATA).cloned(),
+                partial: concat == Some("partial"),
+                data,
+            },
+        );
+        let offset = state.uploads[&location].data.len();
+
+        Self::response(
+            201,
+            HeadersBuilder::new()
+                .insert(headers::LOCATION, location)
+                .upload_offset(offset)
+                .build(),
+        )
+    }
+
+    /// Creates a final upload from the partial uploads at `part_urls`, separated by spaces, which must be complete.
+    fn concatenate(
+        state: &mut MockState,
+        part_urls: &str,
+        url: &str,
+    ) -> Result<HttpResponse, Error> {
+        let mut data = Vec::new();
+        for part_url in part_urls.split(' ').filter(|part_url| !part_url.is_empty()) {
+            match state.uploads.get(part_url) {
+                Some(part) if part.partial && part.length == Some(part.data.len()) => {
+                    data.extend_from_slice(&part.data)
+                }
+                _ => return Self::response(400, Headers::new()),
+            }
+        }
+
+        state.next_id += 1;
+        let location = format!("{}/{}", url.trim_end_matches('/'), state.next_id);
+        state.uploads.insert(
+            location.clone(),
+            MockUpload {
+                length: Some(data.len()),
+                data,
+                metadata: None,
+                partial: false,
+            },
+        );
+
+        Self::response(
+            201,
+            HeadersBuilder::new()
+                .insert(headers::LOCATION, location)
+                .build(),
+        )
+    }
+
+    fn head(state: &MockState, url: &str) -> Result<HttpResponse, Error> {
+        let upload = match state.uploads.get(url) {
+            Some(upload) => upload,
+            None => return Self::response(404, Headers::new()),
+        };
+
+        let mut response_headers = HeadersBuilder::new().upload_offset(upload.data.len());
+        response_headers = match upload.length {
+            Some(length) => response_headers.upload_length(length as u64),
+            None => response_headers.insert(headers::UPLOAD_DEFER_LENGTH, "1"),
+        };
+        if let Some(metadata) = &upload.metadata {
+            response_headers = response_headers.insert(headers::UPLOAD_METADATA, metadata.clone());
+        }
+
+        Self::response(200, response_headers.build())
+    }
+
+    fn patch(
+        state: &mut MockState,
+        req: &HeadersBuilder,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> Result<HttpResponse, Error> {
+        let upload = match state.uploads.get_mut(url) {
+            Some(upload) => upload,
+            None => return Self::response(404, Headers::new()),
+        };
+
+        if req.get(headers::CONTENT_TYPE).map(String::as_str)
+            != Some("application/offset+octet-stream")
+        {
+            return Self::response(415, Headers::new());
+        }
+
+        let offset = match req.get(headers::UPLOAD_OFFSET).map(|o| o.parse::<usize>()) {
+            Some(Ok(offset)) => offset,
+            _ => return Self::response(400, Headers::new()),
+        };
+
+        if offset != upload.data.len() {
+            return Self::response(409, Headers::new());
+        }
+
+        // A deferred length is set by the first request that gives one, and can't change after
+        match (
+            req.get(headers::UPLOAD_LENGTH).map(|l| l.parse::<usize>()),
+            upload.length,
+        ) {
+            (None, _) => {}
+            (Some(Ok(length)), None) if length >= offset => upload.length = Some(length),
+            (Some(Ok(length)), Some(existing)) if length == existing => {}
+            _ => return Self::response(400, Headers::new()),
+        }
+
+        let body = body.unwrap_or_default();
+        if let Some(checksum) = req.get(headers::UPLOAD_CHECKSUM) {
+            let algorithm = match checksum.split(' ').next() {
+                Some("sha1") => ChecksumAlgorithm::Sha1,
+                Some("sha256") => ChecksumAlgorithm::Sha256,
+                _ => return Self::response(400, Headers::new()),
+            };
+            if algorithm.upload_checksum(body) != *checksum {
+                return Self::response(460, Headers::new());
+            }
+        }
+
+        if upload
+            .length
+            .is_some_and(|length| offset + body.len() > length)
+        {
+            return Self::response(400, Headers::new());
+        }
+
+        upload.data.extend_from_slice(body);
+
+        Self::response(
+            204,
+            HeadersBuilder::new()
+                .upload_offset(upload.data.len())
+                .build(),
+        )
+    }
+
+    fn delete(state: &mut MockState, url: &str) -> Result<HttpResponse, Error> {
+        match state.uploads.remove(url) {
+            Some(_) => Self::response(204, Headers::new()),
+            None => Self::response(404, Headers::new()),
+        }
+    }
+}
+
+impl HttpHandler for MockTusServer {
+    fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+        let req_headers = HeadersBuilder::from_headers(req.headers);
+        let method = match req_headers
+            .get(headers::X_HTTP_METHOD_OVERRIDE)
+            .map(String::as_str)
+        {
+            Some("Head") => HttpMethod::Head,
+            Some("Patch") => HttpMethod::Patch,
+            Some("Options") => HttpMethod::Options,
+            Some("Delete") => HttpMethod::Delete,
+            _ => req.method,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(method.clone());
+
+        if !matches!(method, HttpMethod::Options)
+            && req_headers.get(headers::TUS_RESUMABLE).map(String::as_str) != Some("1.0.0")
+        {
+            return Self::response(412, Headers::new());
+        }
+
+        if !matches!(method, HttpMethod::Options | HttpMethod::Post)
+            && state.expired.contains(&req.url)
+        {
+            return Self::response(410, Headers::new());
+        }
+
+        match method {
+            HttpMethod::Options => self.options(),
+            HttpMethod::Post => self.create(&mut state, &req_headers, &req.url, req.body),
+            HttpMethod::Head => Self::head(&state, &req.url),
+            HttpMethod::Patch => Self::patch(&mut state, &req_headers, &req.url, req.body),
+            HttpMethod::Delete => Self::delete(&mut state, &req.url),
+        }
+    }
+}
+
+/// Handles requests in memory like the blocking handler, so that the behavior of `AsyncClient` can be tested too.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+impl crate::http::AsyncHttpHandler for MockTusServer {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+        HttpHandler::handle_request(self, req)
+    }
+}
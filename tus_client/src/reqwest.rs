@@ -1,8 +1,9 @@
-use crate::http::{HttpHandler, HttpMethod, HttpRequest, HttpResponse};
+use crate::http::{
+    AsyncHttpHandler, HeadersBuilder, HttpHandler, HttpMethod, HttpRequest, HttpResponse,
+};
 use crate::Error;
 use reqwest::header::{HeaderMap, HeaderName};
 use reqwest::Method;
-use std::collections::HashMap;
 use std::str::FromStr;
 
 impl HttpHandler for reqwest::Client {
@@ -27,12 +28,12 @@ impl HttpHandler for reqwest::Client {
 
         let response = match builder.send() {
             Ok(resp) => resp,
-            Err(err) => return Err(Error::HttpHandlerError(err.to_string())),
+            Err(err) => return Err(handler_error(err)),
         };
 
-        let mut headers = HashMap::new();
+        let mut headers = HeadersBuilder::new();
         for (key, value) in response.headers() {
-            headers.insert(
+            headers = headers.append(
                 key.to_string(),
                 value.to_str().map(String::from).unwrap_or_default(),
             );
@@ -40,7 +41,78 @@ impl HttpHandler for reqwest::Client {
 
         Ok(HttpResponse {
             status_code: response.status().as_u16() as usize,
-            headers,
+            headers: headers.build(),
         })
     }
 }
+
+#[async_trait::async_trait]
+impl AsyncHttpHandler for reqwest_async::Client {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+        let mut headers = reqwest_async::header::HeaderMap::new();
+        for (key, value) in req.headers {
+            let name = reqwest_async::header::HeaderName::from_str(&key)
+                .map_err(|e| Error::HttpHandlerError(e.to_string()))?;
+            let value = value
+                .parse()
+                .map_err(|e: reqwest_async::header::InvalidHeaderValue| {
+                    Error::HttpHandlerError(e.to_string())
+                })?;
+            headers.insert(name, value);
+        }
+
+        let method = match req.method {
+            HttpMethod::Head => reqwest_async::Method::HEAD,
+            HttpMethod::Patch => reqwest_async::Method::PATCH,
+            HttpMethod::Options => reqwest_async::Method::OPTIONS,
+            HttpMethod::Post => reqwest_async::Method::POST,
+            HttpMethod::Delete => reqwest_async::Method::DELETE,
+        };
+        let mut builder = self.request(method, &req.url).headers(headers);
+
+        if let Some(body) = req.body {
+            builder = builder.body(Vec::from(body));
+        }
+
+        let response = builder.send().await.map_err(async_handler_error)?;
+
+        let mut headers = HeadersBuilder::new();
+        for (key, value) in response.headers() {
+            headers = headers.append(
+                key.to_string(),
+                value.to_str().map(String::from).unwrap_or_default(),
+            );
+        }
+
+        Ok(HttpResponse {
+            status_code: response.status().as_u16() as usize,
+            headers: headers.build(),
+        })
+    }
+}
+
+/// Maps an error sending a request to `Error::ConnectionError` if the server couldn't be connected to, `Error::TimeoutError` if it timed out, and `Error::HttpHandlerError` otherwise. reqwest 0.9 can't tell connection errors itself, but the hyper error behind them can.
+fn handler_error(err: reqwest::Error) -> Error {
+    let is_connect = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<hyper::Error>())
+        .is_some_and(hyper::Error::is_connect);
+    if is_connect {
+        Error::ConnectionError(err.to_string())
+    } else if err.is_timeout() {
+        Error::TimeoutError
+    } else {
+        Error::HttpHandlerError(err.to_string())
+    }
+}
+
+/// Maps an error sending a request with the async client like `handler_error` does. A connection attempt that timed out is a connection error.
+fn async_handler_error(err: reqwest_async::Error) -> Error {
+    if err.is_connect() {
+        Error::ConnectionError(err.to_string())
+    } else if err.is_timeout() {
+        Error::TimeoutError
+    } else {
+        Error::HttpHandlerError(err.to_string())
+    }
+}